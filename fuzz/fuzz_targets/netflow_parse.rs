@@ -0,0 +1,14 @@
+#![no_main]
+
+use std::net::SocketAddr;
+use libfuzzer_sys::fuzz_target;
+use multiflow::netflow_parse::NetflowParser;
+
+fuzz_target!(|data: &[u8]| {
+	let addr: SocketAddr = "192.0.2.1:2055".parse().unwrap();
+	let mut parser = NetflowParser::new();
+
+	// The second pass parses data flow sets with the templates registered by the first
+	let _ = parser.parse(data, &addr);
+	let _ = parser.parse(data, &addr);
+});
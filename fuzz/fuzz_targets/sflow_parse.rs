@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use multiflow::sflow_parse::datagram::parse_sflow_data;
+
+fuzz_target!(|data: &[u8]| {
+	let _ = parse_sflow_data(data);
+});
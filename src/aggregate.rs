@@ -0,0 +1,217 @@
+//! Flow aggregation over tumbling time windows
+//!
+//! An [Aggregator] sums up the bytes, packets, and flows of [FlowRecord]s sharing the same [FlowKey], made up of a
+//! configurable set of [KeyField]s. Time is split into fixed windows aligned to the Unix epoch; when a record for a
+//! later window arrives (or [Aggregator::tick] notices the window has passed), the finished window is handed to the
+//! flush callback as an [AggregatedWindow]
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::flow::FlowRecord;
+
+/// Flow field to aggregate by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum KeyField {
+	Exporter,
+	/// Source address, masked to the given IPv4 and IPv6 prefix lengths
+	SrcPrefix { v4: u8, v6: u8 },
+	/// Destination address, masked to the given IPv4 and IPv6 prefix lengths
+	DstPrefix { v4: u8, v6: u8 },
+	SrcPort,
+	DstPort,
+	Protocol,
+	Tos,
+	SrcAs,
+	DstAs,
+	InputIf,
+	OutputIf,
+}
+
+/// Aggregation key holding the selected fields of a flow, with all other fields unset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FlowKey {
+	pub exporter: Option<IpAddr>,
+	pub src_prefix: Option<(IpAddr, u8)>,
+	pub dst_prefix: Option<(IpAddr, u8)>,
+	pub src_port: Option<u16>,
+	pub dst_port: Option<u16>,
+	pub protocol: Option<u8>,
+	pub tos: Option<u8>,
+	pub src_as: Option<u32>,
+	pub dst_as: Option<u32>,
+	pub input_if: Option<u32>,
+	pub output_if: Option<u32>,
+}
+
+/// Mask `addr` to a prefix of `v4` or `v6` bits, depending on its family
+fn mask_addr(addr: IpAddr, v4: u8, v6: u8) -> (IpAddr, u8) {
+	match addr {
+		IpAddr::V4(a) => {
+			let len = v4.min(32);
+			let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+			(IpAddr::V4((u32::from(a) & mask).into()), len)
+		}
+		IpAddr::V6(a) => {
+			let len = v6.min(128);
+			let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+			(IpAddr::V6((u128::from(a) & mask).into()), len)
+		}
+	}
+}
+
+/// Get the start of the window of length `window` containing `at`, with windows aligned to the Unix epoch
+pub(crate) fn window_start(at: SystemTime, window: Duration) -> SystemTime {
+	let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+	let window = window.as_nanos().max(1);
+	let start = since_epoch - since_epoch % window;
+
+	UNIX_EPOCH + Duration::new((start / 1_000_000_000) as u64, (start % 1_000_000_000) as u32)
+}
+
+impl FlowKey {
+	/// Build the key of `record` from the given `fields`
+	pub fn from_record(record: &FlowRecord, fields: &[KeyField]) -> Self {
+		let mut key = Self::default();
+
+		for field in fields {
+			match *field {
+				KeyField::Exporter => key.exporter = Some(record.exporter),
+				KeyField::SrcPrefix { v4, v6 } => key.src_prefix = record.src_addr.map(|a| mask_addr(a, v4, v6)),
+				KeyField::DstPrefix { v4, v6 } => key.dst_prefix = record.dst_addr.map(|a| mask_addr(a, v4, v6)),
+				KeyField::SrcPort => key.src_port = record.src_port,
+				KeyField::DstPort => key.dst_port = record.dst_port,
+				KeyField::Protocol => key.protocol = record.protocol,
+				KeyField::Tos => key.tos = record.tos,
+				KeyField::SrcAs => key.src_as = record.src_as,
+				KeyField::DstAs => key.dst_as = record.dst_as,
+				KeyField::InputIf => key.input_if = record.input_if,
+				KeyField::OutputIf => key.output_if = record.output_if,
+			}
+		}
+
+		key
+	}
+}
+
+/// Summed up traffic counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FlowCounters {
+	pub bytes: u64,
+	pub packets: u64,
+	pub flows: u64,
+}
+
+impl FlowCounters {
+	/// Add the counters of `record`, multiplied by its sampling rate if `scale` is set
+	pub fn add(&mut self, record: &FlowRecord, scale: bool) {
+		let factor = if scale { record.sampling_rate.max(1) as u64 } else { 1 };
+
+		self.bytes = self.bytes.saturating_add(record.bytes.saturating_mul(factor));
+		self.packets = self.packets.saturating_add(record.packets.saturating_mul(factor));
+		self.flows = self.flows.saturating_add(record.flows);
+	}
+}
+
+/// Result of a finished aggregation window
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AggregatedWindow {
+	pub start: SystemTime,
+	pub end: SystemTime,
+	/// Counters per key, in no particular order
+	pub entries: Vec<(FlowKey, FlowCounters)>,
+	/// Counters of flows with keys that did not fit into the key limit
+	pub overflow: FlowCounters,
+}
+
+/// Aggregator summing up flows by key over tumbling time windows
+pub struct Aggregator<F: FnMut(AggregatedWindow)> {
+	fields: Vec<KeyField>,
+	window: Duration,
+	max_keys: usize,
+	scale_sampling: bool,
+	start: Option<SystemTime>,
+	entries: HashMap<FlowKey, FlowCounters>,
+	overflow: FlowCounters,
+	flush: F,
+}
+
+impl<F: FnMut(AggregatedWindow)> Aggregator<F> {
+	/// Create an aggregator keying flows by `fields` over windows of length `window`, calling `flush` with every
+	/// finished window
+	///
+	/// By default, up to 100000 keys are tracked per window and counters are scaled by the sampling rate
+	pub fn new(fields: Vec<KeyField>, window: Duration, flush: F) -> Self {
+		Self {
+			fields,
+			window: window.max(Duration::from_millis(1)),
+			max_keys: 100_000,
+			scale_sampling: true,
+			start: None,
+			entries: HashMap::new(),
+			overflow: FlowCounters::default(),
+			flush,
+		}
+	}
+
+	/// Set the maximum number of keys tracked per window; flows with further keys are summed up as overflow
+	pub fn with_max_keys(mut self, max_keys: usize) -> Self {
+		self.max_keys = max_keys;
+		self
+	}
+
+	/// Set whether bytes and packets are multiplied by the sampling rate of each record
+	pub fn with_sampling_scaled(mut self, scale: bool) -> Self {
+		self.scale_sampling = scale;
+		self
+	}
+
+	/// Add a flow observed at time `at`
+	///
+	/// Flows belonging to an earlier window than the current one are added to the current window
+	pub fn add(&mut self, record: &FlowRecord, at: SystemTime) {
+		self.tick(at);
+		if self.start.is_none() {
+			self.start = Some(window_start(at, self.window));
+		}
+
+		let key = FlowKey::from_record(record, &self.fields);
+		let scale = self.scale_sampling;
+		if let Some(counters) = self.entries.get_mut(&key) {
+			counters.add(record, scale);
+		} else if self.entries.len() < self.max_keys {
+			self.entries.entry(key).or_default().add(record, scale);
+		} else {
+			self.overflow.add(record, scale);
+		}
+	}
+
+	/// Flush the current window if it has ended at `now`
+	pub fn tick(&mut self, now: SystemTime) {
+		if self.start.is_some_and(|start| now >= start + self.window) {
+			self.flush();
+		}
+	}
+
+	/// Flush the current window immediately, even if it has not ended yet
+	pub fn flush(&mut self) {
+		let Some(start) = self.start.take() else { return };
+
+		let window = AggregatedWindow {
+			start,
+			end: start + self.window,
+			entries: self.entries.drain().collect(),
+			overflow: std::mem::take(&mut self.overflow),
+		};
+		(self.flush)(window);
+	}
+
+	/// Get the number of keys in the current window
+	pub fn key_count(&self) -> usize {
+		self.entries.len()
+	}
+}
@@ -0,0 +1,327 @@
+//! Bidirectional flow stitching
+//!
+//! NetFlow v5/v9 records describe a single direction. [BiflowStitcher] pairs each flow with the flow in the opposite
+//! direction (same exporter and protocol, addresses and ports swapped, overlapping in time) into a [BiflowRecord] with
+//! an initiator and a responder side, like the biflows of RFC 5103. Flows that find no partner in time are released on
+//! their own, with empty reverse counters.
+//!
+//! Exporters can also send biflows natively, with RFC 5103 reverse fields: enterprise-specific fields of
+//! [REVERSE_PEN] holding the reverse direction's values. These are parsed with
+//! [NetflowParser::with_enterprise_fields](crate::netflow_parse::NetflowParser::with_enterprise_fields), and
+//! [BiflowRecord::from_rfc5103_record] turns such a record into a biflow without stitching
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use crate::flow::FlowRecord;
+use crate::netflow_parse::datagram_v9_data::{NetflowV9DataField, NetflowV9DataValue};
+
+pub use crate::netflow_parse::datagram_v9_template::REVERSE_PEN;
+
+/// Check whether `field` is a reverse field of an RFC 5103 biflow record, holding a value of the reverse direction
+pub fn is_reverse_field(field: &NetflowV9DataField) -> bool {
+	field.enterprise == Some(REVERSE_PEN)
+}
+
+/// Traffic of one direction of a biflow
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DirectionCounters {
+	pub bytes: u64,
+	pub packets: u64,
+	pub flows: u64,
+	pub tcp_flags: Option<u8>,
+}
+
+impl DirectionCounters {
+	fn add(&mut self, record: &FlowRecord) {
+		self.bytes = self.bytes.saturating_add(record.bytes);
+		self.packets = self.packets.saturating_add(record.packets);
+		self.flows = self.flows.saturating_add(record.flows);
+		self.tcp_flags = match (self.tcp_flags, record.tcp_flags) {
+			(Some(a), Some(b)) => Some(a | b),
+			(a, b) => a.or(b),
+		};
+	}
+}
+
+/// A bidirectional flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BiflowRecord {
+	pub exporter: IpAddr,
+	pub protocol: Option<u8>,
+	pub initiator_addr: Option<IpAddr>,
+	pub initiator_port: Option<u16>,
+	pub responder_addr: Option<IpAddr>,
+	pub responder_port: Option<u16>,
+	/// Exporter uptime in milliseconds when the first packet in either direction was seen
+	pub start: Option<u32>,
+	/// Exporter uptime in milliseconds when the last packet in either direction was seen
+	pub end: Option<u32>,
+	/// Traffic from the initiator to the responder
+	pub forward: DirectionCounters,
+	/// Traffic from the responder to the initiator
+	pub reverse: DirectionCounters,
+}
+
+fn min_opt(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+	match (a, b) {
+		(Some(a), Some(b)) => Some(a.min(b)),
+		(a, b) => a.or(b),
+	}
+}
+
+fn max_opt(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+	a.max(b)
+}
+
+impl BiflowRecord {
+	/// Create a biflow with `initiator` as its forward direction and no reverse traffic yet
+	pub fn from_forward(initiator: &FlowRecord) -> Self {
+		let mut forward = DirectionCounters::default();
+		forward.add(initiator);
+
+		Self {
+			exporter: initiator.exporter,
+			protocol: initiator.protocol,
+			initiator_addr: initiator.src_addr,
+			initiator_port: initiator.src_port,
+			responder_addr: initiator.dst_addr,
+			responder_port: initiator.dst_port,
+			start: initiator.start,
+			end: initiator.end,
+			forward,
+			reverse: DirectionCounters::default(),
+		}
+	}
+
+	/// Add the traffic of `record` in the reverse direction
+	pub fn add_reverse(&mut self, record: &FlowRecord) {
+		self.reverse.add(record);
+		self.start = min_opt(self.start, record.start);
+		self.end = max_opt(self.end, record.end);
+	}
+
+	/// Build a biflow from the fields of a NetFlow v9 data record holding RFC 5103 reverse fields, or `None` if it has
+	/// none
+	///
+	/// The flow key and the forward direction come from the regular fields. Only the reverse counters, TCP flags, and
+	/// timestamps are taken from the reverse fields
+	pub fn from_rfc5103_record(exporter: IpAddr, fields: &[NetflowV9DataField]) -> Option<Self> {
+		if !fields.iter().any(is_reverse_field) {
+			return None;
+		}
+		let mut biflow = Self::from_forward(&FlowRecord::from_netflow_v9_record(exporter, fields));
+
+		let mut counters = DirectionCounters::default();
+		let (mut start, mut end) = (None, None);
+		for field in fields.iter().filter(|f| is_reverse_field(f)) {
+			let NetflowV9DataValue::Number(n) = field.value else { continue };
+			match field.type_id {
+				1 => counters.bytes = n,
+				2 => counters.packets = n,
+				3 => counters.flows = n,
+				6 => counters.tcp_flags = Some(n as u8),
+				21 => end = Some(n as u32),
+				22 => start = Some(n as u32),
+				_ => {}
+			}
+		}
+		if counters.packets > 0 && counters.flows == 0 {
+			counters.flows = 1;
+		}
+
+		biflow.reverse = counters;
+		biflow.start = min_opt(biflow.start, start);
+		biflow.end = max_opt(biflow.end, end);
+		Some(biflow)
+	}
+}
+
+/// Direction independent key of a flow, with the flow's own direction as source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct DirectedKey {
+	exporter: IpAddr,
+	protocol: Option<u8>,
+	src_addr: Option<IpAddr>,
+	src_port: Option<u16>,
+	dst_addr: Option<IpAddr>,
+	dst_port: Option<u16>,
+}
+
+impl DirectedKey {
+	fn of(record: &FlowRecord) -> Self {
+		Self {
+			exporter: record.exporter,
+			protocol: record.protocol,
+			src_addr: record.src_addr,
+			src_port: record.src_port,
+			dst_addr: record.dst_addr,
+			dst_port: record.dst_port,
+		}
+	}
+
+	fn reversed(&self) -> Self {
+		Self { src_addr: self.dst_addr, src_port: self.dst_port, dst_addr: self.src_addr, dst_port: self.src_port, ..*self }
+	}
+}
+
+#[derive(Debug, Clone)]
+struct PendingFlow {
+	id: u64,
+	biflow: BiflowRecord,
+	arrived: Instant,
+}
+
+/// Whether the uptime ranges `a` and `b` overlap, allowing for `slack` milliseconds between them
+///
+/// Ranges with unknown times are assumed to overlap
+fn overlaps(a: &BiflowRecord, b: &FlowRecord, slack: u32) -> bool {
+	match (a.start, a.end, b.start, b.end) {
+		(Some(a_start), Some(a_end), Some(b_start), Some(b_end)) =>
+			a_start <= b_end.saturating_add(slack) && b_start <= a_end.saturating_add(slack),
+		_ => true,
+	}
+}
+
+/// Decide whether `first`, seen before `second`, initiated their conversation
+///
+/// The flow starting earlier is the initiator. With equal or unknown start times, the side using the higher port is
+/// taken as the initiator, as clients usually use ephemeral ports
+fn first_initiates(first: &BiflowRecord, second: &FlowRecord) -> bool {
+	match (first.start, second.start) {
+		(Some(a), Some(b)) if a != b => a < b,
+		_ => match (first.initiator_port, second.src_port) {
+			(Some(a), Some(b)) if a != b => a > b,
+			_ => true,
+		},
+	}
+}
+
+/// Stitcher pairing unidirectional flows into biflows
+pub struct BiflowStitcher<F: FnMut(BiflowRecord)> {
+	pending: HashMap<DirectedKey, PendingFlow>,
+	/// Keys of the waiting flows by ID, i.e. in arrival order
+	order: BTreeMap<u64, DirectedKey>,
+	next_id: u64,
+	timeout: Duration,
+	max_pending: usize,
+	slack_ms: u32,
+	emit: F,
+}
+
+impl<F: FnMut(BiflowRecord)> BiflowStitcher<F> {
+	/// Create a stitcher calling `emit` with every finished biflow
+	///
+	/// By default, flows wait up to 30 seconds for their reverse flow, at most 100000 flows wait at once, and flows
+	/// still pair up if their times are up to 1 second apart
+	pub fn new(emit: F) -> Self {
+		Self {
+			pending: HashMap::new(),
+			order: BTreeMap::new(),
+			next_id: 0,
+			timeout: Duration::from_secs(30),
+			max_pending: 100_000,
+			slack_ms: 1000,
+			emit,
+		}
+	}
+
+	/// Set how long a flow waits for its reverse flow
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		self
+	}
+
+	/// Set how many flows can wait for their reverse flow at once; the oldest are released first
+	pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+		self.max_pending = max_pending.max(1);
+		self
+	}
+
+	/// Set how many milliseconds apart the time ranges of two flows can be and still pair up
+	pub fn with_slack(mut self, slack_ms: u32) -> Self {
+		self.slack_ms = slack_ms;
+		self
+	}
+
+	/// Add a flow received at `now`
+	///
+	/// If a matching reverse flow is waiting, the pair is emitted immediately. Further records of a waiting flow's
+	/// direction are merged into it
+	pub fn add(&mut self, record: &FlowRecord, now: Instant) {
+		self.expire(now);
+
+		let key = DirectedKey::of(record);
+		let reverse_key = key.reversed();
+
+		if let Some(p) = self.pending.get(&reverse_key) {
+			if overlaps(&p.biflow, record, self.slack_ms) {
+				let p = self.pending.remove(&reverse_key).unwrap();
+				self.order.remove(&p.id);
+				let biflow = if first_initiates(&p.biflow, record) {
+					let mut biflow = p.biflow;
+					biflow.add_reverse(record);
+					biflow
+				} else {
+					let mut biflow = BiflowRecord::from_forward(record);
+					biflow.reverse = p.biflow.forward;
+					biflow.start = min_opt(biflow.start, p.biflow.start);
+					biflow.end = max_opt(biflow.end, p.biflow.end);
+					biflow
+				};
+				(self.emit)(biflow);
+				return;
+			}
+		}
+
+		if let Some(p) = self.pending.get_mut(&key) {
+			p.biflow.forward.add(record);
+			p.biflow.start = min_opt(p.biflow.start, record.start);
+			p.biflow.end = max_opt(p.biflow.end, record.end);
+			return;
+		}
+
+		let id = self.next_id;
+		self.next_id += 1;
+		self.pending.insert(key, PendingFlow { id, biflow: BiflowRecord::from_forward(record), arrived: now });
+		self.order.insert(id, key);
+
+		while self.pending.len() > self.max_pending {
+			self.release_oldest();
+		}
+	}
+
+	/// Release the oldest waiting flow, returning false if there was none
+	fn release_oldest(&mut self) -> bool {
+		let Some((_, key)) = self.order.pop_first() else {
+			return false;
+		};
+		if let Some(p) = self.pending.remove(&key) {
+			(self.emit)(p.biflow);
+		}
+
+		true
+	}
+
+	/// Release all flows that have waited for their reverse flow longer than the timeout at `now`
+	pub fn expire(&mut self, now: Instant) {
+		while let Some((_, key)) = self.order.first_key_value() {
+			if self.pending.get(key).is_some_and(|p| now.saturating_duration_since(p.arrived) < self.timeout) {
+				break;
+			}
+			self.release_oldest();
+		}
+	}
+
+	/// Release all waiting flows
+	pub fn flush(&mut self) {
+		while self.release_oldest() {}
+	}
+
+	/// Get the number of flows waiting for their reverse flow
+	pub fn pending(&self) -> usize {
+		self.pending.len()
+	}
+}
@@ -0,0 +1,34 @@
+use std::net::SocketAddr;
+use multiflow::collector::pipeline::{run_collector, CollectorOutput};
+use multiflow::collector::{MultiflowParser, ParsedDatagram, ReceivedDatagram};
+use multiflow::config::CollectorConfig;
+use multiflow::sequence::SequenceTracker;
+
+fn main() {
+	let config = CollectorConfig::from_env(&[
+		"0.0.0.0:2055".parse().unwrap(),
+		"0.0.0.0:4739".parse().unwrap(),
+		"0.0.0.0:6343".parse().unwrap(),
+	]);
+
+	let make_parser = || {
+		let mut parser = MultiflowParser::new();
+		move |dg: &ReceivedDatagram, tracker: &mut SequenceTracker| {
+			let (_, parsed) = parser.parse(&dg.data, &dg.addr).ok()?;
+			let events = match &parsed {
+				ParsedDatagram::Netflow(p) => tracker.observe_netflow(dg.addr.ip(), p),
+				ParsedDatagram::SFlow(p) => tracker.observe_sflow(p),
+			};
+			Some((parsed, events))
+		}
+	};
+
+	let write = |out: &mut CollectorOutput, addr: &SocketAddr, received, parsed: &ParsedDatagram| match parsed {
+		ParsedDatagram::Netflow(p) => out.write_netflow(addr, received, p),
+		ParsedDatagram::SFlow(p) => out.write_sflow(addr, received, p),
+	};
+	if let Err(e) = run_collector(&config, "flow", make_parser, write) {
+		eprintln!("{}", e);
+		std::process::exit(1);
+	}
+}
@@ -0,0 +1,179 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use clap::{ArgAction, Parser};
+use socket2::{Domain, Protocol, Socket, Type};
+use multiflow::collector::ReceivedDatagram;
+use multiflow::collector::archive::ArchiveReader;
+use multiflow::collector::record::{RecordingReader, RECORDING_MAGIC};
+use multiflow::pcap::PcapReader;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Re-send flow datagrams from pcap files or recordings to a collector over UDP")]
+struct Args {
+	/// pcap, pcapng, or datagram recording files, or archive directories, replayed one after the other
+	#[arg(required = true, value_name = "FILE")]
+	files: Vec<PathBuf>,
+	/// Collector address to send the datagrams to
+	#[arg(short, long, value_name = "ADDR")]
+	target: SocketAddr,
+	/// Playback speed relative to the original timing, e.g. 2 for twice as fast
+	#[arg(short, long, value_name = "FACTOR", default_value_t = 1.0)]
+	speed: f64,
+	/// Send as fast as possible, ignoring the original timing
+	#[arg(short, long, conflicts_with = "speed")]
+	max_rate: bool,
+	/// Only replay datagrams sent to this UDP port (pcap files only), may be given multiple times
+	#[arg(short, long, value_name = "PORT")]
+	port: Vec<u16>,
+	/// Send every datagram from this IPv4 exporter address instead of a local socket (requires CAP_NET_RAW)
+	#[arg(short, long, value_name = "ADDR")]
+	exporter: Option<SocketAddrV4>,
+	/// Send every datagram from the IPv4 exporter address it was recorded with (requires CAP_NET_RAW)
+	#[arg(long, conflicts_with = "exporter")]
+	preserve_source: bool,
+	/// Print every datagram sent
+	#[arg(short, long, action = ArgAction::Count)]
+	verbose: u8,
+}
+
+/// Socket the datagrams are sent from
+enum Sender {
+	Udp(UdpSocket),
+	/// Raw socket writing its own IPv4 and UDP headers, to send from the given exporter address, or from the address
+	/// each datagram was recorded with if `None`
+	Raw(Socket, Option<SocketAddrV4>),
+}
+
+impl Sender {
+	fn open(target: &SocketAddr, exporter: Option<SocketAddrV4>, preserve_source: bool) -> io::Result<Self> {
+		let raw = exporter.is_some() || preserve_source;
+		match (raw, target) {
+			(true, SocketAddr::V4(_)) => {
+				let sock = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::UDP))?;
+				sock.set_header_included_v4(true)?;
+				Ok(Sender::Raw(sock, exporter))
+			}
+			(true, SocketAddr::V6(_)) => Err(io::Error::new(io::ErrorKind::Unsupported, "Setting the exporter address requires an IPv4 target")),
+			(false, SocketAddr::V4(_)) => Ok(Sender::Udp(UdpSocket::bind("0.0.0.0:0")?)),
+			(false, SocketAddr::V6(_)) => Ok(Sender::Udp(UdpSocket::bind("[::]:0")?)),
+		}
+	}
+
+	/// Send `payload` to `target`, from `source` if the datagrams keep their recorded exporter address
+	fn send(&self, target: &SocketAddr, source: &SocketAddr, payload: &[u8]) -> io::Result<()> {
+		match self {
+			Sender::Udp(sock) => sock.send_to(payload, target).map(|_| ()),
+			Sender::Raw(sock, exporter) => {
+				let IpAddr::V4(dst) = target.ip() else { unreachable!() };
+				let exporter = match (exporter, source) {
+					(Some(exporter), _) => *exporter,
+					(None, SocketAddr::V4(source)) => *source,
+					(None, SocketAddr::V6(_)) => return Err(io::Error::new(io::ErrorKind::Unsupported, "Cannot send from an IPv6 exporter address")),
+				};
+				let udp_length = u16::try_from(8 + payload.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Datagram too long"))?;
+				let ip_length = udp_length.checked_add(20).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Datagram too long"))?;
+
+				// The kernel fills in the IP identification and checksum, a zero UDP checksum means none
+				let mut packet = Vec::with_capacity(ip_length as usize);
+				packet.extend_from_slice(&[0x45, 0]);
+				packet.extend_from_slice(&ip_length.to_be_bytes());
+				packet.extend_from_slice(&[0, 0, 0, 0, 64, 17, 0, 0]);
+				packet.extend_from_slice(&exporter.ip().octets());
+				packet.extend_from_slice(&dst.octets());
+				packet.extend_from_slice(&exporter.port().to_be_bytes());
+				packet.extend_from_slice(&target.port().to_be_bytes());
+				packet.extend_from_slice(&udp_length.to_be_bytes());
+				packet.extend_from_slice(&[0, 0]);
+				packet.extend_from_slice(payload);
+
+				sock.send_to(&packet, &SocketAddr::new(IpAddr::V4(dst), 0).into()).map(|_| ())
+			}
+		}
+	}
+}
+
+/// Open `path` as a datagram archive directory, a recording, or a capture file, depending on its magic number
+fn open_datagrams(path: &Path, ports: &[u16]) -> io::Result<Box<dyn Iterator<Item = io::Result<ReceivedDatagram>>>> {
+	if path.is_dir() {
+		return Ok(Box::new(ArchiveReader::open(path)?.map(|dg| dg.map(ReceivedDatagram::from))));
+	}
+
+	let mut magic = [0u8; 4];
+	File::open(path)?.read_exact(&mut magic)?;
+
+	if magic == RECORDING_MAGIC {
+		return Ok(Box::new(RecordingReader::open(path)?.map(|dg| dg.map(ReceivedDatagram::from))));
+	}
+
+	let ports = ports.to_vec();
+	Ok(Box::new(PcapReader::open(path)?.filter_map(move |packet| {
+		let packet = match packet {
+			Ok(p) => p,
+			Err(e) => return Some(Err(e)),
+		};
+		let udp = packet.udp().filter(|udp| ports.is_empty() || ports.contains(&udp.dst.port()))?;
+
+		Some(Ok(ReceivedDatagram { data: udp.payload.to_vec(), addr: udp.src, received: packet.timestamp }))
+	})))
+}
+
+fn main() {
+	let args = Args::parse();
+	if !(args.speed > 0.0 && args.speed.is_finite()) {
+		eprintln!("The speed must be a positive number");
+		std::process::exit(2);
+	}
+
+	let sender = Sender::open(&args.target, args.exporter, args.preserve_source).expect("Failed to open socket");
+
+	let started = Instant::now();
+	let mut sent = 0u64;
+	let mut bytes = 0u64;
+	for path in &args.files {
+		let datagrams = match open_datagrams(path, &args.port) {
+			Ok(d) => d,
+			Err(e) => {
+				eprintln!("Failed to open {}: {}", path.display(), e);
+				continue;
+			}
+		};
+
+		// Timing is relative to the first datagram of each file
+		let mut first: Option<(SystemTime, Instant)> = None;
+		for dg in datagrams {
+			let dg = match dg {
+				Ok(dg) => dg,
+				Err(e) => {
+					eprintln!("Failed to read {}: {}", path.display(), e);
+					break;
+				}
+			};
+
+			if !args.max_rate {
+				let (first_received, first_sent) = *first.get_or_insert((dg.received, Instant::now()));
+				// Very low speeds can make the offset too long to represent, it is then never reached
+				let offset = dg.received.duration_since(first_received).unwrap_or_default().as_secs_f64() / args.speed;
+				let offset = Duration::try_from_secs_f64(offset).unwrap_or(Duration::MAX);
+				let wait = offset.saturating_sub(first_sent.elapsed());
+				if wait > Duration::ZERO {
+					std::thread::sleep(wait);
+				}
+			}
+
+			if let Err(e) = sender.send(&args.target, &dg.addr, &dg.data) {
+				eprintln!("Failed to send datagram from {}: {}", dg.addr, e);
+				continue;
+			}
+			sent += 1;
+			bytes += dg.data.len() as u64;
+			if args.verbose > 0 {
+				eprintln!("Sent {} bytes from {}", dg.data.len(), dg.addr);
+			}
+		}
+	}
+
+	eprintln!("Sent {} datagrams ({} bytes) in {:.3}s", sent, bytes, started.elapsed().as_secs_f64());
+}
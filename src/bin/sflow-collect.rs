@@ -1,15 +1,20 @@
+use multiflow::collector::pipeline::run_collector;
+use multiflow::collector::ReceivedDatagram;
+use multiflow::config::CollectorConfig;
+use multiflow::sequence::SequenceTracker;
 use multiflow::sflow_parse::datagram::parse_sflow_data;
 
 fn main() {
-	// Test setup for sFlow
-	let mut recv_buf: [u8; 4096] = [0; 4096];
-	let sock = std::net::UdpSocket::bind("0.0.0.0:6343").expect("Failed to bind to UDP socket");
+	let config = CollectorConfig::from_env(&["0.0.0.0:6343".parse().unwrap()]);
 
-	loop {
-		let (byten, addr) = sock.recv_from(&mut recv_buf).expect("Failed to receive UDP data");
+	let make_parser = || |dg: &ReceivedDatagram, tracker: &mut SequenceTracker| {
+		let (_, parsed) = parse_sflow_data(&dg.data).ok()?;
+		let events = tracker.observe_sflow(&parsed);
+		Some((parsed, events))
+	};
 
-		let dg = parse_sflow_data(&recv_buf).unwrap().1;
-
-		println!("Received {} bytes from {}\nDatagram: {:?}", byten, addr, dg);
+	if let Err(e) = run_collector(&config, "sFlow", make_parser, |out, addr, received, parsed| out.write_sflow(addr, received, parsed)) {
+		eprintln!("{}", e);
+		std::process::exit(1);
 	}
-}
\ No newline at end of file
+}
@@ -0,0 +1,230 @@
+//! Rotating on-disk archive of raw received datagrams
+//!
+//! [DatagramArchive] appends every datagram to a [recording](crate::collector::record) in the archive directory,
+//! starting a new file once the current one reaches a size or age limit, and optionally deleting the oldest files.
+//! File names contain the time the file was started, so they sort chronologically. [ArchiveReader] reads the files
+//! back in order, e.g. to parse the datagrams again after a parser upgrade
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::collector::record::{RecordedDatagram, RecordingReader, RecordingWriter};
+use crate::collector::{FlowProtocol, ReceivedDatagram};
+use crate::netflow_parse::datagram::NetflowDatagramData;
+use crate::netflow_parse::NetflowParser;
+
+const FILE_PREFIX: &str = "datagrams-";
+const FILE_EXTENSION: &str = ".mfdg";
+/// Longest time buffered datagrams wait before being written to the file while datagrams keep arriving
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings for a [DatagramArchive]
+#[derive(Debug, Clone)]
+pub struct ArchiveConfig {
+	/// Directory the archive files are written to, created if missing
+	pub dir: PathBuf,
+	/// Size in bytes after which a new file is started
+	pub rotate_size: u64,
+	/// Age after which a new file is started
+	pub rotate_interval: Duration,
+	/// Number of files to keep, deleting the oldest ones on rotation, 0 to keep all
+	pub max_files: usize,
+}
+
+impl ArchiveConfig {
+	pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+		Self { dir: dir.into(), rotate_size: 64 * 1024 * 1024, rotate_interval: Duration::from_secs(3600), max_files: 0 }
+	}
+}
+
+#[derive(Debug)]
+struct ArchiveFile {
+	writer: RecordingWriter<BufWriter<File>>,
+	size: u64,
+	opened: Instant,
+	flushed: Instant,
+}
+
+#[derive(Debug, Default)]
+struct ArchiveState {
+	file: Option<ArchiveFile>,
+	/// Time in the name of the last file, names of new files must sort after it even if the clock goes back
+	last_millis: u128,
+}
+
+/// Rotating archive of raw datagrams, shared by all threads of a collector
+#[derive(Debug)]
+pub struct DatagramArchive {
+	config: ArchiveConfig,
+	state: Mutex<ArchiveState>,
+}
+
+/// Get the archive files in `dir`, oldest first
+fn archive_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+	let mut files = Vec::new();
+	for entry in std::fs::read_dir(dir)? {
+		let path = entry?.path();
+		let is_archive = path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with(FILE_PREFIX) && n.ends_with(FILE_EXTENSION));
+		if is_archive && path.is_file() {
+			files.push(path);
+		}
+	}
+
+	files.sort();
+	Ok(files)
+}
+
+impl DatagramArchive {
+	/// Open an archive in the configured directory, creating the directory if needed
+	///
+	/// The first file is only created once the first datagram is recorded
+	pub fn open(config: ArchiveConfig) -> io::Result<Self> {
+		std::fs::create_dir_all(&config.dir)?;
+
+		Ok(Self { config, state: Mutex::new(ArchiveState::default()) })
+	}
+
+	/// Get the settings of this archive
+	pub fn config(&self) -> &ArchiveConfig {
+		&self.config
+	}
+
+	/// Append `dg` to the current archive file, rotating it first if it is due
+	///
+	/// Datagrams are buffered, and written to the file on rotation, once a second while datagrams keep arriving, on
+	/// [flush](Self::flush), and when the archive is dropped. Failing to delete old files is logged and does not keep
+	/// the datagram from being recorded
+	pub fn record(&self, dg: &ReceivedDatagram) -> io::Result<()> {
+		let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+		let due = state.file.as_ref().is_none_or(|f| f.size >= self.config.rotate_size || f.opened.elapsed() >= self.config.rotate_interval);
+		if due {
+			if let Some(mut file) = state.file.take() {
+				if let Err(e) = file.writer.flush() {
+					eprintln!("Failed to write archive file: {}", e);
+				}
+			}
+			let file = self.create_file(&mut state.last_millis)?;
+			state.file = Some(file);
+			if let Err(e) = self.prune() {
+				eprintln!("Failed to delete old archive files in {}: {}", self.config.dir.display(), e);
+			}
+		}
+
+		let file = state.file.as_mut().unwrap();
+		file.size += file.writer.write(dg)? as u64;
+		if file.flushed.elapsed() >= FLUSH_INTERVAL {
+			file.writer.flush()?;
+			file.flushed = Instant::now();
+		}
+
+		Ok(())
+	}
+
+	/// Write all buffered datagrams to the current archive file
+	pub fn flush(&self) -> io::Result<()> {
+		let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+		match &mut state.file {
+			Some(file) => {
+				file.flushed = Instant::now();
+				file.writer.flush()
+			}
+			None => Ok(()),
+		}
+	}
+
+	fn create_file(&self, last_millis: &mut u128) -> io::Result<ArchiveFile> {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+		let mut millis = now.max(*last_millis + 1);
+		loop {
+			let path = self.config.dir.join(format!("{}{:013}{}", FILE_PREFIX, millis, FILE_EXTENSION));
+			match OpenOptions::new().write(true).create_new(true).open(&path) {
+				Ok(f) => {
+					*last_millis = millis;
+					let writer = RecordingWriter::new(BufWriter::new(f))?;
+					return Ok(ArchiveFile { writer, size: 0, opened: Instant::now(), flushed: Instant::now() });
+				}
+				// Left behind by a previous run or another collector using the same directory
+				Err(e) if e.kind() == io::ErrorKind::AlreadyExists => millis += 1,
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	/// Delete the oldest files beyond the configured number of files to keep
+	fn prune(&self) -> io::Result<()> {
+		if self.config.max_files == 0 {
+			return Ok(());
+		}
+
+		let files = archive_files(&self.config.dir)?;
+		for path in &files[..files.len().saturating_sub(self.config.max_files)] {
+			std::fs::remove_file(path)?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Reader for the datagrams of an archive, oldest first
+#[derive(Debug)]
+pub struct ArchiveReader {
+	files: VecDeque<PathBuf>,
+	current: Option<RecordingReader<BufReader<File>>>,
+}
+
+impl ArchiveReader {
+	/// Open the archive directory at `path`, or a single archive file
+	pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+		let path = path.as_ref();
+		let files = if path.is_dir() { archive_files(path)? } else { vec![path.to_path_buf()] };
+
+		Ok(Self { files: files.into(), current: None })
+	}
+
+	/// Read the next datagram, returning `None` once all files have been read
+	pub fn next_datagram(&mut self) -> io::Result<Option<RecordedDatagram>> {
+		loop {
+			if let Some(reader) = &mut self.current {
+				match reader.next_datagram()? {
+					Some(dg) => return Ok(Some(dg)),
+					None => self.current = None,
+				}
+			}
+
+			match self.files.pop_front() {
+				Some(path) => self.current = Some(RecordingReader::open(path)?),
+				None => return Ok(None),
+			}
+		}
+	}
+
+	/// Parse every NetFlow datagram in the archive with `parser`, calling `f` with the datagram and its parse result
+	///
+	/// Datagrams are parsed in the order they were received, so templates are learned before the data using them.
+	/// Datagrams recorded as sFlow are skipped. Datagrams that fail to parse are passed to `f` with `None`
+	pub fn replay_netflow<F>(&mut self, parser: &mut NetflowParser, mut f: F) -> io::Result<()>
+		where F: FnMut(&RecordedDatagram, Option<NetflowDatagramData>) {
+		while let Some(dg) = self.next_datagram()? {
+			if dg.protocol == Some(FlowProtocol::SFlow) {
+				continue;
+			}
+
+			let parsed = parser.parse(&dg.data, &dg.addr).ok().map(|(_, parsed)| parsed);
+			f(&dg, parsed);
+		}
+
+		Ok(())
+	}
+}
+
+impl Iterator for ArchiveReader {
+	type Item = io::Result<RecordedDatagram>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.next_datagram().transpose()
+	}
+}
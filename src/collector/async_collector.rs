@@ -0,0 +1,202 @@
+//! Async UDP collector for use inside a tokio runtime
+//!
+//! [FlowCollector] binds one or more UDP sockets, parses every datagram with a shared [ConcurrentNetflowParser] or
+//! [parse_sflow_data], and exposes the results as a [FlowStream]. Shutdown is requested through a [ShutdownHandle],
+//! after which the stream yields the remaining queued datagrams and ends
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use futures_core::Stream;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use crate::collector::{bind_udp, detect_protocol, CollectorStats, FlowProtocol, ParsedDatagram, MAX_DATAGRAM_SIZE};
+use crate::netflow_parse::concurrent::ConcurrentNetflowParser;
+use crate::sflow_parse::datagram::parse_sflow_data;
+
+/// A parsed datagram together with its origin
+#[derive(Debug, Clone)]
+pub struct CollectedDatagram {
+	/// Address of the exporter the datagram came from
+	pub addr: SocketAddr,
+	/// Time at which the datagram was received
+	pub received: SystemTime,
+	/// Parsed datagram contents
+	pub datagram: ParsedDatagram,
+}
+
+/// Async UDP flow collector
+#[derive(Debug)]
+pub struct FlowCollector {
+	sockets: Vec<UdpSocket>,
+	parser: Arc<ConcurrentNetflowParser>,
+	stats: Arc<CollectorStats>,
+	buffer_size: usize,
+	queue_size: usize,
+}
+
+impl FlowCollector {
+	/// Bind UDP sockets to all of `addrs`
+	///
+	/// Must be called from within a tokio runtime
+	pub fn bind(addrs: &[SocketAddr]) -> std::io::Result<Self> {
+		let sockets = addrs.iter().map(|addr| {
+			let sock = bind_udp(addr, None, false)?;
+			sock.set_nonblocking(true)?;
+			UdpSocket::from_std(sock)
+		}).collect::<std::io::Result<Vec<_>>>()?;
+
+		Ok(Self::from_sockets(sockets))
+	}
+
+	/// Create a collector receiving from already bound sockets
+	pub fn from_sockets(sockets: Vec<UdpSocket>) -> Self {
+		Self {
+			sockets,
+			parser: Arc::new(ConcurrentNetflowParser::new()),
+			stats: Arc::new(CollectorStats::default()),
+			buffer_size: MAX_DATAGRAM_SIZE,
+			queue_size: 1024,
+		}
+	}
+
+	/// Set the receive buffer size in bytes; longer datagrams are truncated
+	pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+		self.buffer_size = buffer_size;
+		self
+	}
+
+	/// Set the number of parsed datagrams that can be queued before receiving pauses
+	pub fn with_queue_size(mut self, queue_size: usize) -> Self {
+		self.queue_size = queue_size.max(1);
+		self
+	}
+
+	/// Use `parser` for NetFlow datagrams, e.g. to share templates with other collectors
+	pub fn with_parser(mut self, parser: Arc<ConcurrentNetflowParser>) -> Self {
+		self.parser = parser;
+		self
+	}
+
+	/// Get the statistics of this collector
+	pub fn stats(&self) -> &Arc<CollectorStats> {
+		&self.stats
+	}
+
+	/// Get the local addresses of all sockets
+	pub fn local_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
+		self.sockets.iter().map(|s| s.local_addr()).collect()
+	}
+
+	/// Spawn one receive task per socket, returning the stream of parsed datagrams and a handle for shutting down
+	///
+	/// Datagrams that fail to parse are counted in the statistics and skipped
+	pub fn start(self) -> (FlowStream, ShutdownHandle) {
+		let (tx, rx) = mpsc::channel(self.queue_size);
+		let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+		let tasks = self.sockets.into_iter().map(|sock| {
+			let tx = tx.clone();
+			let mut shutdown_rx = shutdown_rx.clone();
+			let parser = self.parser.clone();
+			let stats = self.stats.clone();
+			let buffer_size = self.buffer_size;
+
+			tokio::spawn(async move {
+				let mut recv_buf = vec![0u8; buffer_size];
+				loop {
+					let (byten, addr) = tokio::select! {
+						_ = shutdown_requested(&mut shutdown_rx) => return,
+						r = sock.recv_from(&mut recv_buf) => match r {
+							Ok(r) => r,
+							Err(e) => {
+								CollectorStats::inc(&stats.receive_errors);
+								eprintln!("Failed to receive UDP data: {}", e);
+								continue;
+							}
+						},
+					};
+					let received = SystemTime::now();
+
+					CollectorStats::inc(&stats.datagrams);
+					stats.bytes.fetch_add(byten as u64, std::sync::atomic::Ordering::Relaxed);
+					if byten == buffer_size {
+						CollectorStats::inc(&stats.truncated);
+					}
+
+					let Some(datagram) = parse_datagram(&parser, &recv_buf[..byten], &addr) else {
+						CollectorStats::inc(&stats.parse_errors);
+						continue;
+					};
+					CollectorStats::inc(&stats.parsed);
+
+					tokio::select! {
+						_ = shutdown_requested(&mut shutdown_rx) => return,
+						r = tx.send(CollectedDatagram { addr, received, datagram }) => if r.is_err() { return },
+					}
+				}
+			})
+		}).collect();
+
+		(FlowStream { rx }, ShutdownHandle { shutdown_tx, tasks })
+	}
+}
+
+/// Resolve once shutdown has been requested, or never if the [ShutdownHandle] was dropped without requesting it
+async fn shutdown_requested(rx: &mut watch::Receiver<bool>) {
+	if rx.wait_for(|shutdown| *shutdown).await.is_err() {
+		std::future::pending::<()>().await;
+	}
+}
+
+fn parse_datagram(parser: &ConcurrentNetflowParser, input: &[u8], addr: &SocketAddr) -> Option<ParsedDatagram> {
+	match detect_protocol(input)? {
+		FlowProtocol::SFlow => parse_sflow_data(input).ok().map(|(_, dg)| ParsedDatagram::SFlow(dg)),
+		FlowProtocol::Netflow(_) => parser.parse(input, addr).ok().map(|(_, dg)| ParsedDatagram::Netflow(dg)),
+	}
+}
+
+/// Stream of parsed datagrams from a [FlowCollector]
+///
+/// The stream ends once the collector has been shut down and all queued datagrams have been consumed
+#[derive(Debug)]
+pub struct FlowStream {
+	rx: mpsc::Receiver<CollectedDatagram>,
+}
+
+impl FlowStream {
+	/// Receive the next parsed datagram, `None` once the stream has ended
+	pub async fn next(&mut self) -> Option<CollectedDatagram> {
+		self.rx.recv().await
+	}
+}
+
+impl Stream for FlowStream {
+	type Item = CollectedDatagram;
+
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		self.rx.poll_recv(cx)
+	}
+}
+
+/// Handle for stopping a running [FlowCollector]
+///
+/// Dropping the handle without calling [ShutdownHandle::shutdown] leaves the collector running
+#[derive(Debug)]
+pub struct ShutdownHandle {
+	shutdown_tx: watch::Sender<bool>,
+	tasks: Vec<JoinHandle<()>>,
+}
+
+impl ShutdownHandle {
+	/// Stop receiving and wait for all receive tasks to finish
+	pub async fn shutdown(self) {
+		let _ = self.shutdown_tx.send(true);
+		for t in self.tasks {
+			let _ = t.await;
+		}
+	}
+}
@@ -0,0 +1,228 @@
+//! Live capture of flow datagrams from a network interface using `AF_PACKET` (Linux only)
+//!
+//! Useful when flow exports are mirrored to a span port rather than addressed to the collector. Frames are captured
+//! without their link layer header, and the UDP datagrams they carry are delivered with the address of the exporter
+//! that sent them. A BPF filter is attached to the socket so the kernel only passes unfragmented UDP to the wanted ports
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::SystemTime;
+use crate::collector::{CollectorStats, ReceiveErrors, ReceivedDatagram};
+use crate::pcap::{decode_udp, LINKTYPE_RAW};
+
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MSH: u16 = 0xA0;
+const BPF_AND: u16 = 0x50;
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JSET: u16 = 0x40;
+
+/// Largest number of ports a filter can match, keeping all jumps within their 8-bit offsets
+pub const MAX_FILTER_PORTS: usize = 200;
+
+/// Largest IP packet, captured frames never exceed it without offloading
+const CAPTURE_BUFFER_SIZE: usize = 65536;
+
+fn statement(code: u16, k: u32) -> libc::sock_filter {
+	libc::sock_filter { code, jt: 0, jf: 0, k }
+}
+
+/// Conditional jump at index `at` to the absolute indices `jt` and `jf`
+fn jump(code: u16, k: u32, at: usize, jt: usize, jf: usize) -> libc::sock_filter {
+	libc::sock_filter { code: BPF_JMP | code, jt: (jt - at - 1) as u8, jf: (jf - at - 1) as u8, k }
+}
+
+/// Build a BPF program accepting unfragmented UDP over IPv4 or IPv6 (without extension headers) to any of `ports`
+///
+/// The program runs on packets starting at their IP header, as captured by a `SOCK_DGRAM` packet socket. An empty
+/// `ports` accepts every UDP datagram
+pub fn udp_port_filter(ports: &[u16]) -> Vec<libc::sock_filter> {
+	let ports_start = 14;
+	let accept = ports_start + ports.len() + usize::from(!ports.is_empty());
+	let drop = accept + 1;
+
+	let mut program = vec![
+		// IP version
+		statement(BPF_LD | BPF_B | BPF_ABS, 0),
+		statement(BPF_ALU | BPF_AND, 0xF0),
+		jump(BPF_JEQ, 0x40, 2, 3, 10),
+		// IPv4: UDP, not a later fragment, destination port after the variable length header
+		statement(BPF_LD | BPF_B | BPF_ABS, 9),
+		jump(BPF_JEQ, 17, 4, 5, drop),
+		statement(BPF_LD | BPF_H | BPF_ABS, 6),
+		jump(BPF_JSET, 0x1FFF, 6, drop, 7),
+		statement(BPF_LDX | BPF_B | BPF_MSH, 0),
+		statement(BPF_LD | BPF_H | BPF_IND, 2),
+		statement(BPF_JMP | BPF_JA, (ports_start - 10) as u32),
+		// IPv6: UDP as the next header, destination port after the fixed header
+		jump(BPF_JEQ, 0x60, 10, 11, drop),
+		statement(BPF_LD | BPF_B | BPF_ABS, 6),
+		jump(BPF_JEQ, 17, 12, 13, drop),
+		statement(BPF_LD | BPF_H | BPF_ABS, 42),
+	];
+
+	for (i, port) in ports.iter().enumerate() {
+		let at = ports_start + i;
+		program.push(jump(BPF_JEQ, *port as u32, at, accept, at + 1));
+	}
+	if !ports.is_empty() {
+		program.push(statement(BPF_RET, 0));
+	}
+	program.push(statement(BPF_RET, u32::MAX));
+	program.push(statement(BPF_RET, 0));
+
+	program
+}
+
+/// Set the `SOL_SOCKET` option `name` of `fd` to `value`
+fn set_option<T>(fd: &OwnedFd, name: libc::c_int, value: &T) -> io::Result<()> {
+	let res = unsafe {
+		libc::setsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, name, (value as *const T).cast(), std::mem::size_of::<T>() as libc::socklen_t)
+	};
+	if res < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(())
+}
+
+/// Packet socket capturing UDP datagrams from one or all network interfaces
+#[derive(Debug)]
+pub struct PacketCapture {
+	fd: OwnedFd,
+	ports: Vec<u16>,
+	buffer: Vec<u8>,
+}
+
+impl PacketCapture {
+	/// Open a capture on `interface`, or on all interfaces if `None`, for UDP datagrams to any of `ports`
+	///
+	/// An empty `ports` captures every UDP datagram. The kernel receive buffer is set to `recv_buffer` bytes if given.
+	/// Requires the `CAP_NET_RAW` capability
+	///
+	/// # Errors
+	///
+	/// Fails if the interface does not exist, if more than [MAX_FILTER_PORTS] ports are given, or if the socket cannot
+	/// be created, e.g. for lack of permissions
+	pub fn open(interface: Option<&str>, ports: &[u16], recv_buffer: Option<usize>) -> io::Result<Self> {
+		if ports.len() > MAX_FILTER_PORTS {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("At most {} capture ports are supported", MAX_FILTER_PORTS)));
+		}
+
+		let index = match interface {
+			Some(name) => {
+				let name = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid interface name"))?;
+				let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+				if index == 0 {
+					return Err(io::Error::last_os_error());
+				}
+				index as i32
+			}
+			// Index 0 binds to all interfaces
+			None => 0,
+		};
+
+		// A packet socket with protocol 0 receives nothing until it is bound to a protocol, so the filter is in place
+		// before the first packet is queued
+		let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+		let program = udp_port_filter(ports);
+		let fprog = libc::sock_fprog { len: program.len() as u16, filter: program.as_ptr() as *mut libc::sock_filter };
+		set_option(&fd, libc::SO_ATTACH_FILTER, &fprog)?;
+		if let Some(size) = recv_buffer {
+			set_option(&fd, libc::SO_RCVBUF, &(size.min(libc::c_int::MAX as usize) as libc::c_int))?;
+		}
+
+		let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+		addr.sll_family = libc::AF_PACKET as u16;
+		addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+		addr.sll_ifindex = index;
+		let res = unsafe {
+			libc::bind(fd.as_raw_fd(), (&addr as *const libc::sockaddr_ll).cast(), std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t)
+		};
+		if res < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		Ok(Self { fd, ports: ports.to_vec(), buffer: vec![0u8; CAPTURE_BUFFER_SIZE] })
+	}
+
+	/// Block until a packet is captured, returning the UDP datagram it carries
+	///
+	/// Returns `None` for packets that are not a complete UDP datagram to one of the capture ports, and for packets
+	/// sent by this host, which would otherwise be seen twice on the loopback interface
+	///
+	/// # Errors
+	///
+	/// Fails with [io::ErrorKind::InvalidData] if the packet was longer than the capture buffer
+	pub fn recv(&mut self) -> io::Result<Option<ReceivedDatagram>> {
+		let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+		let mut addr_len = std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+		let n = unsafe {
+			libc::recvfrom(self.fd.as_raw_fd(), self.buffer.as_mut_ptr().cast(), self.buffer.len(), libc::MSG_TRUNC,
+				(&mut addr as *mut libc::sockaddr_ll).cast(), &mut addr_len)
+		};
+		if n < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		if n as usize > self.buffer.len() {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "Captured packet exceeds the capture buffer"));
+		}
+		if addr.sll_pkttype == libc::PACKET_OUTGOING {
+			return Ok(None);
+		}
+
+		let datagram = decode_udp(LINKTYPE_RAW, &self.buffer[..n as usize])
+			.filter(|udp| self.ports.is_empty() || self.ports.contains(&udp.dst.port()))
+			.map(|udp| ReceivedDatagram { data: udp.payload.to_vec(), addr: udp.src, received: SystemTime::now() });
+
+		Ok(datagram)
+	}
+}
+
+/// Capture datagrams with `capture` until `deliver` returns false
+///
+/// Captured datagrams and packets too long for the capture buffer are counted in `stats`. Receive errors are counted
+/// and skipped, backing off while they persist
+pub(crate) fn capture_loop<F: FnMut(ReceivedDatagram) -> bool>(capture: &mut PacketCapture, stats: &CollectorStats, mut deliver: F) {
+	let mut errors = ReceiveErrors::new(stats, "Failed to capture packet");
+	loop {
+		let dg = match capture.recv() {
+			Ok(Some(dg)) => dg,
+			Ok(None) => {
+				errors.succeeded();
+				continue;
+			}
+			Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+				errors.succeeded();
+				CollectorStats::inc(&stats.truncated);
+				continue;
+			}
+			Err(e) => {
+				errors.failed(e);
+				continue;
+			}
+		};
+		errors.succeeded();
+
+		CollectorStats::inc(&stats.datagrams);
+		stats.bytes.fetch_add(dg.data.len() as u64, std::sync::atomic::Ordering::Relaxed);
+		if !deliver(dg) {
+			return;
+		}
+	}
+}
@@ -0,0 +1,90 @@
+//! Batched datagram receiving using `recvmmsg` (Linux only)
+
+use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::AsRawFd;
+use socket2::SockAddr;
+
+/// Receiver pulling up to a whole batch of datagrams from a socket per system call
+///
+/// The receive buffers are allocated once and reused for every batch
+pub struct BatchReceiver {
+	buffer_size: usize,
+	buffers: Vec<Vec<u8>>,
+	addrs: Vec<libc::sockaddr_storage>,
+	iovecs: Vec<libc::iovec>,
+	headers: Vec<libc::mmsghdr>,
+	received: usize,
+}
+
+// The raw pointers in `iovecs` and `headers` only ever point into buffers owned by the receiver itself
+unsafe impl Send for BatchReceiver {}
+
+impl BatchReceiver {
+	/// Create a receiver for batches of up to `batch_size` datagrams, each up to `buffer_size` bytes long
+	pub fn new(batch_size: usize, buffer_size: usize) -> Self {
+		let batch_size = batch_size.max(1);
+
+		Self {
+			buffer_size,
+			buffers: vec![vec![0u8; buffer_size]; batch_size],
+			addrs: vec![unsafe { std::mem::zeroed() }; batch_size],
+			iovecs: Vec::with_capacity(batch_size),
+			headers: Vec::with_capacity(batch_size),
+			received: 0,
+		}
+	}
+
+	/// Get the maximum number of datagrams received per call
+	pub fn batch_size(&self) -> usize {
+		self.buffers.len()
+	}
+
+	/// Get the size of each receive buffer
+	pub fn buffer_size(&self) -> usize {
+		self.buffer_size
+	}
+
+	/// Block until at least one datagram is available on `sock`, then receive as many as are queued, up to the batch size
+	///
+	/// Returns the number of datagrams received, which can then be read with [BatchReceiver::datagrams]
+	pub fn recv(&mut self, sock: &UdpSocket) -> std::io::Result<usize> {
+		self.received = 0;
+		self.iovecs.clear();
+		self.headers.clear();
+
+		for buf in self.buffers.iter_mut() {
+			self.iovecs.push(libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() });
+		}
+
+		for (iov, addr) in self.iovecs.iter_mut().zip(self.addrs.iter_mut()) {
+			let mut hdr: libc::mmsghdr = unsafe { std::mem::zeroed() };
+			hdr.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+			hdr.msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+			hdr.msg_hdr.msg_iov = iov;
+			hdr.msg_hdr.msg_iovlen = 1;
+			self.headers.push(hdr);
+		}
+
+		let n = unsafe {
+			libc::recvmmsg(sock.as_raw_fd(), self.headers.as_mut_ptr(), self.headers.len() as libc::c_uint, libc::MSG_WAITFORONE, std::ptr::null_mut())
+		};
+		if n < 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+
+		self.received = n as usize;
+		Ok(self.received)
+	}
+
+	/// Iterate over the payloads and source addresses of the datagrams from the last [BatchReceiver::recv] call
+	///
+	/// Datagrams with an unsupported source address family are skipped
+	pub fn datagrams(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> + '_ {
+		self.headers[..self.received].iter().zip(&self.buffers).zip(&self.addrs).filter_map(|((hdr, buf), addr)| {
+			let sockaddr = unsafe { SockAddr::new(*addr, hdr.msg_hdr.msg_namelen) };
+			let len = (hdr.msg_len as usize).min(buf.len());
+
+			sockaddr.as_socket().map(|a| (&buf[..len], a))
+		})
+	}
+}
@@ -0,0 +1,488 @@
+//! UDP socket setup and receive helpers for flow collectors
+
+pub mod sharded;
+pub mod record;
+pub mod archive;
+#[cfg(target_os = "linux")]
+pub mod mmsg;
+#[cfg(target_os = "linux")]
+pub mod capture;
+#[cfg(feature = "tokio")]
+pub mod async_collector;
+#[cfg(feature = "cli")]
+pub mod pipeline;
+
+use std::fmt::{Display, Formatter};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use nom::IResult;
+use socket2::{Domain, Protocol, Socket, Type};
+use crate::netflow_parse::datagram::NetflowDatagramData;
+use crate::netflow_parse::NetflowParser;
+use crate::pcap::PcapReader;
+use crate::sequence::SequenceEvent;
+use crate::sflow_parse::datagram::{parse_sflow_data, Datagram};
+
+/// Largest possible UDP payload, used as the default receive buffer size
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Default number of datagrams received per system call: batches using `recvmmsg` on Linux, one at a time elsewhere
+#[cfg(target_os = "linux")]
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+/// Default number of datagrams received per system call: batches using `recvmmsg` on Linux, one at a time elsewhere
+#[cfg(not(target_os = "linux"))]
+pub const DEFAULT_BATCH_SIZE: usize = 1;
+
+/// A single datagram as received from the network
+#[derive(Debug, Clone)]
+pub struct ReceivedDatagram {
+	/// Raw datagram payload, without any Ethernet, IP, or UDP headers
+	pub data: Vec<u8>,
+	/// Address of the exporter the datagram came from
+	pub addr: SocketAddr,
+	/// Time at which the datagram was received
+	pub received: SystemTime,
+}
+
+/// Bind a UDP socket to `addr`, optionally setting the kernel receive buffer size (SO_RCVBUF) to `recv_buffer` bytes
+///
+/// IPv6 sockets are bound as IPv6-only, so that IPv4 and IPv6 listeners can be used side by side on the same port.
+/// With `reuse_port`, SO_REUSEPORT is set so that several sockets can be bound to the same address and have the kernel
+/// balance datagrams between them (Unix only, ignored elsewhere)
+pub fn bind_udp(addr: &SocketAddr, recv_buffer: Option<usize>, reuse_port: bool) -> std::io::Result<UdpSocket> {
+	let socket = Socket::new(Domain::for_address(*addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+	if addr.is_ipv6() {
+		socket.set_only_v6(true)?;
+	}
+
+	#[cfg(unix)]
+	if reuse_port {
+		socket.set_reuse_port(true)?;
+	}
+	#[cfg(not(unix))]
+	let _ = reuse_port;
+
+	if let Some(size) = recv_buffer {
+		socket.set_recv_buffer_size(size)?;
+	}
+
+	socket.bind(&(*addr).into())?;
+
+	Ok(socket.into())
+}
+
+/// Counters shared between the receive and processing stages of a collector
+#[derive(Debug, Default)]
+pub struct CollectorStats {
+	/// Datagrams received
+	pub datagrams: AtomicU64,
+	/// Payload bytes received
+	pub bytes: AtomicU64,
+	/// Datagrams that filled the whole receive buffer and were likely truncated
+	pub truncated: AtomicU64,
+	/// Failed receive calls
+	pub receive_errors: AtomicU64,
+	/// Datagrams parsed successfully
+	pub parsed: AtomicU64,
+	/// Datagrams that failed to parse
+	pub parse_errors: AtomicU64,
+	/// Datagrams dropped because a processing queue was full
+	pub queue_drops: AtomicU64,
+	/// Results dropped because the output queue was full
+	pub output_drops: AtomicU64,
+	/// Sequence units reported missing by sequence tracking
+	pub seq_missing: AtomicU64,
+	/// Duplicate datagrams or samples detected by sequence tracking
+	pub seq_duplicates: AtomicU64,
+	/// Exporter restarts detected by sequence tracking
+	pub seq_restarts: AtomicU64,
+}
+
+/// Point-in-time copy of [CollectorStats]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CollectorStatsSnapshot {
+	pub datagrams: u64,
+	pub bytes: u64,
+	pub truncated: u64,
+	pub receive_errors: u64,
+	pub parsed: u64,
+	pub parse_errors: u64,
+	pub queue_drops: u64,
+	pub output_drops: u64,
+	pub seq_missing: u64,
+	pub seq_duplicates: u64,
+	pub seq_restarts: u64,
+}
+
+impl CollectorStats {
+	/// Increment a counter by one
+	pub fn inc(counter: &AtomicU64) {
+		counter.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Take a copy of the current counter values
+	pub fn snapshot(&self) -> CollectorStatsSnapshot {
+		CollectorStatsSnapshot {
+			datagrams: self.datagrams.load(Ordering::Relaxed),
+			bytes: self.bytes.load(Ordering::Relaxed),
+			truncated: self.truncated.load(Ordering::Relaxed),
+			receive_errors: self.receive_errors.load(Ordering::Relaxed),
+			parsed: self.parsed.load(Ordering::Relaxed),
+			parse_errors: self.parse_errors.load(Ordering::Relaxed),
+			queue_drops: self.queue_drops.load(Ordering::Relaxed),
+			output_drops: self.output_drops.load(Ordering::Relaxed),
+			seq_missing: self.seq_missing.load(Ordering::Relaxed),
+			seq_duplicates: self.seq_duplicates.load(Ordering::Relaxed),
+			seq_restarts: self.seq_restarts.load(Ordering::Relaxed),
+		}
+	}
+
+	/// Add the outcome of sequence tracking to the counters
+	///
+	/// Late arrivals are subtracted from the missing count again
+	pub fn record_sequence_events(&self, events: &[SequenceEvent]) {
+		for event in events {
+			match event {
+				SequenceEvent::Gap { missing, .. } => {
+					self.seq_missing.fetch_add(*missing as u64, Ordering::Relaxed);
+				}
+				SequenceEvent::Reordered { units, .. } => {
+					let _ = self.seq_missing.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| Some(v.saturating_sub(*units as u64)));
+				}
+				SequenceEvent::Duplicate { .. } => CollectorStats::inc(&self.seq_duplicates),
+				SequenceEvent::Restart { .. } => CollectorStats::inc(&self.seq_restarts),
+			}
+		}
+	}
+}
+
+impl Display for CollectorStatsSnapshot {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(f, "datagrams={} bytes={} truncated={} receive_errors={} parsed={} parse_errors={} queue_drops={} output_drops={} seq_missing={} seq_duplicates={} seq_restarts={}",
+			self.datagrams, self.bytes, self.truncated, self.receive_errors, self.parsed, self.parse_errors, self.queue_drops, self.output_drops,
+			self.seq_missing, self.seq_duplicates, self.seq_restarts)
+	}
+}
+
+/// Number of consecutive receive errors after which [ReceiveErrors] starts sleeping between attempts
+const ERROR_BACKOFF_AFTER: u32 = 10;
+/// Longest sleep between attempts after persistent receive errors
+const MAX_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+/// Shortest time between two receive error messages
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handling of failed receives, so that a persistently failing socket neither spins nor floods stderr
+///
+/// Errors are counted in `stats` and printed at most once a second, with the number of errors left out in between.
+/// After a run of consecutive errors, the receiving thread sleeps before trying again, doubling the time up to a second
+#[derive(Debug)]
+pub(crate) struct ReceiveErrors<'a> {
+	stats: &'a CollectorStats,
+	message: &'static str,
+	consecutive: u32,
+	suppressed: u64,
+	last_log: Option<Instant>,
+}
+
+impl<'a> ReceiveErrors<'a> {
+	pub(crate) fn new(stats: &'a CollectorStats, message: &'static str) -> Self {
+		Self { stats, message, consecutive: 0, suppressed: 0, last_log: None }
+	}
+
+	/// Handle a failed receive, interruptions by signals are retried right away
+	pub(crate) fn failed(&mut self, e: std::io::Error) {
+		if e.kind() == std::io::ErrorKind::Interrupted {
+			return;
+		}
+		CollectorStats::inc(&self.stats.receive_errors);
+
+		if self.last_log.is_none_or(|t| t.elapsed() >= ERROR_LOG_INTERVAL) {
+			match self.suppressed {
+				0 => eprintln!("{}: {}", self.message, e),
+				n => eprintln!("{}: {} ({} more errors since the last message)", self.message, e, n),
+			}
+			self.suppressed = 0;
+			self.last_log = Some(Instant::now());
+		} else {
+			self.suppressed += 1;
+		}
+
+		self.consecutive = self.consecutive.saturating_add(1);
+		if let Some(n) = self.consecutive.checked_sub(ERROR_BACKOFF_AFTER) {
+			std::thread::sleep(MAX_ERROR_BACKOFF.min(Duration::from_millis(1 << n.min(10))));
+		}
+	}
+
+	/// Note a successful receive, ending a run of consecutive errors
+	pub(crate) fn succeeded(&mut self) {
+		self.consecutive = 0;
+	}
+}
+
+/// Receive datagrams from `sock` until `deliver` returns false
+///
+/// Uses a receive buffer of `buffer_size` bytes; longer datagrams are truncated by the OS and counted in `stats`.
+/// Receive errors are counted and skipped, backing off while they persist (see [ReceiveErrors]). With a `batch_size`
+/// above 1, datagrams are received in batches using `recvmmsg` on Linux; other platforms always receive one datagram
+/// at a time
+pub(crate) fn receive_loop<F: FnMut(ReceivedDatagram) -> bool>(sock: &UdpSocket, buffer_size: usize, batch_size: usize, stats: &CollectorStats, mut deliver: F) {
+	let count = |byten: usize| {
+		CollectorStats::inc(&stats.datagrams);
+		stats.bytes.fetch_add(byten as u64, Ordering::Relaxed);
+		if byten == buffer_size {
+			CollectorStats::inc(&stats.truncated);
+		}
+	};
+	let mut errors = ReceiveErrors::new(stats, "Failed to receive UDP data");
+
+	#[cfg(target_os = "linux")]
+	if batch_size > 1 {
+		let mut receiver = mmsg::BatchReceiver::new(batch_size, buffer_size);
+		loop {
+			if let Err(e) = receiver.recv(sock) {
+				errors.failed(e);
+				continue;
+			}
+			errors.succeeded();
+
+			let received = SystemTime::now();
+			for (data, addr) in receiver.datagrams() {
+				count(data.len());
+				if !deliver(ReceivedDatagram { data: data.to_vec(), addr, received }) {
+					return;
+				}
+			}
+		}
+	}
+	#[cfg(not(target_os = "linux"))]
+	let _ = batch_size;
+
+	let mut recv_buf = vec![0u8; buffer_size];
+	loop {
+		let (byten, addr) = match sock.recv_from(&mut recv_buf) {
+			Ok(r) => r,
+			Err(e) => {
+				errors.failed(e);
+				continue;
+			}
+		};
+		errors.succeeded();
+
+		count(byten);
+		if !deliver(ReceivedDatagram { data: recv_buf[..byten].to_vec(), addr, received: SystemTime::now() }) {
+			return;
+		}
+	}
+}
+
+/// Spawn one receive thread per socket, forwarding every received datagram into the returned channel
+///
+/// Each thread uses a receive buffer of `buffer_size` bytes; longer datagrams are truncated by the OS and counted in
+/// `stats`. Receive errors are counted and skipped, backing off while they persist. A thread stops when the returned
+/// receiver is dropped
+pub fn spawn_receivers(sockets: Vec<UdpSocket>, buffer_size: usize, stats: Arc<CollectorStats>) -> Receiver<ReceivedDatagram> {
+	let (tx, rx) = channel();
+
+	for sock in sockets {
+		let tx = tx.clone();
+		let stats = stats.clone();
+		std::thread::spawn(move || {
+			receive_loop(&sock, buffer_size, 1, &stats, |dg| tx.send(dg).is_ok());
+		});
+	}
+
+	rx
+}
+
+/// Longest time [for_each_datagram_with_idle] goes without calling its idle callback
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set by the handler installed by [handle_shutdown_signals]
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Make SIGINT and SIGTERM stop [for_each_datagram] and [for_each_pcap_datagram] instead of killing the process
+///
+/// Both return within a second of the signal, so that buffered output and archive files can still be flushed. A second
+/// signal kills the process as usual. Only supported on Linux, elsewhere this does nothing
+pub fn handle_shutdown_signals() -> std::io::Result<()> {
+	#[cfg(target_os = "linux")]
+	{
+		extern "C" fn handler(signal: libc::c_int) {
+			SHUTDOWN.store(true, Ordering::Relaxed);
+			unsafe { libc::signal(signal, libc::SIG_DFL) };
+		}
+
+		for signal in [libc::SIGINT, libc::SIGTERM] {
+			if unsafe { libc::signal(signal, handler as *const () as libc::sighandler_t) } == libc::SIG_ERR {
+				return Err(std::io::Error::last_os_error());
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Check whether a signal handled by [handle_shutdown_signals] was received
+pub fn shutdown_requested() -> bool {
+	SHUTDOWN.load(Ordering::Relaxed)
+}
+
+/// Call `f` for every item (usually a datagram) from `rx` until all senders are gone or [shutdown_requested]
+///
+/// If `stats_interval` is set, a summary of `stats` is printed to stderr at that interval, and once more at the end
+pub fn for_each_datagram<T, F: FnMut(T)>(rx: &Receiver<T>, stats: &CollectorStats, stats_interval: Option<Duration>, f: F) {
+	for_each_datagram_with_idle(rx, stats, stats_interval, f, || {});
+}
+
+/// Like [for_each_datagram], but also call `idle` whenever `rx` has been drained, at least once a second, and at the end
+///
+/// This is where buffered output should be flushed: once per burst of items instead of after every single one
+pub fn for_each_datagram_with_idle<T, F, I>(rx: &Receiver<T>, stats: &CollectorStats, stats_interval: Option<Duration>, mut f: F, mut idle: I)
+	where F: FnMut(T), I: FnMut() {
+	let mut last_report = Instant::now();
+	let mut last_idle = Instant::now();
+	let mut pending = false;
+
+	while !shutdown_requested() {
+		let item = match rx.try_recv() {
+			Ok(item) => Some(item),
+			Err(TryRecvError::Disconnected) => break,
+			Err(TryRecvError::Empty) => {
+				if pending {
+					idle();
+					pending = false;
+					last_idle = Instant::now();
+				}
+
+				let timeout = IDLE_INTERVAL.saturating_sub(last_idle.elapsed());
+				let timeout = stats_interval.map_or(timeout, |i| timeout.min(i.saturating_sub(last_report.elapsed())));
+				match rx.recv_timeout(timeout) {
+					Ok(item) => Some(item),
+					Err(RecvTimeoutError::Timeout) => None,
+					Err(RecvTimeoutError::Disconnected) => break,
+				}
+			}
+		};
+
+		if let Some(item) = item {
+			f(item);
+			pending = true;
+		}
+
+		if last_idle.elapsed() >= IDLE_INTERVAL {
+			idle();
+			pending = false;
+			last_idle = Instant::now();
+		}
+		if stats_interval.is_some_and(|i| last_report.elapsed() >= i) {
+			eprintln!("Stats: {}", stats.snapshot());
+			last_report = Instant::now();
+		}
+	}
+
+	idle();
+	if stats_interval.is_some() {
+		eprintln!("Stats: {}", stats.snapshot());
+	}
+}
+
+/// Read every UDP datagram from the pcap or pcapng file at `path`, pass it to `handler` as if it had been received,
+/// and call `f` with every result `handler` returns
+///
+/// Datagrams keep the source address and capture time recorded in the file. Packets that do not carry a complete UDP
+/// datagram are skipped, as are datagrams to ports other than `ports`, unless `ports` is empty. Stops early once
+/// [shutdown_requested]. If `stats_interval` is set, a summary of `stats` is printed to stderr at the end
+pub fn for_each_pcap_datagram<T, H, F>(path: &Path, ports: &[u16], stats: &CollectorStats, stats_interval: Option<Duration>, mut handler: H, mut f: F) -> std::io::Result<()>
+	where H: FnMut(ReceivedDatagram) -> Option<T>, F: FnMut(T) {
+	for packet in PcapReader::open(path)? {
+		if shutdown_requested() {
+			break;
+		}
+		let packet = packet?;
+		let Some(udp) = packet.udp().filter(|udp| ports.is_empty() || ports.contains(&udp.dst.port())) else { continue };
+
+		CollectorStats::inc(&stats.datagrams);
+		stats.bytes.fetch_add(udp.payload.len() as u64, Ordering::Relaxed);
+		if let Some(result) = handler(ReceivedDatagram { data: udp.payload.to_vec(), addr: udp.src, received: packet.timestamp }) {
+			f(result);
+		}
+	}
+
+	if stats_interval.is_some() {
+		eprintln!("Stats: {}", stats.snapshot());
+	}
+
+	Ok(())
+}
+
+/// Flow protocol of a datagram, as detected by [detect_protocol]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FlowProtocol {
+	/// NetFlow with the given version
+	Netflow(u16),
+	/// sFlow version 5
+	SFlow,
+}
+
+/// Detect the protocol of a raw datagram from its version field
+///
+/// sFlow datagrams start with a 32-bit version number of 5, while NetFlow datagrams start with a 16-bit version number
+/// of 1, 5, or 9. A NetFlow datagram can never look like sFlow, as its version field would read as 0. NetFlow v7 and
+/// IPFIX datagrams are not detected, as the parser does not support them
+pub fn detect_protocol(input: &[u8]) -> Option<FlowProtocol> {
+	match input {
+		[0, 0, 0, 5, ..] => Some(FlowProtocol::SFlow),
+		[0, v @ (1 | 5 | 9), ..] => Some(FlowProtocol::Netflow(*v as u16)),
+		_ => None,
+	}
+}
+
+/// A parsed datagram of any supported protocol
+#[derive(Debug, Clone)]
+pub enum ParsedDatagram {
+	Netflow(NetflowDatagramData),
+	SFlow(Datagram),
+}
+
+/// Parser detecting the protocol of every datagram and dispatching it to [NetflowParser] or [parse_sflow_data]
+#[derive(Debug, Clone, Default)]
+pub struct MultiflowParser {
+	netflow: NetflowParser,
+}
+
+impl MultiflowParser {
+	/// Initialize parser state
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Get the NetFlow parser used for NetFlow and IPFIX datagrams
+	pub fn netflow_parser(&mut self) -> &mut NetflowParser {
+		&mut self.netflow
+	}
+
+	/// Parse the datagram bytes from `input` that are coming in from `addr`, detecting the protocol with [detect_protocol]
+	///
+	/// # Errors
+	///
+	/// Fails with a nom Fail error if the protocol could not be detected, and otherwise in the same situations as the
+	/// protocol-specific parsers
+	pub fn parse<'a>(&mut self, input: &'a [u8], addr: &SocketAddr) -> IResult<&'a [u8], ParsedDatagram> {
+		match detect_protocol(input) {
+			Some(FlowProtocol::SFlow) => {
+				let (res, dg) = parse_sflow_data(input)?;
+				Ok((res, ParsedDatagram::SFlow(dg)))
+			}
+			Some(FlowProtocol::Netflow(_)) => {
+				let (res, dg) = self.netflow.parse(input, addr)?;
+				Ok((res, ParsedDatagram::Netflow(dg)))
+			}
+			None => nom::combinator::fail(input),
+		}
+	}
+}
@@ -0,0 +1,113 @@
+//! Complete collector pipeline as run by the collector binaries
+//!
+//! [run_collector] receives datagrams as set up by a [CollectorConfig], from UDP sockets, a live capture, or a pcap
+//! file. Every datagram is archived if configured, parsed on the worker of its exporter, checked for sequence number
+//! events, and the parsed result is written to the configured output
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use crate::collector::archive::DatagramArchive;
+use crate::collector::sharded::ShardedCollector;
+use crate::collector::{for_each_datagram_with_idle, for_each_pcap_datagram, handle_shutdown_signals, CollectorStats, ReceivedDatagram};
+use crate::config::CollectorConfig;
+use crate::output::FlowWriter;
+use crate::sequence::{SequenceEvent, SequenceTracker};
+
+/// Output the collector binaries write parsed datagrams to
+pub type CollectorOutput = FlowWriter<Box<dyn Write + Send>>;
+
+/// Add context to an error of one of the collector's setup steps
+fn context(step: &str, e: io::Error) -> io::Error {
+	io::Error::new(e.kind(), format!("{}: {}", step, e))
+}
+
+/// Run the collector configured by `config` until its input ends
+///
+/// `make_parser` is called once per worker thread. A parser returns the parsed datagram together with the sequence
+/// number events it caused in the worker's [SequenceTracker], or `None` if the datagram could not be parsed, which is
+/// counted and reported as a failed `protocol` packet. `write` writes a parsed datagram to the output
+///
+/// Listening collectors run until SIGINT or SIGTERM, while reading a pcap file returns once the whole file was processed.
+/// Output and archive are flushed whenever the collector is idle, at least once a second, and before returning
+///
+/// # Errors
+///
+/// Fails if the signal handlers cannot be installed, or the output, archive, sockets, capture, or pcap file cannot be
+/// opened or read
+///
+/// # Panics
+///
+/// Panics if writing the output fails
+pub fn run_collector<T, M, P, W>(config: &CollectorConfig, protocol: &'static str, make_parser: M, mut write: W) -> io::Result<()>
+	where T: Send + 'static,
+		M: Fn() -> P,
+		P: FnMut(&ReceivedDatagram, &mut SequenceTracker) -> Option<(T, Vec<SequenceEvent>)> + Send + 'static,
+		W: FnMut(&mut CollectorOutput, &SocketAddr, SystemTime, &T) -> io::Result<()> {
+	handle_shutdown_signals().map_err(|e| context("Failed to handle signals", e))?;
+	let stats = Arc::new(CollectorStats::default());
+	let out = config.open_output().map_err(|e| context("Failed to open output", e))?;
+	let archive = match config.archive_config() {
+		Some(c) => Some(Arc::new(DatagramArchive::open(c).map_err(|e| context("Failed to open archive", e))?)),
+		None => None,
+	};
+
+	let verbosity = config.verbosity;
+	let make_handler = |_: usize| {
+		let mut parse = make_parser();
+		let mut tracker = SequenceTracker::new();
+		let stats = stats.clone();
+		let archive = archive.clone();
+		move |dg: ReceivedDatagram| {
+			if verbosity > 0 {
+				eprintln!("Received {} bytes from {}", dg.data.len(), dg.addr);
+			}
+			if let Some(Err(e)) = archive.as_ref().map(|a| a.record(&dg)) {
+				eprintln!("Failed to archive datagram from {}: {}", dg.addr, e);
+			}
+
+			let Some((parsed, events)) = parse(&dg, &mut tracker) else {
+				CollectorStats::inc(&stats.parse_errors);
+				if verbosity >= 0 {
+					eprintln!("Failed to process {} packet from {}", protocol, dg.addr);
+				}
+				return None;
+			};
+
+			CollectorStats::inc(&stats.parsed);
+			stats.record_sequence_events(&events);
+			if verbosity > 0 {
+				for event in &events {
+					eprintln!("Sequence event from {}: {:?}", dg.addr, event);
+				}
+			}
+			Some((dg.addr, dg.received, parsed))
+		}
+	};
+
+	// Flushed once the queue of parsed datagrams runs empty, not after every datagram
+	let out = RefCell::new(out);
+	let write = |(addr, received, parsed): (SocketAddr, SystemTime, T)| {
+		write(&mut out.borrow_mut(), &addr, received, &parsed).expect("Failed to write output");
+	};
+	let flush = || {
+		out.borrow_mut().flush().expect("Failed to write output");
+		if let Some(Err(e)) = archive.as_ref().map(|a| a.flush()) {
+			eprintln!("Failed to write archive file: {}", e);
+		}
+	};
+
+	if let Some(path) = &config.pcap {
+		let res = for_each_pcap_datagram(path, &config.listen_ports(), &stats, config.stats_interval(), make_handler(0), write);
+		flush();
+		return res.map_err(|e| context("Failed to read capture file", e));
+	}
+
+	let (_collector, rx) = ShardedCollector::spawn(&config.sharded_config(), stats.clone(), make_handler)
+		.map_err(|e| context("Failed to bind to UDP socket", e))?;
+	for_each_datagram_with_idle(&rx, &stats, config.stats_interval(), write, flush);
+
+	Ok(())
+}
@@ -0,0 +1,198 @@
+//! Simple file format for recorded flow datagrams
+//!
+//! A recording starts with the 4 byte magic `MFDG` and a version byte, padded to 8 bytes. Every datagram follows as a
+//! record, all numbers big-endian:
+//!
+//! | Field | Size |
+//! |-------|------|
+//! | Receive time, seconds since the Unix epoch | 8 |
+//! | Receive time, nanoseconds | 4 |
+//! | Protocol: 0 unknown, 1 NetFlow, 2 sFlow | 1 |
+//! | Protocol version | 1 |
+//! | Exporter address family: 4 or 6 | 1 |
+//! | Reserved, 0 | 1 |
+//! | Exporter IP address | 4 or 16 |
+//! | Exporter port | 2 |
+//! | Datagram length | 4 |
+//! | Datagram | length |
+
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::collector::{detect_protocol, FlowProtocol, ReceivedDatagram, MAX_DATAGRAM_SIZE};
+
+/// Magic at the start of every recording
+pub const RECORDING_MAGIC: [u8; 4] = *b"MFDG";
+
+const RECORDING_VERSION: u8 = 1;
+
+/// A datagram read from a recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedDatagram {
+	/// Time at which the datagram was received
+	pub received: SystemTime,
+	/// Address of the exporter the datagram came from
+	pub addr: SocketAddr,
+	/// Protocol detected when the datagram was recorded
+	pub protocol: Option<FlowProtocol>,
+	pub data: Vec<u8>,
+}
+
+impl From<RecordedDatagram> for ReceivedDatagram {
+	fn from(dg: RecordedDatagram) -> Self {
+		ReceivedDatagram { data: dg.data, addr: dg.addr, received: dg.received }
+	}
+}
+
+/// Writer appending datagrams to a recording
+#[derive(Debug)]
+pub struct RecordingWriter<W: Write> {
+	writer: W,
+}
+
+impl<W: Write> RecordingWriter<W> {
+	/// Start a new recording written to `writer`, writing its header
+	pub fn new(mut writer: W) -> io::Result<Self> {
+		writer.write_all(&RECORDING_MAGIC)?;
+		writer.write_all(&[RECORDING_VERSION, 0, 0, 0])?;
+
+		Ok(Self { writer })
+	}
+
+	/// Append `dg` to the recording, detecting its protocol
+	///
+	/// Returns the number of bytes written
+	pub fn write(&mut self, dg: &ReceivedDatagram) -> io::Result<usize> {
+		let since_epoch = dg.received.duration_since(UNIX_EPOCH).unwrap_or_default();
+		let (protocol, version) = match detect_protocol(&dg.data) {
+			Some(FlowProtocol::Netflow(v)) => (1, v as u8),
+			Some(FlowProtocol::SFlow) => (2, 5),
+			None => (0, 0),
+		};
+		let length = u32::try_from(dg.data.len()).map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Datagram too long"))?;
+
+		let mut header = Vec::with_capacity(36);
+		header.extend_from_slice(&since_epoch.as_secs().to_be_bytes());
+		header.extend_from_slice(&since_epoch.subsec_nanos().to_be_bytes());
+		header.push(protocol);
+		header.push(version);
+		match dg.addr.ip() {
+			IpAddr::V4(ip) => {
+				header.extend_from_slice(&[4, 0]);
+				header.extend_from_slice(&ip.octets());
+			}
+			IpAddr::V6(ip) => {
+				header.extend_from_slice(&[6, 0]);
+				header.extend_from_slice(&ip.octets());
+			}
+		}
+		header.extend_from_slice(&dg.addr.port().to_be_bytes());
+		header.extend_from_slice(&length.to_be_bytes());
+
+		self.writer.write_all(&header)?;
+		self.writer.write_all(&dg.data)?;
+		Ok(header.len() + dg.data.len())
+	}
+
+	pub fn flush(&mut self) -> io::Result<()> {
+		self.writer.flush()
+	}
+
+	/// Get the underlying writer
+	pub fn into_inner(self) -> W {
+		self.writer
+	}
+}
+
+/// Reader for recordings written by [RecordingWriter]
+#[derive(Debug)]
+pub struct RecordingReader<R: Read> {
+	reader: R,
+}
+
+impl RecordingReader<BufReader<File>> {
+	/// Open the recording at `path`
+	pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+		Self::new(BufReader::new(File::open(path)?))
+	}
+}
+
+impl<R: Read> RecordingReader<R> {
+	/// Create a reader for the recording read from `reader`, checking its header
+	///
+	/// # Errors
+	///
+	/// Fails if the header cannot be read, or if it is not a recording of a supported version
+	pub fn new(mut reader: R) -> io::Result<Self> {
+		let mut header = [0u8; 8];
+		reader.read_exact(&mut header)?;
+		if header[..4] != RECORDING_MAGIC {
+			return Err(io::Error::new(ErrorKind::InvalidData, "Not a datagram recording"));
+		}
+		if header[4] != RECORDING_VERSION {
+			return Err(io::Error::new(ErrorKind::InvalidData, format!("Unsupported recording version {}", header[4])));
+		}
+
+		Ok(Self { reader })
+	}
+
+	/// Read the next datagram, returning `None` at the end of the recording
+	pub fn next_datagram(&mut self) -> io::Result<Option<RecordedDatagram>> {
+		let mut header = [0u8; 16];
+		match self.reader.read_exact(&mut header[..1]) {
+			Ok(()) => {}
+			Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+			Err(e) => return Err(e),
+		}
+		self.reader.read_exact(&mut header[1..])?;
+
+		let secs = u64::from_be_bytes(header[..8].try_into().unwrap());
+		let nanos = u32::from_be_bytes(header[8..12].try_into().unwrap());
+		if nanos >= 1_000_000_000 {
+			return Err(io::Error::new(ErrorKind::InvalidData, "Invalid timestamp in recording"));
+		}
+		let protocol = match (header[12], header[13]) {
+			(1, v) => Some(FlowProtocol::Netflow(v as u16)),
+			(2, _) => Some(FlowProtocol::SFlow),
+			_ => None,
+		};
+
+		let ip = match header[14] {
+			4 => {
+				let mut octets = [0u8; 4];
+				self.reader.read_exact(&mut octets)?;
+				IpAddr::V4(Ipv4Addr::from(octets))
+			}
+			6 => {
+				let mut octets = [0u8; 16];
+				self.reader.read_exact(&mut octets)?;
+				IpAddr::V6(Ipv6Addr::from(octets))
+			}
+			_ => return Err(io::Error::new(ErrorKind::InvalidData, "Invalid address family in recording")),
+		};
+
+		let mut port_length = [0u8; 6];
+		self.reader.read_exact(&mut port_length)?;
+		let port = u16::from_be_bytes([port_length[0], port_length[1]]);
+		let length = u32::from_be_bytes(port_length[2..].try_into().unwrap()) as usize;
+		if length > MAX_DATAGRAM_SIZE {
+			return Err(io::Error::new(ErrorKind::InvalidData, "Invalid datagram length in recording"));
+		}
+
+		let mut data = vec![0u8; length];
+		self.reader.read_exact(&mut data)?;
+
+		let received = UNIX_EPOCH.checked_add(Duration::new(secs, nanos)).unwrap_or(UNIX_EPOCH);
+		Ok(Some(RecordedDatagram { received, addr: SocketAddr::new(ip, port), protocol, data }))
+	}
+}
+
+impl<R: Read> Iterator for RecordingReader<R> {
+	type Item = io::Result<RecordedDatagram>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.next_datagram().transpose()
+	}
+}
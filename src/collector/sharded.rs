@@ -0,0 +1,177 @@
+//! Multi-threaded collector sharding exporters across worker threads
+//!
+//! The collector runs three stages connected by bounded channels:
+//! - receive threads, several per listen address when SO_REUSEPORT is available, or a single packet capture thread
+//! - worker threads, each owning its own handler (e.g. a [NetflowParser](crate::netflow_parse::NetflowParser)). Every
+//!   exporter IP address is always handled by the same worker, so its template state lives in exactly one parser
+//! - the output, read from the [Receiver] returned by [ShardedCollector::spawn]
+//!
+//! When a channel is full, the item is dropped and counted in [CollectorStats] instead of blocking the previous stage
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use crate::collector::{bind_udp, receive_loop, CollectorStats, ReceivedDatagram, DEFAULT_BATCH_SIZE, MAX_DATAGRAM_SIZE};
+#[cfg(target_os = "linux")]
+use crate::collector::capture::{capture_loop, PacketCapture};
+
+/// Settings for a [ShardedCollector]
+#[derive(Debug, Clone)]
+pub struct ShardedCollectorConfig {
+	/// Addresses to listen on
+	pub listen: Vec<SocketAddr>,
+	/// Receive threads (and sockets) per listen address; values above 1 require SO_REUSEPORT and are rejected when
+	/// capturing
+	pub receive_threads: usize,
+	/// Number of worker threads
+	pub workers: usize,
+	/// Capacity of each worker queue and of the output queue, at least 1
+	pub queue_size: usize,
+	/// Size of the receive buffer in bytes
+	pub buffer_size: usize,
+	/// Datagrams received per system call using `recvmmsg` (Linux only), 1 to use `recv_from`; not used when capturing
+	pub batch_size: usize,
+	/// Kernel socket receive buffer size (SO_RCVBUF) in bytes, if it should be changed
+	pub recv_buffer: Option<usize>,
+	/// Interface to capture datagrams from with `AF_PACKET` (Linux only) instead of binding the listen addresses, whose
+	/// ports are used to filter the captured traffic. `any` captures from all interfaces
+	pub capture: Option<String>,
+}
+
+impl Default for ShardedCollectorConfig {
+	fn default() -> Self {
+		Self { listen: vec![], receive_threads: 1, workers: 1, queue_size: 1024, buffer_size: MAX_DATAGRAM_SIZE, batch_size: DEFAULT_BATCH_SIZE, recv_buffer: None, capture: None }
+	}
+}
+
+/// Get the worker index handling datagrams from `addr` when there are `workers` workers
+pub fn shard_for(addr: &IpAddr, workers: usize) -> usize {
+	let mut hasher = DefaultHasher::new();
+	addr.hash(&mut hasher);
+
+	(hasher.finish() % workers.max(1) as u64) as usize
+}
+
+fn try_send_counted<T>(tx: &SyncSender<T>, item: T, drops: &std::sync::atomic::AtomicU64) -> bool {
+	match tx.try_send(item) {
+		Ok(()) => true,
+		Err(TrySendError::Full(_)) => {
+			CollectorStats::inc(drops);
+			true
+		}
+		Err(TrySendError::Disconnected(_)) => false,
+	}
+}
+
+/// Running multi-threaded collector
+pub struct ShardedCollector {
+	stats: Arc<CollectorStats>,
+	threads: Vec<JoinHandle<()>>,
+}
+
+impl ShardedCollector {
+	/// Bind all sockets and start the receive and worker threads
+	///
+	/// `make_handler` is called once per worker with its index. Every datagram is passed to the handler of its
+	/// exporter's worker, and each `Some` result is forwarded to the returned output channel. The threads stop once
+	/// the output receiver is dropped and a further result or datagram arrives
+	///
+	/// # Errors
+	///
+	/// Fails if any of the sockets could not be bound, or if the capture could not be opened or was configured with more
+	/// than one receive thread
+	pub fn spawn<T, H, M>(config: &ShardedCollectorConfig, stats: Arc<CollectorStats>, mut make_handler: M) -> std::io::Result<(Self, Receiver<T>)>
+		where T: Send + 'static, H: FnMut(ReceivedDatagram) -> Option<T> + Send + 'static, M: FnMut(usize) -> H {
+		let receive_threads = config.receive_threads.max(1);
+		let mut sockets = Vec::with_capacity(config.listen.len() * receive_threads);
+		#[cfg(target_os = "linux")]
+		let mut capture = None;
+		match &config.capture {
+			#[cfg(target_os = "linux")]
+			Some(interface) => {
+				if receive_threads > 1 {
+					return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Packet capture uses a single receive thread"));
+				}
+				let mut ports: Vec<u16> = config.listen.iter().map(|a| a.port()).collect();
+				ports.sort_unstable();
+				ports.dedup();
+				let interface = Some(interface.as_str()).filter(|i| *i != "any");
+				capture = Some(PacketCapture::open(interface, &ports, config.recv_buffer)?);
+			}
+			#[cfg(not(target_os = "linux"))]
+			Some(_) => return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Packet capture is only supported on Linux")),
+			None => {
+				for addr in &config.listen {
+					for _ in 0..receive_threads {
+						sockets.push(bind_udp(addr, config.recv_buffer, receive_threads > 1)?);
+					}
+				}
+			}
+		}
+
+		let mut threads = Vec::new();
+		// A capacity of 0 would make rendezvous channels, dropping everything no thread is waiting for
+		let queue_size = config.queue_size.max(1);
+		let (out_tx, out_rx) = sync_channel(queue_size);
+
+		let mut worker_txs = Vec::with_capacity(config.workers.max(1));
+		for i in 0..config.workers.max(1) {
+			let (tx, rx) = sync_channel::<ReceivedDatagram>(queue_size);
+			worker_txs.push(tx);
+
+			let mut handler = make_handler(i);
+			let out_tx = out_tx.clone();
+			let stats = stats.clone();
+			threads.push(std::thread::spawn(move || {
+				for dg in rx {
+					if let Some(result) = handler(dg) {
+						if !try_send_counted(&out_tx, result, &stats.output_drops) {
+							return;
+						}
+					}
+				}
+			}));
+		}
+
+		for sock in sockets {
+			let worker_txs = worker_txs.clone();
+			let stats = stats.clone();
+			let buffer_size = config.buffer_size;
+			let batch_size = config.batch_size;
+			threads.push(std::thread::spawn(move || {
+				receive_loop(&sock, buffer_size, batch_size, &stats, |dg| {
+					let tx = &worker_txs[shard_for(&dg.addr.ip(), worker_txs.len())];
+					try_send_counted(tx, dg, &stats.queue_drops)
+				});
+			}));
+		}
+
+		#[cfg(target_os = "linux")]
+		if let Some(mut capture) = capture {
+			let stats = stats.clone();
+			threads.push(std::thread::spawn(move || {
+				capture_loop(&mut capture, &stats, |dg| {
+					let tx = &worker_txs[shard_for(&dg.addr.ip(), worker_txs.len())];
+					try_send_counted(tx, dg, &stats.queue_drops)
+				});
+			}));
+		}
+
+		Ok((Self { stats, threads }, out_rx))
+	}
+
+	/// Get the statistics shared by all stages
+	pub fn stats(&self) -> &Arc<CollectorStats> {
+		&self.stats
+	}
+
+	/// Wait for all threads to finish
+	pub fn join(self) {
+		for t in self.threads {
+			let _ = t.join();
+		}
+	}
+}
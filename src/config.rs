@@ -0,0 +1,327 @@
+//! Command-line and configuration file handling for the collector binaries
+//!
+//! All options can be given on the command line or in a TOML configuration file passed with `--config`.
+//! Command-line values take precedence over the configuration file. An example configuration file:
+//!
+//! ```toml
+//! listen = ["0.0.0.0:9000", "[::]:9000"]
+//! # pcap = "flows.pcapng"
+//! archive = "/var/lib/multiflow/archive"
+//! archive_max_files = 48
+//! buffer_size = 65535
+//! recv_buffer = 8388608
+//! batch_size = 32
+//! format = "json"
+//! granularity = "record"
+//! output = "/var/log/flows.jsonl"
+//! verbosity = 1
+//! stats_interval = 60
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use clap::{ArgAction, Parser};
+use serde::Deserialize;
+use crate::collector::{DEFAULT_BATCH_SIZE, MAX_DATAGRAM_SIZE};
+use crate::collector::archive::ArchiveConfig;
+use crate::collector::sharded::ShardedCollectorConfig;
+use crate::output::{FlowWriter, OutputFormat, OutputMode};
+
+/// Command-line arguments shared by the collector binaries
+#[derive(Debug, Clone, Default, Parser)]
+#[command(version, about = "Receive flow datagrams over UDP and write them out as JSON lines")]
+pub struct CollectorArgs {
+	/// TOML configuration file; command-line options override its values
+	#[arg(short, long, value_name = "FILE")]
+	pub config: Option<PathBuf>,
+	/// Address to listen on (IPv4 or IPv6), may be given multiple times
+	#[arg(short, long, value_name = "ADDR")]
+	pub listen: Vec<SocketAddr>,
+	/// Read datagrams to the listen ports from a pcap or pcapng capture file instead of listening, and exit at its end
+	#[arg(long, value_name = "FILE")]
+	pub pcap: Option<PathBuf>,
+	/// Capture datagrams mirrored to interface IFACE (or `any`) instead of listening, keeping those to the listen ports
+	/// (Linux only, requires CAP_NET_RAW)
+	#[arg(long, value_name = "IFACE")]
+	pub capture: Option<String>,
+	/// Directory to archive every received datagram in, for parsing again later
+	#[arg(short, long, value_name = "DIR")]
+	pub archive: Option<PathBuf>,
+	/// Size in bytes after which a new archive file is started
+	#[arg(long, value_name = "BYTES")]
+	pub archive_rotate_size: Option<u64>,
+	/// Interval in seconds after which a new archive file is started
+	#[arg(long, value_name = "SECONDS")]
+	pub archive_rotate_interval: Option<u64>,
+	/// Number of archive files to keep, deleting the oldest ones, 0 to keep all
+	#[arg(long, value_name = "COUNT")]
+	pub archive_max_files: Option<usize>,
+	/// Size of the receive buffer in bytes; longer datagrams are truncated
+	#[arg(short, long, value_name = "BYTES")]
+	pub buffer_size: Option<usize>,
+	/// Kernel socket receive buffer size (SO_RCVBUF) in bytes
+	#[arg(short, long, value_name = "BYTES")]
+	pub recv_buffer: Option<usize>,
+	/// Datagrams received per system call using recvmmsg (Linux only, default 32), 1 to disable batching
+	#[arg(long, value_name = "COUNT")]
+	pub batch_size: Option<usize>,
+	/// Receive threads (and SO_REUSEPORT sockets) per listen address
+	#[arg(short = 't', long, value_name = "COUNT")]
+	pub receive_threads: Option<usize>,
+	/// Worker threads parsing datagrams, exporters are sharded across them
+	#[arg(short, long, value_name = "COUNT")]
+	pub workers: Option<usize>,
+	/// Capacity of the queues between the receive, worker, and output stages
+	#[arg(long, value_name = "COUNT")]
+	pub queue_size: Option<usize>,
+	/// Output format
+	#[arg(short, long, value_enum)]
+	pub format: Option<OutputFormat>,
+	/// Emit one object per flow record or per datagram
+	#[arg(short, long, value_enum)]
+	pub granularity: Option<OutputMode>,
+	/// Output file, `-` for standard output
+	#[arg(short, long, value_name = "FILE")]
+	pub output: Option<PathBuf>,
+	/// Interval in seconds at which to print statistics to stderr, 0 to disable
+	#[arg(short, long, value_name = "SECONDS")]
+	pub stats_interval: Option<u64>,
+	/// Increase verbosity, may be repeated
+	#[arg(short, long, action = ArgAction::Count)]
+	pub verbose: u8,
+	/// Decrease verbosity, may be repeated
+	#[arg(short, long, action = ArgAction::Count)]
+	pub quiet: u8,
+}
+
+/// Resolved collector configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CollectorConfig {
+	/// Addresses to listen on
+	pub listen: Vec<SocketAddr>,
+	/// Capture file to read datagrams from instead of listening
+	pub pcap: Option<PathBuf>,
+	/// Interface to capture datagrams from instead of listening, `any` for all interfaces
+	pub capture: Option<String>,
+	/// Directory to archive every received datagram in
+	pub archive: Option<PathBuf>,
+	/// Size in bytes after which a new archive file is started
+	pub archive_rotate_size: u64,
+	/// Interval in seconds after which a new archive file is started
+	pub archive_rotate_interval: u64,
+	/// Number of archive files to keep, 0 to keep all
+	pub archive_max_files: usize,
+	/// Size of the receive buffer in bytes
+	pub buffer_size: usize,
+	/// Kernel socket receive buffer size (SO_RCVBUF) in bytes, if it should be changed
+	pub recv_buffer: Option<usize>,
+	/// Datagrams received per system call, [DEFAULT_BATCH_SIZE] if not set
+	pub batch_size: Option<usize>,
+	/// Receive threads per listen address
+	pub receive_threads: usize,
+	/// Worker threads
+	pub workers: usize,
+	/// Capacity of the queues between stages
+	pub queue_size: usize,
+	/// Output format
+	pub format: OutputFormat,
+	/// Output granularity
+	pub granularity: OutputMode,
+	/// Output file, standard output if `None` or `-`
+	pub output: Option<PathBuf>,
+	/// Interval in seconds at which to print statistics, 0 to disable
+	pub stats_interval: u64,
+	/// Verbosity level: negative values silence errors, 0 reports errors, 1 and above report every datagram
+	pub verbosity: i8,
+}
+
+impl Default for CollectorConfig {
+	fn default() -> Self {
+		Self {
+			listen: vec![],
+			pcap: None,
+			capture: None,
+			archive: None,
+			archive_rotate_size: 64 * 1024 * 1024,
+			archive_rotate_interval: 3600,
+			archive_max_files: 0,
+			buffer_size: MAX_DATAGRAM_SIZE,
+			recv_buffer: None,
+			batch_size: None,
+			receive_threads: 1,
+			workers: 1,
+			queue_size: 1024,
+			format: OutputFormat::default(),
+			granularity: OutputMode::default(),
+			output: None,
+			stats_interval: 60,
+			verbosity: 0,
+		}
+	}
+}
+
+/// Error while loading the collector configuration
+#[derive(Debug)]
+pub enum ConfigError {
+	/// The configuration file could not be read
+	Io(PathBuf, std::io::Error),
+	/// The configuration file is not valid
+	Parse(PathBuf, toml::de::Error),
+	/// The given options cannot be combined
+	Conflict(&'static str),
+}
+
+impl Display for ConfigError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ConfigError::Io(path, e) => write!(f, "Failed to read config file {}: {}", path.display(), e),
+			ConfigError::Parse(path, e) => write!(f, "Invalid config file {}: {}", path.display(), e),
+			ConfigError::Conflict(message) => write!(f, "{}", message),
+		}
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+impl CollectorConfig {
+	/// Build the configuration from command-line arguments, reading the configuration file if one was given
+	///
+	/// `default_listen` is used when no listen address was given in either place. Capturing from an interface rejects
+	/// `batch_size` and more than one receive thread, as the capture socket is read by a single thread
+	pub fn load(args: CollectorArgs, default_listen: &[SocketAddr]) -> Result<Self, ConfigError> {
+		let mut config = match &args.config {
+			Some(path) => {
+				let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+				toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?
+			}
+			None => Self::default(),
+		};
+
+		if !args.listen.is_empty() {
+			config.listen = args.listen;
+		}
+		if config.listen.is_empty() {
+			config.listen = default_listen.to_vec();
+		}
+		if args.pcap.is_some() {
+			config.pcap = args.pcap;
+		}
+		if args.capture.is_some() {
+			config.capture = args.capture;
+		}
+		if args.archive.is_some() {
+			config.archive = args.archive;
+		}
+		if let Some(s) = args.archive_rotate_size {
+			config.archive_rotate_size = s;
+		}
+		if let Some(i) = args.archive_rotate_interval {
+			config.archive_rotate_interval = i;
+		}
+		if let Some(m) = args.archive_max_files {
+			config.archive_max_files = m;
+		}
+		if let Some(b) = args.buffer_size {
+			config.buffer_size = b;
+		}
+		if args.recv_buffer.is_some() {
+			config.recv_buffer = args.recv_buffer;
+		}
+		if args.batch_size.is_some() {
+			config.batch_size = args.batch_size;
+		}
+		if let Some(t) = args.receive_threads {
+			config.receive_threads = t;
+		}
+		if let Some(w) = args.workers {
+			config.workers = w;
+		}
+		if let Some(q) = args.queue_size {
+			config.queue_size = q;
+		}
+		if let Some(f) = args.format {
+			config.format = f;
+		}
+		if let Some(g) = args.granularity {
+			config.granularity = g;
+		}
+		if args.output.is_some() {
+			config.output = args.output;
+		}
+		if let Some(i) = args.stats_interval {
+			config.stats_interval = i;
+		}
+		config.verbosity = config.verbosity.saturating_add(args.verbose as i8).saturating_sub(args.quiet as i8);
+
+		if config.capture.is_some() && (config.batch_size.is_some() || config.receive_threads > 1) {
+			return Err(ConfigError::Conflict("batch_size and receive_threads cannot be used with capture"));
+		}
+
+		Ok(config)
+	}
+
+	/// Parse the process arguments and load the configuration, exiting with an error message on failure
+	pub fn from_env(default_listen: &[SocketAddr]) -> Self {
+		match Self::load(CollectorArgs::parse(), default_listen) {
+			Ok(c) => c,
+			Err(e) => {
+				eprintln!("{}", e);
+				std::process::exit(2);
+			}
+		}
+	}
+
+	/// Get the statistics interval, `None` if disabled or when running with negative verbosity
+	pub fn stats_interval(&self) -> Option<std::time::Duration> {
+		(self.stats_interval > 0 && self.verbosity >= 0).then(|| std::time::Duration::from_secs(self.stats_interval))
+	}
+
+	/// Get the ports of the listen addresses, to keep datagrams sent to them from a capture
+	pub fn listen_ports(&self) -> Vec<u16> {
+		let mut ports: Vec<u16> = self.listen.iter().map(|a| a.port()).collect();
+		ports.sort_unstable();
+		ports.dedup();
+		ports
+	}
+
+	/// Get the settings for a [ShardedCollector](crate::collector::sharded::ShardedCollector)
+	pub fn sharded_config(&self) -> ShardedCollectorConfig {
+		ShardedCollectorConfig {
+			listen: self.listen.clone(),
+			receive_threads: self.receive_threads,
+			workers: self.workers,
+			queue_size: self.queue_size,
+			buffer_size: self.buffer_size,
+			batch_size: self.batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
+			recv_buffer: self.recv_buffer,
+			capture: self.capture.clone(),
+		}
+	}
+
+	/// Get the settings for a [DatagramArchive](crate::collector::archive::DatagramArchive), `None` if archiving is
+	/// disabled
+	pub fn archive_config(&self) -> Option<ArchiveConfig> {
+		self.archive.as_ref().map(|dir| ArchiveConfig {
+			dir: dir.clone(),
+			rotate_size: self.archive_rotate_size,
+			rotate_interval: std::time::Duration::from_secs(self.archive_rotate_interval),
+			max_files: self.archive_max_files,
+		})
+	}
+
+	/// Open the configured output, appending to the output file if it already exists
+	pub fn open_output(&self) -> std::io::Result<FlowWriter<Box<dyn Write + Send>>> {
+		let out: Box<dyn Write + Send> = match &self.output {
+			Some(path) if path.as_os_str() != "-" => {
+				let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+				Box::new(std::io::BufWriter::new(file))
+			}
+			_ => Box::new(std::io::BufWriter::new(std::io::stdout())),
+		};
+
+		Ok(FlowWriter::new(out, self.format, self.granularity))
+	}
+}
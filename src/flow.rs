@@ -0,0 +1,219 @@
+//! Protocol independent flow records
+//!
+//! [FlowRecord] holds the commonly used fields of a single flow, extracted from NetFlow v1/v5 records, NetFlow v9 data
+//! records, or sFlow flow samples (by decoding their raw packet headers with [crate::packet])
+
+use std::net::IpAddr;
+use crate::netflow_parse::datagram::NetflowDatagramData;
+use crate::netflow_parse::datagram_v9::NetflowDatagramV9FlowSet;
+use crate::netflow_parse::datagram_v9_data::{NetflowDatagramRecordsType, NetflowV9DataField, NetflowV9DataValue};
+use crate::packet;
+use crate::sflow_parse::datagram::Datagram;
+use crate::sflow_parse::sample::flow::SFlowFlowSampleRecord;
+use crate::sflow_parse::sample::SFlowSample;
+
+/// sFlow header protocol number for Ethernet frames
+const SFLOW_HEADER_ETHERNET: u32 = 1;
+/// sFlow header protocol number for raw IPv4 packets
+const SFLOW_HEADER_IPV4: u32 = 11;
+/// sFlow header protocol number for raw IPv6 packets
+const SFLOW_HEADER_IPV6: u32 = 12;
+
+/// A single flow with the fields common to all protocols
+///
+/// Fields the exporter did not provide are `None`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FlowRecord {
+	/// Address of the exporter (NetFlow) or agent (sFlow)
+	pub exporter: IpAddr,
+	pub src_addr: Option<IpAddr>,
+	pub dst_addr: Option<IpAddr>,
+	pub src_port: Option<u16>,
+	pub dst_port: Option<u16>,
+	pub protocol: Option<u8>,
+	pub tos: Option<u8>,
+	pub tcp_flags: Option<u8>,
+	pub src_as: Option<u32>,
+	pub dst_as: Option<u32>,
+	pub input_if: Option<u32>,
+	pub output_if: Option<u32>,
+	/// Exporter uptime in milliseconds when the first packet of the flow was seen
+	pub start: Option<u32>,
+	/// Exporter uptime in milliseconds when the last packet of the flow was seen
+	pub end: Option<u32>,
+	/// Bytes as reported, not scaled by the sampling rate
+	pub bytes: u64,
+	/// Packets as reported, not scaled by the sampling rate
+	pub packets: u64,
+	/// Number of flows this record stands for, usually 1
+	pub flows: u64,
+	/// One in how many packets were sampled, 1 if unsampled or unknown
+	pub sampling_rate: u32,
+}
+
+impl FlowRecord {
+	fn new(exporter: IpAddr) -> Self {
+		Self {
+			exporter,
+			src_addr: None,
+			dst_addr: None,
+			src_port: None,
+			dst_port: None,
+			protocol: None,
+			tos: None,
+			tcp_flags: None,
+			src_as: None,
+			dst_as: None,
+			input_if: None,
+			output_if: None,
+			start: None,
+			end: None,
+			bytes: 0,
+			packets: 0,
+			flows: 1,
+			sampling_rate: 1,
+		}
+	}
+
+	/// Extract the flows from a parsed NetFlow datagram received from `exporter`
+	///
+	/// Options data records carry no flows and are skipped
+	pub fn from_netflow(exporter: IpAddr, dg: &NetflowDatagramData) -> Vec<Self> {
+		match dg {
+			NetflowDatagramData::DatagramV1(d) => d.flow_records.iter().map(|r| Self {
+				src_addr: Some(IpAddr::V4(r.src_ip)),
+				dst_addr: Some(IpAddr::V4(r.dst_ip)),
+				src_port: Some(r.src_port),
+				dst_port: Some(r.dst_port),
+				protocol: Some(r.ip_protocol),
+				tos: Some(r.ip_tos),
+				tcp_flags: Some(r.tcp_flags),
+				input_if: Some(r.snmp_in_if_idx as u32),
+				output_if: Some(r.snmp_out_if_idx as u32),
+				start: Some(r.start_sys_uptime),
+				end: Some(r.end_sys_uptime),
+				bytes: r.flow_octets as u64,
+				packets: r.flow_packets as u64,
+				..Self::new(exporter)
+			}).collect(),
+			NetflowDatagramData::DatagramV5(d) => {
+				// The top two bits hold the sampling mode
+				let sampling_rate = (d.sampling_interval & 0x3FFF).max(1) as u32;
+
+				d.flow_records.iter().map(|r| Self {
+					src_addr: Some(IpAddr::V4(r.src_ip)),
+					dst_addr: Some(IpAddr::V4(r.dst_ip)),
+					src_port: Some(r.src_port),
+					dst_port: Some(r.dst_port),
+					protocol: Some(r.ip_protocol),
+					tos: Some(r.ip_tos),
+					tcp_flags: Some(r.tcp_flags),
+					src_as: Some(r.src_asn as u32),
+					dst_as: Some(r.dst_asn as u32),
+					input_if: Some(r.snmp_in_if_idx as u32),
+					output_if: Some(r.snmp_out_if_idx as u32),
+					start: Some(r.start_sys_uptime),
+					end: Some(r.end_sys_uptime),
+					bytes: r.flow_octets as u64,
+					packets: r.flow_packets as u64,
+					sampling_rate,
+					..Self::new(exporter)
+				}).collect()
+			}
+			NetflowDatagramData::DatagramV9(d) => d.flow_records.iter().filter_map(|set| match set {
+				NetflowDatagramV9FlowSet::Data(data) => match &data.records {
+					NetflowDatagramRecordsType::Regular(records) => Some(records),
+					NetflowDatagramRecordsType::Option(_) => None,
+				},
+				_ => None,
+			}).flatten().map(|r| Self::from_netflow_v9_record(exporter, r)).collect(),
+			NetflowDatagramData::IPFIX => vec![],
+		}
+	}
+
+	/// Extract a flow from the fields of a single NetFlow v9 data record
+	///
+	/// Enterprise-specific fields, such as the reverse fields of RFC 5103 biflows, are skipped
+	pub fn from_netflow_v9_record(exporter: IpAddr, fields: &[NetflowV9DataField]) -> Self {
+		let mut flow = Self::new(exporter);
+
+		for field in fields.iter().filter(|f| f.enterprise.is_none()) {
+			let number = match &field.value {
+				NetflowV9DataValue::Number(n) => Some(*n),
+				_ => None,
+			};
+			let addr = match &field.value {
+				NetflowV9DataValue::IPv4(a) => Some(IpAddr::V4(*a)),
+				NetflowV9DataValue::IPv6(a) => Some(IpAddr::V6(*a)),
+				_ => None,
+			};
+
+			match field.type_id {
+				1 => flow.bytes = number.unwrap_or(flow.bytes),
+				2 => flow.packets = number.unwrap_or(flow.packets),
+				3 => flow.flows = number.unwrap_or(flow.flows),
+				4 => flow.protocol = number.map(|n| n as u8),
+				5 => flow.tos = number.map(|n| n as u8),
+				6 => flow.tcp_flags = number.map(|n| n as u8),
+				7 => flow.src_port = number.map(|n| n as u16),
+				8 | 27 => flow.src_addr = addr.or(flow.src_addr),
+				10 => flow.input_if = number.map(|n| n as u32),
+				11 => flow.dst_port = number.map(|n| n as u16),
+				12 | 28 => flow.dst_addr = addr.or(flow.dst_addr),
+				14 => flow.output_if = number.map(|n| n as u32),
+				16 => flow.src_as = number.map(|n| n as u32),
+				17 => flow.dst_as = number.map(|n| n as u32),
+				21 => flow.end = number.map(|n| n as u32),
+				22 => flow.start = number.map(|n| n as u32),
+				34 => flow.sampling_rate = number.map(|n| n.clamp(1, u32::MAX as u64) as u32).unwrap_or(flow.sampling_rate),
+				_ => {}
+			}
+		}
+
+		flow
+	}
+
+	/// Extract the flows from the flow samples of a parsed sFlow datagram
+	///
+	/// Each sample becomes one flow of one packet with the sampled frame length as bytes. Samples without a decodable
+	/// raw packet header still count, but only carry the interfaces
+	pub fn from_sflow(dg: &Datagram) -> Vec<Self> {
+		dg.sample_record.iter().filter_map(|sample| match sample {
+			SFlowSample::Flow(s) => Some(s),
+			_ => None,
+		}).map(|s| {
+			let mut flow = Self {
+				input_if: Some(s.input_if & 0x3FFFFFFF),
+				output_if: Some(s.output_if & 0x3FFFFFFF),
+				start: Some(dg.uptime),
+				end: Some(dg.uptime),
+				packets: 1,
+				sampling_rate: s.rate.max(1),
+				..Self::new(dg.agent_addr)
+			};
+
+			for record in &s.records {
+				let SFlowFlowSampleRecord::Raw(raw) = record else { continue };
+				let headers = match raw.protocol {
+					SFLOW_HEADER_ETHERNET => packet::parse_ethernet(&raw.header),
+					SFLOW_HEADER_IPV4 | SFLOW_HEADER_IPV6 => packet::parse_ip(&raw.header),
+					_ => continue,
+				};
+
+				flow.bytes = raw.frame_length as u64;
+				if let Ok((_, h)) = headers {
+					flow.src_addr = Some(h.src_addr);
+					flow.dst_addr = Some(h.dst_addr);
+					flow.src_port = h.src_port;
+					flow.dst_port = h.dst_port;
+					flow.protocol = Some(h.protocol);
+					flow.tos = Some(h.tos);
+					flow.tcp_flags = h.tcp_flags;
+				}
+			}
+
+			flow
+		}).collect()
+	}
+}
@@ -0,0 +1,214 @@
+//! Per-interface traffic rates from sFlow generic interface counters
+//!
+//! sFlow agents export cumulative interface counters. [InterfaceCounterTracker] keeps the previous sample of every
+//! interface, keyed by [InterfaceKey], and turns each new sample into deltas and per-second rates over the interval
+//! measured by the agent's uptime. 32-bit counters wrapping around are handled; an agent whose uptime goes backwards by
+//! more than a minute has restarted, and its next samples start a new baseline instead of producing bogus deltas.
+//! Samples going back by less than that arrived late and are ignored
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+use crate::sequence::UPTIME_WRAP_THRESHOLD;
+use crate::sflow_parse::datagram::Datagram;
+use crate::sflow_parse::sample::counter::{SFlowCounterDataGeneric, SFlowCounterRecord};
+use crate::sflow_parse::sample::SFlowSample;
+
+/// Identifies a single interface of an sFlow agent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InterfaceKey {
+	pub agent: IpAddr,
+	pub sub_agent_id: u32,
+	/// sFlow data source ID of the counter sample
+	pub source_id: u32,
+	pub if_index: u32,
+}
+
+/// Counter increases between two samples
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InterfaceDeltas {
+	pub in_octets: u64,
+	/// Unicast, multicast, and broadcast packets received
+	pub in_packets: u64,
+	pub in_errors: u64,
+	pub in_discards: u64,
+	pub out_octets: u64,
+	/// Unicast, multicast, and broadcast packets sent
+	pub out_packets: u64,
+	pub out_errors: u64,
+	pub out_discards: u64,
+}
+
+/// Traffic of an interface over the interval between two counter samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InterfaceRates {
+	pub key: InterfaceKey,
+	/// Interval between the two samples, by the agent's uptime
+	pub interval: Duration,
+	/// Interface speed in bits per second as reported by the agent
+	pub speed: u64,
+	pub deltas: InterfaceDeltas,
+	pub in_bps: f64,
+	pub out_bps: f64,
+	pub in_pps: f64,
+	pub out_pps: f64,
+	pub in_errors_ps: f64,
+	pub out_errors_ps: f64,
+	pub in_discards_ps: f64,
+	pub out_discards_ps: f64,
+}
+
+/// Outcome of feeding a counter sample to the tracker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterfaceCounterEvent {
+	/// Rates since the previous sample of the interface
+	Rates(InterfaceRates),
+	/// The agent restarted or the counters were reset, the sample only serves as the new baseline
+	Restart(InterfaceKey),
+}
+
+/// Samples with an uptime at most this many milliseconds before the previous sample arrived late, rather than after a
+/// restart
+const LATE_SAMPLE_TOLERANCE: u32 = 60 * 1000;
+
+#[derive(Debug, Clone, Copy)]
+struct Previous {
+	uptime: u32,
+	counters: SFlowCounterDataGeneric,
+}
+
+/// Increase of a 32-bit counter, allowing for one wraparound
+fn delta32(prev: u32, now: u32) -> u64 {
+	now.wrapping_sub(prev) as u64
+}
+
+/// Increase of a 64-bit counter, or `None` if it went backwards
+///
+/// Some agents fill the 64-bit octet counters from 32-bit ones, so a counter below 2^32 going backwards is taken as a
+/// 32-bit wraparound
+fn delta64(prev: u64, now: u64) -> Option<u64> {
+	if now >= prev {
+		Some(now - prev)
+	} else if prev <= u32::MAX as u64 {
+		Some((now + (1 << 32)) - prev)
+	} else {
+		None
+	}
+}
+
+/// Tracker turning cumulative sFlow interface counters into rates
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceCounterTracker {
+	interfaces: HashMap<InterfaceKey, Previous>,
+	restarts: u64,
+}
+
+impl InterfaceCounterTracker {
+	/// Create an empty tracker
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Feed a generic interface counter record sampled at agent uptime `uptime` (in milliseconds)
+	///
+	/// Returns `None` for the first sample of an interface and for samples not newer than the previous one; samples
+	/// that arrived late do not replace the previous sample as the baseline for the next one
+	pub fn observe(&mut self, key: InterfaceKey, uptime: u32, counters: &SFlowCounterDataGeneric) -> Option<InterfaceCounterEvent> {
+		let Some(prev) = self.interfaces.get(&key).copied() else {
+			self.interfaces.insert(key, Previous { uptime, counters: *counters });
+			return None;
+		};
+
+		// The uptime wraps around after about 49.7 days
+		if uptime < prev.uptime && prev.uptime < UPTIME_WRAP_THRESHOLD {
+			if prev.uptime - uptime <= LATE_SAMPLE_TOLERANCE {
+				return None;
+			}
+			self.interfaces.insert(key, Previous { uptime, counters: *counters });
+			self.restarts += 1;
+			return Some(InterfaceCounterEvent::Restart(key));
+		}
+
+		let interval_ms = uptime.wrapping_sub(prev.uptime);
+		if interval_ms == 0 {
+			return None;
+		}
+		self.interfaces.insert(key, Previous { uptime, counters: *counters });
+
+		let (p, c) = (&prev.counters, counters);
+		let (Some(in_octets), Some(out_octets)) = (delta64(p.in_octets, c.in_octets), delta64(p.out_octets, c.out_octets)) else {
+			self.restarts += 1;
+			return Some(InterfaceCounterEvent::Restart(key));
+		};
+
+		let deltas = InterfaceDeltas {
+			in_octets,
+			in_packets: delta32(p.in_ucast_packets, c.in_ucast_packets) + delta32(p.in_multicast_packets, c.in_multicast_packets)
+				+ delta32(p.in_broadcast_packets, c.in_broadcast_packets),
+			in_errors: delta32(p.in_errors, c.in_errors),
+			in_discards: delta32(p.in_discarded, c.in_discarded),
+			out_octets,
+			out_packets: delta32(p.out_ucast_packets, c.out_ucast_packets) + delta32(p.out_multicast_packets, c.out_multicast_packets)
+				+ delta32(p.out_broadcast_packets, c.out_broadcast_packets),
+			out_errors: delta32(p.out_errors, c.out_errors),
+			out_discards: delta32(p.out_discarded, c.out_discarded),
+		};
+
+		let interval = Duration::from_millis(interval_ms as u64);
+		let per_sec = |v: u64| v as f64 / interval.as_secs_f64();
+
+		Some(InterfaceCounterEvent::Rates(InterfaceRates {
+			key,
+			interval,
+			speed: c.speed,
+			deltas,
+			in_bps: per_sec(deltas.in_octets) * 8.0,
+			out_bps: per_sec(deltas.out_octets) * 8.0,
+			in_pps: per_sec(deltas.in_packets),
+			out_pps: per_sec(deltas.out_packets),
+			in_errors_ps: per_sec(deltas.in_errors),
+			out_errors_ps: per_sec(deltas.out_errors),
+			in_discards_ps: per_sec(deltas.in_discards),
+			out_discards_ps: per_sec(deltas.out_discards),
+		}))
+	}
+
+	/// Feed all generic interface counter records of a parsed sFlow datagram
+	pub fn observe_sflow(&mut self, dg: &Datagram) -> Vec<InterfaceCounterEvent> {
+		let mut events = vec![];
+
+		for sample in &dg.sample_record {
+			let SFlowSample::Counter(s) = sample else { continue };
+			for record in &s.records {
+				let SFlowCounterRecord::Generic(counters) = record else { continue };
+				let key = InterfaceKey { agent: dg.agent_addr, sub_agent_id: dg.sub_agent_id, source_id: s.src, if_index: counters.index };
+				events.extend(self.observe(key, dg.uptime, counters));
+			}
+		}
+
+		events
+	}
+
+	/// Get the number of detected restarts and counter resets
+	pub fn restarts(&self) -> u64 {
+		self.restarts
+	}
+
+	/// Get the number of tracked interfaces
+	pub fn len(&self) -> usize {
+		self.interfaces.len()
+	}
+
+	/// Check whether no interfaces are tracked
+	pub fn is_empty(&self) -> bool {
+		self.interfaces.is_empty()
+	}
+
+	/// Stop tracking an interface
+	pub fn remove(&mut self, key: &InterfaceKey) {
+		self.interfaces.remove(key);
+	}
+}
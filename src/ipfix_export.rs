@@ -0,0 +1,400 @@
+//! IPFIX exporting over UDP
+//!
+//! [IpfixExporter] packs data records into IPFIX messages (RFC 7011) and sends them to a collector. Templates are
+//! defined per observation domain and sent before the first record using them, and again every refresh interval, as
+//! UDP collectors may miss them or restart. Every observation domain has its own sequence number, counting the data
+//! records sent in it. Records are collected until the next one would make the message exceed the MTU or until
+//! [IpfixExporter::flush] is called
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::netflow_parse::datagram_v9_data::NetflowV9DataValue;
+use crate::netflow_parse::encode::{encode_value, EncodeError};
+
+/// Field length marking a variable length field
+pub const VARIABLE_LENGTH: u16 = u16::MAX;
+
+const MESSAGE_HEADER_LENGTH: usize = 16;
+const SET_HEADER_LENGTH: usize = 4;
+const TEMPLATE_SET_ID: u16 = 2;
+const OPTIONS_TEMPLATE_SET_ID: u16 = 3;
+
+/// Field specifier of an IPFIX template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IpfixField {
+	/// Information element ID, without the enterprise bit
+	pub element_id: u16,
+	/// Private enterprise number for enterprise-specific information elements
+	pub enterprise: Option<u32>,
+	/// Length in bytes, or [VARIABLE_LENGTH]
+	pub length: u16,
+}
+
+impl IpfixField {
+	/// Create a field specifier for an IANA information element
+	pub fn new(element_id: u16, length: u16) -> Self {
+		Self { element_id, enterprise: None, length }
+	}
+
+	/// Create a field specifier for an enterprise-specific information element
+	pub fn enterprise(element_id: u16, enterprise: u32, length: u16) -> Self {
+		Self { element_id, enterprise: Some(enterprise), length }
+	}
+
+	fn write_bytes(&self, out: &mut Vec<u8>) {
+		let id = match self.enterprise {
+			Some(_) => self.element_id | 0x8000,
+			None => self.element_id & 0x7fff,
+		};
+		out.extend_from_slice(&id.to_be_bytes());
+		out.extend_from_slice(&self.length.to_be_bytes());
+		if let Some(pen) = self.enterprise {
+			out.extend_from_slice(&pen.to_be_bytes());
+		}
+	}
+}
+
+/// Template or options template for IPFIX data records
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IpfixTemplate {
+	/// Template ID, at least 256
+	pub template_id: u16,
+	/// Number of leading scope fields, zero for regular templates
+	pub scope_field_count: u16,
+	pub fields: Vec<IpfixField>,
+}
+
+impl IpfixTemplate {
+	/// Create a regular template
+	pub fn new(template_id: u16, fields: Vec<IpfixField>) -> Self {
+		Self { template_id, scope_field_count: 0, fields }
+	}
+
+	/// Create an options template whose records hold the `scope_fields` followed by the `option_fields`
+	pub fn options(template_id: u16, scope_fields: Vec<IpfixField>, option_fields: Vec<IpfixField>) -> Self {
+		let scope_field_count = scope_fields.len() as u16;
+		let mut fields = scope_fields;
+		fields.extend(option_fields);
+
+		Self { template_id, scope_field_count, fields }
+	}
+
+	fn set_id(&self) -> u16 {
+		if self.scope_field_count > 0 { OPTIONS_TEMPLATE_SET_ID } else { TEMPLATE_SET_ID }
+	}
+
+	fn to_record_bytes(&self) -> Vec<u8> {
+		let mut out = Vec::with_capacity(6 + self.fields.len() * 8);
+		out.extend_from_slice(&self.template_id.to_be_bytes());
+		out.extend_from_slice(&(self.fields.len() as u16).to_be_bytes());
+		if self.scope_field_count > 0 {
+			out.extend_from_slice(&self.scope_field_count.to_be_bytes());
+		}
+		for f in &self.fields {
+			f.write_bytes(&mut out);
+		}
+
+		out
+	}
+
+	/// Encode a data record with one value per field
+	fn encode_record(&self, values: &[NetflowV9DataValue]) -> Result<Vec<u8>, EncodeError> {
+		if values.len() != self.fields.len() {
+			return Err(EncodeError::FieldCountMismatch { template_id: self.template_id, expected: self.fields.len(), count: values.len() });
+		}
+
+		let mut out = vec![];
+		for (value, field) in values.iter().zip(&self.fields) {
+			if field.length != VARIABLE_LENGTH {
+				encode_value(&mut out, value, field.element_id, field.length)?;
+				continue;
+			}
+
+			let bytes = match value {
+				NetflowV9DataValue::String(s) => s.as_bytes(),
+				NetflowV9DataValue::Unknown(b) => b.as_slice(),
+				_ => return Err(EncodeError::InvalidFieldValue { type_id: field.element_id, length: field.length }),
+			};
+			if bytes.len() < 255 {
+				out.push(bytes.len() as u8);
+			} else {
+				let length = u16::try_from(bytes.len()).map_err(|_| EncodeError::TooLong)?;
+				out.push(255);
+				out.extend_from_slice(&length.to_be_bytes());
+			}
+			out.extend_from_slice(bytes);
+		}
+
+		Ok(out)
+	}
+}
+
+/// Reasons records cannot be exported
+#[derive(Debug)]
+pub enum IpfixExportError {
+	/// Sending a message failed
+	Io(io::Error),
+	/// A record or template cannot be encoded, or a record does not fit into a message
+	Encode(EncodeError),
+	/// Template IDs below 256 are reserved for set IDs
+	InvalidTemplateId(u16),
+}
+
+impl Display for IpfixExportError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			IpfixExportError::Io(e) => write!(f, "Could not send IPFIX message: {}", e),
+			IpfixExportError::Encode(e) => write!(f, "Could not encode IPFIX record: {}", e),
+			IpfixExportError::InvalidTemplateId(id) => write!(f, "Invalid template ID {}, must be at least 256", id),
+		}
+	}
+}
+
+impl std::error::Error for IpfixExportError {}
+
+impl From<io::Error> for IpfixExportError {
+	fn from(e: io::Error) -> Self {
+		IpfixExportError::Io(e)
+	}
+}
+
+impl From<EncodeError> for IpfixExportError {
+	fn from(e: EncodeError) -> Self {
+		IpfixExportError::Encode(e)
+	}
+}
+
+/// Per observation domain state
+#[derive(Debug, Default)]
+struct Domain {
+	templates: BTreeMap<u16, IpfixTemplate>,
+	/// Templates to include in the next message
+	unsent: Vec<u16>,
+	templates_sent: Option<Instant>,
+	/// Data records sent so far, modulo 2^32
+	sequence: u32,
+	/// Sets of the message being built
+	body: Vec<u8>,
+	/// Set ID and start offset of the set being built
+	open_set: Option<(u16, usize)>,
+	/// Data records in the message being built
+	records: u32,
+}
+
+impl Domain {
+	fn close_set(&mut self) {
+		if let Some((_, start)) = self.open_set.take() {
+			let length = (self.body.len() - start) as u16;
+			self.body[start + 2..start + 4].copy_from_slice(&length.to_be_bytes());
+		}
+	}
+
+	/// Space needed to append `len` bytes to a set with ID `set_id`
+	fn needed(&self, set_id: u16, len: usize) -> usize {
+		match self.open_set {
+			Some((id, _)) if id == set_id => len,
+			_ => SET_HEADER_LENGTH + len,
+		}
+	}
+
+	fn append(&mut self, set_id: u16, bytes: &[u8]) {
+		if !matches!(self.open_set, Some((id, _)) if id == set_id) {
+			self.close_set();
+			self.open_set = Some((set_id, self.body.len()));
+			self.body.extend_from_slice(&set_id.to_be_bytes());
+			self.body.extend_from_slice(&[0, 0]);
+		}
+		self.body.extend_from_slice(bytes);
+	}
+}
+
+/// Exporter sending IPFIX messages to a single collector over UDP
+#[derive(Debug)]
+pub struct IpfixExporter {
+	socket: UdpSocket,
+	collector: SocketAddr,
+	max_message_length: usize,
+	template_refresh: Duration,
+	domains: HashMap<u32, Domain>,
+	messages_sent: u64,
+}
+
+impl IpfixExporter {
+	/// Create an exporter sending to `collector` from an ephemeral port
+	///
+	/// By default, messages fit into an MTU of 1500 bytes and templates are sent again every 10 minutes
+	pub fn new(collector: SocketAddr) -> io::Result<Self> {
+		let bind: SocketAddr = match collector.ip() {
+			IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+			IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+		};
+
+		Self::with_socket(UdpSocket::bind(bind)?, collector)
+	}
+
+	/// Create an exporter sending to `collector` from an already bound socket
+	pub fn with_socket(socket: UdpSocket, collector: SocketAddr) -> io::Result<Self> {
+		socket.connect(collector)?;
+
+		Ok(Self {
+			socket,
+			collector,
+			max_message_length: max_message_length(1500, &collector),
+			template_refresh: Duration::from_secs(600),
+			domains: HashMap::new(),
+			messages_sent: 0,
+		})
+	}
+
+	/// Set the MTU of the path to the collector; messages are kept small enough to fit into a single IP packet
+	pub fn with_mtu(mut self, mtu: usize) -> Self {
+		self.max_message_length = max_message_length(mtu, &self.collector);
+		self
+	}
+
+	/// Set how often templates are sent again
+	pub fn with_template_refresh(mut self, interval: Duration) -> Self {
+		self.template_refresh = interval;
+		self
+	}
+
+	/// Define `template` in observation domain `domain`, replacing any template with the same ID
+	///
+	/// The template is sent with the next message of the domain
+	pub fn add_template(&mut self, domain: u32, template: IpfixTemplate) -> Result<(), IpfixExportError> {
+		if template.template_id < 256 {
+			return Err(IpfixExportError::InvalidTemplateId(template.template_id));
+		}
+		if MESSAGE_HEADER_LENGTH + SET_HEADER_LENGTH + template.to_record_bytes().len() > self.max_message_length {
+			return Err(EncodeError::TooLong.into());
+		}
+
+		let d = self.domains.entry(domain).or_default();
+		let id = template.template_id;
+		d.templates.insert(id, template);
+		if !d.unsent.contains(&id) {
+			d.unsent.push(id);
+		}
+
+		Ok(())
+	}
+
+	/// Add a data record to observation domain `domain`, with one value per field of template `template_id`
+	///
+	/// If the record does not fit into the message being built, that message is sent first. If sending it fails, the
+	/// record is not added and the message is kept to be sent again
+	pub fn add_record(&mut self, domain: u32, template_id: u16, values: &[NetflowV9DataValue]) -> Result<(), IpfixExportError> {
+		let record = self.domains.get(&domain).and_then(|d| d.templates.get(&template_id))
+			.ok_or(EncodeError::UnknownTemplate(template_id))?
+			.encode_record(values)?;
+		if MESSAGE_HEADER_LENGTH + SET_HEADER_LENGTH + record.len() > self.max_message_length {
+			return Err(EncodeError::TooLong.into());
+		}
+
+		self.write_templates(domain)?;
+
+		let d = self.domains.get_mut(&domain).unwrap();
+		if MESSAGE_HEADER_LENGTH + d.body.len() + d.needed(template_id, record.len()) > self.max_message_length {
+			self.send(domain)?;
+		}
+
+		let d = self.domains.get_mut(&domain).unwrap();
+		d.append(template_id, &record);
+		d.records += 1;
+
+		Ok(())
+	}
+
+	/// Write the templates that are new or due for a refresh into the message being built
+	fn write_templates(&mut self, domain: u32) -> Result<(), IpfixExportError> {
+		let Some(d) = self.domains.get_mut(&domain) else { return Ok(()) };
+		if d.templates_sent.is_none_or(|t| t.elapsed() >= self.template_refresh) {
+			d.unsent = d.templates.keys().copied().collect();
+			d.templates_sent = Some(Instant::now());
+		}
+
+		while !self.domains[&domain].unsent.is_empty() {
+			let d = self.domains.get_mut(&domain).unwrap();
+			let id = d.unsent[0];
+			let Some(template) = d.templates.get(&id) else {
+				d.unsent.remove(0);
+				continue;
+			};
+			let (set_id, bytes) = (template.set_id(), template.to_record_bytes());
+
+			if MESSAGE_HEADER_LENGTH + d.body.len() + d.needed(set_id, bytes.len()) > self.max_message_length {
+				self.send(domain)?;
+			}
+
+			let d = self.domains.get_mut(&domain).unwrap();
+			d.append(set_id, &bytes);
+			d.unsent.remove(0);
+		}
+
+		Ok(())
+	}
+
+	/// Send the message being built for `domain`, if there is one
+	fn send(&mut self, domain: u32) -> Result<(), IpfixExportError> {
+		let Some(d) = self.domains.get_mut(&domain) else { return Ok(()) };
+		if d.body.is_empty() {
+			return Ok(());
+		}
+		d.close_set();
+
+		let export_time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or_default();
+		let length = MESSAGE_HEADER_LENGTH + d.body.len();
+		let mut message = Vec::with_capacity(length);
+		message.extend_from_slice(&10u16.to_be_bytes());
+		message.extend_from_slice(&(length as u16).to_be_bytes());
+		message.extend_from_slice(&export_time.to_be_bytes());
+		message.extend_from_slice(&d.sequence.to_be_bytes());
+		message.extend_from_slice(&domain.to_be_bytes());
+		message.extend_from_slice(&d.body);
+
+		// A message that could not be sent is kept, to be sent again by the next call
+		self.socket.send(&message)?;
+		self.messages_sent += 1;
+
+		d.sequence = d.sequence.wrapping_add(d.records);
+		d.body.clear();
+		d.records = 0;
+
+		Ok(())
+	}
+
+	/// Send all messages being built, including templates due for a refresh
+	///
+	/// Messages that cannot be sent are kept, with their records and sequence number, and sent again by the next call
+	pub fn flush(&mut self) -> Result<(), IpfixExportError> {
+		let domains: Vec<u32> = self.domains.keys().copied().collect();
+		for domain in domains {
+			self.write_templates(domain)?;
+			self.send(domain)?;
+		}
+
+		Ok(())
+	}
+
+	/// Get the number of data records added to observation domain `domain` so far, modulo 2^32
+	pub fn sequence(&self, domain: u32) -> u32 {
+		self.domains.get(&domain).map(|d| d.sequence.wrapping_add(d.records)).unwrap_or_default()
+	}
+
+	/// Get the number of messages sent
+	pub fn messages_sent(&self) -> u64 {
+		self.messages_sent
+	}
+}
+
+/// Largest IPFIX message fitting into a single packet to `collector` with the given MTU
+fn max_message_length(mtu: usize, collector: &SocketAddr) -> usize {
+	let ip_header = if collector.is_ipv4() { 20 } else { 40 };
+
+	mtu.saturating_sub(ip_header + 8).clamp(MESSAGE_HEADER_LENGTH + SET_HEADER_LENGTH + 1, u16::MAX as usize)
+}
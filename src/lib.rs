@@ -2,6 +2,9 @@
 //!
 //! Regular parsing is performed using the [netflow_parse::NetflowParser] struct for NetFlow and the [sflow_parse::datagram::parse_sflow_data]
 //! function for sFlow (no state required).
+//!
+//! With the `serde` feature enabled, all parsed datagram types implement `Serialize` and `Deserialize`. IP addresses and
+//! MACs are represented as strings, and raw bytes as lowercase hex strings.
 
 extern crate nom;
 
@@ -10,3 +13,22 @@ extern crate lazy_static;
 
 pub mod netflow_parse;
 pub mod sflow_parse;
+pub mod sequence;
+pub mod packet;
+pub mod flow;
+pub mod aggregate;
+pub mod topn;
+pub mod interface_rates;
+pub mod biflow;
+pub mod ipfix_export;
+pub mod pcap;
+
+#[cfg(feature = "json")]
+pub mod output;
+#[cfg(feature = "collector")]
+pub mod collector;
+#[cfg(feature = "cli")]
+pub mod config;
+
+#[cfg(feature = "serde")]
+mod serde_util;
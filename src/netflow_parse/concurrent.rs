@@ -0,0 +1,176 @@
+//! Thread-safe NetFlow parser sharing template state between threads
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use arc_swap::ArcSwap;
+use nom::IResult;
+use crate::netflow_parse::datagram::{self, NetflowDatagramData, NetflowPeekResult};
+use crate::netflow_parse::datagram_v9_template::{NetflowDatagramOptionsTemplate, NetflowDatagramOptionsTemplateSet, NetflowDatagramTemplate, NetflowDatagramTemplateSet};
+use crate::netflow_parse::NetflowTemplateStore;
+
+/// Number of independently updated template maps
+const SHARD_COUNT: usize = 16;
+
+type TemplateMap<T> = HashMap<(SocketAddr, u16), Arc<T>>;
+
+/// Template cache split into shards, each an immutable map that gets replaced as a whole on updates (read-copy-update)
+#[derive(Debug)]
+struct TemplateShards<T> {
+	shards: Vec<ArcSwap<TemplateMap<T>>>,
+	hasher: RandomState,
+}
+
+impl<T: PartialEq> TemplateShards<T> {
+	fn new() -> Self {
+		Self { shards: (0..SHARD_COUNT).map(|_| ArcSwap::from_pointee(HashMap::new())).collect(), hasher: RandomState::new() }
+	}
+
+	fn shard(&self, key: &(SocketAddr, u16)) -> &ArcSwap<TemplateMap<T>> {
+		&self.shards[(self.hasher.hash_one(key) % SHARD_COUNT as u64) as usize]
+	}
+
+	fn with<R, F: FnOnce(Option<&T>) -> R>(&self, key: &(SocketAddr, u16), f: F) -> R {
+		let map = self.shard(key).load();
+		f(map.get(key).map(|t| &**t))
+	}
+
+	/// Insert a template, returning whether the shard was replaced
+	fn insert(&self, key: (SocketAddr, u16), value: T) -> bool {
+		let shard = self.shard(&key);
+
+		// Exporters resend their templates periodically, skip copying the shard if nothing changed
+		if shard.load().get(&key).is_some_and(|t| **t == value) {
+			return false;
+		}
+
+		let value = Arc::new(value);
+		shard.rcu(|map| {
+			let mut map = HashMap::clone(map);
+			map.insert(key, value.clone());
+			map
+		});
+		true
+	}
+
+	fn len(&self) -> usize {
+		self.shards.iter().map(|s| s.load().len()).sum()
+	}
+}
+
+/// NetFlow parser that can be shared between threads
+///
+/// Unlike [NetflowParser](crate::netflow_parse::NetflowParser), parsing only needs `&self`. Template updates become
+/// visible to all threads as soon as they are registered, and parsing never waits for template updates: readers work
+/// on a snapshot of the template cache while writers replace it
+#[derive(Debug)]
+pub struct ConcurrentNetflowParser {
+	templates: TemplateShards<NetflowDatagramTemplate>,
+	options_templates: TemplateShards<NetflowDatagramOptionsTemplate>,
+	record_count_mismatches: AtomicU64,
+	template_updates: AtomicU64,
+	enterprise_fields: bool,
+}
+
+impl Default for ConcurrentNetflowParser {
+	fn default() -> Self {
+		Self {
+			templates: TemplateShards::new(),
+			options_templates: TemplateShards::new(),
+			record_count_mismatches: AtomicU64::new(0),
+			template_updates: AtomicU64::new(0),
+			enterprise_fields: false,
+		}
+	}
+}
+
+impl ConcurrentNetflowParser {
+	/// Initialize parser state
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Parse template fields with the enterprise bit as enterprise-specific fields
+	///
+	/// See [NetflowParser::with_enterprise_fields](crate::netflow_parse::NetflowParser::with_enterprise_fields)
+	pub fn with_enterprise_fields(mut self, enabled: bool) -> Self {
+		self.enterprise_fields = enabled;
+		self
+	}
+
+	/// Parse the netflow datagram bytes from `input` that are coming in from `addr`
+	///
+	/// See [NetflowParser::parse](crate::netflow_parse::NetflowParser::parse) for details and errors
+	pub fn parse<'a>(&self, input: &'a [u8], addr: &SocketAddr) -> IResult<&'a [u8], NetflowDatagramData> {
+		let mut store = self;
+		datagram::parse_netflow_data(input, addr, &mut store)
+	}
+
+	/// Parse the initial non-data meta parts of NetFlow datagrams, returning the original array slice
+	pub fn peek_netflow_basic_info(input: &[u8]) -> IResult<&[u8], NetflowPeekResult> {
+		datagram::peek_netflow_basic_info(input)
+	}
+
+	/// Manually register a new NetFlow template
+	pub fn register_netflow_template(&self, set: &NetflowDatagramTemplateSet, addr: &SocketAddr) {
+		for t in set.templates() {
+			if self.templates.insert((*addr, t.template_id), t) {
+				self.template_updates.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+	}
+
+	/// Manually register a new NetFlow options template
+	pub fn register_netflow_options_template(&self, set: &NetflowDatagramOptionsTemplateSet, addr: &SocketAddr) {
+		for t in set.templates() {
+			if self.options_templates.insert((*addr, t.template_id), t) {
+				self.template_updates.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+	}
+
+	/// Get the number of registered templates and options templates
+	pub fn template_count(&self) -> (usize, usize) {
+		(self.templates.len(), self.options_templates.len())
+	}
+
+	/// Get the number of times the template cache was updated by a new or changed template or options template
+	///
+	/// Templates resent without changes leave the cache as it is and are not counted
+	pub fn template_updates(&self) -> u64 {
+		self.template_updates.load(Ordering::Relaxed)
+	}
+
+	/// Get the number of NetFlow v9 datagrams parsed despite a header record count not matching their flow sets
+	pub fn record_count_mismatches(&self) -> u64 {
+		self.record_count_mismatches.load(Ordering::Relaxed)
+	}
+}
+
+impl NetflowTemplateStore for &ConcurrentNetflowParser {
+	fn with_template<R, F: FnOnce(Option<&NetflowDatagramTemplate>) -> R>(&self, addr: &SocketAddr, id: u16, f: F) -> R {
+		self.templates.with(&(*addr, id), f)
+	}
+
+	fn with_options_template<R, F: FnOnce(Option<&NetflowDatagramOptionsTemplate>) -> R>(&self, addr: &SocketAddr, id: u16, f: F) -> R {
+		self.options_templates.with(&(*addr, id), f)
+	}
+
+	fn register_template_set(&mut self, set: &NetflowDatagramTemplateSet, addr: &SocketAddr) {
+		self.register_netflow_template(set, addr);
+	}
+
+	fn register_options_template_set(&mut self, set: &NetflowDatagramOptionsTemplateSet, addr: &SocketAddr) {
+		self.register_netflow_options_template(set, addr);
+	}
+
+	fn count_record_count_mismatch(&mut self) {
+		self.record_count_mismatches.fetch_add(1, Ordering::Relaxed);
+	}
+
+	fn enterprise_fields(&self) -> bool {
+		self.enterprise_fields
+	}
+}
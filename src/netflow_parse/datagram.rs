@@ -8,12 +8,13 @@ use nom::sequence::tuple;
 use crate::netflow_parse::datagram_v1::NetflowDatagramV1;
 use crate::netflow_parse::datagram_v5::NetflowDatagramV5;
 use crate::netflow_parse::datagram_v9::NetflowDatagramV9;
-use crate::netflow_parse::NetflowParser;
+use crate::netflow_parse::NetflowTemplateStore;
 
 /// Datagram enum for the various supported NetFlow versions
 ///
 /// Each enum variant contains the version's respective datagram data
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NetflowDatagramData {
 	DatagramV1(NetflowDatagramV1),
 	DatagramV5(NetflowDatagramV5),
@@ -24,6 +25,7 @@ pub enum NetflowDatagramData {
 
 /// Data contained in the NetFlow v1 header
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowV1PeekData {
 	pub flow_count: u16,
 	pub sys_uptime: u32,
@@ -33,11 +35,13 @@ pub struct NetflowV1PeekData {
 
 /// Data contained in the NetFlow v5 header
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowV5PeekData {
 	pub flow_count: u16,
 	pub sys_uptime: u32,
 	pub unix_secs: u32,
 	pub unix_nsecs: u32,
+	/// Sequence number of the first flow in the datagram, i.e. the number of flows exported before it
 	pub total_flows: u32,
 	pub engine_type: u8,
 	pub engine_id: u8,
@@ -46,6 +50,7 @@ pub struct NetflowV5PeekData {
 
 /// Data contained in the NetFlow v9 header
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowV9PeekData {
 	pub flow_set_count: u16,
 	pub sys_uptime: u32,
@@ -59,6 +64,7 @@ pub struct NetflowV9PeekData {
 ///
 /// Each enum variant contains the version's respective header data
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NetflowPeekResult {
 	V1(NetflowV1PeekData),
 	V5(NetflowV5PeekData),
@@ -68,7 +74,8 @@ pub enum NetflowPeekResult {
 
 /// Parse the initial non-data meta parts of NetFlow datagrams, returning the original array slice
 ///
-/// This function can be used to handle UDP packets that arrive in the wrong order by matching the sequence number and caching results
+/// This function can be used to handle UDP packets that arrive in the wrong order by matching the sequence number and caching results,
+/// as done by [ReorderBuffer](crate::netflow_parse::reorder::ReorderBuffer)
 pub(super) fn peek_netflow_basic_info(input: &[u8]) -> IResult<&[u8], NetflowPeekResult> {
 	let (res, netflow_version) = be_u16(input)?;
 
@@ -109,7 +116,8 @@ pub(super) fn peek_netflow_basic_info(input: &[u8]) -> IResult<&[u8], NetflowPee
 /// - Unsupported NetFlow version
 /// - Template with given ID has not been defined yet or has an ID between 2-255 (inclusive)
 /// - The packet ends prematurely (due to the buffer being full)
-pub(super) fn parse_netflow_data<'a>(input: &'a [u8], addr: &SocketAddr, parser: &mut NetflowParser) -> IResult<&'a [u8], NetflowDatagramData> {
+/// - A NetFlow v9 flow set does not fit into the datagram
+pub(super) fn parse_netflow_data<'a, P: NetflowTemplateStore>(input: &'a [u8], addr: &SocketAddr, parser: &mut P) -> IResult<&'a [u8], NetflowDatagramData> {
 	let (res, netflow_version) = be_u16(input)?;
 
 	// TODO: IPFIX = 10
@@ -10,6 +10,7 @@ use nom::sequence::tuple;
 
 /// Data record contained in a NetFlow v1 packet
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowDatagramV1Record {
 	pub src_ip: Ipv4Addr,
 	pub dst_ip: Ipv4Addr,
@@ -22,10 +23,12 @@ pub struct NetflowDatagramV1Record {
 	pub end_sys_uptime: u32,
 	pub src_port: u16,
 	pub dst_port: u16,
+	#[cfg_attr(feature = "serde", serde(skip))]
 	_pad0: u8,
 	pub ip_protocol: u8,
 	pub ip_tos: u8,
 	pub tcp_flags: u8,
+	#[cfg_attr(feature = "serde", serde(skip))]
 	_pad1: u64,
 }
 
@@ -65,6 +68,7 @@ impl NetflowDatagramV1Record {
 
 /// Full NetFlow v1 datagram data
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowDatagramV1 {
 	pub sys_uptime_ms: u32,
 	pub unix_sec: u32,
@@ -6,9 +6,11 @@ use nom::IResult;
 use nom::multi::count;
 use nom::number::complete::{be_u16, be_u32, be_u8};
 use nom::sequence::tuple;
+use crate::netflow_parse::encode::EncodeError;
 
 /// Data record contained in a NetFlow v5 packet
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowDatagramV5Record {
 	pub src_ip: Ipv4Addr,
 	pub dst_ip: Ipv4Addr,
@@ -21,6 +23,7 @@ pub struct NetflowDatagramV5Record {
 	pub end_sys_uptime: u32,
 	pub src_port: u16,
 	pub dst_port: u16,
+	#[cfg_attr(feature = "serde", serde(skip))]
 	_pad0: u8,
 	pub tcp_flags: u8,
 	pub ip_protocol: u8,
@@ -29,6 +32,7 @@ pub struct NetflowDatagramV5Record {
 	pub dst_asn: u16,
 	pub src_mask: u8,
 	pub dst_mask: u8,
+	#[cfg_attr(feature = "serde", serde(skip))]
 	_pad1: u16,
 }
 
@@ -48,10 +52,59 @@ impl NetflowDatagramV5Record {
 		Ok((res, Self { src_ip, dst_ip, next_hop_ip, snmp_in_if_idx, snmp_out_if_idx, flow_packets, flow_octets,
 			start_sys_uptime, end_sys_uptime, src_port, dst_port, _pad0, tcp_flags, ip_protocol, ip_tos, src_asn, dst_asn, src_mask, dst_mask, _pad1 }))
 	}
+
+	/// Create a record for a flow from `src_ip` to `dst_ip` with all other fields set to zero
+	pub fn new(src_ip: Ipv4Addr, dst_ip: Ipv4Addr) -> Self {
+		Self {
+			src_ip,
+			dst_ip,
+			next_hop_ip: Ipv4Addr::UNSPECIFIED,
+			snmp_in_if_idx: 0,
+			snmp_out_if_idx: 0,
+			flow_packets: 0,
+			flow_octets: 0,
+			start_sys_uptime: 0,
+			end_sys_uptime: 0,
+			src_port: 0,
+			dst_port: 0,
+			_pad0: 0,
+			tcp_flags: 0,
+			ip_protocol: 0,
+			ip_tos: 0,
+			src_asn: 0,
+			dst_asn: 0,
+			src_mask: 0,
+			dst_mask: 0,
+			_pad1: 0,
+		}
+	}
+
+	/// Append the wire representation of this record to `out`
+	pub(crate) fn write_bytes(&self, out: &mut Vec<u8>) {
+		out.extend_from_slice(&self.src_ip.octets());
+		out.extend_from_slice(&self.dst_ip.octets());
+		out.extend_from_slice(&self.next_hop_ip.octets());
+		out.extend_from_slice(&self.snmp_in_if_idx.to_be_bytes());
+		out.extend_from_slice(&self.snmp_out_if_idx.to_be_bytes());
+		out.extend_from_slice(&self.flow_packets.to_be_bytes());
+		out.extend_from_slice(&self.flow_octets.to_be_bytes());
+		out.extend_from_slice(&self.start_sys_uptime.to_be_bytes());
+		out.extend_from_slice(&self.end_sys_uptime.to_be_bytes());
+		out.extend_from_slice(&self.src_port.to_be_bytes());
+		out.extend_from_slice(&self.dst_port.to_be_bytes());
+		out.extend_from_slice(&[0, self.tcp_flags, self.ip_protocol, self.ip_tos]);
+		out.extend_from_slice(&self.src_asn.to_be_bytes());
+		out.extend_from_slice(&self.dst_asn.to_be_bytes());
+		out.extend_from_slice(&[self.src_mask, self.dst_mask, 0, 0]);
+	}
 }
 
+/// Maximum number of records in a NetFlow v5 datagram
+pub const MAX_V5_RECORDS: usize = 30;
+
 /// Full NetFlow v5 datagram data
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowDatagramV5 {
 	pub sys_uptime_ms: u32,
 	pub unix_sec: u32,
@@ -72,6 +125,33 @@ impl NetflowDatagramV5 {
 
 		Ok((res, Self { sys_uptime_ms, unix_sec, unix_nsec, flow_seqnum, engine_type, engine_id, sampling_interval, flow_records }))
 	}
+
+	/// Encode this datagram to its wire representation
+	///
+	/// # Errors
+	///
+	/// Fails if there are more than [MAX_V5_RECORDS] records
+	pub fn to_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+		let count = self.flow_records.len();
+		if count > MAX_V5_RECORDS {
+			return Err(EncodeError::TooManyRecords { max: MAX_V5_RECORDS, count });
+		}
+
+		let mut out = Vec::with_capacity(24 + 48 * count);
+		out.extend_from_slice(&5u16.to_be_bytes());
+		out.extend_from_slice(&(count as u16).to_be_bytes());
+		out.extend_from_slice(&self.sys_uptime_ms.to_be_bytes());
+		out.extend_from_slice(&self.unix_sec.to_be_bytes());
+		out.extend_from_slice(&self.unix_nsec.to_be_bytes());
+		out.extend_from_slice(&self.flow_seqnum.to_be_bytes());
+		out.extend_from_slice(&[self.engine_type, self.engine_id]);
+		out.extend_from_slice(&self.sampling_interval.to_be_bytes());
+		for r in &self.flow_records {
+			r.write_bytes(&mut out);
+		}
+
+		Ok(out)
+	}
 }
 
 
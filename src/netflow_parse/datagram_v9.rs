@@ -1,17 +1,18 @@
 //! NetFlow v9 parsing
 
 use std::net::SocketAddr;
+use nom::bytes::complete::take;
 use nom::combinator::fail;
 use nom::IResult;
-use nom::multi::many_m_n;
 use nom::number::complete::{be_u16, be_u32};
 use nom::sequence::tuple;
 use crate::netflow_parse::datagram_v9_data::NetflowDatagramDataFlowSet;
 use crate::netflow_parse::datagram_v9_template::{NetflowDatagramOptionsTemplateSet, NetflowDatagramTemplateSet};
-use crate::netflow_parse::NetflowParser;
+use crate::netflow_parse::NetflowTemplateStore;
 
 /// Enum containing the three types of data sets in NetFlow v9
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NetflowDatagramV9FlowSet {
 	/// The actual data contained in a packet, parsed using data from Template and TemplateOption fields from this or previous packets
 	Data(NetflowDatagramDataFlowSet),
@@ -22,38 +23,59 @@ pub enum NetflowDatagramV9FlowSet {
 }
 
 impl NetflowDatagramV9FlowSet {
-	pub(crate) fn parse_from_datagram<'a>(input: &'a [u8], socket: &SocketAddr, parser: &mut NetflowParser) -> IResult<&'a [u8], Self> {
-		let (res, set_id) = be_u16(input)?;
+	/// Parse a single flow set, strictly within the length given in its header
+	pub(crate) fn parse_from_datagram<'a, P: NetflowTemplateStore>(input: &'a [u8], socket: &SocketAddr, parser: &mut P) -> IResult<&'a [u8], Self> {
+		let (_, (set_id, length)) = tuple((be_u16, be_u16))(input)?;
+		if length < 4 {
+			eprintln!("Flow set length {} is shorter than the set header", length);
+			return fail(input);
+		}
+		if length as usize > input.len() {
+			eprintln!("Flow set length {} exceeds the remaining {} bytes of the datagram", length, input.len());
+			return fail(input);
+		}
+		let (res, set) = take(length)(input)?;
+		// The set parsers start at the length field; padding at the end of the set is skipped along with it
+		let set = &set[2..];
 
 		match set_id {
 			0 => {
-				let (res, parsed) = NetflowDatagramTemplateSet::parse_from_datagram(res)?;
-				parser.register_netflow_template(&parsed, socket);
+				let (_, parsed) = NetflowDatagramTemplateSet::parse_from_datagram(set, parser.enterprise_fields())?;
+				parser.register_template_set(&parsed, socket);
 
 				Ok((res, Self::Template(parsed)))
 			}
 			1 => {
-				let (res, parsed) = NetflowDatagramOptionsTemplateSet::parse_from_datagram(res)?;
-				parser.register_netflow_options_template(&parsed, socket);
+				let (_, parsed) = NetflowDatagramOptionsTemplateSet::parse_from_datagram(set)?;
+				parser.register_options_template_set(&parsed, socket);
 
 				Ok((res, Self::TemplateOption(parsed)))
 			}
 			2..=255 => {
 				eprintln!("Got set id {}. This is an invalid set", set_id);
-				fail(res)
+				fail(input)
 			}
 			256..=u16::MAX => {
-				let (res, parsed) = NetflowDatagramDataFlowSet::parse_from_datagram(res, socket, set_id, parser)?;
+				let (_, parsed) = NetflowDatagramDataFlowSet::parse_from_datagram(set, socket, set_id, parser)?;
 
 				Ok((res, Self::Data(parsed)))
 			}
 		}
 	}
-}
 
+	/// Get the number of records in this flow set: templates, options templates, or data records
+	pub fn record_count(&self) -> usize {
+		match self {
+			Self::Data(d) => d.record_list().len(),
+			Self::Template(t) => t.template_ids.len(),
+			Self::TemplateOption(t) => t.template_ids.len(),
+		}
+	}
+}
 
 /// Full NetFlow v9 datagram data
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowDatagramV9 {
 	pub sys_uptime_ms: u32,
 	pub unix_sec: u32,
@@ -63,11 +85,25 @@ pub struct NetflowDatagramV9 {
 }
 
 impl NetflowDatagramV9 {
-	pub(crate) fn parse_from_datagram<'a>(input: &'a [u8], addr: &SocketAddr, parser: &mut NetflowParser) -> IResult<&'a [u8], Self> {
-		let (res, (_num_records, sys_uptime_ms, unix_sec, package_sequence, source_id)) =
+	pub(crate) fn parse_from_datagram<'a, P: NetflowTemplateStore>(input: &'a [u8], addr: &SocketAddr, parser: &mut P) -> IResult<&'a [u8], Self> {
+		let (mut res, (num_records, sys_uptime_ms, unix_sec, package_sequence, source_id)) =
 			tuple((be_u16, be_u32, be_u32, be_u32, be_u32))(input)?;
 
-		let (res, flow_records) = many_m_n(1, 30, |pd| { NetflowDatagramV9FlowSet::parse_from_datagram(pd, addr, parser) })(res)?;
+		// Flow sets fill the rest of the datagram
+		let mut flow_records = vec![];
+		let mut record_count = 0;
+		while !res.is_empty() {
+			let (rem, set) = NetflowDatagramV9FlowSet::parse_from_datagram(res, addr, parser)?;
+			record_count += set.record_count();
+			flow_records.push(set);
+			res = rem;
+		}
+
+		// Many exporters fill in the header count inaccurately, so a mismatch is only counted
+		if record_count != num_records as usize {
+			eprintln!("NetFlow v9 header from {} announces {} records, but the datagram contains {}", addr, num_records, record_count);
+			parser.count_record_count_mismatch();
+		}
 
 		Ok((res, Self { sys_uptime_ms, unix_sec, package_sequence, source_id, flow_records }))
 	}
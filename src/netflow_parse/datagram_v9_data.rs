@@ -1,17 +1,19 @@
 //! NetFlow v9 data field parsing
 
 use std::borrow::Cow;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use nom::bytes::complete::take;
 use nom::combinator::fail;
 use nom::IResult;
 use nom::number::complete::{be_u128, be_u16, be_u24, be_u32, be_u64, be_u8};
 use crate::netflow_parse::datagram_v9_template::NetflowDatagramTemplateField;
+use crate::netflow_parse::encode::{encode_value, pad_to_u32, u16_length, EncodeError};
 use crate::netflow_parse::netflow_v9_typemap::NetflowV9TypeHandlingMode;
-use crate::netflow_parse::NetflowParser;
+use crate::netflow_parse::NetflowTemplateStore;
 
 /// Parsed data field's value with a given representation
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NetflowV9DataValue {
 	/// A number up to 8 bytes long (64-bit), made up of either a 1, 2, 3, 4, or 8 byte number. Other numbers are represented as `Unknown`
 	Number(u64),
@@ -23,99 +25,80 @@ pub enum NetflowV9DataValue {
 	MAC(String),
 	/// An arbitrary UTF-8/ASCII string
 	String(String),
-	/// Unknown type, the bytes get stored raw
-	Unknown(Vec<u8>),
+	/// Unknown type or a length the type cannot have, the bytes get stored raw
+	Unknown(#[cfg_attr(feature = "serde", serde(with = "crate::serde_util::hex_bytes"))] Vec<u8>),
 }
 
 /// A single data field with a string name and a value
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct NetflowV9DataField {
 	pub name: &'static str,
 	pub type_id: u16,
 	pub value: NetflowV9DataValue,
+	/// Private enterprise number of an enterprise-specific field, see [NetflowDatagramTemplateField::enterprise]
+	#[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+	pub enterprise: Option<u32>,
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for NetflowV9DataField {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let field = crate::serde_util::DataFieldRepr::deserialize(deserializer)?;
+
+		Ok(Self { name: crate::serde_util::static_field_name(&field.name), type_id: field.type_id, value: field.value, enterprise: field.enterprise })
+	}
 }
 
 impl NetflowV9DataField {
 	pub(crate) fn parse_from_datagram<'a>(input: &'a [u8], type_info: &NetflowDatagramTemplateField) -> IResult<&'a [u8], Self> {
-		match type_info.field_type {
-			None => {
-				let (res, bytes) = take(type_info.field_length as usize)(input)?;
-				Ok((res, Self { name: "UNKNOWN", type_id: 0, value: NetflowV9DataValue::Unknown(Vec::from(bytes)) }))
-			}
-			Some(ft) => {
-				match ft.2 {
-					NetflowV9TypeHandlingMode::Number => {
-						match type_info.field_length {
-							1 => {
-								let (res, numval) = be_u8(input)?;
-								Ok((res, Self { name: ft.0, type_id: ft.3, value: NetflowV9DataValue::Number(numval as u64) }))
-							}
-							2 => {
-								let (res, numval) = be_u16(input)?;
-								Ok((res, Self { name: ft.0, type_id: ft.3, value: NetflowV9DataValue::Number(numval as u64) }))
-							}
-							3 => {
-								let (res, numval) = be_u24(input)?;
-								Ok((res, Self { name: ft.0, type_id: ft.3, value: NetflowV9DataValue::Number(numval as u64) }))
-							}
-							4 => {
-								let (res, numval) = be_u32(input)?;
-								Ok((res, Self { name: ft.0, type_id: ft.3, value: NetflowV9DataValue::Number(numval as u64) }))
-							}
-							8 => {
-								let (res, numval) = be_u64(input)?;
-								Ok((res, Self { name: ft.0, type_id: ft.3, value: NetflowV9DataValue::Number(numval as u64) }))
-							}
-							_ => {
-								let (res, numval) = take(type_info.field_length as usize)(input)?;
-								Ok((res, Self { name: ft.0, type_id: ft.3, value: NetflowV9DataValue::Unknown(Vec::from(numval)) }))
-							}
-						}
-					}
-					NetflowV9TypeHandlingMode::IPv4 => {
-						let (res, ipint) = be_u32(input)?;
-
-						Ok((res, Self { name: ft.0, type_id: ft.3, value: NetflowV9DataValue::IPv4(Ipv4Addr::from(ipint)) }))
-					}
-					NetflowV9TypeHandlingMode::IPv6 => {
-						let (res, ipint) = be_u128(input)?;
-
-						Ok((res, Self { name: ft.0, type_id: ft.3, value: NetflowV9DataValue::IPv6(Ipv6Addr::from(ipint)) }))
-					}
-					NetflowV9TypeHandlingMode::MAC => {
-						let (res, macbytes) = take(6usize)(input)?;
-						let macstr = format!("{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
-											 macbytes[0], macbytes[1], macbytes[2], macbytes[3], macbytes[4], macbytes[5]);
-
-						Ok((res, Self { name: ft.0, type_id: ft.3, value: NetflowV9DataValue::MAC(macstr) }))
-					}
-					NetflowV9TypeHandlingMode::String => {
-						let (res, rawstr) = take(type_info.field_length as usize)(input)?;
-
-						Ok((res, Self {
-							name: ft.0,
-							type_id: ft.3,
-							value: NetflowV9DataValue::String(match String::from_utf8_lossy(rawstr) {
-								Cow::Borrowed(s) => String::from(s),
-								Cow::Owned(s) => s
-							}),
-						}))
-					}
-				}
-			}
-		}
+		// Values are read from exactly the number of bytes the template gives, so a field of unexpected length never
+		// shifts the following fields
+		let (res, bytes) = take(type_info.field_length as usize)(input)?;
+
+		let Some(ft) = type_info.field_type else {
+			return Ok((res, Self {
+				name: "UNKNOWN",
+				type_id: type_info.type_id,
+				value: NetflowV9DataValue::Unknown(Vec::from(bytes)),
+				enterprise: type_info.enterprise,
+			}));
+		};
+
+		let value = match (ft.2, type_info.field_length) {
+			(NetflowV9TypeHandlingMode::Number, 1) => NetflowV9DataValue::Number(be_u8(bytes)?.1 as u64),
+			(NetflowV9TypeHandlingMode::Number, 2) => NetflowV9DataValue::Number(be_u16(bytes)?.1 as u64),
+			(NetflowV9TypeHandlingMode::Number, 3) => NetflowV9DataValue::Number(be_u24(bytes)?.1 as u64),
+			(NetflowV9TypeHandlingMode::Number, 4) => NetflowV9DataValue::Number(be_u32(bytes)?.1 as u64),
+			(NetflowV9TypeHandlingMode::Number, 8) => NetflowV9DataValue::Number(be_u64(bytes)?.1),
+			(NetflowV9TypeHandlingMode::IPv4, 4) => NetflowV9DataValue::IPv4(Ipv4Addr::from(be_u32(bytes)?.1)),
+			(NetflowV9TypeHandlingMode::IPv6, 16) => NetflowV9DataValue::IPv6(Ipv6Addr::from(be_u128(bytes)?.1)),
+			(NetflowV9TypeHandlingMode::MAC, 6) => NetflowV9DataValue::MAC(format!("{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+				bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5])),
+			(NetflowV9TypeHandlingMode::String, _) => NetflowV9DataValue::String(match String::from_utf8_lossy(bytes) {
+				Cow::Borrowed(s) => String::from(s),
+				Cow::Owned(s) => s
+			}),
+			// Lengths the type cannot have are kept as raw bytes
+			_ => NetflowV9DataValue::Unknown(Vec::from(bytes)),
+		};
+
+		Ok((res, Self { name: ft.0, type_id: type_info.type_id, value, enterprise: type_info.enterprise }))
 	}
 }
 
 /// Type of the records contained in a flow set
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NetflowDatagramRecordsType {
 	Regular(Vec<Vec<NetflowV9DataField>>),
+	/// Options data records, each holding the scope fields followed by the option fields
 	Option(Vec<Vec<NetflowV9DataField>>),
 }
 
 /// Source template type for a flow set
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NetflowDatagramSourceTemplateType {
 	Regular((SocketAddr, u16)),
 	Option((SocketAddr, u16)),
@@ -123,67 +106,147 @@ pub enum NetflowDatagramSourceTemplateType {
 
 /// A single flow set containing the records and fields
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowDatagramDataFlowSet {
 	pub length: u16,
 	pub source_template: NetflowDatagramSourceTemplateType,
 	pub records: NetflowDatagramRecordsType,
 }
 
-impl NetflowDatagramDataFlowSet {
-	pub(crate) fn parse_from_datagram<'a>(input: &'a [u8], addr: &SocketAddr, template_id: u16, parser: &mut NetflowParser) -> IResult<&'a [u8], Self> {
-		let (res, length) = be_u16(input)?;
+/// Parse as many records of `fields` as fit into a flow set of `length` bytes, returning the input after the flow set
+fn parse_records<'a>(input: &'a [u8], length: u16, template_id: u16, fields: &[NetflowDatagramTemplateField], template_length: u16) -> IResult<&'a [u8], Vec<Vec<NetflowV9DataField>>> {
+	let Some(body_length) = length.checked_sub(4) else {
+		eprintln!("Data flow set length {} is shorter than the set header", length);
+		return fail(input);
+	};
+	// Templates registered by hand are not validated while parsing
+	if template_length == 0 {
+		eprintln!("Template {} describes records of 0 bytes", template_id);
+		return fail(input);
+	}
+	let mut elem_count = body_length / template_length;
+	// Records shorter than 4 bytes cannot be told apart from the padding to 4 bytes by length alone. Trailing records
+	// that fit into the last 3 bytes and consist of zeros only, like padding should, are taken as padding
+	if template_length < 4 {
+		let body = input.get(..body_length as usize).unwrap_or(input);
+		while elem_count > 0 && body_length - (elem_count - 1) * template_length < 4
+			&& body[((elem_count - 1) * template_length) as usize..].iter().all(|b| *b == 0) {
+			elem_count -= 1;
+		}
+	}
 
-		if let Some(ts) = parser.templates.get(&(*addr, template_id)) {
-			let template_length = ts.total_field_length();
+	let mut curpos = input;
 
-			let elem_count = (length - 4) / template_length;
+	let mut records: Vec<Vec<NetflowV9DataField>> = Vec::with_capacity(elem_count as usize);
+	for _ in 0..elem_count {
+		let mut record: Vec<NetflowV9DataField> = Vec::with_capacity(fields.len());
+		for field_def in fields {
+			let (res1, field) = NetflowV9DataField::parse_from_datagram(curpos, field_def)?;
+			curpos = res1;
 
-			let mut curpos = res;
+			record.push(field);
+		}
+		records.push(record);
+	}
 
-			let mut records: Vec<Vec<NetflowV9DataField>> = Vec::with_capacity(elem_count as usize);
-			for _ in 0..elem_count {
-				let mut fields: Vec<NetflowV9DataField> = Vec::with_capacity(ts.fields.len());
-				for field_def in &ts.fields {
-					let (res1, field) = NetflowV9DataField::parse_from_datagram(curpos, field_def)?;
-					curpos = res1;
+	let (res, _padding) = take(body_length - template_length * elem_count)(curpos)?;
 
-					fields.push(field);
-				}
-				records.push(fields);
-			}
+	Ok((res, records))
+}
 
-			Ok((&curpos[((length - template_length * elem_count) - 4) as usize..], Self {
+impl NetflowDatagramDataFlowSet {
+	pub(crate) fn parse_from_datagram<'a, P: NetflowTemplateStore>(input: &'a [u8], addr: &SocketAddr, template_id: u16, parser: &mut P) -> IResult<&'a [u8], Self> {
+		let (res, length) = be_u16(input)?;
+
+		let regular = parser.with_template(addr, template_id, |ts| {
+			ts.map(|ts| parse_records(res, length, template_id, &ts.fields, ts.total_field_length()))
+		});
+		if let Some(parsed) = regular {
+			let (res, records) = parsed?;
+
+			return Ok((res, Self {
 				length,
 				source_template: NetflowDatagramSourceTemplateType::Regular((*addr, template_id)),
 				records: NetflowDatagramRecordsType::Regular(records),
-			}))
-		} else if let Some(ts) = parser.options_templates.get(&(*addr, template_id)) {
-			let template_length = ts.total_field_length();
-
-			let elem_count = (length - 4) / template_length;
-
-			let mut curpos = res;
-
-			let mut records: Vec<Vec<NetflowV9DataField>> = Vec::with_capacity(elem_count as usize);
-			for _ in 0..elem_count {
-				let mut fields: Vec<NetflowV9DataField> = Vec::with_capacity(ts.option_fields.len());
-				for field_def in &ts.option_fields {
-					let (res1, field) = NetflowV9DataField::parse_from_datagram(curpos, field_def)?;
-					curpos = res1;
+			}));
+		}
 
-					fields.push(field);
-				}
-				records.push(fields);
-			}
+		let options = parser.with_options_template(addr, template_id, |ts| {
+			ts.map(|ts| parse_records(res, length, template_id, &ts.record_fields(), ts.total_field_length()))
+		});
+		if let Some(parsed) = options {
+			let (res, records) = parsed?;
 
-			Ok((&curpos[((length - template_length * elem_count) - 4) as usize..], Self {
+			return Ok((res, Self {
 				length,
 				records: NetflowDatagramRecordsType::Option(records),
 				source_template: NetflowDatagramSourceTemplateType::Option((*addr, template_id)),
-			}))
-		} else {
-			eprintln!("Could not find template with ID {} for address {}", template_id, addr);
-			fail(res)
+			}));
+		}
+
+		eprintln!("Could not find template with ID {} for address {}", template_id, addr);
+		fail(res)
+	}
+
+	/// Create a data flow set of regular `records` described by template `template_id`
+	///
+	/// The length is computed when encoding, the template source address is left unspecified
+	pub fn regular(template_id: u16, records: Vec<Vec<NetflowV9DataField>>) -> Self {
+		Self {
+			length: 0,
+			source_template: NetflowDatagramSourceTemplateType::Regular((UNSPECIFIED_SOURCE, template_id)),
+			records: NetflowDatagramRecordsType::Regular(records),
+		}
+	}
+
+	/// Create a data flow set of options `records` described by options template `template_id`
+	///
+	/// Each record holds the scope fields followed by the option fields
+	pub fn options(template_id: u16, records: Vec<Vec<NetflowV9DataField>>) -> Self {
+		Self {
+			length: 0,
+			source_template: NetflowDatagramSourceTemplateType::Option((UNSPECIFIED_SOURCE, template_id)),
+			records: NetflowDatagramRecordsType::Option(records),
+		}
+	}
+
+	/// Get the ID of the template describing the records
+	pub fn template_id(&self) -> u16 {
+		match self.source_template {
+			NetflowDatagramSourceTemplateType::Regular((_, id)) | NetflowDatagramSourceTemplateType::Option((_, id)) => id,
 		}
 	}
-}
\ No newline at end of file
+
+	/// Get the records of this flow set, whether regular or options records
+	pub fn record_list(&self) -> &[Vec<NetflowV9DataField>] {
+		match &self.records {
+			NetflowDatagramRecordsType::Regular(r) | NetflowDatagramRecordsType::Option(r) => r,
+		}
+	}
+
+	/// Encode this flow set to its wire representation using the template `fields`, starting with the set ID
+	///
+	/// The set is padded to a multiple of 4 bytes
+	pub fn to_bytes(&self, fields: &[NetflowDatagramTemplateField]) -> Result<Vec<u8>, EncodeError> {
+		let template_id = self.template_id();
+		let mut out = Vec::with_capacity(4);
+		out.extend_from_slice(&template_id.to_be_bytes());
+		out.extend_from_slice(&[0, 0]);
+
+		for record in self.record_list() {
+			if record.len() != fields.len() {
+				return Err(EncodeError::FieldCountMismatch { template_id, expected: fields.len(), count: record.len() });
+			}
+			for (field, def) in record.iter().zip(fields) {
+				encode_value(&mut out, &field.value, def.type_id, def.field_length)?;
+			}
+		}
+		pad_to_u32(&mut out, 0);
+
+		let length = u16_length(out.len())?;
+		out[2..4].copy_from_slice(&length.to_be_bytes());
+		Ok(out)
+	}
+}
+
+const UNSPECIFIED_SOURCE: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
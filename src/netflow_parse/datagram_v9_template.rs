@@ -1,30 +1,74 @@
 //! NetFlow v9 template mappings and types
 
+use nom::bytes::complete::take;
+use nom::combinator::fail;
 use nom::IResult;
 use nom::multi::count;
-use nom::number::complete::be_u16;
+use nom::number::complete::{be_u16, be_u32};
 use nom::sequence::tuple;
-use crate::netflow_parse::netflow_v9_typemap::{NETFLOW_V9_DATATYPES, NetflowTypeInfo, NetflowV9ScopeType};
+use crate::netflow_parse::encode::{pad_to_u32, u16_length, EncodeError};
+use crate::netflow_parse::netflow_v9_typemap::{scope_type_info, NETFLOW_V9_DATATYPES, NetflowTypeInfo, NetflowV9ScopeType};
+
+/// Private enterprise number marking the reverse direction's fields of a biflow (RFC 5103)
+///
+/// The type of a reverse field is the type of its forward counterpart
+pub const REVERSE_PEN: u32 = 29305;
+
+/// Bit marking an enterprise-specific field type, followed by the enterprise number (RFC 7011)
+const ENTERPRISE_BIT: u16 = 0x8000;
 
 /// Data field specification from template
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowDatagramTemplateField {
+	#[cfg_attr(feature = "serde", serde(with = "crate::serde_util::type_info"))]
 	pub field_type: Option<NetflowTypeInfo>,
+	/// Field type number as sent by the exporter, also for unknown types, without the enterprise bit for
+	/// enterprise-specific fields
+	pub type_id: u16,
 	pub field_length: u16,
+	/// Private enterprise number of an enterprise-specific field, only parsed if enabled with
+	/// [NetflowParser::with_enterprise_fields](crate::netflow_parse::NetflowParser::with_enterprise_fields)
+	#[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+	pub enterprise: Option<u32>,
 }
 
 impl NetflowDatagramTemplateField {
-	pub(crate) fn parse_from_datagram(input: &[u8]) -> IResult<&[u8], Self> {
+	/// Parse a field specifier, with `enterprise_fields` reading the enterprise number of fields with the enterprise bit
+	pub(crate) fn parse_from_datagram(input: &[u8], enterprise_fields: bool) -> IResult<&[u8], Self> {
 		let (res, (field_type_num, field_length)) = tuple((be_u16, be_u16))(input)?;
 
-		let field_type = NETFLOW_V9_DATATYPES.get(&field_type_num).map(|(s0, s1, hm, ft)| (*s0, *s1, *hm, *ft));
+		if enterprise_fields && field_type_num & ENTERPRISE_BIT != 0 {
+			let (res, enterprise) = be_u32(res)?;
+			return Ok((res, Self::enterprise(field_type_num & !ENTERPRISE_BIT, enterprise, field_length)));
+		}
 
-		Ok((res, Self { field_type, field_length }))
+		Ok((res, Self::new(field_type_num, field_length)))
+	}
+
+	/// Create a field of type `type_id`, looking up its type information
+	pub fn new(type_id: u16, field_length: u16) -> Self {
+		Self { field_type: NETFLOW_V9_DATATYPES.get(&type_id).copied(), type_id, field_length, enterprise: None }
+	}
+
+	/// Create an enterprise-specific field of type `type_id` defined by `enterprise`
+	///
+	/// Only reverse fields ([REVERSE_PEN]) get type information, that of their forward counterpart
+	pub fn enterprise(type_id: u16, enterprise: u32, field_length: u16) -> Self {
+		let field_type = (enterprise == REVERSE_PEN).then(|| NETFLOW_V9_DATATYPES.get(&type_id).copied()).flatten();
+
+		Self { field_type, type_id, field_length, enterprise: Some(enterprise) }
+	}
+
+	/// Length of this field specifier in a template
+	fn specifier_length(&self) -> usize {
+		if self.enterprise.is_some() { 8 } else { 4 }
 	}
 }
 
 /// Single template
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowDatagramTemplate {
 	pub template_id: u16,
 	pub field_count: u16,
@@ -36,15 +80,32 @@ impl NetflowDatagramTemplate {
 	pub fn total_field_length(&self) -> u16 {
 		let mut acc: u16 = 0;
 		for f in &self.fields {
-			acc += f.field_length;
+			acc = acc.saturating_add(f.field_length);
 		}
 
 		acc
 	}
 }
 
+/// Check that records described by template `template_id` with fields of the given lengths take up space, and fit into a flow set
+fn validate_record_length(input: &[u8], template_id: u16, field_lengths: impl Iterator<Item = u16>) -> IResult<&[u8], ()> {
+	let record_length: u32 = field_lengths.map(u32::from).sum();
+
+	if record_length == 0 {
+		eprintln!("Template {} describes records of 0 bytes", template_id);
+		return fail(input);
+	}
+	if record_length > u16::MAX as u32 - 4 {
+		eprintln!("Template {} describes records of {} bytes, which do not fit into a flow set", template_id, record_length);
+		return fail(input);
+	}
+
+	Ok((input, ()))
+}
+
 /// Regular template set data
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowDatagramTemplateSet {
 	pub length: u16,
 	pub template_ids: Vec<u16>,
@@ -53,7 +114,8 @@ pub struct NetflowDatagramTemplateSet {
 }
 
 impl NetflowDatagramTemplateSet {
-	pub(crate) fn parse_from_datagram(input: &[u8]) -> IResult<&[u8], Self> {
+	/// Parse a template set, with `enterprise_fields` reading the enterprise numbers of enterprise-specific fields
+	pub(crate) fn parse_from_datagram(input: &[u8], enterprise_fields: bool) -> IResult<&[u8], Self> {
 		let (res, length) = be_u16(input)?;
 
 		let mut template_ids: Vec<u16> = vec!();
@@ -61,27 +123,94 @@ impl NetflowDatagramTemplateSet {
 		let mut fields_vec: Vec<Vec<NetflowDatagramTemplateField>> = vec!();
 
 		let mut res_rem = res;
-		let mut len_rem = length - 4;
-		while len_rem != 0 {
+		let Some(mut len_rem) = length.checked_sub(4) else {
+			eprintln!("Template set length {} is shorter than the set header", length);
+			return fail(res);
+		};
+		// Less than 4 remaining bytes are padding (RFC 3954)
+		while len_rem >= 4 {
 			let (res, (template_id, field_count)) = tuple((be_u16, be_u16))(res_rem)?;
-
-			let (res, fields) = count(NetflowDatagramTemplateField::parse_from_datagram, field_count as usize)(res)?;
+			if template_id < 256 {
+				eprintln!("Invalid template ID {}, must be at least 256", template_id);
+				return fail(res);
+			}
+
+			let Some(rem) = (len_rem as usize).checked_sub(field_count as usize * 4 + 4) else {
+				eprintln!("Template {} with {} fields does not fit into the remaining {} bytes of its set", template_id, field_count, len_rem);
+				return fail(res);
+			};
+			let (res, fields) = count(|i| NetflowDatagramTemplateField::parse_from_datagram(i, enterprise_fields), field_count as usize)(res)?;
+			// Enterprise numbers take 4 more bytes each
+			let Some(rem) = rem.checked_sub(fields.iter().map(|f| f.specifier_length() - 4).sum()) else {
+				eprintln!("Template {} with enterprise-specific fields does not fit into the remaining {} bytes of its set", template_id, len_rem);
+				return fail(res);
+			};
+			let (res, _) = validate_record_length(res, template_id, fields.iter().map(|f| f.field_length))?;
 			res_rem = res;
-			len_rem -= field_count * 4 + 4;
+			len_rem = rem as u16;
 
 			template_ids.push(template_id);
 			field_counts.push(field_count);
 			fields_vec.push(fields);
 		}
 
+		let (res_rem, _padding) = take(len_rem)(res_rem)?;
+
 		Ok((res_rem, Self { length, template_ids, field_counts, fields_vec }))
 	}
+
+	/// Get the individual templates defined by this set
+	pub fn templates(&self) -> impl Iterator<Item = NetflowDatagramTemplate> + '_ {
+		self.template_ids.iter().zip(&self.field_counts).zip(&self.fields_vec)
+			.map(|((template_id, field_count), fields)| NetflowDatagramTemplate { template_id: *template_id, field_count: *field_count, fields: fields.clone() })
+	}
+
+	/// Build a template set defining `templates`
+	pub fn from_templates(templates: &[NetflowDatagramTemplate]) -> Self {
+		let length = 4 + templates.iter().map(|t| 4 + t.fields.iter().map(|f| f.specifier_length()).sum::<usize>()).sum::<usize>();
+
+		Self {
+			length: length.min(u16::MAX as usize) as u16,
+			template_ids: templates.iter().map(|t| t.template_id).collect(),
+			field_counts: templates.iter().map(|t| t.fields.len() as u16).collect(),
+			fields_vec: templates.iter().map(|t| t.fields.clone()).collect(),
+		}
+	}
+
+	/// Encode this set to its wire representation, starting with the set ID; the length is recomputed from the fields
+	pub fn to_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+		let mut out = vec![0, 0, 0, 0];
+		for (template_id, fields) in self.template_ids.iter().zip(&self.fields_vec) {
+			out.extend_from_slice(&template_id.to_be_bytes());
+			out.extend_from_slice(&u16_length(fields.len())?.to_be_bytes());
+			for f in fields {
+				match f.enterprise {
+					Some(enterprise) => {
+						out.extend_from_slice(&(f.type_id | ENTERPRISE_BIT).to_be_bytes());
+						out.extend_from_slice(&f.field_length.to_be_bytes());
+						out.extend_from_slice(&enterprise.to_be_bytes());
+					}
+					None => {
+						out.extend_from_slice(&f.type_id.to_be_bytes());
+						out.extend_from_slice(&f.field_length.to_be_bytes());
+					}
+				}
+			}
+		}
+
+		let length = u16_length(out.len())?;
+		out[2..4].copy_from_slice(&length.to_be_bytes());
+		Ok(out)
+	}
 }
 
 /// Data scope specification from template
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowDatagramOptionsTemplateScopeField {
 	pub field_type: Option<NetflowV9ScopeType>,
+	/// Scope type number as sent by the exporter, also for unknown types
+	pub type_id: u16,
 	pub field_length: u16,
 }
 
@@ -91,12 +220,18 @@ impl NetflowDatagramOptionsTemplateScopeField {
 
 		let field_type = NetflowV9ScopeType::try_from(field_type_num).ok();
 
-		Ok((res, Self { field_type, field_length }))
+		Ok((res, Self { field_type, type_id: field_type_num, field_length }))
+	}
+
+	/// Create a scope field of type `type_id`
+	pub fn new(type_id: u16, field_length: u16) -> Self {
+		Self { field_type: NetflowV9ScopeType::try_from(type_id).ok(), type_id, field_length }
 	}
 }
 
 /// Single template
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowDatagramOptionsTemplate {
 	pub template_id: u16,
 	pub scope_field_count: u16,
@@ -106,20 +241,34 @@ pub struct NetflowDatagramOptionsTemplate {
 }
 
 impl NetflowDatagramOptionsTemplate {
-	/// Get the total length of all template fields
+	/// Get the total length of all template fields, scope fields included
 	pub fn total_field_length(&self) -> u16 {
 		let mut acc: u16 = 0;
 
+		for f in &self.scope_fields {
+			acc = acc.saturating_add(f.field_length);
+		}
 		for f in &self.option_fields {
-			acc += f.field_length;
+			acc = acc.saturating_add(f.field_length);
 		}
 
 		acc
 	}
+
+	/// Get the fields of a data record described by this template: the scope fields followed by the option fields
+	pub fn record_fields(&self) -> Vec<NetflowDatagramTemplateField> {
+		self.scope_fields.iter().map(|f| NetflowDatagramTemplateField {
+			field_type: Some(scope_type_info(f.type_id)),
+			type_id: f.type_id,
+			field_length: f.field_length,
+			enterprise: None,
+		}).chain(self.option_fields.iter().copied()).collect()
+	}
 }
 
 /// Options template set data
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NetflowDatagramOptionsTemplateSet {
 	pub length: u16,
 	pub template_ids: Vec<u16>,
@@ -135,7 +284,10 @@ impl NetflowDatagramOptionsTemplateSet {
 	pub(crate) fn parse_from_datagram(input: &[u8]) -> IResult<&[u8], Self> {
 		let (res, length) = be_u16(input)?;
 
-		let mut len_rem = length - 4;
+		let Some(mut len_rem) = length.checked_sub(4) else {
+			eprintln!("Options template set length {} is shorter than the set header", length);
+			return fail(res);
+		};
 		let mut res_rem = res;
 
 		let mut template_ids: Vec<u16> = vec!();
@@ -147,7 +299,22 @@ impl NetflowDatagramOptionsTemplateSet {
 		while len_rem >= 4 {
 			let (res, (template_id, scope_fields_length, option_fields_length)) = tuple((be_u16, be_u16, be_u16))(res_rem)?;
 
-			len_rem -= 6;
+			if template_id < 256 {
+				eprintln!("Invalid options template ID {}, must be at least 256", template_id);
+				return fail(res);
+			}
+			if scope_fields_length % INFO_LENGTH != 0 || option_fields_length % INFO_LENGTH != 0 {
+				eprintln!("Options template {} has scope and option lengths {} and {}, which are not multiples of {}",
+						  template_id, scope_fields_length, option_fields_length, INFO_LENGTH);
+				return fail(res);
+			}
+
+			let used = 6 + scope_fields_length as usize + option_fields_length as usize;
+			let Some(rem) = (len_rem as usize).checked_sub(used) else {
+				eprintln!("Options template {} with {} bytes of fields does not fit into the remaining {} bytes of its set",
+						  template_id, used - 6, len_rem);
+				return fail(res);
+			};
 
 			let scope_iter_count = scope_fields_length / INFO_LENGTH;
 			let option_iter_count = option_fields_length / INFO_LENGTH;
@@ -156,10 +323,11 @@ impl NetflowDatagramOptionsTemplateSet {
 				= count(NetflowDatagramOptionsTemplateScopeField::parse_from_datagram, scope_iter_count as usize)(res)?;
 
 			let (res, option_fields)
-				= count(NetflowDatagramTemplateField::parse_from_datagram, option_iter_count as usize)(res)?;
+				= count(|i| NetflowDatagramTemplateField::parse_from_datagram(i, false), option_iter_count as usize)(res)?;
+			let (res, _) = validate_record_length(res, template_id, scope_fields.iter().map(|f| f.field_length).chain(option_fields.iter().map(|f| f.field_length)))?;
 
 			res_rem = res;
-			len_rem -= scope_fields_length + option_fields_length;
+			len_rem = rem as u16;
 
 			template_ids.push(template_id);
 			scope_fields_lengths.push(scope_fields_length);
@@ -168,6 +336,61 @@ impl NetflowDatagramOptionsTemplateSet {
 			option_fields_vec.push(option_fields);
 		}
 
-		Ok(((&res_rem[len_rem as usize..]), Self { length, template_ids, scope_fields_lengths, option_fields_lengths, scope_fields_vec, option_fields_vec }))
+		let (res_rem, _padding) = take(len_rem)(res_rem)?;
+
+		Ok((res_rem, Self { length, template_ids, scope_fields_lengths, option_fields_lengths, scope_fields_vec, option_fields_vec }))
+	}
+
+	/// Get the individual options templates defined by this set
+	///
+	/// Sets built by hand with vectors of different lengths yield as many templates as the shortest vector has entries
+	pub fn templates(&self) -> impl Iterator<Item = NetflowDatagramOptionsTemplate> + '_ {
+		self.template_ids.iter().zip(&self.scope_fields_lengths).zip(&self.option_fields_lengths)
+			.zip(&self.scope_fields_vec).zip(&self.option_fields_vec)
+			.map(|((((template_id, scope_length), option_length), scope_fields), option_fields)| NetflowDatagramOptionsTemplate {
+				template_id: *template_id,
+				option_field_count: option_length / 4,
+				scope_field_count: scope_length / 4,
+				scope_fields: scope_fields.clone(),
+				option_fields: option_fields.clone(),
+			})
+	}
+
+	/// Build an options template set defining `templates`
+	pub fn from_templates(templates: &[NetflowDatagramOptionsTemplate]) -> Self {
+		let length = 4 + templates.iter().map(|t| 6 + 4 * (t.scope_fields.len() + t.option_fields.len())).sum::<usize>();
+
+		Self {
+			length: length.next_multiple_of(4).min(u16::MAX as usize) as u16,
+			template_ids: templates.iter().map(|t| t.template_id).collect(),
+			scope_fields_lengths: templates.iter().map(|t| (t.scope_fields.len() * 4) as u16).collect(),
+			option_fields_lengths: templates.iter().map(|t| (t.option_fields.len() * 4) as u16).collect(),
+			scope_fields_vec: templates.iter().map(|t| t.scope_fields.clone()).collect(),
+			option_fields_vec: templates.iter().map(|t| t.option_fields.clone()).collect(),
+		}
+	}
+
+	/// Encode this set to its wire representation, starting with the set ID; the lengths are recomputed from the fields
+	/// and the set is padded to a multiple of 4 bytes
+	pub fn to_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+		let mut out = vec![0, 1, 0, 0];
+		for ((template_id, scope_fields), option_fields) in self.template_ids.iter().zip(&self.scope_fields_vec).zip(&self.option_fields_vec) {
+			out.extend_from_slice(&template_id.to_be_bytes());
+			out.extend_from_slice(&u16_length(scope_fields.len() * 4)?.to_be_bytes());
+			out.extend_from_slice(&u16_length(option_fields.len() * 4)?.to_be_bytes());
+			for f in scope_fields {
+				out.extend_from_slice(&f.type_id.to_be_bytes());
+				out.extend_from_slice(&f.field_length.to_be_bytes());
+			}
+			for f in option_fields {
+				out.extend_from_slice(&f.type_id.to_be_bytes());
+				out.extend_from_slice(&f.field_length.to_be_bytes());
+			}
+		}
+		pad_to_u32(&mut out, 0);
+
+		let length = u16_length(out.len())?;
+		out[2..4].copy_from_slice(&length.to_be_bytes());
+		Ok(out)
 	}
 }
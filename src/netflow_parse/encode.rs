@@ -0,0 +1,174 @@
+//! Encoding NetFlow datagrams to wire bytes
+//!
+//! NetFlow v5 datagrams are self-contained and encoded with [NetflowDatagramV5::to_bytes](crate::netflow_parse::datagram_v5::NetflowDatagramV5::to_bytes).
+//! NetFlow v9 data flow sets can only be encoded with their template, so [NetflowV9Encoder] remembers the templates it
+//! has been given or has encoded before, like the parser does on the receiving side
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use crate::netflow_parse::datagram_v9::{NetflowDatagramV9, NetflowDatagramV9FlowSet};
+use crate::netflow_parse::datagram_v9_data::{NetflowDatagramRecordsType, NetflowV9DataValue};
+use crate::netflow_parse::datagram_v9_template::{NetflowDatagramOptionsTemplate, NetflowDatagramTemplate, NetflowDatagramTemplateField};
+
+/// Reasons a datagram cannot be encoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+	/// More records than the format allows in one datagram
+	TooManyRecords { max: usize, count: usize },
+	/// A data flow set refers to a template that has not been registered or sent before
+	UnknownTemplate(u16),
+	/// A data record has a different number of fields than its template
+	FieldCountMismatch { template_id: u16, expected: usize, count: usize },
+	/// A field value does not fit the length given by the template
+	InvalidFieldValue { type_id: u16, length: u16 },
+	/// A set or the whole datagram exceeds 65535 bytes
+	TooLong,
+}
+
+impl Display for EncodeError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			EncodeError::TooManyRecords { max, count } => write!(f, "Too many records: {} (at most {})", count, max),
+			EncodeError::UnknownTemplate(id) => write!(f, "Could not find template with ID {}", id),
+			EncodeError::FieldCountMismatch { template_id, expected, count } =>
+				write!(f, "Record has {} fields, but template {} has {}", count, template_id, expected),
+			EncodeError::InvalidFieldValue { type_id, length } => write!(f, "Value of field type {} does not fit into {} bytes", type_id, length),
+			EncodeError::TooLong => write!(f, "Encoded data exceeds 65535 bytes"),
+		}
+	}
+}
+
+impl std::error::Error for EncodeError {}
+
+/// Convert a length to the 16-bit length used in headers
+pub(crate) fn u16_length(length: usize) -> Result<u16, EncodeError> {
+	u16::try_from(length).map_err(|_| EncodeError::TooLong)
+}
+
+/// Pad `out` with zeros to a multiple of 4 bytes, counted from `start`
+pub(crate) fn pad_to_u32(out: &mut Vec<u8>, start: usize) {
+	while !(out.len() - start).is_multiple_of(4) {
+		out.push(0);
+	}
+}
+
+/// Parse a MAC address in the format AA:BB:CC:DD:EE:FF
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+	let mut bytes = [0u8; 6];
+	let mut parts = mac.split(':');
+	for b in bytes.iter_mut() {
+		*b = u8::from_str_radix(parts.next()?, 16).ok()?;
+	}
+
+	parts.next().is_none().then_some(bytes)
+}
+
+/// Append `value` as a field of type `type_id` that is `length` bytes long
+///
+/// Numbers are written big-endian, strings are padded with zeros; all other values must be exactly `length` bytes long
+pub(crate) fn encode_value(out: &mut Vec<u8>, value: &NetflowV9DataValue, type_id: u16, length: u16) -> Result<(), EncodeError> {
+	let invalid = EncodeError::InvalidFieldValue { type_id, length };
+	let len = length as usize;
+
+	match value {
+		NetflowV9DataValue::Number(n) => {
+			if len == 0 || len > 8 || (len < 8 && *n >> (len * 8) != 0) {
+				return Err(invalid);
+			}
+			out.extend_from_slice(&n.to_be_bytes()[8 - len..]);
+		}
+		NetflowV9DataValue::IPv4(a) if len == 4 => out.extend_from_slice(&a.octets()),
+		NetflowV9DataValue::IPv6(a) if len == 16 => out.extend_from_slice(&a.octets()),
+		NetflowV9DataValue::MAC(mac) if len == 6 => out.extend_from_slice(&parse_mac(mac).ok_or(invalid)?),
+		NetflowV9DataValue::String(s) if s.len() <= len => {
+			out.extend_from_slice(s.as_bytes());
+			out.resize(out.len() + len - s.len(), 0);
+		}
+		NetflowV9DataValue::Unknown(bytes) if bytes.len() == len => out.extend_from_slice(bytes),
+		_ => return Err(invalid),
+	}
+
+	Ok(())
+}
+
+/// Encoder for NetFlow v9 datagrams, keeping the templates needed to encode data flow sets
+#[derive(Debug, Clone, Default)]
+pub struct NetflowV9Encoder {
+	templates: HashMap<u16, Vec<NetflowDatagramTemplateField>>,
+	options_templates: HashMap<u16, Vec<NetflowDatagramTemplateField>>,
+}
+
+impl NetflowV9Encoder {
+	/// Create an encoder without any templates
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Make `template` available for encoding data flow sets
+	pub fn register_template(&mut self, template: &NetflowDatagramTemplate) {
+		self.templates.insert(template.template_id, template.fields.clone());
+	}
+
+	/// Make `template` available for encoding options data flow sets
+	pub fn register_options_template(&mut self, template: &NetflowDatagramOptionsTemplate) {
+		self.options_templates.insert(template.template_id, template.record_fields());
+	}
+
+	/// Encode `datagram` to its wire representation
+	///
+	/// Data flow sets can use templates defined earlier in the same datagram. Templates in the datagram's template sets
+	/// are registered once the whole datagram was encoded. The record count in the header is computed from the flow sets
+	///
+	/// # Errors
+	///
+	/// Fails if a data flow set uses an unknown template, if a record does not match its template, or if the datagram
+	/// is longer than 65535 bytes
+	pub fn encode(&mut self, datagram: &NetflowDatagramV9) -> Result<Vec<u8>, EncodeError> {
+		let mut out = Vec::with_capacity(1500);
+		out.extend_from_slice(&9u16.to_be_bytes());
+		out.extend_from_slice(&[0, 0]);
+		out.extend_from_slice(&datagram.sys_uptime_ms.to_be_bytes());
+		out.extend_from_slice(&datagram.unix_sec.to_be_bytes());
+		out.extend_from_slice(&datagram.package_sequence.to_be_bytes());
+		out.extend_from_slice(&datagram.source_id.to_be_bytes());
+
+		// Templates are only kept once the whole datagram is encoded, a failed datagram leaves the encoder unchanged
+		let mut templates = HashMap::new();
+		let mut options_templates = HashMap::new();
+		let mut count = 0usize;
+		for set in &datagram.flow_records {
+			match set {
+				NetflowDatagramV9FlowSet::Template(ts) => {
+					out.extend_from_slice(&ts.to_bytes()?);
+					for t in ts.templates() {
+						templates.insert(t.template_id, t.fields);
+						count += 1;
+					}
+				}
+				NetflowDatagramV9FlowSet::TemplateOption(ts) => {
+					out.extend_from_slice(&ts.to_bytes()?);
+					for t in ts.templates() {
+						options_templates.insert(t.template_id, t.record_fields());
+						count += 1;
+					}
+				}
+				NetflowDatagramV9FlowSet::Data(ds) => {
+					let template_id = ds.template_id();
+					let fields = match ds.records {
+						NetflowDatagramRecordsType::Regular(_) => templates.get(&template_id).or_else(|| self.templates.get(&template_id)),
+						NetflowDatagramRecordsType::Option(_) => options_templates.get(&template_id).or_else(|| self.options_templates.get(&template_id)),
+					}.ok_or(EncodeError::UnknownTemplate(template_id))?;
+
+					out.extend_from_slice(&ds.to_bytes(fields)?);
+					count += ds.record_list().len();
+				}
+			}
+		}
+
+		u16_length(out.len())?;
+		out[2..4].copy_from_slice(&u16_length(count)?.to_be_bytes());
+		self.templates.extend(templates);
+		self.options_templates.extend(options_templates);
+		Ok(out)
+	}
+}
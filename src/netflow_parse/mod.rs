@@ -14,6 +14,29 @@ pub mod datagram_ipfix;
 pub mod netflow_v9_typemap;
 pub mod datagram_v9_template;
 pub mod datagram_v9_data;
+pub mod encode;
+pub mod reorder;
+#[cfg(feature = "concurrent")]
+pub mod concurrent;
+
+/// Template storage used while parsing NetFlow v9 datagrams
+///
+/// This lets the datagram parsers work both with the single-threaded [NetflowParser] and with shared parsers such as
+/// `ConcurrentNetflowParser`
+pub(crate) trait NetflowTemplateStore {
+	/// Call `f` with the template registered under `id` for `addr`, if there is one
+	fn with_template<R, F: FnOnce(Option<&NetflowDatagramTemplate>) -> R>(&self, addr: &SocketAddr, id: u16, f: F) -> R;
+	/// Call `f` with the options template registered under `id` for `addr`, if there is one
+	fn with_options_template<R, F: FnOnce(Option<&NetflowDatagramOptionsTemplate>) -> R>(&self, addr: &SocketAddr, id: u16, f: F) -> R;
+	/// Register all templates from `set` for `addr`
+	fn register_template_set(&mut self, set: &NetflowDatagramTemplateSet, addr: &SocketAddr);
+	/// Register all options templates from `set` for `addr`
+	fn register_options_template_set(&mut self, set: &NetflowDatagramOptionsTemplateSet, addr: &SocketAddr);
+	/// Count a NetFlow v9 datagram whose header record count does not match its flow sets
+	fn count_record_count_mismatch(&mut self);
+	/// Check whether template fields with the enterprise bit are parsed as enterprise-specific fields
+	fn enterprise_fields(&self) -> bool;
+}
 
 
 /// Main NetFlow parser handling parsing, state, and providing an interface for it. It serves as the main entry point into the library
@@ -21,6 +44,8 @@ pub mod datagram_v9_data;
 pub struct NetflowParser {
 	templates: HashMap<(SocketAddr, u16), NetflowDatagramTemplate>,
 	options_templates: HashMap<(SocketAddr, u16), NetflowDatagramOptionsTemplate>,
+	record_count_mismatches: u64,
+	enterprise_fields: bool,
 }
 
 impl NetflowParser {
@@ -29,6 +54,17 @@ impl NetflowParser {
 		Self::default()
 	}
 
+	/// Parse template fields whose type has the high bit set as enterprise-specific fields followed by their enterprise
+	/// number, as IPFIX does (RFC 7011), instead of as vendor-specific types
+	///
+	/// This decodes biflows with RFC 5103 reverse fields (see [crate::biflow]). It is off by default, as NetFlow v9
+	/// exporters such as Cisco ASA (NSEL) use types above 32767 without enterprise numbers. Enterprise-specific fields
+	/// are only supported in regular templates, not in options templates
+	pub fn with_enterprise_fields(mut self, enabled: bool) -> Self {
+		self.enterprise_fields = enabled;
+		self
+	}
+
 	/// Parse the netflow datagram bytes from `input` that are coming in from `addr`
 	///
 	/// The `addr` parameter is used for storing template information for NetFlow v9 and v10
@@ -40,39 +76,64 @@ impl NetflowParser {
 	/// - Unsupported NetFlow version
 	/// - Template with given ID has not been defined yet or has an ID between 2-255 (inclusive)
 	/// - The packet ends prematurely (due to the buffer being full)
+	/// - A NetFlow v9 flow set does not fit into the datagram
+	///
+	/// A NetFlow v9 header record count that does not match the flow sets is only counted, see
+	/// [NetflowParser::record_count_mismatches]
 	pub fn parse<'a>(&mut self, input: &'a [u8], addr: &SocketAddr) -> IResult<&'a [u8], NetflowDatagramData> {
 		datagram::parse_netflow_data(input, addr, self)
 	}
 
 	/// Parse the initial non-data meta parts of NetFlow datagrams, returning the original array slice
 	///
-	/// This function can be used to handle UDP packets that arrive in the wrong order by matching the sequence number and caching packets
+	/// This function can be used to handle UDP packets that arrive in the wrong order by matching the sequence number and caching packets,
+	/// as done by [reorder::ReorderBuffer]
 	pub fn peek_netflow_basic_info(input: &[u8]) -> IResult<&[u8], NetflowPeekResult> {
 		datagram::peek_netflow_basic_info(input)
 	}
 
 	/// Manually register a new NetFlow template
 	pub fn register_netflow_template(&mut self, set: &NetflowDatagramTemplateSet, addr: &SocketAddr) {
-		for s in 0..set.template_ids.len() {
-			let s = NetflowDatagramTemplate{template_id: *set.template_ids.get(s).unwrap(),
-				field_count: *set.field_counts.get(s).unwrap(),
-				fields: set.fields_vec.get(s).unwrap().clone()
-			};
-			self.templates.insert((*addr, s.template_id), s);
+		for t in set.templates() {
+			self.templates.insert((*addr, t.template_id), t);
 		}
 	}
 
 	/// Manually register a new NetFlow options template
 	pub fn register_netflow_options_template(&mut self, set: &NetflowDatagramOptionsTemplateSet, addr: &SocketAddr) {
-		// FIXME:
-		for s in 0..set.template_ids.len() {
-			let s = NetflowDatagramOptionsTemplate{template_id: *set.template_ids.get(s).unwrap(),
-				option_field_count: *set.option_fields_lengths.get(s).unwrap() / 4,
-				scope_field_count: *set.scope_fields_lengths.get(s).unwrap() / 4,
-				scope_fields: set.scope_fields_vec.get(s).unwrap().clone(),
-				option_fields: set.option_fields_vec.get(s).unwrap().clone()
-			};
-			self.options_templates.insert((*addr, s.template_id), s);
+		for t in set.templates() {
+			self.options_templates.insert((*addr, t.template_id), t);
 		}
 	}
-}
\ No newline at end of file
+
+	/// Get the number of NetFlow v9 datagrams parsed despite a header record count not matching their flow sets
+	pub fn record_count_mismatches(&self) -> u64 {
+		self.record_count_mismatches
+	}
+}
+
+impl NetflowTemplateStore for NetflowParser {
+	fn with_template<R, F: FnOnce(Option<&NetflowDatagramTemplate>) -> R>(&self, addr: &SocketAddr, id: u16, f: F) -> R {
+		f(self.templates.get(&(*addr, id)))
+	}
+
+	fn with_options_template<R, F: FnOnce(Option<&NetflowDatagramOptionsTemplate>) -> R>(&self, addr: &SocketAddr, id: u16, f: F) -> R {
+		f(self.options_templates.get(&(*addr, id)))
+	}
+
+	fn register_template_set(&mut self, set: &NetflowDatagramTemplateSet, addr: &SocketAddr) {
+		self.register_netflow_template(set, addr);
+	}
+
+	fn register_options_template_set(&mut self, set: &NetflowDatagramOptionsTemplateSet, addr: &SocketAddr) {
+		self.register_netflow_options_template(set, addr);
+	}
+
+	fn count_record_count_mismatch(&mut self) {
+		self.record_count_mismatches += 1;
+	}
+
+	fn enterprise_fields(&self) -> bool {
+		self.enterprise_fields
+	}
+}
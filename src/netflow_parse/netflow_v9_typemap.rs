@@ -1,7 +1,8 @@
 //! Mapping for known NetFlow v9 types
 
 /// How a data field should be parsed
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NetflowV9TypeHandlingMode {
 	/// Parse as a 1, 2, 3, 4, or 8-byte number
 	Number,
@@ -16,7 +17,8 @@ pub enum NetflowV9TypeHandlingMode {
 }
 
 /// Which scope an options template field describes
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NetflowV9ScopeType {
 	System = 1,
 	Interface = 2,
@@ -40,6 +42,20 @@ impl TryFrom<u16> for NetflowV9ScopeType {
 	}
 }
 
+/// Get the type information used for the values of options template scope fields of type `type_id`
+pub(crate) fn scope_type_info(type_id: u16) -> NetflowTypeInfo {
+	let (name, description) = match NetflowV9ScopeType::try_from(type_id) {
+		Ok(NetflowV9ScopeType::System) => ("SCOPE_SYSTEM", "Scope: system"),
+		Ok(NetflowV9ScopeType::Interface) => ("SCOPE_INTERFACE", "Scope: interface"),
+		Ok(NetflowV9ScopeType::LineCard) => ("SCOPE_LINE_CARD", "Scope: line card"),
+		Ok(NetflowV9ScopeType::NetFlowCache) => ("SCOPE_NETFLOW_CACHE", "Scope: NetFlow cache"),
+		Ok(NetflowV9ScopeType::Template) => ("SCOPE_TEMPLATE", "Scope: template"),
+		Err(()) => ("SCOPE_UNKNOWN", "Scope: unknown"),
+	};
+
+	(name, description, NetflowV9TypeHandlingMode::Number, type_id)
+}
+
 /// Type map content description. Consists of the field name, field description, handling info, and the type ID
 pub type NetflowTypeInfo = (&'static str, &'static str, NetflowV9TypeHandlingMode, u16);
 
@@ -0,0 +1,285 @@
+//! Reorder buffer for NetFlow datagrams arriving out of order
+//!
+//! UDP does not guarantee ordering, and a NetFlow v9 data flow set arriving before the template flow set it depends on
+//! cannot be parsed. [ReorderBuffer] holds datagrams back per exporter stream until the datagrams before them (by the
+//! header sequence number) have arrived, or until a timeout or depth limit is reached. [ReorderingNetflowParser]
+//! combines such a buffer with a [NetflowParser]
+//!
+//! Streams are identified by the exporter address and the v5 engine type and ID or the v9 source ID. NetFlow v1 has no
+//! sequence number, so v1 datagrams (and anything that cannot be peeked) are released immediately. The number of
+//! tracked streams is limited, so spoofed source addresses cannot grow the buffer without bound
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use nom::IResult;
+use crate::netflow_parse::datagram::{NetflowDatagramData, NetflowPeekResult};
+use crate::netflow_parse::NetflowParser;
+
+/// Datagrams arriving this many sequence units behind the expected one are taken as an exporter restart
+const RESTART_THRESHOLD: u32 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct StreamKey {
+	addr: SocketAddr,
+	version: u16,
+	id: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Pending {
+	seq: u32,
+	increment: u32,
+	data: Vec<u8>,
+	arrived: Instant,
+}
+
+#[derive(Debug, Clone)]
+struct Stream {
+	next_expected: u32,
+	/// Sequence number the keys of `pending` are relative to, so that they keep their order across wraparounds
+	base: u32,
+	pending: BTreeMap<u32, Pending>,
+	last_seen: Instant,
+}
+
+impl Stream {
+	/// Release all pending datagrams up to and including the one at `offset` regardless of any gaps, followed by all
+	/// consecutive ones
+	fn release_through<F: FnMut(&SocketAddr, &[u8])>(&mut self, addr: &SocketAddr, offset: u32, f: &mut F) {
+		while let Some(entry) = self.pending.first_entry() {
+			if *entry.key() > offset {
+				break;
+			}
+			let p = entry.remove();
+			self.next_expected = p.seq.wrapping_add(p.increment);
+			f(addr, &p.data);
+		}
+		self.release_consecutive(addr, f);
+	}
+
+	/// Get the key and arrival time of the pending datagram that arrived first
+	fn oldest(&self) -> Option<(u32, Instant)> {
+		self.pending.iter().map(|(offset, p)| (*offset, p.arrived)).min_by_key(|(_, arrived)| *arrived)
+	}
+
+	/// Release the earliest pending datagram regardless of any gap before it, followed by all consecutive ones
+	fn release_front<F: FnMut(&SocketAddr, &[u8])>(&mut self, addr: &SocketAddr, f: &mut F) {
+		if let Some((_, p)) = self.pending.pop_first() {
+			self.next_expected = p.seq.wrapping_add(p.increment);
+			f(addr, &p.data);
+		}
+		self.release_consecutive(addr, f);
+	}
+
+	/// Release pending datagrams as long as the earliest one is the expected one
+	fn release_consecutive<F: FnMut(&SocketAddr, &[u8])>(&mut self, addr: &SocketAddr, f: &mut F) {
+		while let Some(entry) = self.pending.first_entry() {
+			if entry.get().seq != self.next_expected {
+				break;
+			}
+			let p = entry.remove();
+			self.next_expected = p.seq.wrapping_add(p.increment);
+			f(addr, &p.data);
+		}
+	}
+
+	fn release_all<F: FnMut(&SocketAddr, &[u8])>(&mut self, addr: &SocketAddr, f: &mut F) {
+		while !self.pending.is_empty() {
+			self.release_front(addr, f);
+		}
+	}
+}
+
+/// Buffer releasing raw NetFlow datagrams in sequence number order per exporter stream
+#[derive(Debug, Clone)]
+pub struct ReorderBuffer {
+	streams: HashMap<StreamKey, Stream>,
+	max_delay: Duration,
+	max_pending: usize,
+	max_streams: usize,
+}
+
+impl Default for ReorderBuffer {
+	fn default() -> Self {
+		Self { streams: HashMap::new(), max_delay: Duration::from_millis(100), max_pending: 32, max_streams: 4096 }
+	}
+}
+
+impl ReorderBuffer {
+	/// Create a buffer holding datagrams for at most 100ms, at most 32 datagrams per stream, and tracking at most 4096
+	/// streams
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Set how long a datagram is held back waiting for earlier ones at most
+	pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+		self.max_delay = max_delay;
+		self
+	}
+
+	/// Set how many datagrams are held back per stream at most before giving up on the missing ones
+	pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+		self.max_pending = max_pending.max(1);
+		self
+	}
+
+	/// Set how many streams are tracked at most, releasing and forgetting the least recently seen stream for a new one
+	pub fn with_max_streams(mut self, max_streams: usize) -> Self {
+		self.max_streams = max_streams.max(1);
+		self
+	}
+
+	/// Add a datagram received from `addr` at `now`, calling `f` with every datagram that can be released in order
+	///
+	/// The new datagram is passed to `f` immediately if it is the expected one or cannot be ordered, e.g. because it
+	/// is late, a duplicate, or not a NetFlow v5 or v9 datagram
+	pub fn push<F: FnMut(&SocketAddr, &[u8])>(&mut self, data: Vec<u8>, addr: SocketAddr, now: Instant, mut f: F) {
+		let (key, seq, increment) = match NetflowParser::peek_netflow_basic_info(&data) {
+			Ok((_, NetflowPeekResult::V5(h))) => {
+				let id = (h.engine_type as u32) << 8 | h.engine_id as u32;
+				(StreamKey { addr, version: 5, id }, h.total_flows, h.flow_count as u32)
+			}
+			Ok((_, NetflowPeekResult::V9(h))) => (StreamKey { addr, version: 9, id: h.source_id }, h.package_sequence_num, 1),
+			_ => {
+				f(&addr, &data);
+				return;
+			}
+		};
+
+		let Some(stream) = self.streams.get_mut(&key) else {
+			if self.streams.len() >= self.max_streams {
+				self.evict_least_recent(&mut f);
+			}
+			let next_expected = seq.wrapping_add(increment);
+			self.streams.insert(key, Stream { next_expected, base: next_expected, pending: BTreeMap::new(), last_seen: now });
+			f(&addr, &data);
+			return;
+		};
+		stream.last_seen = now;
+
+		let ahead = seq.wrapping_sub(stream.next_expected);
+		if ahead == 0 {
+			stream.next_expected = seq.wrapping_add(increment);
+			f(&addr, &data);
+			stream.release_consecutive(&addr, &mut f);
+			return;
+		}
+
+		if ahead >= 1 << 31 {
+			if stream.next_expected.wrapping_sub(seq) > RESTART_THRESHOLD {
+				stream.release_all(&addr, &mut f);
+				stream.next_expected = seq.wrapping_add(increment);
+			}
+			f(&addr, &data);
+			return;
+		}
+
+		if stream.pending.is_empty() {
+			stream.base = stream.next_expected;
+		}
+		let offset = seq.wrapping_sub(stream.base);
+		if stream.pending.contains_key(&offset) {
+			f(&addr, &data);
+			return;
+		}
+		stream.pending.insert(offset, Pending { seq, increment, data, arrived: now });
+
+		while stream.pending.len() > self.max_pending {
+			stream.release_front(&addr, &mut f);
+		}
+	}
+
+	/// Release and forget the stream that was seen least recently
+	fn evict_least_recent<F: FnMut(&SocketAddr, &[u8])>(&mut self, f: &mut F) {
+		let Some(key) = self.streams.iter().min_by_key(|(_, s)| s.last_seen).map(|(k, _)| *k) else {
+			return;
+		};
+		if let Some(mut stream) = self.streams.remove(&key) {
+			stream.release_all(&key.addr, f);
+		}
+	}
+
+	/// Release all datagrams that have been held back for longer than the maximum delay at `now`
+	///
+	/// Datagrams before an expired one are released with it, so no datagram is held back longer than the maximum
+	/// delay, even if it arrived before the datagrams preceding it
+	pub fn expire<F: FnMut(&SocketAddr, &[u8])>(&mut self, now: Instant, mut f: F) {
+		for (key, stream) in self.streams.iter_mut() {
+			while let Some((offset, arrived)) = stream.oldest() {
+				if now.saturating_duration_since(arrived) < self.max_delay {
+					break;
+				}
+				stream.release_through(&key.addr, offset, &mut f);
+			}
+		}
+	}
+
+	/// Release all held back datagrams
+	pub fn flush<F: FnMut(&SocketAddr, &[u8])>(&mut self, mut f: F) {
+		for (key, stream) in self.streams.iter_mut() {
+			stream.release_all(&key.addr, &mut f);
+		}
+	}
+
+	/// Get the time at which the next held back datagram expires, if any
+	pub fn next_deadline(&self) -> Option<Instant> {
+		self.streams.values().filter_map(|s| s.oldest()).map(|(_, arrived)| arrived).min().map(|t| t + self.max_delay)
+	}
+
+	/// Get the number of tracked streams
+	pub fn streams(&self) -> usize {
+		self.streams.len()
+	}
+
+	/// Get the number of held back datagrams
+	pub fn pending(&self) -> usize {
+		self.streams.values().map(|s| s.pending.len()).sum()
+	}
+}
+
+/// [NetflowParser] parsing datagrams in sequence number order using a [ReorderBuffer]
+#[derive(Debug, Clone, Default)]
+pub struct ReorderingNetflowParser {
+	parser: NetflowParser,
+	buffer: ReorderBuffer,
+}
+
+impl ReorderingNetflowParser {
+	/// Create a parser using `buffer` for reordering
+	pub fn new(buffer: ReorderBuffer) -> Self {
+		Self { parser: NetflowParser::new(), buffer }
+	}
+
+	/// Get the underlying parser, e.g. to register templates
+	pub fn parser(&mut self) -> &mut NetflowParser {
+		&mut self.parser
+	}
+
+	/// Get the reorder buffer
+	pub fn buffer(&self) -> &ReorderBuffer {
+		&self.buffer
+	}
+
+	/// Add a datagram received from `addr` at `now`, calling `f` with the parse result of every released datagram
+	///
+	/// Expired datagrams of all streams are released first
+	pub fn push<F: FnMut(&SocketAddr, IResult<&[u8], NetflowDatagramData>)>(&mut self, data: Vec<u8>, addr: SocketAddr, now: Instant, mut f: F) {
+		let parser = &mut self.parser;
+		self.buffer.expire(now, |addr, data| f(addr, parser.parse(data, addr)));
+		self.buffer.push(data, addr, now, |addr, data| f(addr, parser.parse(data, addr)));
+	}
+
+	/// Parse all datagrams that have been held back for longer than the maximum delay at `now`
+	pub fn expire<F: FnMut(&SocketAddr, IResult<&[u8], NetflowDatagramData>)>(&mut self, now: Instant, mut f: F) {
+		let parser = &mut self.parser;
+		self.buffer.expire(now, |addr, data| f(addr, parser.parse(data, addr)));
+	}
+
+	/// Parse all held back datagrams
+	pub fn flush<F: FnMut(&SocketAddr, IResult<&[u8], NetflowDatagramData>)>(&mut self, mut f: F) {
+		let parser = &mut self.parser;
+		self.buffer.flush(|addr, data| f(addr, parser.parse(data, addr)));
+	}
+}
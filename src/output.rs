@@ -0,0 +1,289 @@
+//! JSON-lines output of parsed datagrams
+//!
+//! Every written line is a single JSON object containing the receive timestamp (`received_at`, milliseconds since the Unix epoch),
+//! the exporter address (`exporter`), the protocol (`protocol`), and either the whole datagram (`datagram`) or a single
+//! flow record/sample (`record`), depending on the [OutputMode]
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use crate::netflow_parse::datagram::NetflowDatagramData;
+use crate::netflow_parse::datagram_v9::NetflowDatagramV9FlowSet;
+use crate::netflow_parse::datagram_v9_data::{NetflowDatagramRecordsType, NetflowDatagramSourceTemplateType, NetflowV9DataField, NetflowV9DataValue};
+use crate::netflow_parse::datagram_v9_template::REVERSE_PEN;
+use crate::sflow_parse::datagram::Datagram;
+
+/// Granularity of the emitted JSON objects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputMode {
+	/// One object per flow record (NetFlow) or sample (sFlow)
+	#[default]
+	Record,
+	/// One object per received datagram
+	Datagram,
+}
+
+/// Format of the collector output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+	/// JSON lines
+	#[default]
+	Json,
+	/// Rust `Debug` representation of every datagram, one per line
+	Debug,
+}
+
+#[derive(Serialize)]
+struct DatagramLine<'a, T: Serialize> {
+	received_at: u64,
+	exporter: &'a SocketAddr,
+	protocol: &'static str,
+	datagram: &'a T,
+}
+
+#[derive(Serialize)]
+struct RecordLine<'a, H: Serialize, T: Serialize> {
+	received_at: u64,
+	exporter: &'a SocketAddr,
+	protocol: &'static str,
+	header: H,
+	record: T,
+}
+
+#[derive(Serialize)]
+struct NetflowHeader {
+	sys_uptime_ms: u32,
+	unix_sec: u32,
+	sequence: u32,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	source_id: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct SFlowHeader<'a> {
+	agent_addr: &'a std::net::IpAddr,
+	sub_agent_id: u32,
+	seq_num: u32,
+	uptime: u32,
+}
+
+#[derive(Serialize)]
+struct V9Record<'a> {
+	template_id: u16,
+	options: bool,
+	fields: V9Fields<'a>,
+}
+
+/// NetFlow v9 record fields as a flat `name: value` map
+///
+/// Fields of unknown type are named `UNKNOWN_<type ID>`. RFC 5103 reverse fields get a `REVERSE_` prefix, other
+/// enterprise-specific fields are named `UNKNOWN_<enterprise number>_<type ID>`. A name occurring more than once in a record gets a suffix
+/// counting its occurrences from the second one on, e.g. `IN_BYTES` and `IN_BYTES_2`, so no key is repeated
+struct V9Fields<'a>(&'a [NetflowV9DataField]);
+
+impl Serialize for V9Fields<'_> {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		let mut map = serializer.serialize_map(Some(self.0.len()))?;
+		let mut seen: HashMap<Cow<str>, usize> = HashMap::with_capacity(self.0.len());
+		for f in self.0 {
+			let name = match (f.enterprise, f.name) {
+				(None, "UNKNOWN") => Cow::Owned(format!("UNKNOWN_{}", f.type_id)),
+				(None, name) => Cow::Borrowed(name),
+				(Some(REVERSE_PEN), name) if name != "UNKNOWN" => Cow::Owned(format!("REVERSE_{}", name)),
+				(Some(enterprise), _) => Cow::Owned(format!("UNKNOWN_{}_{}", enterprise, f.type_id)),
+			};
+			let occurrence = seen.entry(name.clone()).or_insert(0);
+			*occurrence += 1;
+
+			let value = V9Value(&f.value);
+			if *occurrence == 1 {
+				map.serialize_entry(&name, &value)?;
+			} else {
+				map.serialize_entry(&format!("{}_{}", name, occurrence), &value)?;
+			}
+		}
+
+		map.end()
+	}
+}
+
+/// NetFlow v9 value without the enum tag
+struct V9Value<'a>(&'a NetflowV9DataValue);
+
+impl Serialize for V9Value<'_> {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		match self.0 {
+			NetflowV9DataValue::Number(n) => serializer.serialize_u64(*n),
+			NetflowV9DataValue::IPv4(ip) => ip.serialize(serializer),
+			NetflowV9DataValue::IPv6(ip) => ip.serialize(serializer),
+			NetflowV9DataValue::MAC(s) | NetflowV9DataValue::String(s) => serializer.serialize_str(s),
+			NetflowV9DataValue::Unknown(b) => serializer.serialize_str(&crate::serde_util::to_hex(b)),
+		}
+	}
+}
+
+/// Milliseconds since the Unix epoch for `time`, saturating to 0 for times before the epoch
+pub fn unix_millis(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Protocol name used in the `protocol` field for a NetFlow datagram
+pub fn netflow_protocol_name(dg: &NetflowDatagramData) -> &'static str {
+	match dg {
+		NetflowDatagramData::DatagramV1(_) => "netflow_v1",
+		NetflowDatagramData::DatagramV5(_) => "netflow_v5",
+		NetflowDatagramData::DatagramV9(_) => "netflow_v9",
+		NetflowDatagramData::IPFIX => "ipfix",
+	}
+}
+
+/// Writer emitting parsed datagrams as JSON lines into any [Write] implementation
+pub struct JsonLinesWriter<W: Write> {
+	out: W,
+	mode: OutputMode,
+}
+
+impl<W: Write> JsonLinesWriter<W> {
+	/// Create a new writer with the given output granularity
+	pub fn new(out: W, mode: OutputMode) -> Self {
+		Self { out, mode }
+	}
+
+	/// Get the output granularity
+	pub fn mode(&self) -> OutputMode {
+		self.mode
+	}
+
+	/// Flush the underlying writer
+	pub fn flush(&mut self) -> std::io::Result<()> {
+		self.out.flush()
+	}
+
+	/// Consume the writer, returning the underlying output
+	pub fn into_inner(self) -> W {
+		self.out
+	}
+
+	fn write_line<T: Serialize>(&mut self, line: &T) -> std::io::Result<()> {
+		serde_json::to_writer(&mut self.out, line)?;
+		self.out.write_all(b"\n")
+	}
+
+	/// Write a parsed NetFlow datagram received from `exporter` at `received`
+	///
+	/// In record mode, template sets produce no output, while each data and options data record produces one line
+	pub fn write_netflow(&mut self, exporter: &SocketAddr, received: SystemTime, dg: &NetflowDatagramData) -> std::io::Result<()> {
+		let received_at = unix_millis(received);
+		let protocol = netflow_protocol_name(dg);
+
+		if self.mode == OutputMode::Datagram {
+			return self.write_line(&DatagramLine { received_at, exporter, protocol, datagram: dg });
+		}
+
+		match dg {
+			NetflowDatagramData::DatagramV1(d) => {
+				for r in d {
+					let header = NetflowHeader { sys_uptime_ms: d.sys_uptime_ms, unix_sec: d.unix_sec, sequence: 0, source_id: None };
+					self.write_line(&RecordLine { received_at, exporter, protocol, header, record: r })?;
+				}
+			}
+			NetflowDatagramData::DatagramV5(d) => {
+				for r in d {
+					let header = NetflowHeader { sys_uptime_ms: d.sys_uptime_ms, unix_sec: d.unix_sec, sequence: d.flow_seqnum, source_id: None };
+					self.write_line(&RecordLine { received_at, exporter, protocol, header, record: r })?;
+				}
+			}
+			NetflowDatagramData::DatagramV9(d) => {
+				for set in &d.flow_records {
+					let NetflowDatagramV9FlowSet::Data(data) = set else { continue };
+
+					let template_id = match data.source_template {
+						NetflowDatagramSourceTemplateType::Regular((_, id)) | NetflowDatagramSourceTemplateType::Option((_, id)) => id,
+					};
+					let (options, records) = match &data.records {
+						NetflowDatagramRecordsType::Regular(r) => (false, r),
+						NetflowDatagramRecordsType::Option(r) => (true, r),
+					};
+
+					for r in records {
+						let header = NetflowHeader { sys_uptime_ms: d.sys_uptime_ms, unix_sec: d.unix_sec, sequence: d.package_sequence, source_id: Some(d.source_id) };
+						let record = V9Record { template_id, options, fields: V9Fields(r) };
+						self.write_line(&RecordLine { received_at, exporter, protocol, header, record })?;
+					}
+				}
+			}
+			NetflowDatagramData::IPFIX => {}
+		}
+
+		Ok(())
+	}
+
+	/// Write a parsed sFlow datagram received from `exporter` at `received`
+	///
+	/// In record mode, each flow and counter sample produces one line
+	pub fn write_sflow(&mut self, exporter: &SocketAddr, received: SystemTime, dg: &Datagram) -> std::io::Result<()> {
+		let received_at = unix_millis(received);
+		let protocol = "sflow_v5";
+
+		if self.mode == OutputMode::Datagram {
+			return self.write_line(&DatagramLine { received_at, exporter, protocol, datagram: dg });
+		}
+
+		for sample in &dg.sample_record {
+			let header = SFlowHeader { agent_addr: &dg.agent_addr, sub_agent_id: dg.sub_agent_id, seq_num: dg.seq_num, uptime: dg.uptime };
+			self.write_line(&RecordLine { received_at, exporter, protocol, header, record: sample })?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Writer for any of the supported [OutputFormat]s
+pub enum FlowWriter<W: Write> {
+	Json(JsonLinesWriter<W>),
+	Debug(W),
+}
+
+impl<W: Write> FlowWriter<W> {
+	/// Create a new writer with the given format and output granularity
+	///
+	/// The granularity only applies to JSON output
+	pub fn new(out: W, format: OutputFormat, mode: OutputMode) -> Self {
+		match format {
+			OutputFormat::Json => Self::Json(JsonLinesWriter::new(out, mode)),
+			OutputFormat::Debug => Self::Debug(out),
+		}
+	}
+
+	/// Flush the underlying writer
+	pub fn flush(&mut self) -> std::io::Result<()> {
+		match self {
+			Self::Json(w) => w.flush(),
+			Self::Debug(w) => w.flush(),
+		}
+	}
+
+	/// Write a parsed NetFlow datagram received from `exporter` at `received`
+	pub fn write_netflow(&mut self, exporter: &SocketAddr, received: SystemTime, dg: &NetflowDatagramData) -> std::io::Result<()> {
+		match self {
+			Self::Json(w) => w.write_netflow(exporter, received, dg),
+			Self::Debug(w) => writeln!(w, "[{}] {}: {:?}", unix_millis(received), exporter, dg),
+		}
+	}
+
+	/// Write a parsed sFlow datagram received from `exporter` at `received`
+	pub fn write_sflow(&mut self, exporter: &SocketAddr, received: SystemTime, dg: &Datagram) -> std::io::Result<()> {
+		match self {
+			Self::Json(w) => w.write_sflow(exporter, received, dg),
+			Self::Debug(w) => writeln!(w, "[{}] {}: {:?}", unix_millis(received), exporter, dg),
+		}
+	}
+}
@@ -0,0 +1,184 @@
+//! Decoding of captured packet headers (Ethernet, IPv4, IPv6, TCP, UDP)
+//!
+//! Used for the raw packet headers contained in sFlow flow samples. Captured headers are usually truncated, so only the
+//! headers themselves need to be complete; the payload is whatever follows them in the capture
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use nom::bytes::complete::take;
+use nom::combinator::fail;
+use nom::IResult;
+use nom::number::complete::{be_u128, be_u16, be_u32, be_u8};
+use nom::sequence::tuple;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_IPV6: u16 = 0x86DD;
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+pub const ETHERTYPE_QINQ: u16 = 0x88A8;
+
+pub const IP_PROTOCOL_TCP: u8 = 6;
+pub const IP_PROTOCOL_UDP: u8 = 17;
+
+/// Header fields of a decoded IP packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketHeaders<'a> {
+	/// Innermost VLAN ID, if the packet was VLAN tagged
+	pub vlan: Option<u16>,
+	pub src_addr: IpAddr,
+	pub dst_addr: IpAddr,
+	/// IP protocol number, or IPv6 next header after skipping extension headers
+	pub protocol: u8,
+	/// IPv4 type of service or IPv6 traffic class
+	pub tos: u8,
+	/// Total length of the IP packet as stated in its header
+	pub ip_length: u16,
+	/// Whether this is a non-first fragment, which carries no transport header
+	pub fragment: bool,
+	pub src_port: Option<u16>,
+	pub dst_port: Option<u16>,
+	pub tcp_flags: Option<u8>,
+	/// Data following the innermost decoded header, limited to the length stated in the IP or UDP header
+	pub payload: &'a [u8],
+}
+
+/// Decode an Ethernet frame carrying IPv4 or IPv6, optionally VLAN tagged
+pub fn parse_ethernet(input: &[u8]) -> IResult<&[u8], PacketHeaders<'_>> {
+	let (mut res, (_dst_mac, _src_mac, mut ethertype)) = tuple((take(6usize), take(6usize), be_u16))(input)?;
+
+	let mut vlan = None;
+	while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+		let (r, (tci, inner)) = tuple((be_u16, be_u16))(res)?;
+		vlan = Some(tci & 0x0FFF);
+		ethertype = inner;
+		res = r;
+	}
+
+	let (res, mut headers) = match ethertype {
+		ETHERTYPE_IPV4 => parse_ipv4(res)?,
+		ETHERTYPE_IPV6 => parse_ipv6(res)?,
+		_ => return fail(res),
+	};
+	headers.vlan = vlan;
+
+	Ok((res, headers))
+}
+
+/// Decode a raw IPv4 or IPv6 packet, choosing the version from the first nibble
+pub fn parse_ip(input: &[u8]) -> IResult<&[u8], PacketHeaders<'_>> {
+	match input.first().map(|b| b >> 4) {
+		Some(4) => parse_ipv4(input),
+		Some(6) => parse_ipv6(input),
+		_ => fail(input),
+	}
+}
+
+/// Decode a raw IPv4 packet
+pub fn parse_ipv4(input: &[u8]) -> IResult<&[u8], PacketHeaders<'_>> {
+	let (res, (version_ihl, tos, ip_length, _id, flags_offset, _ttl, protocol, _checksum, src, dst))
+		= tuple((be_u8, be_u8, be_u16, be_u16, be_u16, be_u8, be_u8, be_u16, be_u32, be_u32))(input)?;
+
+	let header_length = ((version_ihl & 0x0F) as usize) * 4;
+	if version_ihl >> 4 != 4 || header_length < 20 {
+		return fail(input);
+	}
+	let (res, _options) = take(header_length - 20)(res)?;
+
+	let fragment = flags_offset & 0x1FFF != 0;
+	// Segmentation offload can leave the length at 0 in locally captured packets
+	let payload_length = if ip_length == 0 { res.len() } else { (ip_length as usize).saturating_sub(header_length) };
+
+	parse_transport(res, PacketHeaders {
+		vlan: None,
+		src_addr: IpAddr::V4(Ipv4Addr::from(src)),
+		dst_addr: IpAddr::V4(Ipv4Addr::from(dst)),
+		protocol,
+		tos,
+		ip_length,
+		fragment,
+		src_port: None,
+		dst_port: None,
+		tcp_flags: None,
+		payload: &res[..payload_length.min(res.len())],
+	})
+}
+
+/// Decode a raw IPv6 packet, skipping the common extension headers
+pub fn parse_ipv6(input: &[u8]) -> IResult<&[u8], PacketHeaders<'_>> {
+	let (mut res, (version_class_label, payload_length, mut next_header, _hop_limit, src, dst))
+		= tuple((be_u32, be_u16, be_u8, be_u8, be_u128, be_u128))(input)?;
+
+	if version_class_label >> 28 != 6 {
+		return fail(input);
+	}
+
+	let mut fragment = false;
+	loop {
+		match next_header {
+			// Hop-by-hop, routing, and destination options headers
+			0 | 43 | 60 => {
+				let (r, (nh, len)) = tuple((be_u8, be_u8))(res)?;
+				let (r, _) = take(len as usize * 8 + 6)(r)?;
+				next_header = nh;
+				res = r;
+			}
+			// Fragment header
+			44 => {
+				let (r, (nh, _reserved, offset, _id)) = tuple((be_u8, be_u8, be_u16, be_u32))(res)?;
+				fragment = offset & 0xFFF8 != 0;
+				next_header = nh;
+				res = r;
+			}
+			_ => break,
+		}
+	}
+
+	let consumed = input.len() - res.len() - 40;
+	let payload_length = (payload_length as usize).saturating_sub(consumed);
+
+	parse_transport(res, PacketHeaders {
+		vlan: None,
+		src_addr: IpAddr::V6(Ipv6Addr::from(src)),
+		dst_addr: IpAddr::V6(Ipv6Addr::from(dst)),
+		protocol: next_header,
+		tos: ((version_class_label >> 20) & 0xFF) as u8,
+		ip_length: payload_length.saturating_add(40).min(u16::MAX as usize) as u16,
+		fragment,
+		src_port: None,
+		dst_port: None,
+		tcp_flags: None,
+		payload: &res[..payload_length.min(res.len())],
+	})
+}
+
+/// Decode the TCP or UDP header at the start of `headers.payload`, if there is one and it was captured
+fn parse_transport<'a>(input: &'a [u8], mut headers: PacketHeaders<'a>) -> IResult<&'a [u8], PacketHeaders<'a>> {
+	if headers.fragment {
+		return Ok((input, headers));
+	}
+
+	if headers.protocol != IP_PROTOCOL_UDP && headers.protocol != IP_PROTOCOL_TCP {
+		return Ok((input, headers));
+	}
+
+	let ports: IResult<&[u8], (u16, u16)> = tuple((be_u16, be_u16))(headers.payload);
+	let Ok((res, (src_port, dst_port))) = ports else {
+		return Ok((input, headers));
+	};
+	headers.src_port = Some(src_port);
+	headers.dst_port = Some(dst_port);
+
+	if headers.protocol == IP_PROTOCOL_UDP {
+		let udp: IResult<&[u8], (u16, u16)> = tuple((be_u16, be_u16))(res);
+		if let Ok((res, (length, _checksum))) = udp {
+			headers.payload = &res[..(length as usize).saturating_sub(8).min(res.len())];
+		}
+	} else {
+		let tcp: IResult<&[u8], (u32, u32, u8, u8)> = tuple((be_u32, be_u32, be_u8, be_u8))(res);
+		if let Ok((_, (_seq, _ack, offset, flags))) = tcp {
+			headers.tcp_flags = Some(flags);
+			let header_length = ((offset >> 4) as usize * 4).max(20);
+			headers.payload = headers.payload.get(header_length..).unwrap_or_default();
+		}
+	}
+
+	Ok((input, headers))
+}
@@ -0,0 +1,362 @@
+//! Reading flow datagrams from pcap and pcapng capture files
+//!
+//! [PcapReader] reads the packets of a capture file, as written by tcpdump or Wireshark, detecting the format from its
+//! magic number. [CapturedPacket::udp] strips the link layer, IP, and UDP headers, giving the flow datagram and the
+//! address it was sent from. Ethernet (optionally VLAN tagged), raw IP, Linux cooked (SLL and SLL2), and BSD loopback
+//! captures are supported. IP fragments are not reassembled, so datagrams fragmented on the way to the capture point
+//! are skipped
+
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use nom::IResult;
+use nom::bytes::complete::take;
+use nom::number::complete::{u16 as endian_u16, u32 as endian_u32};
+use nom::number::Endianness;
+use nom::sequence::tuple;
+use crate::packet::{parse_ethernet, parse_ip, parse_ipv4, parse_ipv6, PacketHeaders, IP_PROTOCOL_UDP, ETHERTYPE_IPV4, ETHERTYPE_IPV6};
+
+pub const LINKTYPE_NULL: u16 = 0;
+pub const LINKTYPE_ETHERNET: u16 = 1;
+pub const LINKTYPE_RAW: u16 = 101;
+pub const LINKTYPE_LINUX_SLL: u16 = 113;
+pub const LINKTYPE_IPV4: u16 = 228;
+pub const LINKTYPE_IPV6: u16 = 229;
+pub const LINKTYPE_LINUX_SLL2: u16 = 276;
+
+/// Packets larger than this are considered a sign of a corrupt file
+const MAX_PACKET_LENGTH: u32 = 16 * 1024 * 1024;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+/// A packet read from a capture file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedPacket {
+	/// Time at which the packet was captured
+	pub timestamp: SystemTime,
+	/// Link layer type of the capturing interface (`LINKTYPE_*`)
+	pub link_type: u16,
+	/// Length of the packet on the wire, `data` may have been cut short by the snapshot length
+	pub original_length: u32,
+	pub data: Vec<u8>,
+}
+
+/// UDP datagram extracted from a captured packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpPayload<'a> {
+	pub src: SocketAddr,
+	pub dst: SocketAddr,
+	/// Datagram contents, without any headers
+	pub payload: &'a [u8],
+}
+
+impl CapturedPacket {
+	/// Decode the headers of this packet, returning the UDP datagram it carries
+	///
+	/// Returns `None` for other protocols, IP fragments, unsupported link types, and packets cut short by the snapshot
+	/// length
+	pub fn udp(&self) -> Option<UdpPayload<'_>> {
+		if self.data.len() < self.original_length as usize {
+			return None;
+		}
+
+		decode_udp(self.link_type, &self.data)
+	}
+}
+
+/// Decode a packet with link layer type `link_type`, returning the UDP datagram it carries
+///
+/// Returns `None` for other protocols, IP fragments, and unsupported link types
+pub fn decode_udp(link_type: u16, data: &[u8]) -> Option<UdpPayload<'_>> {
+	let (_, headers) = decode_link(link_type, data).ok()?;
+	if headers.protocol != IP_PROTOCOL_UDP || headers.fragment {
+		return None;
+	}
+
+	let src = SocketAddr::new(headers.src_addr, headers.src_port?);
+	let dst = SocketAddr::new(headers.dst_addr, headers.dst_port?);
+
+	Some(UdpPayload { src, dst, payload: headers.payload })
+}
+
+fn decode_link(link_type: u16, data: &[u8]) -> IResult<&[u8], PacketHeaders<'_>> {
+	match link_type {
+		LINKTYPE_ETHERNET => parse_ethernet(data),
+		LINKTYPE_RAW => parse_ip(data),
+		LINKTYPE_IPV4 => parse_ipv4(data),
+		LINKTYPE_IPV6 => parse_ipv6(data),
+		// Address family in host byte order, then the IP packet
+		LINKTYPE_NULL => parse_ip(take(4usize)(data)?.0),
+		LINKTYPE_LINUX_SLL => {
+			let (res, (_, protocol)) = tuple((take(14usize), endian_u16(Endianness::Big)))(data)?;
+			parse_ethertype(res, protocol)
+		}
+		LINKTYPE_LINUX_SLL2 => {
+			let (res, (protocol, _)) = tuple((endian_u16(Endianness::Big), take(18usize)))(data)?;
+			parse_ethertype(res, protocol)
+		}
+		_ => nom::combinator::fail(data),
+	}
+}
+
+fn parse_ethertype(input: &[u8], ethertype: u16) -> IResult<&[u8], PacketHeaders<'_>> {
+	match ethertype {
+		ETHERTYPE_IPV4 => parse_ipv4(input),
+		ETHERTYPE_IPV6 => parse_ipv6(input),
+		_ => nom::combinator::fail(input),
+	}
+}
+
+fn invalid(message: &str) -> io::Error {
+	io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Fill `buf` from `reader`, returning false if the reader was already at its end
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+	let mut filled = 0;
+	while filled < buf.len() {
+		match reader.read(&mut buf[filled..]) {
+			Ok(0) if filled == 0 => return Ok(false),
+			Ok(0) => return Err(invalid("Capture file ends in the middle of a packet")),
+			Ok(n) => filled += n,
+			Err(e) if e.kind() == ErrorKind::Interrupted => {}
+			Err(e) => return Err(e),
+		}
+	}
+
+	Ok(true)
+}
+
+fn parse_u16(input: &[u8], endian: Endianness) -> io::Result<(&[u8], u16)> {
+	endian_u16::<_, nom::error::Error<&[u8]>>(endian)(input).map_err(|_| invalid("Truncated capture file header"))
+}
+
+fn parse_u32(input: &[u8], endian: Endianness) -> io::Result<(&[u8], u32)> {
+	endian_u32::<_, nom::error::Error<&[u8]>>(endian)(input).map_err(|_| invalid("Truncated capture file header"))
+}
+
+/// Timestamp of `ticks` units of `1 / ticks_per_sec` seconds since the Unix epoch
+///
+/// Fails if the timestamp cannot be represented, which a coarse pcapng resolution with a large tick count can cause
+fn timestamp(ticks: u64, ticks_per_sec: u64) -> io::Result<SystemTime> {
+	let nanos = (ticks % ticks_per_sec) as u128 * 1_000_000_000 / ticks_per_sec as u128;
+
+	UNIX_EPOCH.checked_add(Duration::new(ticks / ticks_per_sec, nanos as u32)).ok_or_else(|| invalid("Invalid packet timestamp"))
+}
+
+/// Interface described by a pcapng interface description block
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+	link_type: u16,
+	ticks_per_sec: u64,
+}
+
+#[derive(Debug, Clone)]
+enum Format {
+	Pcap { endian: Endianness, ticks_per_sec: u64, link_type: u16 },
+	PcapNg { endian: Endianness, interfaces: Vec<Interface> },
+}
+
+/// Reader for pcap and pcapng capture files
+#[derive(Debug)]
+pub struct PcapReader<R: Read> {
+	reader: R,
+	format: Format,
+}
+
+impl PcapReader<BufReader<File>> {
+	/// Open the capture file at `path`
+	pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+		Self::new(BufReader::new(File::open(path)?))
+	}
+}
+
+impl<R: Read> PcapReader<R> {
+	/// Create a reader for the capture file read from `reader`, reading its file or section header
+	///
+	/// # Errors
+	///
+	/// Fails if the file is neither a pcap nor a pcapng file, or if its header cannot be read
+	pub fn new(mut reader: R) -> io::Result<Self> {
+		let mut magic = [0u8; 4];
+		reader.read_exact(&mut magic)?;
+
+		let format = match magic {
+			[0xA1, 0xB2, 0xC3, 0xD4] => Self::pcap_format(&mut reader, Endianness::Big, 1_000_000)?,
+			[0xD4, 0xC3, 0xB2, 0xA1] => Self::pcap_format(&mut reader, Endianness::Little, 1_000_000)?,
+			[0xA1, 0xB2, 0x3C, 0x4D] => Self::pcap_format(&mut reader, Endianness::Big, 1_000_000_000)?,
+			[0x4D, 0x3C, 0xB2, 0xA1] => Self::pcap_format(&mut reader, Endianness::Little, 1_000_000_000)?,
+			[0x0A, 0x0D, 0x0D, 0x0A] => {
+				let endian = read_section_header(&mut reader)?;
+				Format::PcapNg { endian, interfaces: vec![] }
+			}
+			_ => return Err(invalid("Not a pcap or pcapng file")),
+		};
+
+		Ok(Self { reader, format })
+	}
+
+	fn pcap_format(reader: &mut R, endian: Endianness, ticks_per_sec: u64) -> io::Result<Format> {
+		// Version, time zone, timestamp accuracy, snapshot length, link type
+		let mut header = [0u8; 20];
+		reader.read_exact(&mut header)?;
+		let (_, network) = parse_u32(&header[16..], endian)?;
+
+		Ok(Format::Pcap { endian, ticks_per_sec, link_type: network as u16 })
+	}
+
+	/// Read the next packet, returning `None` at the end of the file
+	pub fn next_packet(&mut self) -> io::Result<Option<CapturedPacket>> {
+		match &mut self.format {
+			Format::Pcap { endian, ticks_per_sec, link_type } => {
+				let mut header = [0u8; 16];
+				if !read_exact_or_eof(&mut self.reader, &mut header)? {
+					return Ok(None);
+				}
+
+				let (res, ts_sec) = parse_u32(&header, *endian)?;
+				let (res, ts_frac) = parse_u32(res, *endian)?;
+				let (res, captured_length) = parse_u32(res, *endian)?;
+				let (_, original_length) = parse_u32(res, *endian)?;
+				if captured_length > MAX_PACKET_LENGTH {
+					return Err(invalid("Invalid pcap packet length"));
+				}
+
+				let mut data = vec![0u8; captured_length as usize];
+				self.reader.read_exact(&mut data)?;
+
+				let ticks = ts_sec as u64 * *ticks_per_sec + ts_frac as u64;
+				Ok(Some(CapturedPacket { timestamp: timestamp(ticks, *ticks_per_sec)?, link_type: *link_type, original_length, data }))
+			}
+			Format::PcapNg { .. } => self.next_pcapng_packet(),
+		}
+	}
+
+	fn next_pcapng_packet(&mut self) -> io::Result<Option<CapturedPacket>> {
+		loop {
+			let mut header = [0u8; 8];
+			if !read_exact_or_eof(&mut self.reader, &mut header)? {
+				return Ok(None);
+			}
+
+			let Format::PcapNg { endian, interfaces } = &mut self.format else { unreachable!() };
+			let (_, block_type) = parse_u32(&header, *endian)?;
+			if block_type == PCAPNG_SECTION_HEADER {
+				// A new section may use a different byte order and has its own interfaces
+				let mut rest = [0u8; 4];
+				self.reader.read_exact(&mut rest)?;
+				let mut chained = header[4..].chain(&rest[..]).chain(&mut self.reader);
+				let endian = read_section_header(&mut chained)?;
+				self.format = Format::PcapNg { endian, interfaces: vec![] };
+				continue;
+			}
+
+			let (_, length) = parse_u32(&header[4..], *endian)?;
+			if length < 12 || length % 4 != 0 || length > MAX_PACKET_LENGTH {
+				return Err(invalid("Invalid pcapng block length"));
+			}
+			let mut body = vec![0u8; length as usize - 8];
+			self.reader.read_exact(&mut body)?;
+			// Without the trailing copy of the block length
+			let body = &body[..body.len() - 4];
+
+			match block_type {
+				// Interface description
+				1 => interfaces.push(parse_interface(body, *endian)?),
+				// Enhanced packet
+				6 => {
+					let (res, interface) = parse_u32(body, *endian)?;
+					let (res, ts_high) = parse_u32(res, *endian)?;
+					let (res, ts_low) = parse_u32(res, *endian)?;
+					let (res, captured_length) = parse_u32(res, *endian)?;
+					let (res, original_length) = parse_u32(res, *endian)?;
+					let data = res.get(..captured_length as usize).ok_or_else(|| invalid("Invalid pcapng packet length"))?;
+					let iface = interfaces.get(interface as usize).ok_or_else(|| invalid("Packet of undescribed pcapng interface"))?;
+
+					let ticks = (ts_high as u64) << 32 | ts_low as u64;
+					return Ok(Some(CapturedPacket {
+						timestamp: timestamp(ticks, iface.ticks_per_sec)?,
+						link_type: iface.link_type,
+						original_length,
+						data: data.to_vec(),
+					}));
+				}
+				// Simple packet, without a timestamp
+				3 => {
+					let (res, original_length) = parse_u32(body, *endian)?;
+					let iface = interfaces.first().ok_or_else(|| invalid("Packet of undescribed pcapng interface"))?;
+					let data = &res[..res.len().min(original_length as usize)];
+
+					return Ok(Some(CapturedPacket { timestamp: UNIX_EPOCH, link_type: iface.link_type, original_length, data: data.to_vec() }));
+				}
+				// Name resolution, statistics, and other blocks are skipped
+				_ => {}
+			}
+		}
+	}
+}
+
+/// Read the rest of a pcapng section header block after its block type, returning the section's byte order
+fn read_section_header<R: Read>(reader: &mut R) -> io::Result<Endianness> {
+	let mut header = [0u8; 8];
+	reader.read_exact(&mut header)?;
+
+	let endian = match parse_u32(&header[4..], Endianness::Big)?.1 {
+		PCAPNG_BYTE_ORDER_MAGIC => Endianness::Big,
+		m if m.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => Endianness::Little,
+		_ => return Err(invalid("Invalid pcapng byte order magic")),
+	};
+
+	let (_, length) = parse_u32(&header, endian)?;
+	if length < 28 || length % 4 != 0 || length > MAX_PACKET_LENGTH {
+		return Err(invalid("Invalid pcapng section header length"));
+	}
+	// Version, section length, options, and the trailing block length are not needed
+	io::copy(&mut (&mut *reader).take(length as u64 - 12), &mut io::sink())?;
+
+	Ok(endian)
+}
+
+/// Parse the body of a pcapng interface description block
+fn parse_interface(body: &[u8], endian: Endianness) -> io::Result<Interface> {
+	let (res, link_type) = parse_u16(body, endian)?;
+	// Reserved, snapshot length
+	let mut res = res.get(6..).ok_or_else(|| invalid("Truncated pcapng interface description"))?;
+
+	let mut ticks_per_sec = 1_000_000;
+	while res.len() >= 4 {
+		let (r, code) = parse_u16(res, endian)?;
+		let (r, length) = parse_u16(r, endian)?;
+		let padded = (length as usize).next_multiple_of(4);
+		let value = r.get(..length as usize).ok_or_else(|| invalid("Truncated pcapng option"))?;
+		res = r.get(padded..).unwrap_or_default();
+
+		match code {
+			// End of options
+			0 => break,
+			// if_tsresol: negative power of 10, or of 2 with the high bit set
+			9 if length == 1 => {
+				let exponent = (value[0] & 0x7F) as u32;
+				ticks_per_sec = if value[0] & 0x80 == 0 {
+					10u64.checked_pow(exponent)
+				} else {
+					1u64.checked_shl(exponent)
+				}.ok_or_else(|| invalid("Unsupported pcapng timestamp resolution"))?;
+			}
+			_ => {}
+		}
+	}
+
+	Ok(Interface { link_type, ticks_per_sec })
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+	type Item = io::Result<CapturedPacket>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.next_packet().transpose()
+	}
+}
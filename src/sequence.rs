@@ -0,0 +1,215 @@
+//! Sequence number tracking and loss detection
+//!
+//! Every exporter numbers its datagrams (or records), and the [SequenceTracker] follows these numbers per sequence
+//! space ([SequenceKey]) to report gaps, duplicates, reordered datagrams, and exporter restarts:
+//! - NetFlow v5 counts flows, per exporter, engine type, and engine ID
+//! - NetFlow v9 counts export packets, per exporter and source ID
+//! - sFlow counts datagrams per agent and sub-agent, and flow and counter samples separately per source ID
+//!
+//! Missing, duplicate, and reordered counts are in units of the respective sequence counter
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use crate::netflow_parse::datagram::NetflowDatagramData;
+use crate::sflow_parse::datagram::Datagram;
+use crate::sflow_parse::sample::SFlowSample;
+
+/// Identifies a single sequence number space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SequenceKey {
+	NetflowV5 { exporter: IpAddr, engine_type: u8, engine_id: u8 },
+	NetflowV9 { exporter: IpAddr, source_id: u32 },
+	SFlowDatagram { agent: IpAddr, sub_agent_id: u32 },
+	SFlowFlowSample { agent: IpAddr, sub_agent_id: u32, source_id: u32 },
+	SFlowCounterSample { agent: IpAddr, sub_agent_id: u32, source_id: u32 },
+}
+
+/// Notable event detected while tracking sequence numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceEvent {
+	/// Sequence numbers between `expected` and `received` were skipped
+	Gap { key: SequenceKey, expected: u32, received: u32, missing: u32 },
+	/// A sequence number was seen again
+	Duplicate { key: SequenceKey, seq: u32 },
+	/// A sequence number previously reported missing arrived late, covering `units` of the missing sequence units
+	Reordered { key: SequenceKey, seq: u32, expected: u32, units: u32 },
+	/// The exporter restarted: the sequence number is neither a duplicate nor within the reorder window, and the
+	/// exporter's uptime went backwards or the sequence number jumped far back
+	Restart { key: SequenceKey, seq: u32 },
+}
+
+/// Counters for a single sequence number space
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SequenceStats {
+	/// Observed datagrams or samples
+	pub received: u64,
+	/// Sequence units skipped and not (yet) received late
+	pub missing: u64,
+	/// Duplicate observations
+	pub duplicates: u64,
+	/// Late observations of previously skipped units
+	pub reordered: u64,
+	/// Detected exporter restarts
+	pub restarts: u64,
+	/// Next expected sequence number
+	pub next_expected: u32,
+	/// Last observed exporter uptime in milliseconds, if known
+	pub last_uptime: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct SequenceState {
+	stats: SequenceStats,
+	recent: VecDeque<u32>,
+}
+
+/// Tracker following sequence numbers of any number of exporters
+#[derive(Debug, Clone)]
+pub struct SequenceTracker {
+	streams: HashMap<SequenceKey, SequenceState>,
+	reorder_window: u32,
+	history: usize,
+}
+
+impl Default for SequenceTracker {
+	fn default() -> Self {
+		Self { streams: HashMap::new(), reorder_window: 1 << 16, history: 64 }
+	}
+}
+
+/// Exporter uptimes (in milliseconds) above this are considered close enough to wrapping around that a smaller uptime
+/// is not a restart
+pub(crate) const UPTIME_WRAP_THRESHOLD: u32 = u32::MAX - 24 * 3600 * 1000;
+
+impl SequenceTracker {
+	/// Create a tracker with the default reorder window of 65536 sequence units and duplicate history of 64 observations
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Set how far behind the expected number a sequence number can be and still count as reordered rather than a restart
+	pub fn with_reorder_window(mut self, window: u32) -> Self {
+		self.reorder_window = window;
+		self
+	}
+
+	/// Set how many recent sequence numbers per space are remembered to detect duplicates
+	pub fn with_history(mut self, history: usize) -> Self {
+		self.history = history;
+		self
+	}
+
+	/// Record an observation of sequence number `seq` covering `increment` sequence units, with the exporter's uptime
+	/// in milliseconds if known
+	///
+	/// Late datagrams carry an older uptime, so an uptime going backwards only signals a restart if the sequence number
+	/// is not a duplicate and not within the reorder window behind the expected number. Observations covering 0 units,
+	/// like NetFlow v5 datagrams without records, are in order if they carry the expected number
+	pub fn observe(&mut self, key: SequenceKey, seq: u32, increment: u32, uptime: Option<u32>) -> Option<SequenceEvent> {
+		let history = self.history;
+		let reorder_window = self.reorder_window;
+
+		let Some(state) = self.streams.get_mut(&key) else {
+			let mut state = SequenceState::default();
+			state.stats.received = 1;
+			state.stats.next_expected = seq.wrapping_add(increment);
+			state.stats.last_uptime = uptime;
+			state.recent.push_back(seq);
+			self.streams.insert(key, state);
+			return None;
+		};
+
+		let stats = &mut state.stats;
+		stats.received += 1;
+
+		let uptime_went_back = matches!((stats.last_uptime, uptime), (Some(last), Some(now)) if now < last && last < UPTIME_WRAP_THRESHOLD);
+		let ahead = seq.wrapping_sub(stats.next_expected) < 1 << 31;
+
+		let event = if seq == stats.next_expected {
+			stats.next_expected = seq.wrapping_add(increment);
+			None
+		} else if state.recent.contains(&seq) {
+			stats.duplicates += 1;
+			return Some(SequenceEvent::Duplicate { key, seq });
+		} else if !ahead && stats.next_expected.wrapping_sub(seq) <= reorder_window {
+			stats.reordered += 1;
+			stats.missing = stats.missing.saturating_sub(increment as u64);
+			Some(SequenceEvent::Reordered { key, seq, expected: stats.next_expected, units: increment })
+		} else if ahead && !uptime_went_back {
+			let expected = stats.next_expected;
+			let missing = seq.wrapping_sub(expected);
+			stats.missing += missing as u64;
+			stats.next_expected = seq.wrapping_add(increment);
+			Some(SequenceEvent::Gap { key, expected, received: seq, missing })
+		} else {
+			stats.restarts += 1;
+			stats.next_expected = seq.wrapping_add(increment);
+			state.recent.clear();
+			Some(SequenceEvent::Restart { key, seq })
+		};
+
+		// Late datagrams must not move the uptime back, or the next in-order datagram would look like a restart
+		if uptime.is_some() && !matches!(event, Some(SequenceEvent::Reordered { .. })) {
+			stats.last_uptime = uptime;
+		}
+
+		state.recent.push_back(seq);
+		while state.recent.len() > history {
+			state.recent.pop_front();
+		}
+
+		event
+	}
+
+	/// Record the sequence numbers of a parsed NetFlow datagram received from `exporter`
+	pub fn observe_netflow(&mut self, exporter: IpAddr, dg: &NetflowDatagramData) -> Vec<SequenceEvent> {
+		let event = match dg {
+			NetflowDatagramData::DatagramV5(d) => {
+				let key = SequenceKey::NetflowV5 { exporter, engine_type: d.engine_type, engine_id: d.engine_id };
+				self.observe(key, d.flow_seqnum, d.flow_records.len() as u32, Some(d.sys_uptime_ms))
+			}
+			NetflowDatagramData::DatagramV9(d) => {
+				let key = SequenceKey::NetflowV9 { exporter, source_id: d.source_id };
+				self.observe(key, d.package_sequence, 1, Some(d.sys_uptime_ms))
+			}
+			NetflowDatagramData::DatagramV1(_) | NetflowDatagramData::IPFIX => None,
+		};
+
+		event.into_iter().collect()
+	}
+
+	/// Record the datagram and sample sequence numbers of a parsed sFlow datagram
+	pub fn observe_sflow(&mut self, dg: &Datagram) -> Vec<SequenceEvent> {
+		let (agent, sub_agent_id) = (dg.agent_addr, dg.sub_agent_id);
+		let mut events = vec![];
+
+		events.extend(self.observe(SequenceKey::SFlowDatagram { agent, sub_agent_id }, dg.seq_num, 1, Some(dg.uptime)));
+
+		for sample in &dg.sample_record {
+			let (key, seq) = match sample {
+				SFlowSample::Flow(s) => (SequenceKey::SFlowFlowSample { agent, sub_agent_id, source_id: s.src }, s.seq),
+				SFlowSample::Counter(s) => (SequenceKey::SFlowCounterSample { agent, sub_agent_id, source_id: s.src }, s.seq),
+				_ => continue,
+			};
+			events.extend(self.observe(key, seq, 1, Some(dg.uptime)));
+		}
+
+		events
+	}
+
+	/// Get the counters for a sequence number space
+	pub fn stats(&self, key: &SequenceKey) -> Option<&SequenceStats> {
+		self.streams.get(key).map(|s| &s.stats)
+	}
+
+	/// Iterate over the counters of all tracked sequence number spaces
+	pub fn iter(&self) -> impl Iterator<Item = (&SequenceKey, &SequenceStats)> {
+		self.streams.iter().map(|(k, s)| (k, &s.stats))
+	}
+
+	/// Stop tracking a sequence number space
+	pub fn remove(&mut self, key: &SequenceKey) -> Option<SequenceStats> {
+		self.streams.remove(key).map(|s| s.stats)
+	}
+}
+
@@ -0,0 +1,74 @@
+//! Helpers for the custom serde representations used by the `serde` feature
+
+use serde::{Deserialize, Deserializer, Serializer};
+use serde::de::Error;
+use crate::netflow_parse::datagram_v9_data::NetflowV9DataValue;
+use crate::netflow_parse::netflow_v9_typemap::{scope_type_info, NETFLOW_V9_DATATYPES, NetflowTypeInfo, NetflowV9TypeHandlingMode};
+
+/// Encode raw bytes as a lowercase hex string
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+	let mut out = String::with_capacity(bytes.len() * 2);
+	for b in bytes {
+		out.push_str(&format!("{:02x}", b));
+	}
+
+	out
+}
+
+/// Decode a hex string (either case) into raw bytes
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+	if !s.len().is_multiple_of(2) || !s.is_ascii() {
+		return None;
+	}
+
+	(0..s.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+		.collect()
+}
+
+/// Raw byte vectors represented as hex strings
+pub(crate) mod hex_bytes {
+	use super::*;
+
+	pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&to_hex(bytes))
+	}
+
+	pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		from_hex(&s).ok_or_else(|| D::Error::custom("invalid hex string"))
+	}
+}
+
+/// Owned representation of a v9 data field, used to deserialize its static name
+#[derive(Deserialize)]
+pub(crate) struct DataFieldRepr {
+	pub name: String,
+	pub type_id: u16,
+	pub value: NetflowV9DataValue,
+	#[serde(default)]
+	pub enterprise: Option<u32>,
+}
+
+/// Map a deserialized field name back onto the matching static name from the type map
+pub(crate) fn static_field_name(name: &str) -> &'static str {
+	NETFLOW_V9_DATATYPES.values().find(|ti| ti.0 == name).map(|ti| ti.0)
+		.or_else(|| (1..=6).map(scope_type_info).find(|ti| ti.0 == name).map(|ti| ti.0))
+		.unwrap_or("UNKNOWN")
+}
+
+/// Template field type information, restored from the type map by its ID on deserialization
+pub(crate) mod type_info {
+	use super::*;
+
+	pub(crate) fn serialize<S: Serializer>(info: &Option<NetflowTypeInfo>, serializer: S) -> Result<S::Ok, S::Error> {
+		serde::Serialize::serialize(info, serializer)
+	}
+
+	pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<NetflowTypeInfo>, D::Error> {
+		let info: Option<(String, String, NetflowV9TypeHandlingMode, u16)> = Option::deserialize(deserializer)?;
+
+		Ok(info.and_then(|(_, _, _, id)| NETFLOW_V9_DATATYPES.get(&id).copied()))
+	}
+}
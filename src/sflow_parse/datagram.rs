@@ -4,10 +4,12 @@ use nom::number::complete::{be_u128, be_u32};
 use nom::IResult;
 use nom::multi::count;
 use nom::sequence::tuple;
+use crate::sflow_parse::encode::{put_u32, u32_length, SFlowEncodeError};
 use crate::sflow_parse::sample::SFlowSample;
 
 /// Base sFlow datagram
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Datagram {
 	pub sflow_version: u32,
 	pub agent_addr: std::net::IpAddr,
@@ -17,6 +19,40 @@ pub struct Datagram {
 	pub sample_record: Vec<SFlowSample>,
 }
 
+impl Datagram {
+	/// Encode this datagram in XDR; the sample count is taken from `sample_record`
+	///
+	/// Like [parse_sflow_data], this does not include Ethernet, IP, or UDP headers
+	///
+	/// # Errors
+	///
+	/// Only what the parser decodes can be encoded: fails with [SFlowEncodeError::Unsupported] for expanded samples and
+	/// for flow records other than raw packet headers
+	pub fn to_bytes(&self) -> Result<Vec<u8>, SFlowEncodeError> {
+		let mut out = Vec::with_capacity(1400);
+		put_u32(&mut out, self.sflow_version);
+		match self.agent_addr {
+			std::net::IpAddr::V4(a) => {
+				put_u32(&mut out, 1);
+				out.extend_from_slice(&a.octets());
+			}
+			std::net::IpAddr::V6(a) => {
+				put_u32(&mut out, 2);
+				out.extend_from_slice(&a.octets());
+			}
+		}
+		put_u32(&mut out, self.sub_agent_id);
+		put_u32(&mut out, self.seq_num);
+		put_u32(&mut out, self.uptime);
+		put_u32(&mut out, u32_length(self.sample_record.len())?);
+		for s in &self.sample_record {
+			s.write_bytes(&mut out)?;
+		}
+
+		Ok(out)
+	}
+}
+
 fn parse_ipv4_or_ipv6(input: &[u8]) -> IResult<&[u8], std::net::IpAddr> {
 	let (input, ver) = be_u32(input)?;
 	if ver == 1 { // IPv4
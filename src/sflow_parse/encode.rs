@@ -0,0 +1,58 @@
+//! Shared helpers for encoding sFlow datagrams to XDR wire bytes
+
+use std::fmt::{Display, Formatter};
+
+/// Reasons a datagram cannot be encoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SFlowEncodeError {
+	/// The sample or record has no data to encode, as its format is not supported by the parser either
+	Unsupported(&'static str),
+	/// A sample, record, or header exceeds the 32-bit length field
+	TooLong,
+}
+
+impl Display for SFlowEncodeError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			SFlowEncodeError::Unsupported(kind) => write!(f, "Encoding {} is not supported", kind),
+			SFlowEncodeError::TooLong => write!(f, "Encoded data exceeds the maximum length"),
+		}
+	}
+}
+
+impl std::error::Error for SFlowEncodeError {}
+
+pub(crate) fn put_u32(out: &mut Vec<u8>, v: u32) {
+	out.extend_from_slice(&v.to_be_bytes());
+}
+
+pub(crate) fn put_u64(out: &mut Vec<u8>, v: u64) {
+	out.extend_from_slice(&v.to_be_bytes());
+}
+
+/// Convert a length or count to the 32-bit representation used by XDR
+pub(crate) fn u32_length(length: usize) -> Result<u32, SFlowEncodeError> {
+	u32::try_from(length).map_err(|_| SFlowEncodeError::TooLong)
+}
+
+/// Append variable length opaque data, preceded by its length and padded to a multiple of 4 bytes
+pub(crate) fn put_opaque(out: &mut Vec<u8>, data: &[u8]) -> Result<(), SFlowEncodeError> {
+	put_u32(out, u32_length(data.len())?);
+	out.extend_from_slice(data);
+	out.resize(out.len() + (4 - data.len() % 4) % 4, 0);
+
+	Ok(())
+}
+
+/// Append a structure of type `format` written by `body`, preceded by its type and length
+pub(crate) fn put_tagged<F>(out: &mut Vec<u8>, format: u32, body: F) -> Result<(), SFlowEncodeError>
+	where F: FnOnce(&mut Vec<u8>) -> Result<(), SFlowEncodeError> {
+	put_u32(out, format);
+	let start = out.len();
+	put_u32(out, 0);
+	body(out)?;
+
+	let length = u32_length(out.len() - start - 4)?;
+	out[start..start + 4].copy_from_slice(&length.to_be_bytes());
+	Ok(())
+}
@@ -1,4 +1,5 @@
 //! Module dealing with parsing sFlow data
 
 pub mod datagram;
+pub mod encode;
 pub mod sample;
@@ -4,9 +4,11 @@ use nom::IResult;
 use nom::multi::count;
 use nom::number::complete::{be_u32, be_u64};
 use nom::sequence::tuple;
+use crate::sflow_parse::encode::{put_tagged, put_u32, put_u64, u32_length, SFlowEncodeError};
 
 /// Generic counter data
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SFlowCounterDataGeneric {
 	pub index: u32,
 	pub interface_type: u32,
@@ -61,11 +63,34 @@ impl SFlowCounterDataGeneric {
 			out_promiscuous,
 		}))
 	}
+
+	fn write_bytes(&self, out: &mut Vec<u8>) {
+		put_u32(out, self.index);
+		put_u32(out, self.interface_type);
+		put_u64(out, self.speed);
+		put_u32(out, self.direction);
+		put_u32(out, self.status);
+		put_u64(out, self.in_octets);
+		put_u32(out, self.in_ucast_packets);
+		put_u32(out, self.in_multicast_packets);
+		put_u32(out, self.in_broadcast_packets);
+		put_u32(out, self.in_discarded);
+		put_u32(out, self.in_errors);
+		put_u32(out, self.in_unknown_protos);
+		put_u64(out, self.out_octets);
+		put_u32(out, self.out_ucast_packets);
+		put_u32(out, self.out_multicast_packets);
+		put_u32(out, self.out_broadcast_packets);
+		put_u32(out, self.out_discarded);
+		put_u32(out, self.out_errors);
+		put_u32(out, self.out_promiscuous);
+	}
 }
 
 
 /// Ethernet interface counter data
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SFlowCounterDataEthernet {
 	pub alignment_errors: u32,
 	pub fcs_errors: u32,
@@ -107,10 +132,27 @@ impl SFlowCounterDataEthernet {
 			symbol_errors,
 		}))
 	}
+
+	fn write_bytes(&self, out: &mut Vec<u8>) {
+		put_u32(out, self.alignment_errors);
+		put_u32(out, self.fcs_errors);
+		put_u32(out, self.single_collision_frames);
+		put_u32(out, self.multiple_collision_frames);
+		put_u32(out, self.sqe_test_errors);
+		put_u32(out, self.deferred_transmissions);
+		put_u32(out, self.late_collisions);
+		put_u32(out, self.excessive_collisions);
+		put_u32(out, self.internal_mac_transmit_errors);
+		put_u32(out, self.carrier_sense_errors);
+		put_u32(out, self.frame_too_longs);
+		put_u32(out, self.internal_mac_receive_errors);
+		put_u32(out, self.symbol_errors);
+	}
 }
 
 /// Token ring counter data
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SFlowCounterDataTokenRing {
 	pub line_errors: u32,
 	pub burst_errors: u32,
@@ -161,10 +203,32 @@ impl SFlowCounterDataTokenRing {
 			freq_errors,
 		}))
 	}
+
+	fn write_bytes(&self, out: &mut Vec<u8>) {
+		put_u32(out, self.line_errors);
+		put_u32(out, self.burst_errors);
+		put_u32(out, self.ac_errors);
+		put_u32(out, self.abort_trans_errors);
+		put_u32(out, self.internal_errors);
+		put_u32(out, self.lost_frame_errors);
+		put_u32(out, self.receive_congestions);
+		put_u32(out, self.frame_copied_errors);
+		put_u32(out, self.token_errors);
+		put_u32(out, self.soft_errors);
+		put_u32(out, self.hard_errors);
+		put_u32(out, self.signal_loss);
+		put_u32(out, self.transmit_beacons);
+		put_u32(out, self.recoverys);
+		put_u32(out, self.lobe_wires);
+		put_u32(out, self.removes);
+		put_u32(out, self.singles);
+		put_u32(out, self.freq_errors);
+	}
 }
 
 /// 100 BaseVG interface counter data
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SFlowCounterDataBaseVG {
 	pub in_high_priority_frames: u32,
 	pub in_high_priority_octets: u64,
@@ -208,10 +272,28 @@ impl SFlowCounterDataBaseVG {
 			hc_out_high_priority_octets,
 		}))
 	}
+
+	fn write_bytes(&self, out: &mut Vec<u8>) {
+		put_u32(out, self.in_high_priority_frames);
+		put_u64(out, self.in_high_priority_octets);
+		put_u32(out, self.in_norm_priority_frames);
+		put_u64(out, self.in_norm_priority_octets);
+		put_u32(out, self.in_ipm_errors);
+		put_u32(out, self.in_oversize_frame_errors);
+		put_u32(out, self.in_data_errors);
+		put_u32(out, self.in_null_addressed_frames);
+		put_u32(out, self.out_high_priority_frames);
+		put_u64(out, self.out_high_priority_octets);
+		put_u32(out, self.transition_into_trainings);
+		put_u64(out, self.hc_in_high_priority_octets);
+		put_u64(out, self.hc_in_norm_priority_octets);
+		put_u64(out, self.hc_out_high_priority_octets);
+	}
 }
 
 /// VLAN counter data
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SFlowCounterDataVLAN {
 	pub vlan_id: u32,
 	pub octets: u64,
@@ -228,10 +310,20 @@ impl SFlowCounterDataVLAN {
 
 		Ok((res, Self { vlan_id, octets, ucast_packets, multicast_packets, broadcast_packets, discards }))
 	}
+
+	fn write_bytes(&self, out: &mut Vec<u8>) {
+		put_u32(out, self.vlan_id);
+		put_u64(out, self.octets);
+		put_u32(out, self.ucast_packets);
+		put_u32(out, self.multicast_packets);
+		put_u32(out, self.broadcast_packets);
+		put_u32(out, self.discards);
+	}
 }
 
 /// Processor information data
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SFlowCounterDataProcessor {
 	pub cpu_percent_5s: u32,
 	pub cpu_percent_1m: u32,
@@ -247,10 +339,19 @@ impl SFlowCounterDataProcessor {
 
 		Ok((res, Self { cpu_percent_5s, cpu_percent_1m, cpu_percent_5m, total_memory, free_memory }))
 	}
+
+	fn write_bytes(&self, out: &mut Vec<u8>) {
+		put_u32(out, self.cpu_percent_5s);
+		put_u32(out, self.cpu_percent_1m);
+		put_u32(out, self.cpu_percent_5m);
+		put_u64(out, self.total_memory);
+		put_u64(out, self.free_memory);
+	}
 }
 
 /// Enum with variants for the supported sFlow counters
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SFlowCounterRecord {
 	Generic(SFlowCounterDataGeneric),
 	Ethernet(SFlowCounterDataEthernet),
@@ -295,10 +396,37 @@ impl SFlowCounterRecord {
 			}
 		}
 	}
+
+	/// Get the sFlow data format number of this record
+	pub fn format(&self) -> u32 {
+		match self {
+			Self::Generic(_) => 1,
+			Self::Ethernet(_) => 2,
+			Self::TokenRing(_) => 3,
+			Self::BaseVG(_) => 4,
+			Self::VLAN(_) => 5,
+			Self::Processor(_) => 1001,
+		}
+	}
+
+	fn write_bytes(&self, out: &mut Vec<u8>) -> Result<(), SFlowEncodeError> {
+		put_tagged(out, self.format(), |out| {
+			match self {
+				Self::Generic(r) => r.write_bytes(out),
+				Self::Ethernet(r) => r.write_bytes(out),
+				Self::TokenRing(r) => r.write_bytes(out),
+				Self::BaseVG(r) => r.write_bytes(out),
+				Self::VLAN(r) => r.write_bytes(out),
+				Self::Processor(r) => r.write_bytes(out),
+			}
+			Ok(())
+		})
+	}
 }
 
 /// Single sFlow counter sample
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SFlowCounterSample {
 	pub seq: u32,
 	pub src: u32,
@@ -314,4 +442,28 @@ impl SFlowCounterSample {
 
 		Ok((res, Self { seq, src, records_count, records }))
 	}
+
+	/// Create a counter sample of data source `src`
+	pub fn new(seq: u32, src: u32, records: Vec<SFlowCounterRecord>) -> Self {
+		Self { seq, src, records_count: records.len() as u32, records }
+	}
+
+	/// Encode the sample data in XDR, without the sample type and length; the record count is taken from `records`
+	pub fn to_bytes(&self) -> Result<Vec<u8>, SFlowEncodeError> {
+		let mut out = Vec::with_capacity(128);
+		self.write_bytes(&mut out)?;
+
+		Ok(out)
+	}
+
+	pub(crate) fn write_bytes(&self, out: &mut Vec<u8>) -> Result<(), SFlowEncodeError> {
+		put_u32(out, self.seq);
+		put_u32(out, self.src);
+		put_u32(out, u32_length(self.records.len())?);
+		for r in &self.records {
+			r.write_bytes(out)?;
+		}
+
+		Ok(())
+	}
 }
\ No newline at end of file
@@ -5,14 +5,17 @@ use nom::IResult;
 use nom::multi::count;
 use nom::number::complete::be_u32;
 use nom::sequence::tuple;
+use crate::sflow_parse::encode::{put_opaque, put_tagged, put_u32, u32_length, SFlowEncodeError};
 
 /// Raw packet header with the header preserved as a byte vector
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SFlowFlowRawPacketHeader {
 	pub protocol: u32,
 	pub frame_length: u32,
 	pub stripped: u32,
 	pub header_size: u32,
+	#[cfg_attr(feature = "serde", serde(with = "crate::serde_util::hex_bytes"))]
 	pub header: Vec<u8>,
 }
 
@@ -22,13 +25,28 @@ impl SFlowFlowRawPacketHeader {
 			tuple((be_u32, be_u32, be_u32, be_u32))(input)?;
 
 		let (res, header) = take(header_size)(res)?;
+		// Opaque data is padded to a multiple of 4 bytes
+		let (res, _) = take((4 - header_size % 4) % 4)(res)?;
 
 		Ok((res, Self { protocol, frame_length, stripped, header_size, header: Vec::from(header) }))
 	}
+
+	/// Create a raw packet header record for a packet of `frame_length` bytes whose first bytes are `header`
+	pub fn new(protocol: u32, frame_length: u32, stripped: u32, header: Vec<u8>) -> Self {
+		Self { protocol, frame_length, stripped, header_size: header.len() as u32, header }
+	}
+
+	fn write_bytes(&self, out: &mut Vec<u8>) -> Result<(), SFlowEncodeError> {
+		put_u32(out, self.protocol);
+		put_u32(out, self.frame_length);
+		put_u32(out, self.stripped);
+		put_opaque(out, &self.header)
+	}
 }
 
 /// Enum with variants representing the supported sample records
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SFlowFlowSampleRecord {
 	Raw(SFlowFlowRawPacketHeader),
 	Ethernet,
@@ -125,11 +143,19 @@ impl SFlowFlowSampleRecord {
 			}
 		}
 	}
+
+	fn write_bytes(&self, out: &mut Vec<u8>) -> Result<(), SFlowEncodeError> {
+		match self {
+			Self::Raw(r) => put_tagged(out, 1, |out| r.write_bytes(out)),
+			_ => Err(SFlowEncodeError::Unsupported("flow records other than raw packet headers")),
+		}
+	}
 }
 
 
 /// Single sFlow flow sample
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SFlowFlowSample {
 	pub seq: u32,
 	pub src: u32,
@@ -151,4 +177,24 @@ impl SFlowFlowSample {
 
 		Ok((res, Self { seq, src, rate, pool, dropped, input_if, output_if, record_count, records }))
 	}
+
+	/// Encode the sample data in XDR, without the sample type and length; the record count is taken from `records`
+	pub fn to_bytes(&self) -> Result<Vec<u8>, SFlowEncodeError> {
+		let mut out = Vec::with_capacity(256);
+		self.write_bytes(&mut out)?;
+
+		Ok(out)
+	}
+
+	pub(crate) fn write_bytes(&self, out: &mut Vec<u8>) -> Result<(), SFlowEncodeError> {
+		for v in [self.seq, self.src, self.rate, self.pool, self.dropped, self.input_if, self.output_if] {
+			put_u32(out, v);
+		}
+		put_u32(out, u32_length(self.records.len())?);
+		for r in &self.records {
+			r.write_bytes(out)?;
+		}
+
+		Ok(())
+	}
 }
\ No newline at end of file
@@ -5,11 +5,13 @@ pub mod counter;
 use nom::combinator::fail;
 use nom::IResult;
 use nom::number::complete::be_u32;
+use crate::sflow_parse::encode::{put_tagged, SFlowEncodeError};
 use crate::sflow_parse::sample::counter::SFlowCounterSample;
 use crate::sflow_parse::sample::flow::SFlowFlowSample;
 
 // TODO: Expanded
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SFlowSample {
 	Flow(SFlowFlowSample),
 	Counter(SFlowCounterSample),
@@ -22,7 +24,7 @@ impl SFlowSample {
 		let (res, sample_type): (&[u8], u32) = be_u32(input)?;
 		let (res, _sample_size): (&[u8], u32) = be_u32(res)?;
 
-		return match sample_type {
+		match sample_type {
 			1 => {
 				let (res, s) = SFlowFlowSample::parse_from_datagram(res)?;
 				Ok((res, Self::Flow(s)))
@@ -34,6 +36,23 @@ impl SFlowSample {
 			// 3 => ExpFlow,
 			// 4 => ExpCounter,
 			_ => { fail(res) }
-		};
+		}
+	}
+
+	/// Encode the sample in XDR, including its type and length
+	pub fn to_bytes(&self) -> Result<Vec<u8>, SFlowEncodeError> {
+		let mut out = vec![];
+		self.write_bytes(&mut out)?;
+
+		Ok(out)
+	}
+
+	pub(crate) fn write_bytes(&self, out: &mut Vec<u8>) -> Result<(), SFlowEncodeError> {
+		match self {
+			Self::Flow(s) => put_tagged(out, 1, |out| s.write_bytes(out)),
+			Self::Counter(s) => put_tagged(out, 2, |out| s.write_bytes(out)),
+			Self::ExpFlow => Err(SFlowEncodeError::Unsupported("expanded flow samples")),
+			Self::ExpCounter => Err(SFlowEncodeError::Unsupported("expanded counter samples")),
+		}
 	}
 }
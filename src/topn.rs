@@ -0,0 +1,193 @@
+//! Streaming top-N (heavy hitter) computation over tumbling time windows
+//!
+//! [TopN] keeps a fixed number of counters per window using the Space-Saving algorithm: when a new key arrives and all
+//! counters are taken, the smallest counter is handed over to the new key. Every key whose true count exceeds
+//! `total / capacity` is guaranteed to be tracked, and each estimate overcounts by at most its reported `error`.
+//! The dimension to rank by is a set of [KeyField]s, e.g. `SrcPrefix { v4: 32, v6: 128 }` for source addresses or
+//! `SrcPrefix` and `DstPrefix` together for address pairs
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, SystemTime};
+use crate::aggregate::{window_start, FlowCounters, FlowKey, KeyField};
+use crate::flow::FlowRecord;
+
+/// Counter to rank keys by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TopMetric {
+	Bytes,
+	Packets,
+	Flows,
+}
+
+/// A single ranked key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TopEntry {
+	pub key: FlowKey,
+	/// Estimated count, never lower than the true count
+	pub estimate: u64,
+	/// Maximum overestimation, the true count is at least `estimate - error`
+	pub error: u64,
+}
+
+/// Result of a finished top-N window
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TopNWindow {
+	pub start: SystemTime,
+	pub end: SystemTime,
+	pub metric: TopMetric,
+	/// Sum of the metric over all flows in the window
+	pub total: u64,
+	/// Top keys, highest estimate first
+	pub entries: Vec<TopEntry>,
+}
+
+/// Space-Saving summary with a fixed number of counters
+#[derive(Debug, Clone)]
+pub struct SpaceSaving {
+	capacity: usize,
+	counters: HashMap<FlowKey, (u64, u64)>,
+	/// Counters ordered by count, for finding the smallest one
+	order: BTreeSet<(u64, FlowKey)>,
+	total: u64,
+}
+
+impl SpaceSaving {
+	/// Create a summary with `capacity` counters
+	pub fn new(capacity: usize) -> Self {
+		let capacity = capacity.max(1);
+
+		Self { capacity, counters: HashMap::with_capacity(capacity), order: BTreeSet::new(), total: 0 }
+	}
+
+	/// Count `weight` for `key`
+	pub fn add(&mut self, key: FlowKey, weight: u64) {
+		self.total = self.total.saturating_add(weight);
+
+		if let Some((count, _)) = self.counters.get_mut(&key) {
+			self.order.remove(&(*count, key));
+			*count = count.saturating_add(weight);
+			self.order.insert((*count, key));
+			return;
+		}
+
+		let (count, error) = if self.counters.len() < self.capacity {
+			(weight, 0)
+		} else {
+			let Some((min_count, min_key)) = self.order.pop_first() else { return };
+			self.counters.remove(&min_key);
+			(min_count.saturating_add(weight), min_count)
+		};
+
+		self.counters.insert(key, (count, error));
+		self.order.insert((count, key));
+	}
+
+	/// Get the `n` keys with the highest estimates, highest first
+	pub fn top(&self, n: usize) -> Vec<TopEntry> {
+		self.order.iter().rev().take(n).map(|(estimate, key)| TopEntry {
+			key: *key,
+			estimate: *estimate,
+			error: self.counters.get(key).map(|c| c.1).unwrap_or_default(),
+		}).collect()
+	}
+
+	/// Get the sum of all counted weights
+	pub fn total(&self) -> u64 {
+		self.total
+	}
+
+	/// Remove all counters
+	pub fn clear(&mut self) {
+		self.counters.clear();
+		self.order.clear();
+		self.total = 0;
+	}
+}
+
+/// Top-N computation over tumbling time windows
+pub struct TopN<F: FnMut(TopNWindow)> {
+	fields: Vec<KeyField>,
+	metric: TopMetric,
+	n: usize,
+	window: Duration,
+	scale_sampling: bool,
+	start: Option<SystemTime>,
+	summary: SpaceSaving,
+	flush: F,
+}
+
+impl<F: FnMut(TopNWindow)> TopN<F> {
+	/// Create a top-`n` computation ranking keys made of `fields` by `metric` over windows of length `window`, calling
+	/// `flush` with every finished window
+	///
+	/// By default, `10 * n` counters are kept and counters are scaled by the sampling rate
+	pub fn new(fields: Vec<KeyField>, metric: TopMetric, n: usize, window: Duration, flush: F) -> Self {
+		Self {
+			fields,
+			metric,
+			n,
+			window: window.max(Duration::from_millis(1)),
+			scale_sampling: true,
+			start: None,
+			summary: SpaceSaving::new(n.saturating_mul(10)),
+			flush,
+		}
+	}
+
+	/// Set the number of counters kept, trading memory for accuracy; at least `n` are always kept
+	pub fn with_capacity(mut self, capacity: usize) -> Self {
+		self.summary = SpaceSaving::new(capacity.max(self.n));
+		self
+	}
+
+	/// Set whether bytes and packets are multiplied by the sampling rate of each record
+	pub fn with_sampling_scaled(mut self, scale: bool) -> Self {
+		self.scale_sampling = scale;
+		self
+	}
+
+	/// Add a flow observed at time `at`
+	///
+	/// Flows belonging to an earlier window than the current one are added to the current window
+	pub fn add(&mut self, record: &FlowRecord, at: SystemTime) {
+		self.tick(at);
+		if self.start.is_none() {
+			self.start = Some(window_start(at, self.window));
+		}
+
+		let mut counters = FlowCounters::default();
+		counters.add(record, self.scale_sampling);
+		let weight = match self.metric {
+			TopMetric::Bytes => counters.bytes,
+			TopMetric::Packets => counters.packets,
+			TopMetric::Flows => counters.flows,
+		};
+
+		self.summary.add(FlowKey::from_record(record, &self.fields), weight);
+	}
+
+	/// Flush the current window if it has ended at `now`
+	pub fn tick(&mut self, now: SystemTime) {
+		if self.start.is_some_and(|start| now >= start + self.window) {
+			self.flush();
+		}
+	}
+
+	/// Flush the current window immediately, even if it has not ended yet
+	pub fn flush(&mut self) {
+		let Some(start) = self.start.take() else { return };
+
+		let window = TopNWindow {
+			start,
+			end: start + self.window,
+			metric: self.metric,
+			total: self.summary.total(),
+			entries: self.summary.top(self.n),
+		};
+		self.summary.clear();
+		(self.flush)(window);
+	}
+}
@@ -0,0 +1,109 @@
+//! Aggregation windows, key limit, and sampling scaling
+
+use std::cell::RefCell;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use multiflow::aggregate::{AggregatedWindow, Aggregator, FlowCounters, KeyField};
+use multiflow::flow::FlowRecord;
+
+fn flow(src: Ipv4Addr, dst_port: u16, bytes: u64) -> FlowRecord {
+	FlowRecord {
+		exporter: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+		src_addr: Some(IpAddr::V4(src)),
+		dst_addr: Some(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1))),
+		src_port: Some(40000),
+		dst_port: Some(dst_port),
+		protocol: Some(6),
+		tos: None,
+		tcp_flags: None,
+		src_as: None,
+		dst_as: None,
+		input_if: None,
+		output_if: None,
+		start: None,
+		end: None,
+		bytes,
+		packets: 1,
+		flows: 1,
+		sampling_rate: 1,
+	}
+}
+
+/// Time `secs` after the start of a minute
+fn at(secs: u64) -> SystemTime {
+	UNIX_EPOCH + Duration::from_secs(1_699_999_980 + secs)
+}
+
+fn counters(window: &AggregatedWindow, dst_port: u16) -> FlowCounters {
+	window.entries.iter().find(|(k, _)| k.dst_port == Some(dst_port)).map(|(_, c)| *c).unwrap_or_default()
+}
+
+#[test]
+fn window_rollover_flushes() {
+	let windows = RefCell::new(vec![]);
+	let mut aggregator = Aggregator::new(vec![KeyField::DstPort], Duration::from_secs(60), |w| windows.borrow_mut().push(w));
+
+	aggregator.add(&flow(Ipv4Addr::new(10, 0, 0, 1), 443, 100), at(0));
+	aggregator.add(&flow(Ipv4Addr::new(10, 0, 0, 2), 443, 50), at(30));
+	aggregator.add(&flow(Ipv4Addr::new(10, 0, 0, 1), 53, 10), at(59));
+	assert!(windows.borrow().is_empty());
+
+	// The first record of the next window flushes the previous one
+	aggregator.add(&flow(Ipv4Addr::new(10, 0, 0, 1), 443, 1), at(60));
+	assert_eq!(windows.borrow().len(), 1);
+	assert_eq!(aggregator.key_count(), 1);
+
+	// Ticking past the end flushes without a new record
+	aggregator.tick(at(119));
+	assert_eq!(windows.borrow().len(), 1);
+	aggregator.tick(at(120));
+	drop(aggregator);
+
+	let windows = windows.into_inner();
+	assert_eq!(windows.len(), 2);
+	assert_eq!((windows[0].start, windows[0].end), (at(0), at(60)));
+	assert_eq!(counters(&windows[0], 443), FlowCounters { bytes: 150, packets: 2, flows: 2 });
+	assert_eq!(counters(&windows[0], 53), FlowCounters { bytes: 10, packets: 1, flows: 1 });
+	assert_eq!(windows[1].start, at(60));
+	assert_eq!(counters(&windows[1], 443).bytes, 1);
+}
+
+#[test]
+fn key_limit_sums_overflow() {
+	let windows = RefCell::new(vec![]);
+	let mut aggregator = Aggregator::new(vec![KeyField::DstPort], Duration::from_secs(60), |w| windows.borrow_mut().push(w)).with_max_keys(2);
+
+	for port in 1..=5 {
+		aggregator.add(&flow(Ipv4Addr::new(10, 0, 0, 1), port, 10), at(0));
+	}
+	// Known keys keep counting after the limit is reached
+	aggregator.add(&flow(Ipv4Addr::new(10, 0, 0, 1), 1, 10), at(1));
+	assert_eq!(aggregator.key_count(), 2);
+	aggregator.flush();
+	drop(aggregator);
+
+	let windows = windows.into_inner();
+	assert_eq!(windows[0].entries.len(), 2);
+	assert_eq!(counters(&windows[0], 1).bytes, 20);
+	assert_eq!(windows[0].overflow, FlowCounters { bytes: 30, packets: 3, flows: 3 });
+}
+
+#[test]
+fn prefix_keys_and_sampling() {
+	let windows = RefCell::new(vec![]);
+	let mut aggregator = Aggregator::new(vec![KeyField::SrcPrefix { v4: 24, v6: 64 }], Duration::from_secs(60), |w| windows.borrow_mut().push(w));
+
+	let mut sampled = flow(Ipv4Addr::new(10, 0, 0, 1), 443, 100);
+	sampled.sampling_rate = 10;
+	aggregator.add(&sampled, at(0));
+	aggregator.add(&flow(Ipv4Addr::new(10, 0, 0, 200), 80, 5), at(0));
+	aggregator.flush();
+	drop(aggregator);
+
+	let windows = windows.into_inner();
+	assert_eq!(windows[0].entries.len(), 1);
+	let (key, counters) = windows[0].entries[0];
+	assert_eq!(key.src_prefix, Some((IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 24)));
+	assert_eq!(key.dst_port, None);
+	assert_eq!(counters, FlowCounters { bytes: 1005, packets: 11, flows: 2 });
+}
@@ -0,0 +1,47 @@
+//! Shutdown behaviour of the async collector
+#![cfg(feature = "tokio")]
+
+use std::net::{Ipv4Addr, UdpSocket};
+use multiflow::collector::async_collector::FlowCollector;
+use multiflow::collector::ParsedDatagram;
+use multiflow::netflow_parse::datagram_v5::{NetflowDatagramV5, NetflowDatagramV5Record};
+
+fn v5() -> Vec<u8> {
+	NetflowDatagramV5 {
+		sys_uptime_ms: 1000,
+		unix_sec: 1_700_000_000,
+		unix_nsec: 0,
+		flow_seqnum: 1,
+		engine_type: 0,
+		engine_id: 0,
+		sampling_interval: 0,
+		flow_records: vec![NetflowDatagramV5Record::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2))],
+	}.to_bytes().unwrap()
+}
+
+#[tokio::test]
+async fn dropped_handle_keeps_collecting() {
+	let collector = FlowCollector::bind(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+	let addr = collector.local_addrs().unwrap()[0];
+	let (mut stream, _) = collector.start();
+
+	UdpSocket::bind("127.0.0.1:0").unwrap().send_to(&v5(), addr).unwrap();
+
+	let collected = stream.next().await.expect("Stream ended without a datagram");
+	assert!(matches!(collected.datagram, ParsedDatagram::Netflow(_)));
+}
+
+#[tokio::test]
+async fn shutdown_ends_stream() {
+	let collector = FlowCollector::bind(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+	let addr = collector.local_addrs().unwrap()[0];
+	let (mut stream, handle) = collector.start();
+
+	let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+	sender.send_to(&v5(), addr).unwrap();
+	assert!(stream.next().await.is_some());
+
+	handle.shutdown().await;
+	sender.send_to(&v5(), addr).unwrap();
+	assert!(stream.next().await.is_none());
+}
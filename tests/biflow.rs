@@ -0,0 +1,154 @@
+//! Biflow stitching pairs opposite directions and releases unpaired flows
+
+use std::cell::RefCell;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+use multiflow::biflow::{BiflowRecord, BiflowStitcher, DirectionCounters, REVERSE_PEN};
+use multiflow::flow::FlowRecord;
+use multiflow::netflow_parse::datagram_v9_data::{NetflowV9DataField, NetflowV9DataValue};
+
+const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+const SERVER: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+
+fn flow(src: (IpAddr, u16), dst: (IpAddr, u16), start: u32, bytes: u64) -> FlowRecord {
+	FlowRecord {
+		exporter: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+		src_addr: Some(src.0),
+		dst_addr: Some(dst.0),
+		src_port: Some(src.1),
+		dst_port: Some(dst.1),
+		protocol: Some(6),
+		tos: None,
+		tcp_flags: Some(0x02),
+		src_as: None,
+		dst_as: None,
+		input_if: None,
+		output_if: None,
+		start: Some(start),
+		end: Some(start + 500),
+		bytes,
+		packets: 1,
+		flows: 1,
+		sampling_rate: 1,
+	}
+}
+
+#[test]
+fn pairs_opposite_directions() {
+	let emitted = RefCell::new(vec![]);
+	let mut stitcher = BiflowStitcher::new(|b| emitted.borrow_mut().push(b));
+	let now = Instant::now();
+
+	// The response is exported first, the request started earlier
+	stitcher.add(&flow((SERVER, 443), (CLIENT, 50000), 1100, 5000), now);
+	stitcher.add(&flow((CLIENT, 50000), (SERVER, 443), 1000, 300), now);
+	assert_eq!(stitcher.pending(), 0);
+	drop(stitcher);
+
+	let emitted: Vec<BiflowRecord> = emitted.into_inner();
+	assert_eq!(emitted.len(), 1);
+	let biflow = emitted[0];
+	assert_eq!((biflow.initiator_addr, biflow.initiator_port), (Some(CLIENT), Some(50000)));
+	assert_eq!((biflow.responder_addr, biflow.responder_port), (Some(SERVER), Some(443)));
+	assert_eq!((biflow.forward.bytes, biflow.reverse.bytes), (300, 5000));
+	assert_eq!((biflow.start, biflow.end), (Some(1000), Some(1600)));
+}
+
+#[test]
+fn merges_same_direction_and_skips_distant_times() {
+	let emitted = RefCell::new(vec![]);
+	let mut stitcher = BiflowStitcher::new(|b| emitted.borrow_mut().push(b)).with_slack(100);
+	let now = Instant::now();
+
+	stitcher.add(&flow((CLIENT, 50000), (SERVER, 443), 1000, 300), now);
+	stitcher.add(&flow((CLIENT, 50000), (SERVER, 443), 1200, 200), now);
+	// Too far apart in time to be the reverse direction
+	stitcher.add(&flow((SERVER, 443), (CLIENT, 50000), 10_000, 5000), now);
+	assert_eq!(stitcher.pending(), 2);
+	stitcher.flush();
+	drop(stitcher);
+
+	let emitted = emitted.into_inner();
+	assert_eq!(emitted.len(), 2);
+	assert_eq!((emitted[0].forward.bytes, emitted[0].forward.flows, emitted[0].reverse.flows), (500, 2, 0));
+	assert_eq!(emitted[1].forward.bytes, 5000);
+}
+
+#[test]
+fn unpaired_flows_time_out() {
+	let emitted = RefCell::new(vec![]);
+	let mut stitcher = BiflowStitcher::new(|b| emitted.borrow_mut().push(b)).with_timeout(Duration::from_secs(10));
+	let start = Instant::now();
+
+	stitcher.add(&flow((CLIENT, 50000), (SERVER, 443), 1000, 300), start);
+	stitcher.add(&flow((CLIENT, 50001), (SERVER, 443), 1000, 300), start + Duration::from_secs(5));
+	stitcher.expire(start + Duration::from_secs(9));
+	assert_eq!(emitted.borrow().len(), 0);
+
+	stitcher.expire(start + Duration::from_secs(10));
+	assert_eq!(emitted.borrow().len(), 1);
+	assert_eq!(emitted.borrow()[0].initiator_port, Some(50000));
+	assert_eq!(stitcher.pending(), 1);
+}
+
+#[test]
+fn pending_limit_releases_oldest() {
+	let emitted = RefCell::new(vec![]);
+	let mut stitcher = BiflowStitcher::new(|b| emitted.borrow_mut().push(b)).with_max_pending(2);
+	let now = Instant::now();
+
+	for port in 0..1000 {
+		// Every other flow pairs up immediately
+		stitcher.add(&flow((CLIENT, 40000 + port), (SERVER, 443), 1000, 300), now);
+		if port % 2 == 0 {
+			stitcher.add(&flow((SERVER, 443), (CLIENT, 40000 + port), 1100, 3000), now);
+		}
+	}
+	assert_eq!(stitcher.pending(), 2);
+	drop(stitcher);
+
+	let emitted = emitted.into_inner();
+	assert_eq!(emitted.len(), 998);
+	assert_eq!(emitted.iter().filter(|b| b.reverse.bytes == 3000).count(), 500);
+	// The released unpaired flows come out oldest first
+	let unpaired = emitted.iter().filter(|b| b.reverse.flows == 0).map(|b| b.initiator_port.unwrap()).collect::<Vec<_>>();
+	assert!(unpaired.windows(2).all(|w| w[0] < w[1]));
+}
+
+fn v9_field(type_id: u16, value: NetflowV9DataValue, enterprise: Option<u32>) -> NetflowV9DataField {
+	NetflowV9DataField { name: "", type_id, value, enterprise }
+}
+
+#[test]
+fn rfc5103_reverse_fields() {
+	let exporter = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+	let number = |type_id, n, enterprise| v9_field(type_id, NetflowV9DataValue::Number(n), enterprise);
+	let mut fields = vec![
+		v9_field(8, NetflowV9DataValue::IPv4(Ipv4Addr::new(10, 0, 0, 1)), None),
+		v9_field(12, NetflowV9DataValue::IPv4(Ipv4Addr::new(198, 51, 100, 1)), None),
+		number(7, 40000, None),
+		number(11, 443, None),
+		number(4, 6, None),
+		number(1, 600, None),
+		number(2, 5, None),
+		number(22, 1000, None),
+		number(21, 1500, None),
+	];
+	assert!(BiflowRecord::from_rfc5103_record(exporter, &fields).is_none());
+
+	fields.extend([
+		number(1, 9000, Some(REVERSE_PEN)),
+		number(2, 7, Some(REVERSE_PEN)),
+		number(6, 0x12, Some(REVERSE_PEN)),
+		number(21, 1800, Some(REVERSE_PEN)),
+		// Other enterprises are neither direction
+		number(1, 1, Some(9)),
+	]);
+	let biflow = BiflowRecord::from_rfc5103_record(exporter, &fields).unwrap();
+
+	assert_eq!((biflow.initiator_addr, biflow.initiator_port), (Some(CLIENT), Some(40000)));
+	assert_eq!((biflow.responder_addr, biflow.responder_port), (Some(SERVER), Some(443)));
+	assert_eq!((biflow.forward.bytes, biflow.forward.packets), (600, 5));
+	assert_eq!(biflow.reverse, DirectionCounters { bytes: 9000, packets: 7, flows: 1, tcp_flags: Some(0x12) });
+	assert_eq!((biflow.start, biflow.end), (Some(1000), Some(1800)));
+}
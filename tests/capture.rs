@@ -0,0 +1,125 @@
+//! Packet capture: the BPF port filter and capture settings
+#![cfg(all(feature = "collector", target_os = "linux"))]
+
+use std::sync::Arc;
+use multiflow::collector::capture::{udp_port_filter, MAX_FILTER_PORTS};
+use multiflow::collector::sharded::{ShardedCollector, ShardedCollectorConfig};
+use multiflow::collector::{CollectorStats, ReceivedDatagram};
+
+/// Run a classic BPF program on `packet` like the kernel, returning the number of bytes to keep
+///
+/// Only the instructions used by the port filter are supported. Loads beyond the end of the packet drop it
+fn run(program: &[libc::sock_filter], packet: &[u8]) -> u32 {
+	let load = |offset: usize, size: usize| packet.get(offset..offset + size).map(|b| b.iter().fold(0u32, |v, b| v << 8 | *b as u32));
+	let (mut a, mut x) = (0u32, 0usize);
+	let mut pc = 0;
+	loop {
+		let libc::sock_filter { code, jt, jf, k } = program[pc];
+		pc += 1;
+		match code {
+			// ld b/h [k]
+			0x30 | 0x28 => match load(k as usize, if code == 0x30 { 1 } else { 2 }) {
+				Some(v) => a = v,
+				None => return 0,
+			},
+			// ld h [x + k]
+			0x48 => match load(x + k as usize, 2) {
+				Some(v) => a = v,
+				None => return 0,
+			},
+			// ldx 4 * ([k] & 0xf)
+			0xB1 => match load(k as usize, 1) {
+				Some(v) => x = 4 * (v as usize & 0xF),
+				None => return 0,
+			},
+			// and #k
+			0x54 => a &= k,
+			// ja
+			0x05 => pc += k as usize,
+			// jeq, jset
+			0x15 | 0x45 => {
+				let taken = if code == 0x15 { a == k } else { a & k != 0 };
+				pc += if taken { jt } else { jf } as usize;
+			}
+			// ret #k
+			0x06 => return k,
+			_ => panic!("Unexpected instruction {:#x}", code),
+		}
+	}
+}
+
+fn ipv4_udp(ihl: u8, fragment: u16, protocol: u8, dst_port: u16) -> Vec<u8> {
+	let mut packet = vec![0x40 | ihl, 0, 0, 0, 0, 1, 0, 0, 64, protocol, 0, 0, 192, 0, 2, 1, 192, 0, 2, 2];
+	packet[6..8].copy_from_slice(&fragment.to_be_bytes());
+	packet.resize(4 * ihl as usize, 0);
+	packet.extend_from_slice(&40000u16.to_be_bytes());
+	packet.extend_from_slice(&dst_port.to_be_bytes());
+	packet.extend_from_slice(&[0, 8, 0, 0]);
+	packet
+}
+
+fn ipv6_udp(next_header: u8, dst_port: u16) -> Vec<u8> {
+	let mut packet = vec![0x60, 0, 0, 0, 0, 8, next_header, 64];
+	packet.resize(40, 0);
+	packet.extend_from_slice(&40000u16.to_be_bytes());
+	packet.extend_from_slice(&dst_port.to_be_bytes());
+	packet.extend_from_slice(&[0, 8, 0, 0]);
+	packet
+}
+
+#[test]
+fn filter_matches_udp_ports() {
+	let program = udp_port_filter(&[2055, 6343]);
+	let accepted = |packet: Vec<u8>| run(&program, &packet) != 0;
+
+	assert!(accepted(ipv4_udp(5, 0, 17, 2055)));
+	assert!(accepted(ipv4_udp(5, 0, 17, 6343)));
+	assert!(!accepted(ipv4_udp(5, 0, 17, 53)));
+	// The port is found after IPv4 options
+	assert!(accepted(ipv4_udp(7, 0, 17, 2055)));
+	// Don't fragment is fine, later fragments and TCP are not
+	assert!(accepted(ipv4_udp(5, 0x4000, 17, 2055)));
+	assert!(!accepted(ipv4_udp(5, 0x0010, 17, 2055)));
+	assert!(!accepted(ipv4_udp(5, 0, 6, 2055)));
+
+	assert!(accepted(ipv6_udp(17, 6343)));
+	assert!(!accepted(ipv6_udp(17, 53)));
+	assert!(!accepted(ipv6_udp(6, 2055)));
+	// Neither IPv4 nor IPv6
+	assert!(!accepted(vec![0x50; 40]));
+}
+
+#[test]
+fn filter_without_ports_accepts_all_udp() {
+	let program = udp_port_filter(&[]);
+
+	assert_ne!(run(&program, &ipv4_udp(5, 0, 17, 53)), 0);
+	assert_ne!(run(&program, &ipv6_udp(17, 1)), 0);
+	assert_eq!(run(&program, &ipv4_udp(5, 0, 6, 53)), 0);
+}
+
+#[test]
+fn filter_with_most_ports() {
+	let ports = (1000..1000 + MAX_FILTER_PORTS as u16).collect::<Vec<_>>();
+	let program = udp_port_filter(&ports);
+
+	for port in [1000, 1000 + MAX_FILTER_PORTS as u16 - 1] {
+		assert_ne!(run(&program, &ipv4_udp(5, 0, 17, port)), 0);
+		assert_ne!(run(&program, &ipv6_udp(17, port)), 0);
+	}
+	assert_eq!(run(&program, &ipv4_udp(5, 0, 17, 999)), 0);
+	assert_eq!(run(&program, &ipv6_udp(17, 1000 + MAX_FILTER_PORTS as u16)), 0);
+}
+
+#[test]
+fn capture_rejects_receive_threads() {
+	let config = ShardedCollectorConfig {
+		listen: vec!["127.0.0.1:2055".parse().unwrap()],
+		receive_threads: 2,
+		capture: Some("any".into()),
+		..Default::default()
+	};
+	let res = ShardedCollector::spawn(&config, Arc::new(CollectorStats::default()), |_| |dg: ReceivedDatagram| Some(dg.data));
+
+	assert_eq!(res.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+}
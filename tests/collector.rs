@@ -0,0 +1,79 @@
+//! Collector helpers: protocol detection and the sharded pipeline
+#![cfg(feature = "collector")]
+
+use std::net::UdpSocket;
+use std::cell::RefCell;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::Duration;
+use multiflow::collector::{bind_udp, detect_protocol, for_each_datagram_with_idle, spawn_receivers, CollectorStats, FlowProtocol, ReceivedDatagram};
+use multiflow::collector::sharded::{ShardedCollector, ShardedCollectorConfig};
+
+#[test]
+fn detect_supported_protocols() {
+	assert_eq!(detect_protocol(&[0, 0, 0, 5, 0, 0, 0, 1]), Some(FlowProtocol::SFlow));
+	assert_eq!(detect_protocol(&[0, 1, 0, 0]), Some(FlowProtocol::Netflow(1)));
+	assert_eq!(detect_protocol(&[0, 5, 0, 1]), Some(FlowProtocol::Netflow(5)));
+	assert_eq!(detect_protocol(&[0, 9, 0, 1]), Some(FlowProtocol::Netflow(9)));
+}
+
+#[test]
+fn unsupported_versions_are_not_detected() {
+	// NetFlow v7 and IPFIX cannot be parsed
+	assert_eq!(detect_protocol(&[0, 7, 0, 1]), None);
+	assert_eq!(detect_protocol(&[0, 10, 0, 16]), None);
+	// sFlow v4
+	assert_eq!(detect_protocol(&[0, 0, 0, 4, 0, 0, 0, 1]), None);
+	assert_eq!(detect_protocol(&[0]), None);
+}
+
+#[test]
+fn zero_queue_size_delivers_datagrams() {
+	// Find a free port
+	let listen = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+	let config = ShardedCollectorConfig { listen: vec![listen], queue_size: 0, ..Default::default() };
+	let (_collector, rx) = ShardedCollector::spawn(&config, Arc::new(CollectorStats::default()), |_| |dg: ReceivedDatagram| Some(dg.data)).unwrap();
+
+	let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+	for i in 0..5u8 {
+		sender.send_to(&[i], listen).unwrap();
+		assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), [i]);
+	}
+}
+
+#[test]
+fn idle_once_queue_is_drained() {
+	let (tx, rx) = channel();
+	for i in 0..3 {
+		tx.send(i).unwrap();
+	}
+	let stats = CollectorStats::default();
+	let events = RefCell::new(Vec::new());
+
+	let sender = std::thread::spawn(move || {
+		std::thread::sleep(Duration::from_millis(100));
+		tx.send(3).unwrap();
+	});
+	for_each_datagram_with_idle(&rx, &stats, None, |i| events.borrow_mut().push(Some(i)), || events.borrow_mut().push(None));
+	sender.join().unwrap();
+
+	// Once after the queued burst and once after the late item, which may coincide with the call at the end
+	let events = events.into_inner();
+	assert_eq!(events[..6], [Some(0), Some(1), Some(2), None, Some(3), None]);
+	assert!(events[6..].iter().all(Option::is_none));
+}
+
+#[test]
+fn persistent_receive_errors_back_off() {
+	// Every receive times out right away
+	let sock = bind_udp(&"127.0.0.1:0".parse().unwrap(), None, false).unwrap();
+	sock.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+	let stats = Arc::new(CollectorStats::default());
+
+	let _rx = spawn_receivers(vec![sock], 1500, stats.clone());
+	std::thread::sleep(Duration::from_millis(500));
+
+	// Without backing off, close to 500 errors
+	let errors = stats.snapshot().receive_errors;
+	assert!((10..50).contains(&errors), "{} errors", errors);
+}
@@ -0,0 +1,108 @@
+//! Concurrent NetFlow parser: templates shared between threads
+#![cfg(feature = "concurrent")]
+
+use std::net::SocketAddr;
+use multiflow::netflow_parse::concurrent::ConcurrentNetflowParser;
+use multiflow::netflow_parse::datagram::NetflowDatagramData;
+use multiflow::netflow_parse::datagram_v9::{NetflowDatagramV9, NetflowDatagramV9FlowSet};
+use multiflow::netflow_parse::datagram_v9_data::NetflowV9DataValue;
+
+fn exporter() -> SocketAddr {
+	"192.0.2.1:2055".parse().unwrap()
+}
+
+/// Raw NetFlow v9 datagram with `count` records in the given flow sets
+fn raw_datagram(count: u16, sets: &[&[u8]]) -> Vec<u8> {
+	let mut bytes = vec![0, 9];
+	bytes.extend_from_slice(&count.to_be_bytes());
+	bytes.extend_from_slice(&[0, 0, 3, 232, 101, 83, 241, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+	for set in sets {
+		bytes.extend_from_slice(set);
+	}
+	bytes
+}
+
+/// Template 256: IN_BYTES (4 bytes), L4_SRC_PORT (2 bytes)
+const TEMPLATE: &[u8] = &[0, 0, 0, 16, 1, 0, 0, 2, 0, 1, 0, 4, 0, 7, 0, 2];
+/// Two records of template 256 and 4 bytes of padding
+const DATA: &[u8] = &[1, 0, 0, 20, 0, 0, 5, 220, 0, 80, 0, 0, 0, 100, 1, 187, 0, 0, 0, 0];
+/// Options template 257: scope interface (4 bytes), options SAMPLING_INTERVAL (4 bytes)
+const OPTIONS_TEMPLATE: &[u8] = &[0, 1, 0, 20, 1, 1, 0, 4, 0, 4, 0, 2, 0, 4, 0, 34, 0, 4, 0, 0];
+/// One record of options template 257
+const OPTIONS_DATA: &[u8] = &[1, 1, 0, 12, 0, 0, 0, 7, 0, 0, 0, 100];
+
+fn parse(parser: &ConcurrentNetflowParser, bytes: &[u8]) -> NetflowDatagramV9 {
+	match parser.parse(bytes, &exporter()) {
+		Ok((_, NetflowDatagramData::DatagramV9(dg))) => dg,
+		res => panic!("Not a v9 datagram: {:?}", res),
+	}
+}
+
+/// Type and numeric value of every field of every record in the data set at `index`
+fn numbers(dg: &NetflowDatagramV9, index: usize) -> Vec<Vec<(u16, u64)>> {
+	let NetflowDatagramV9FlowSet::Data(data) = &dg.flow_records[index] else { panic!("Not a data set") };
+	data.record_list().iter().map(|r| r.iter().map(|f| match f.value {
+		NetflowV9DataValue::Number(n) => (f.type_id, n),
+		_ => panic!("Not a number: {:?}", f),
+	}).collect()).collect()
+}
+
+#[test]
+fn template_from_another_thread() {
+	let parser = ConcurrentNetflowParser::new();
+
+	std::thread::scope(|s| {
+		s.spawn(|| parse(&parser, &raw_datagram(2, &[TEMPLATE, OPTIONS_TEMPLATE]))).join().unwrap();
+		let dg = s.spawn(|| parse(&parser, &raw_datagram(3, &[DATA, OPTIONS_DATA]))).join().unwrap();
+
+		assert_eq!(numbers(&dg, 0), [[(1, 1500), (7, 80)], [(1, 100), (7, 443)]]);
+		// Options records include their scope fields
+		assert_eq!(numbers(&dg, 1), [[(2, 7), (34, 100)]]);
+	});
+	assert_eq!(parser.template_count(), (1, 1));
+}
+
+#[test]
+fn templates_are_kept_per_exporter() {
+	let parser = ConcurrentNetflowParser::new();
+	assert!(parser.parse(&raw_datagram(2, &[DATA]), &exporter()).is_err());
+
+	parse(&parser, &raw_datagram(1, &[TEMPLATE]));
+	let other = "192.0.2.2:2055".parse().unwrap();
+	assert!(parser.parse(&raw_datagram(2, &[DATA]), &other).is_err());
+	assert_eq!(numbers(&parse(&parser, &raw_datagram(2, &[DATA])), 0).len(), 2);
+}
+
+#[test]
+fn unchanged_templates_keep_the_cache() {
+	let parser = ConcurrentNetflowParser::new();
+	parse(&parser, &raw_datagram(2, &[TEMPLATE, OPTIONS_TEMPLATE]));
+	assert_eq!(parser.template_updates(), 2);
+
+	// Exporters resend their templates periodically
+	for _ in 0..3 {
+		parse(&parser, &raw_datagram(2, &[TEMPLATE, OPTIONS_TEMPLATE]));
+	}
+	assert_eq!(parser.template_updates(), 2);
+
+	// Changed: L4_DST_PORT instead of L4_SRC_PORT
+	let changed: &[u8] = &[0, 0, 0, 16, 1, 0, 0, 2, 0, 1, 0, 4, 0, 11, 0, 2];
+	let dg = parse(&parser, &raw_datagram(3, &[changed, DATA]));
+	assert_eq!(parser.template_updates(), 3);
+	assert_eq!(parser.template_count(), (1, 1));
+	assert_eq!(numbers(&dg, 1), [[(1, 1500), (11, 80)], [(1, 100), (11, 443)]]);
+}
+
+#[test]
+fn record_count_mismatches() {
+	let parser = ConcurrentNetflowParser::new();
+	parse(&parser, &raw_datagram(3, &[TEMPLATE, DATA]));
+	assert_eq!(parser.record_count_mismatches(), 0);
+
+	std::thread::scope(|s| {
+		for _ in 0..4 {
+			s.spawn(|| parse(&parser, &raw_datagram(5, &[DATA])));
+		}
+	});
+	assert_eq!(parser.record_count_mismatches(), 4);
+}
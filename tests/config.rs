@@ -0,0 +1,88 @@
+//! Collector configuration: merging the config file with command-line arguments, and rejected combinations
+#![cfg(feature = "cli")]
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::Parser;
+use multiflow::config::{CollectorArgs, CollectorConfig, ConfigError};
+use multiflow::output::{OutputFormat, OutputMode};
+
+fn default_listen() -> Vec<SocketAddr> {
+	vec!["0.0.0.0:9000".parse().unwrap()]
+}
+
+fn load(args: &[&str]) -> Result<CollectorConfig, ConfigError> {
+	let args = std::iter::once("collect").chain(args.iter().copied());
+	CollectorConfig::load(CollectorArgs::parse_from(args), &default_listen())
+}
+
+/// Write `text` to a config file unique to the test `name`
+fn config_file(name: &str, text: &str) -> PathBuf {
+	let path = std::env::temp_dir().join(format!("multiflow-config-{}-{}.toml", name, std::process::id()));
+	std::fs::write(&path, text).unwrap();
+	path
+}
+
+#[test]
+fn defaults() {
+	let config = load(&[]).unwrap();
+
+	assert_eq!(config.listen, default_listen());
+	assert_eq!(config.format, OutputFormat::Json);
+	assert_eq!(config.granularity, OutputMode::Record);
+	assert_eq!(config.stats_interval(), Some(Duration::from_secs(60)));
+	assert!(config.archive_config().is_none());
+}
+
+#[test]
+fn arguments_override_the_config_file() {
+	let path = config_file("merge", r#"
+		listen = ["127.0.0.1:2055", "[::1]:2055"]
+		workers = 4
+		granularity = "datagram"
+		archive = "/tmp/archive"
+		archive_max_files = 10
+		verbosity = 1
+	"#);
+	let path_arg = path.to_str().unwrap();
+
+	let config = load(&["--config", path_arg]).unwrap();
+	assert_eq!(config.listen.len(), 2);
+	assert_eq!(config.listen_ports(), [2055]);
+	assert_eq!(config.workers, 4);
+	assert_eq!(config.granularity, OutputMode::Datagram);
+	assert_eq!(config.archive_config().unwrap().max_files, 10);
+
+	// Listen addresses are replaced, not added to, and -v/-q adjust the configured verbosity
+	let config = load(&["--config", path_arg, "-l", "127.0.0.1:6343", "-w", "2", "--archive-max-files", "3", "-qq"]).unwrap();
+	assert_eq!(config.listen, ["127.0.0.1:6343".parse::<SocketAddr>().unwrap()]);
+	assert_eq!(config.workers, 2);
+	assert_eq!(config.archive_config().unwrap().max_files, 3);
+	assert_eq!(config.verbosity, -1);
+	assert_eq!(config.stats_interval(), None);
+
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn invalid_config_files() {
+	assert!(matches!(load(&["--config", "/nonexistent/multiflow.toml"]), Err(ConfigError::Io(..))));
+
+	let path = config_file("unknown", "listen = [\"127.0.0.1:2055\"]\nworkerz = 4\n");
+	assert!(matches!(load(&["--config", path.to_str().unwrap()]), Err(ConfigError::Parse(..))));
+	std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn batching_is_rejected_with_capture() {
+	assert!(load(&["--capture", "any", "--recv-buffer", "1048576"]).is_ok());
+	assert!(matches!(load(&["--capture", "any", "--batch-size", "8"]), Err(ConfigError::Conflict(_))));
+	assert!(matches!(load(&["--capture", "any", "-t", "2"]), Err(ConfigError::Conflict(_))));
+	assert!(load(&["--batch-size", "8", "-t", "2"]).is_ok());
+
+	// Also when set in the config file
+	let path = config_file("capture", "capture = \"any\"\nbatch_size = 32\n");
+	assert!(matches!(load(&["--config", path.to_str().unwrap()]), Err(ConfigError::Conflict(_))));
+	std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,165 @@
+//! NetFlow encoders: wire layout, template handling, and rejected input
+
+use std::net::Ipv4Addr;
+use multiflow::netflow_parse::datagram_v5::{NetflowDatagramV5, NetflowDatagramV5Record, MAX_V5_RECORDS};
+use multiflow::netflow_parse::datagram_v9::{NetflowDatagramV9, NetflowDatagramV9FlowSet};
+use multiflow::netflow_parse::datagram_v9_data::{NetflowDatagramDataFlowSet, NetflowV9DataField, NetflowV9DataValue};
+use multiflow::netflow_parse::datagram_v9_template::{NetflowDatagramOptionsTemplate, NetflowDatagramOptionsTemplateScopeField,
+	NetflowDatagramOptionsTemplateSet, NetflowDatagramTemplate, NetflowDatagramTemplateField, NetflowDatagramTemplateSet};
+use multiflow::netflow_parse::encode::{EncodeError, NetflowV9Encoder};
+
+fn v5(records: usize) -> NetflowDatagramV5 {
+	NetflowDatagramV5 {
+		sys_uptime_ms: 1000,
+		unix_sec: 1_700_000_000,
+		unix_nsec: 5,
+		flow_seqnum: 42,
+		engine_type: 1,
+		engine_id: 2,
+		sampling_interval: 0x4064,
+		flow_records: vec![NetflowDatagramV5Record::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)); records],
+	}
+}
+
+#[test]
+fn v5_layout() {
+	let bytes = v5(2).to_bytes().unwrap();
+
+	assert_eq!(bytes.len(), 24 + 2 * 48);
+	assert_eq!(bytes[..24], [0, 5, 0, 2, 0, 0, 3, 232, 101, 83, 241, 0, 0, 0, 0, 5, 0, 0, 0, 42, 1, 2, 0x40, 0x64]);
+	assert_eq!(bytes[24..32], [10, 0, 0, 1, 10, 0, 0, 2]);
+}
+
+#[test]
+fn v5_record_limit() {
+	assert!(v5(MAX_V5_RECORDS).to_bytes().is_ok());
+	assert_eq!(v5(MAX_V5_RECORDS + 1).to_bytes().unwrap_err(), EncodeError::TooManyRecords { max: MAX_V5_RECORDS, count: MAX_V5_RECORDS + 1 });
+}
+
+fn datagram(flow_records: Vec<NetflowDatagramV9FlowSet>) -> NetflowDatagramV9 {
+	NetflowDatagramV9 { sys_uptime_ms: 1000, unix_sec: 1_700_000_000, package_sequence: 7, source_id: 3, flow_records }
+}
+
+fn template(template_id: u16, fields: Vec<NetflowDatagramTemplateField>) -> NetflowDatagramTemplate {
+	NetflowDatagramTemplate { template_id, field_count: fields.len() as u16, fields }
+}
+
+fn number(type_id: u16, n: u64) -> NetflowV9DataField {
+	NetflowV9DataField { name: "", type_id, value: NetflowV9DataValue::Number(n), enterprise: None }
+}
+
+#[test]
+fn v9_layout() {
+	let dg = datagram(vec![
+		NetflowDatagramV9FlowSet::Template(NetflowDatagramTemplateSet::from_templates(&[template(256, vec![
+			NetflowDatagramTemplateField::new(7, 2),
+			NetflowDatagramTemplateField::new(4, 1),
+		])])),
+		NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, vec![vec![number(7, 443), number(4, 6)], vec![number(7, 53), number(4, 17)]])),
+	]);
+	let bytes = NetflowV9Encoder::new().encode(&dg).unwrap();
+
+	// The count includes the template and both data records
+	assert_eq!(bytes[..20], [0, 9, 0, 3, 0, 0, 3, 232, 101, 83, 241, 0, 0, 0, 0, 7, 0, 0, 0, 3]);
+	assert_eq!(bytes[20..36], [0, 0, 0, 16, 1, 0, 0, 2, 0, 7, 0, 2, 0, 4, 0, 1]);
+	// Two 3-byte records padded to 8 bytes
+	assert_eq!(bytes[36..], [1, 0, 0, 12, 1, 187, 6, 0, 53, 17, 0, 0]);
+}
+
+#[test]
+fn v9_options_layout() {
+	let options = NetflowDatagramOptionsTemplate {
+		template_id: 257,
+		scope_field_count: 1,
+		option_field_count: 1,
+		scope_fields: vec![NetflowDatagramOptionsTemplateScopeField::new(1, 4)],
+		option_fields: vec![NetflowDatagramTemplateField::new(34, 2)],
+	};
+	let dg = datagram(vec![
+		NetflowDatagramV9FlowSet::TemplateOption(NetflowDatagramOptionsTemplateSet::from_templates(&[options])),
+		NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::options(257, vec![vec![number(1, 9), number(34, 100)]])),
+	]);
+	let bytes = NetflowV9Encoder::new().encode(&dg).unwrap();
+
+	assert_eq!(bytes[2..4], [0, 2]);
+	// Options template set padded from 18 to 20 bytes
+	assert_eq!(bytes[20..40], [0, 1, 0, 20, 1, 1, 0, 4, 0, 4, 0, 1, 0, 4, 0, 34, 0, 2, 0, 0]);
+	// Scope field first, then the option field
+	assert_eq!(bytes[40..], [1, 1, 0, 12, 0, 0, 0, 9, 0, 100, 0, 0]);
+}
+
+#[test]
+fn v9_options_template_set_built_by_hand() {
+	// A second template ID without any fields
+	let set = NetflowDatagramOptionsTemplateSet {
+		length: 0,
+		template_ids: vec![257, 258],
+		scope_fields_lengths: vec![4],
+		option_fields_lengths: vec![4],
+		scope_fields_vec: vec![vec![NetflowDatagramOptionsTemplateScopeField::new(1, 4)]],
+		option_fields_vec: vec![vec![NetflowDatagramTemplateField::new(34, 2)]],
+	};
+	let templates = set.templates().collect::<Vec<_>>();
+	assert_eq!(templates.len(), 1);
+	assert_eq!((templates[0].template_id, templates[0].scope_field_count, templates[0].option_field_count), (257, 1, 1));
+
+	let bytes = NetflowV9Encoder::new().encode(&datagram(vec![NetflowDatagramV9FlowSet::TemplateOption(set)])).unwrap();
+	assert_eq!(bytes[2..4], [0, 1]);
+}
+
+#[test]
+fn v9_templates_are_remembered() {
+	let mut encoder = NetflowV9Encoder::new();
+	let data = datagram(vec![NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, vec![vec![number(1, 1)]]))]);
+	assert_eq!(encoder.encode(&data).unwrap_err(), EncodeError::UnknownTemplate(256));
+
+	let templates = datagram(vec![NetflowDatagramV9FlowSet::Template(NetflowDatagramTemplateSet::from_templates(&[
+		template(256, vec![NetflowDatagramTemplateField::new(1, 4)]),
+	]))]);
+	encoder.encode(&templates).unwrap();
+	assert_eq!(encoder.encode(&data).unwrap()[2..4], [0, 1]);
+
+	let mut encoder = NetflowV9Encoder::new();
+	encoder.register_template(&template(256, vec![NetflowDatagramTemplateField::new(1, 4)]));
+	assert!(encoder.encode(&data).is_ok());
+}
+
+#[test]
+fn v9_failed_datagram_keeps_no_templates() {
+	let mut encoder = NetflowV9Encoder::new();
+	let failing = datagram(vec![
+		NetflowDatagramV9FlowSet::Template(NetflowDatagramTemplateSet::from_templates(&[template(256, vec![NetflowDatagramTemplateField::new(1, 4)])])),
+		NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(300, vec![vec![number(1, 1)]])),
+	]);
+	assert_eq!(encoder.encode(&failing).unwrap_err(), EncodeError::UnknownTemplate(300));
+
+	// Template 256 was never sent, so it cannot be used
+	let data = datagram(vec![NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, vec![vec![number(1, 1)]]))]);
+	assert_eq!(encoder.encode(&data).unwrap_err(), EncodeError::UnknownTemplate(256));
+}
+
+#[test]
+fn v9_rejects_mismatched_records() {
+	let mut encoder = NetflowV9Encoder::new();
+	encoder.register_template(&template(256, vec![NetflowDatagramTemplateField::new(1, 2), NetflowDatagramTemplateField::new(8, 4)]));
+	let encode = |encoder: &mut NetflowV9Encoder, record: Vec<NetflowV9DataField>| {
+		encoder.encode(&datagram(vec![NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, vec![record]))]))
+	};
+
+	assert_eq!(encode(&mut encoder, vec![number(1, 1)]).unwrap_err(), EncodeError::FieldCountMismatch { template_id: 256, expected: 2, count: 1 });
+	// 70000 does not fit into 2 bytes
+	assert_eq!(encode(&mut encoder, vec![number(1, 70000), number(8, 0)]).unwrap_err(), EncodeError::InvalidFieldValue { type_id: 1, length: 2 });
+	// IPv4 address field given a MAC address
+	let mac = NetflowV9DataField { name: "", type_id: 8, value: NetflowV9DataValue::MAC("00:11:22:33:44:55".into()), enterprise: None };
+	assert_eq!(encode(&mut encoder, vec![number(1, 1), mac]).unwrap_err(), EncodeError::InvalidFieldValue { type_id: 8, length: 4 });
+}
+
+#[test]
+fn v9_too_long() {
+	let mut encoder = NetflowV9Encoder::new();
+	encoder.register_template(&template(256, vec![NetflowDatagramTemplateField::new(1, 8)]));
+	let records = (0..10_000).map(|i| vec![number(1, i)]).collect();
+	let dg = datagram(vec![NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, records))]);
+
+	assert_eq!(encoder.encode(&dg).unwrap_err(), EncodeError::TooLong);
+}
@@ -0,0 +1,94 @@
+//! Interface rates from cumulative counters, across wraparounds, restarts, and late samples
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use multiflow::interface_rates::{InterfaceCounterEvent, InterfaceCounterTracker, InterfaceKey, InterfaceRates};
+use multiflow::sflow_parse::sample::counter::SFlowCounterDataGeneric;
+
+const KEY: InterfaceKey = InterfaceKey { agent: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), sub_agent_id: 0, source_id: 3, if_index: 3 };
+
+fn counters(in_octets: u64, out_octets: u64, in_ucast_packets: u32) -> SFlowCounterDataGeneric {
+	SFlowCounterDataGeneric {
+		index: 3,
+		interface_type: 6,
+		speed: 1_000_000_000,
+		direction: 1,
+		status: 3,
+		in_octets,
+		in_ucast_packets,
+		in_multicast_packets: 0,
+		in_broadcast_packets: 0,
+		in_discarded: 0,
+		in_errors: 0,
+		in_unknown_protos: 0,
+		out_octets,
+		out_ucast_packets: 0,
+		out_multicast_packets: 0,
+		out_broadcast_packets: 0,
+		out_discarded: 0,
+		out_errors: 0,
+		out_promiscuous: 0,
+	}
+}
+
+fn rates(event: Option<InterfaceCounterEvent>) -> InterfaceRates {
+	match event {
+		Some(InterfaceCounterEvent::Rates(rates)) => rates,
+		other => panic!("Expected rates, got {:?}", other),
+	}
+}
+
+#[test]
+fn rates_between_samples() {
+	let mut tracker = InterfaceCounterTracker::new();
+	assert_eq!(tracker.observe(KEY, 10_000, &counters(1000, 500, 10)), None);
+
+	let rates = rates(tracker.observe(KEY, 20_000, &counters(11_000, 2500, 110)));
+	assert_eq!(rates.interval, Duration::from_secs(10));
+	assert_eq!((rates.deltas.in_octets, rates.deltas.out_octets, rates.deltas.in_packets), (10_000, 2000, 100));
+	assert_eq!((rates.in_bps, rates.out_bps, rates.in_pps), (8000.0, 1600.0, 10.0));
+}
+
+#[test]
+fn counter_wraparound() {
+	let mut tracker = InterfaceCounterTracker::new();
+	tracker.observe(KEY, 10_000, &counters(u32::MAX as u64 - 99, 0, u32::MAX));
+
+	// 32-bit counters in the 64-bit octet fields and the packet counters wrap around
+	let rates = rates(tracker.observe(KEY, 20_000, &counters(100, 0, 9)));
+	assert_eq!((rates.deltas.in_octets, rates.deltas.in_packets), (200, 10));
+	assert_eq!(tracker.restarts(), 0);
+}
+
+#[test]
+fn uptime_wraparound() {
+	let mut tracker = InterfaceCounterTracker::new();
+	tracker.observe(KEY, u32::MAX - 4999, &counters(0, 0, 0));
+
+	let rates = rates(tracker.observe(KEY, 5000, &counters(1000, 0, 0)));
+	assert_eq!(rates.interval, Duration::from_secs(10));
+}
+
+#[test]
+fn restart_starts_new_baseline() {
+	let mut tracker = InterfaceCounterTracker::new();
+	tracker.observe(KEY, 3_600_000, &counters(1_000_000, 0, 0));
+
+	assert_eq!(tracker.observe(KEY, 30_000, &counters(500, 0, 0)), Some(InterfaceCounterEvent::Restart(KEY)));
+	let rates = rates(tracker.observe(KEY, 40_000, &counters(1500, 0, 0)));
+	assert_eq!(rates.deltas.in_octets, 1000);
+	assert_eq!(tracker.restarts(), 1);
+}
+
+#[test]
+fn late_sample_keeps_baseline() {
+	let mut tracker = InterfaceCounterTracker::new();
+	tracker.observe(KEY, 10_000, &counters(1000, 0, 0));
+	rates(tracker.observe(KEY, 30_000, &counters(3000, 0, 0)));
+
+	// The sample from uptime 20s arrives after the one from 30s
+	assert_eq!(tracker.observe(KEY, 20_000, &counters(2000, 0, 0)), None);
+	let rates = rates(tracker.observe(KEY, 40_000, &counters(4000, 0, 0)));
+	assert_eq!((rates.interval, rates.deltas.in_octets), (Duration::from_secs(10), 1000));
+	assert_eq!(tracker.restarts(), 0);
+}
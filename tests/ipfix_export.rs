@@ -0,0 +1,184 @@
+//! IPFIX messages sent to a local socket: MTU packing, template refresh, and sequence numbers
+
+use std::net::UdpSocket;
+use std::time::Duration;
+use multiflow::ipfix_export::{IpfixExportError, IpfixExporter, IpfixField, IpfixTemplate};
+use multiflow::netflow_parse::datagram_v9_data::NetflowV9DataValue;
+
+/// Header and sets of a received IPFIX message
+#[derive(Debug)]
+struct Message {
+	length: usize,
+	sequence: u32,
+	domain: u32,
+	/// Set IDs and their bodies
+	sets: Vec<(u16, Vec<u8>)>,
+}
+
+impl Message {
+	fn set_ids(&self) -> Vec<u16> {
+		self.sets.iter().map(|(id, _)| *id).collect()
+	}
+
+	/// Number of data records, given the length of a record
+	fn records(&self, record_length: usize) -> usize {
+		self.sets.iter().filter(|(id, _)| *id >= 256).map(|(_, body)| body.len() / record_length).sum()
+	}
+}
+
+fn receiver() -> UdpSocket {
+	let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	socket
+}
+
+fn receive(socket: &UdpSocket) -> Message {
+	let mut buf = [0u8; 65535];
+	let len = socket.recv(&mut buf).unwrap();
+	let bytes = &buf[..len];
+
+	assert_eq!(bytes[..2], [0, 10]);
+	let length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+	assert_eq!(length, len);
+
+	let mut sets = vec![];
+	let mut rest = &bytes[16..];
+	while !rest.is_empty() {
+		let id = u16::from_be_bytes([rest[0], rest[1]]);
+		let set_length = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+		assert!(set_length >= 4 && set_length <= rest.len(), "Bad set length {}", set_length);
+		sets.push((id, rest[4..set_length].to_vec()));
+		rest = &rest[set_length..];
+	}
+
+	Message {
+		length,
+		sequence: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+		domain: u32::from_be_bytes(bytes[12..16].try_into().unwrap()),
+		sets,
+	}
+}
+
+/// Template 256 with a single 4-byte octetDeltaCount field
+fn template() -> IpfixTemplate {
+	IpfixTemplate::new(256, vec![IpfixField::new(1, 4)])
+}
+
+fn record(n: u64) -> [NetflowV9DataValue; 1] {
+	[NetflowV9DataValue::Number(n)]
+}
+
+#[test]
+fn messages_fit_the_mtu() {
+	let socket = receiver();
+	let mut exporter = IpfixExporter::new(socket.local_addr().unwrap()).unwrap().with_mtu(200);
+	exporter.add_template(1, template()).unwrap();
+	for n in 0..100 {
+		exporter.add_record(1, 256, &record(n)).unwrap();
+	}
+	exporter.flush().unwrap();
+
+	let messages = (0..exporter.messages_sent()).map(|_| receive(&socket)).collect::<Vec<_>>();
+	assert!(messages.len() > 1);
+	// 200 bytes minus the IPv4 and UDP headers
+	assert!(messages.iter().all(|m| m.length <= 172));
+	// Only the last message has room to spare
+	assert!(messages[..messages.len() - 1].iter().all(|m| m.length > 168));
+
+	assert_eq!(messages[0].set_ids(), [2, 256]);
+	assert_eq!(messages[0].sets[0].1, [1, 0, 0, 1, 0, 1, 0, 4]);
+	assert!(messages[1..].iter().all(|m| m.set_ids() == [256]));
+	assert_eq!(messages.iter().map(|m| m.records(4)).sum::<usize>(), 100);
+}
+
+#[test]
+fn templates_are_refreshed() {
+	let socket = receiver();
+	let mut exporter = IpfixExporter::new(socket.local_addr().unwrap()).unwrap();
+	exporter.add_template(1, template()).unwrap();
+
+	exporter.add_record(1, 256, &record(1)).unwrap();
+	exporter.flush().unwrap();
+	assert_eq!(receive(&socket).set_ids(), [2, 256]);
+	exporter.add_record(1, 256, &record(2)).unwrap();
+	exporter.flush().unwrap();
+	assert_eq!(receive(&socket).set_ids(), [256]);
+
+	// Redefining a template sends it again
+	exporter.add_template(1, IpfixTemplate::new(256, vec![IpfixField::new(2, 4)])).unwrap();
+	exporter.add_record(1, 256, &record(3)).unwrap();
+	exporter.flush().unwrap();
+	assert_eq!(receive(&socket).set_ids(), [2, 256]);
+
+	let mut exporter = IpfixExporter::new(socket.local_addr().unwrap()).unwrap().with_template_refresh(Duration::ZERO);
+	exporter.add_template(1, template()).unwrap();
+	for n in 0..2 {
+		exporter.add_record(1, 256, &record(n)).unwrap();
+		exporter.flush().unwrap();
+		assert_eq!(receive(&socket).set_ids()[0], 2);
+	}
+}
+
+#[test]
+fn sequence_numbers_per_domain() {
+	let socket = receiver();
+	let mut exporter = IpfixExporter::new(socket.local_addr().unwrap()).unwrap();
+	exporter.add_template(1, template()).unwrap();
+	exporter.add_template(2, template()).unwrap();
+
+	// Flushing sends one message per domain, in no particular order
+	let flush = |exporter: &mut IpfixExporter| {
+		let sent = exporter.messages_sent();
+		exporter.flush().unwrap();
+		let mut messages = (sent..exporter.messages_sent()).map(|_| receive(&socket)).collect::<Vec<_>>();
+		messages.sort_by_key(|m| m.domain);
+		messages.iter().map(|m| (m.domain, m.sequence, m.records(4))).collect::<Vec<_>>()
+	};
+
+	for n in 0..3 {
+		exporter.add_record(1, 256, &record(n)).unwrap();
+	}
+	for n in 0..2 {
+		exporter.add_record(2, 256, &record(n)).unwrap();
+	}
+	assert_eq!(flush(&mut exporter), [(1, 0, 3), (2, 0, 2)]);
+
+	exporter.add_record(1, 256, &record(0)).unwrap();
+	exporter.add_record(2, 256, &record(0)).unwrap();
+	assert_eq!(flush(&mut exporter), [(1, 3, 1), (2, 2, 1)]);
+	assert_eq!((exporter.sequence(1), exporter.sequence(2)), (4, 3));
+}
+
+#[test]
+fn invalid_input() {
+	let mut exporter = IpfixExporter::new("127.0.0.1:4739".parse().unwrap()).unwrap();
+	assert!(matches!(exporter.add_template(1, IpfixTemplate::new(255, vec![])), Err(IpfixExportError::InvalidTemplateId(255))));
+	assert!(matches!(exporter.add_record(1, 256, &record(1)), Err(IpfixExportError::Encode(_))));
+
+	exporter.add_template(1, template()).unwrap();
+	assert!(matches!(exporter.add_record(1, 256, &[]), Err(IpfixExportError::Encode(_))));
+	assert_eq!(exporter.messages_sent(), 0);
+}
+
+#[test]
+fn failed_messages_are_sent_again() {
+	// Nothing listens on the port yet, so the second send is refused once the ICMP error for the first one arrived
+	let addr = receiver().local_addr().unwrap();
+	let mut exporter = IpfixExporter::new(addr).unwrap();
+	exporter.add_template(1, template()).unwrap();
+	exporter.add_record(1, 256, &record(0)).unwrap();
+	exporter.flush().unwrap();
+	std::thread::sleep(Duration::from_millis(50));
+
+	exporter.add_record(1, 256, &record(1)).unwrap();
+	exporter.add_record(1, 256, &record(2)).unwrap();
+	assert!(matches!(exporter.flush(), Err(IpfixExportError::Io(_))));
+	assert_eq!(exporter.messages_sent(), 1);
+
+	let socket = UdpSocket::bind(addr).unwrap();
+	socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	exporter.flush().unwrap();
+	let message = receive(&socket);
+	assert_eq!((message.sequence, message.records(4)), (1, 2));
+	assert_eq!(exporter.sequence(1), 3);
+}
@@ -0,0 +1,201 @@
+//! NetFlow v9 flow set framing: every set is parsed within its own length
+
+use std::net::{Ipv4Addr, SocketAddr};
+use multiflow::netflow_parse::datagram::NetflowDatagramData;
+use multiflow::netflow_parse::datagram_v9::{NetflowDatagramV9, NetflowDatagramV9FlowSet};
+use multiflow::netflow_parse::datagram_v9_data::{NetflowDatagramDataFlowSet, NetflowV9DataField, NetflowV9DataValue};
+use multiflow::netflow_parse::datagram_v9_template::{NetflowDatagramTemplate, NetflowDatagramTemplateField, NetflowDatagramTemplateSet};
+use multiflow::netflow_parse::encode::NetflowV9Encoder;
+use multiflow::netflow_parse::NetflowParser;
+
+fn exporter() -> SocketAddr {
+	"192.0.2.1:2055".parse().unwrap()
+}
+
+fn datagram(flow_records: Vec<NetflowDatagramV9FlowSet>) -> NetflowDatagramV9 {
+	NetflowDatagramV9 { sys_uptime_ms: 1000, unix_sec: 1_700_000_000, package_sequence: 1, source_id: 0, flow_records }
+}
+
+fn template_set(template_id: u16, fields: Vec<NetflowDatagramTemplateField>) -> NetflowDatagramV9FlowSet {
+	let template = NetflowDatagramTemplate { template_id, field_count: fields.len() as u16, fields };
+
+	NetflowDatagramV9FlowSet::Template(NetflowDatagramTemplateSet::from_templates(&[template]))
+}
+
+fn number(type_id: u16, n: u64) -> NetflowV9DataField {
+	NetflowV9DataField { name: "", type_id, value: NetflowV9DataValue::Number(n), enterprise: None }
+}
+
+fn parse(bytes: &[u8]) -> Option<NetflowDatagramV9> {
+	match NetflowParser::new().parse(bytes, &exporter()) {
+		Ok((_, NetflowDatagramData::DatagramV9(dg))) => Some(dg),
+		_ => None,
+	}
+}
+
+#[test]
+fn padded_data_set_followed_by_another_set() {
+	// 5-byte records get 3 bytes of padding
+	let dg = datagram(vec![
+		template_set(256, vec![NetflowDatagramTemplateField::new(4, 1), NetflowDatagramTemplateField::new(1, 4)]),
+		NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, vec![vec![number(4, 6), number(1, 1500)]])),
+		NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, vec![vec![number(4, 17), number(1, 80)]])),
+	]);
+	let bytes = NetflowV9Encoder::new().encode(&dg).unwrap();
+
+	let parsed = parse(&bytes).unwrap();
+	assert_eq!(parsed.flow_records.len(), 3);
+	let NetflowDatagramV9FlowSet::Data(last) = &parsed.flow_records[2] else { panic!("Not a data set") };
+	assert!(matches!(last.record_list()[0][0].value, NetflowV9DataValue::Number(17)));
+}
+
+#[test]
+fn more_than_30_flow_sets() {
+	let mut sets = vec![template_set(256, vec![NetflowDatagramTemplateField::new(1, 4)])];
+	for i in 0..40 {
+		sets.push(NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, vec![vec![number(1, i)]])));
+	}
+	let bytes = NetflowV9Encoder::new().encode(&datagram(sets)).unwrap();
+
+	assert_eq!(parse(&bytes).unwrap().flow_records.len(), 41);
+}
+
+#[test]
+fn record_count_mismatch() {
+	let dg = datagram(vec![
+		template_set(256, vec![NetflowDatagramTemplateField::new(1, 4)]),
+		NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, vec![vec![number(1, 1)], vec![number(1, 2)]])),
+	]);
+	let mut bytes = NetflowV9Encoder::new().encode(&dg).unwrap();
+	let mut parser = NetflowParser::new();
+	assert!(parser.parse(&bytes, &exporter()).is_ok());
+	assert_eq!(parser.record_count_mismatches(), 0);
+
+	// Exporters filling in the count inaccurately are only counted
+	bytes[3] = 2;
+	let Ok((_, NetflowDatagramData::DatagramV9(parsed))) = parser.parse(&bytes, &exporter()) else { panic!("Not parsed") };
+	assert_eq!(parsed.flow_records.len(), 2);
+	assert_eq!(parser.record_count_mismatches(), 1);
+}
+
+#[test]
+fn set_longer_than_datagram() {
+	let dg = datagram(vec![template_set(256, vec![NetflowDatagramTemplateField::new(1, 4)])]);
+	let mut bytes = NetflowV9Encoder::new().encode(&dg).unwrap();
+	bytes[23] += 4;
+
+	assert!(parse(&bytes).is_none());
+}
+
+#[test]
+fn field_of_unexpected_length_keeps_following_fields_aligned() {
+	// An IPv4 address field of 8 bytes cannot be decoded, but must not shift the port after it
+	let dg = datagram(vec![
+		template_set(256, vec![NetflowDatagramTemplateField::new(8, 8), NetflowDatagramTemplateField::new(7, 2)]),
+		NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, vec![vec![
+			NetflowV9DataField { name: "", type_id: 8, value: NetflowV9DataValue::Unknown(vec![10, 0, 0, 1, 0, 0, 0, 0]), enterprise: None },
+			number(7, 443),
+		]])),
+	]);
+	let bytes = NetflowV9Encoder::new().encode(&dg).unwrap();
+
+	let parsed = parse(&bytes).unwrap();
+	let NetflowDatagramV9FlowSet::Data(data) = &parsed.flow_records[1] else { panic!("Not a data set") };
+	let record = &data.record_list()[0];
+	assert!(matches!(&record[0].value, NetflowV9DataValue::Unknown(b) if b.len() == 8));
+	assert!(matches!(record[1].value, NetflowV9DataValue::Number(443)));
+}
+
+#[test]
+fn regular_ipv4_field() {
+	let dg = datagram(vec![
+		template_set(256, vec![NetflowDatagramTemplateField::new(8, 4)]),
+		NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, vec![vec![
+			NetflowV9DataField { name: "", type_id: 8, value: NetflowV9DataValue::IPv4(Ipv4Addr::new(10, 0, 0, 1)), enterprise: None },
+		]])),
+	]);
+	let bytes = NetflowV9Encoder::new().encode(&dg).unwrap();
+
+	let parsed = parse(&bytes).unwrap();
+	let NetflowDatagramV9FlowSet::Data(data) = &parsed.flow_records[1] else { panic!("Not a data set") };
+	assert!(matches!(data.record_list()[0][0].value, NetflowV9DataValue::IPv4(a) if a == Ipv4Addr::new(10, 0, 0, 1)));
+}
+
+/// Raw NetFlow v9 datagram with `count` records in the given flow sets
+fn raw_datagram(count: u16, sets: &[&[u8]]) -> Vec<u8> {
+	let mut bytes = vec![0, 9];
+	bytes.extend_from_slice(&count.to_be_bytes());
+	bytes.extend_from_slice(&[0, 0, 3, 232, 101, 83, 241, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+	for set in sets {
+		bytes.extend_from_slice(set);
+	}
+	bytes
+}
+
+#[test]
+fn options_data_records_include_scope_fields() {
+	// Options template 257: scope interface (4 bytes), options SAMPLING_INTERVAL (4 bytes) and SAMPLING_ALGORITHM (1 byte)
+	let template: &[u8] = &[0, 1, 0, 24, 1, 1, 0, 4, 0, 8, 0, 2, 0, 4, 0, 34, 0, 4, 0, 35, 0, 1, 0, 0];
+	// Two 9-byte records and 2 bytes of padding
+	let data: &[u8] = &[1, 1, 0, 24, 0, 0, 0, 7, 0, 0, 0, 100, 1, 0, 0, 0, 8, 0, 0, 3, 232, 2, 0, 0];
+
+	let parsed = parse(&raw_datagram(3, &[template, data])).unwrap();
+	let NetflowDatagramV9FlowSet::TemplateOption(templates) = &parsed.flow_records[0] else { panic!("Not an options template set") };
+	assert_eq!(templates.templates().next().unwrap().total_field_length(), 9);
+
+	let NetflowDatagramV9FlowSet::Data(data) = &parsed.flow_records[1] else { panic!("Not a data set") };
+	let records = data.record_list();
+	assert_eq!(records.len(), 2);
+	let values = records.iter().map(|r| r.iter().map(|f| match f.value {
+		NetflowV9DataValue::Number(n) => (f.type_id, n),
+		_ => panic!("Not a number: {:?}", f),
+	}).collect::<Vec<_>>()).collect::<Vec<_>>();
+	assert_eq!(values, [[(2, 7), (34, 100), (35, 1)], [(2, 8), (34, 1000), (35, 2)]]);
+}
+
+#[test]
+fn unknown_fields_keep_their_type() {
+	// Template 256: unknown type 40000 (2 bytes), L4_SRC_PORT (2 bytes)
+	let template: &[u8] = &[0, 0, 0, 16, 1, 0, 0, 2, 156, 64, 0, 2, 0, 7, 0, 2];
+	let data: &[u8] = &[1, 0, 0, 8, 0xab, 0xcd, 1, 187];
+
+	let parsed = parse(&raw_datagram(2, &[template, data])).unwrap();
+	let NetflowDatagramV9FlowSet::Template(templates) = &parsed.flow_records[0] else { panic!("Not a template set") };
+	let fields = templates.templates().next().unwrap().fields;
+	assert_eq!((fields[0].type_id, fields[0].field_type), (40000, None));
+	assert_eq!(fields[1].type_id, 7);
+
+	let NetflowDatagramV9FlowSet::Data(data) = &parsed.flow_records[1] else { panic!("Not a data set") };
+	let record = &data.record_list()[0];
+	assert_eq!(record[0].type_id, 40000);
+	assert!(matches!(&record[0].value, NetflowV9DataValue::Unknown(b) if b[..] == [0xab, 0xcd]));
+	assert_eq!(record[1].type_id, 7);
+	assert!(matches!(record[1].value, NetflowV9DataValue::Number(443)));
+}
+
+#[test]
+fn enterprise_fields_when_enabled() {
+	// Template 256: IN_BYTES (4 bytes), reverse IN_BYTES (4 bytes), type 100 of enterprise 9 (2 bytes)
+	let template: &[u8] = &[0, 0, 0, 28, 1, 0, 0, 3, 0, 1, 0, 4, 0x80, 1, 0, 4, 0, 0, 0x72, 0x79, 0x80, 100, 0, 2, 0, 0, 0, 9];
+	let data: &[u8] = &[1, 0, 0, 16, 0, 0, 5, 220, 0, 0, 1, 44, 0xab, 0xcd, 0, 0];
+	let bytes = raw_datagram(2, &[template, data]);
+
+	// Without enterprise fields, the enterprise numbers are read as field specifiers
+	assert!(parse(&bytes).is_none());
+
+	let mut parser = NetflowParser::new().with_enterprise_fields(true);
+	let Ok((_, NetflowDatagramData::DatagramV9(parsed))) = parser.parse(&bytes, &exporter()) else { panic!("Not parsed") };
+	let NetflowDatagramV9FlowSet::Template(templates) = &parsed.flow_records[0] else { panic!("Not a template set") };
+	let fields = &templates.fields_vec[0];
+	assert_eq!(fields[1], NetflowDatagramTemplateField::enterprise(1, 29305, 4));
+	assert_eq!((fields[2].type_id, fields[2].enterprise, fields[2].field_type), (100, Some(9), None));
+
+	// Reverse fields have the type of their forward counterpart
+	let NetflowDatagramV9FlowSet::Data(data) = &parsed.flow_records[1] else { panic!("Not a data set") };
+	let record = &data.record_list()[0];
+	assert_eq!(record.iter().map(|f| (f.name, f.type_id, f.enterprise)).collect::<Vec<_>>(),
+		[("IN_BYTES", 1, None), ("IN_BYTES", 1, Some(29305)), ("UNKNOWN", 100, Some(9))]);
+	assert!(matches!(record[1].value, NetflowV9DataValue::Number(300)));
+
+	assert_eq!(NetflowV9Encoder::new().encode(&parsed).unwrap(), bytes);
+}
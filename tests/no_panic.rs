@@ -0,0 +1,127 @@
+//! The parsers must reject malformed input with an error instead of panicking
+
+use std::net::SocketAddr;
+use proptest::prelude::*;
+use multiflow::netflow_parse::NetflowParser;
+use multiflow::sflow_parse::datagram::parse_sflow_data;
+
+fn exporter() -> SocketAddr {
+	"192.0.2.1:2055".parse().unwrap()
+}
+
+/// NetFlow v9 header with the given flow sets appended
+fn v9(sets: &[u8]) -> Vec<u8> {
+	let mut dg = vec![0, 9, 0, 1, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4];
+	dg.extend_from_slice(sets);
+	dg
+}
+
+/// Parse `input` twice with the same parser, so data flow sets can use the templates from the first pass
+fn parse_netflow_twice(input: &[u8]) {
+	let mut parser = NetflowParser::new();
+	let _ = parser.parse(input, &exporter());
+	let _ = parser.parse(input, &exporter());
+}
+
+#[test]
+fn template_set_shorter_than_header() {
+	for length in 0..4u8 {
+		parse_netflow_twice(&v9(&[0, 0, 0, length, 1, 0, 0, 0]));
+		parse_netflow_twice(&v9(&[0, 1, 0, length, 1, 0, 0, 0, 0, 0]));
+	}
+}
+
+#[test]
+fn template_longer_than_set() {
+	// Template 256 claims 100 fields in a 12 byte set
+	parse_netflow_twice(&v9(&[0, 0, 0, 12, 1, 0, 0, 100, 0, 1, 0, 4]));
+	// Options template 257 claims 64 bytes of scope fields in a 16 byte set
+	parse_netflow_twice(&v9(&[0, 1, 0, 16, 1, 1, 0, 64, 0, 4, 0, 1, 0, 4, 0, 0]));
+	// Options set whose padding lies beyond the end of the datagram
+	parse_netflow_twice(&v9(&[0, 1, 0, 200, 1, 1, 0, 4, 0, 4, 0, 1, 0, 4]));
+}
+
+#[test]
+fn zero_length_template() {
+	// Template 256 with a single zero-length field, followed by data using it
+	parse_netflow_twice(&v9(&[0, 0, 0, 12, 1, 0, 0, 1, 0, 1, 0, 0, 1, 0, 0, 8, 0, 0, 0, 0]));
+	// Options template 257 without any fields, followed by data using it
+	parse_netflow_twice(&v9(&[0, 1, 0, 12, 1, 1, 0, 0, 0, 0, 0, 0, 1, 1, 0, 8, 0, 0, 0, 0]));
+}
+
+#[test]
+fn data_set_shorter_than_header() {
+	for length in 0..4u8 {
+		parse_netflow_twice(&v9(&[0, 0, 0, 12, 1, 0, 0, 1, 0, 1, 0, 4, 1, 0, 0, length, 0, 0, 0, 0]));
+	}
+}
+
+#[test]
+fn template_fields_overflowing_record_length() {
+	// Two fields of 65535 bytes each
+	parse_netflow_twice(&v9(&[0, 0, 0, 16, 1, 0, 0, 2, 0, 1, 255, 255, 0, 2, 255, 255, 1, 0, 0, 8, 0, 0, 0, 0]));
+}
+
+#[test]
+fn invalid_templates_are_rejected() {
+	let invalid = [
+		// Template set length below the set header
+		v9(&[0, 0, 0, 2, 1, 0, 0, 0]),
+		// Options template set length below the set header
+		v9(&[0, 1, 0, 2, 1, 1, 0, 0, 0, 0]),
+		// Template with a zero-length field
+		v9(&[0, 0, 0, 12, 1, 0, 0, 1, 0, 1, 0, 0]),
+		// Template without fields
+		v9(&[0, 0, 0, 8, 1, 0, 0, 0]),
+		// Template with a reserved ID
+		v9(&[0, 0, 0, 12, 0, 255, 0, 1, 0, 1, 0, 4]),
+		// Options template without fields
+		v9(&[0, 1, 0, 12, 1, 1, 0, 0, 0, 0, 0, 0]),
+		// Options template with a scope length that is not a multiple of 4
+		v9(&[0, 1, 0, 20, 1, 1, 0, 6, 0, 4, 0, 1, 0, 4, 0, 1, 0, 4, 0, 0]),
+		// Options template longer than its set
+		v9(&[0, 1, 0, 16, 1, 1, 0, 64, 0, 4, 0, 1, 0, 4, 0, 0]),
+	];
+
+	for dg in invalid {
+		assert!(NetflowParser::new().parse(&dg, &exporter()).is_err(), "Accepted {:?}", dg);
+	}
+}
+
+#[test]
+fn zero_length_template_is_not_registered() {
+	let mut parser = NetflowParser::new();
+	let _ = parser.parse(&v9(&[0, 0, 0, 12, 1, 0, 0, 1, 0, 1, 0, 0]), &exporter());
+
+	assert!(parser.parse(&v9(&[1, 0, 0, 8, 0, 0, 0, 0]), &exporter()).is_err());
+}
+
+proptest! {
+	#[test]
+	fn netflow_arbitrary_bytes(input in prop::collection::vec(any::<u8>(), 0..2000)) {
+		parse_netflow_twice(&input);
+	}
+
+	#[test]
+	fn netflow_arbitrary_v9_sets(sets in prop::collection::vec(any::<u8>(), 0..500)) {
+		parse_netflow_twice(&v9(&sets));
+	}
+
+	#[test]
+	fn netflow_v9_sets_from_small_values(sets in prop::collection::vec(prop_oneof![Just(0u8), Just(1), Just(4), any::<u8>()], 0..200)) {
+		// Small numbers make valid looking set IDs, template IDs, and lengths much more likely
+		parse_netflow_twice(&v9(&sets));
+	}
+
+	#[test]
+	fn sflow_arbitrary_bytes(input in prop::collection::vec(any::<u8>(), 0..2000)) {
+		let _ = parse_sflow_data(&input);
+	}
+
+	#[test]
+	fn sflow_arbitrary_samples(samples in prop::collection::vec(prop_oneof![Just(0u8), Just(1), Just(2), any::<u8>()], 0..500)) {
+		let mut dg = vec![0, 0, 0, 5, 0, 0, 0, 1, 192, 0, 2, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 4];
+		dg.extend_from_slice(&samples);
+		let _ = parse_sflow_data(&dg);
+	}
+}
@@ -0,0 +1,166 @@
+//! JSON lines written for every protocol and output mode
+#![cfg(feature = "json")]
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, UNIX_EPOCH};
+use serde_json::{json, Value};
+use multiflow::netflow_parse::datagram::NetflowDatagramData;
+use multiflow::netflow_parse::datagram_v5::{NetflowDatagramV5, NetflowDatagramV5Record};
+use multiflow::netflow_parse::datagram_v9::{NetflowDatagramV9, NetflowDatagramV9FlowSet};
+use multiflow::netflow_parse::datagram_v9_data::{NetflowDatagramDataFlowSet, NetflowV9DataField, NetflowV9DataValue};
+use multiflow::output::{JsonLinesWriter, OutputMode};
+use multiflow::sflow_parse::datagram::Datagram;
+use multiflow::sflow_parse::sample::flow::{SFlowFlowRawPacketHeader, SFlowFlowSample, SFlowFlowSampleRecord};
+use multiflow::sflow_parse::sample::SFlowSample;
+
+const EXPORTER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 2055);
+
+/// Write with `write` in `mode` and parse the written lines
+fn written<F: FnOnce(&mut JsonLinesWriter<Vec<u8>>)>(mode: OutputMode, write: F) -> Vec<Value> {
+	let mut writer = JsonLinesWriter::new(Vec::new(), mode);
+	write(&mut writer);
+	let out = String::from_utf8(writer.into_inner()).unwrap();
+	assert!(out.ends_with('\n'));
+
+	out.lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+}
+
+fn v5() -> NetflowDatagramData {
+	NetflowDatagramData::DatagramV5(NetflowDatagramV5 {
+		sys_uptime_ms: 1000,
+		unix_sec: 1_700_000_000,
+		unix_nsec: 0,
+		flow_seqnum: 7,
+		engine_type: 0,
+		engine_id: 0,
+		sampling_interval: 0,
+		flow_records: vec![NetflowDatagramV5Record::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)); 2],
+	})
+}
+
+fn field(name: &'static str, type_id: u16, value: NetflowV9DataValue) -> NetflowV9DataField {
+	NetflowV9DataField { name, type_id, value, enterprise: None }
+}
+
+fn v9(records: Vec<Vec<NetflowV9DataField>>) -> NetflowDatagramData {
+	NetflowDatagramData::DatagramV9(NetflowDatagramV9 {
+		sys_uptime_ms: 1000,
+		unix_sec: 1_700_000_000,
+		package_sequence: 3,
+		source_id: 9,
+		flow_records: vec![NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, records))],
+	})
+}
+
+fn sflow() -> Datagram {
+	let sample = SFlowFlowSample {
+		seq: 1,
+		src: 3,
+		rate: 100,
+		pool: 1000,
+		dropped: 0,
+		input_if: 1,
+		output_if: 2,
+		record_count: 1,
+		records: vec![SFlowFlowSampleRecord::Raw(SFlowFlowRawPacketHeader::new(1, 64, 4, vec![1, 2, 3, 4]))],
+	};
+
+	Datagram {
+		sflow_version: 5,
+		agent_addr: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+		sub_agent_id: 0,
+		seq_num: 5,
+		uptime: 1000,
+		sample_record: vec![SFlowSample::Flow(sample.clone()), SFlowSample::Flow(sample)],
+	}
+}
+
+#[test]
+fn netflow_v5_records() {
+	let received = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+	let lines = written(OutputMode::Record, |w| w.write_netflow(&EXPORTER, received, &v5()).unwrap());
+
+	assert_eq!(lines.len(), 2);
+	for line in &lines {
+		assert_eq!(line["received_at"], 1_700_000_000_123u64);
+		assert_eq!(line["exporter"], "192.0.2.1:2055");
+		assert_eq!(line["protocol"], "netflow_v5");
+		assert_eq!(line["header"], json!({ "sys_uptime_ms": 1000, "unix_sec": 1_700_000_000, "sequence": 7 }));
+		assert!(line["record"].is_object());
+	}
+}
+
+#[test]
+fn netflow_v9_fields() {
+	let dg = v9(vec![vec![
+		field("IN_BYTES", 1, NetflowV9DataValue::Number(100)),
+		field("IPV4_SRC_ADDR", 8, NetflowV9DataValue::IPv4(Ipv4Addr::new(10, 0, 0, 1))),
+		field("UNKNOWN", 40000, NetflowV9DataValue::Unknown(vec![0xAB, 0xCD])),
+		field("IN_BYTES", 1, NetflowV9DataValue::Number(200)),
+		field("UNKNOWN", 40000, NetflowV9DataValue::Unknown(vec![0xEF])),
+	]]);
+	let lines = written(OutputMode::Record, |w| w.write_netflow(&EXPORTER, UNIX_EPOCH, &dg).unwrap());
+
+	assert_eq!(lines.len(), 1);
+	assert_eq!(lines[0]["protocol"], "netflow_v9");
+	assert_eq!(lines[0]["header"]["source_id"], 9);
+	assert_eq!(lines[0]["record"]["template_id"], 256);
+	assert_eq!(lines[0]["record"]["options"], false);
+	// Unknown fields are keyed by type, repeated names are numbered
+	assert_eq!(lines[0]["record"]["fields"], json!({
+		"IN_BYTES": 100,
+		"IPV4_SRC_ADDR": "10.0.0.1",
+		"UNKNOWN_40000": "abcd",
+		"IN_BYTES_2": 200,
+		"UNKNOWN_40000_2": "ef",
+	}));
+}
+
+#[test]
+fn netflow_v9_enterprise_fields() {
+	let enterprise = |enterprise, f: NetflowV9DataField| NetflowV9DataField { enterprise: Some(enterprise), ..f };
+	let dg = v9(vec![vec![
+		field("IN_BYTES", 1, NetflowV9DataValue::Number(100)),
+		enterprise(29305, field("IN_BYTES", 1, NetflowV9DataValue::Number(200))),
+		enterprise(29305, field("UNKNOWN", 40000, NetflowV9DataValue::Unknown(vec![1]))),
+		enterprise(9, field("UNKNOWN", 100, NetflowV9DataValue::Unknown(vec![2]))),
+	]]);
+	let lines = written(OutputMode::Record, |w| w.write_netflow(&EXPORTER, UNIX_EPOCH, &dg).unwrap());
+
+	assert_eq!(lines[0]["record"]["fields"], json!({
+		"IN_BYTES": 100,
+		"REVERSE_IN_BYTES": 200,
+		"UNKNOWN_29305_40000": "01",
+		"UNKNOWN_9_100": "02",
+	}));
+}
+
+#[test]
+fn netflow_datagram_mode() {
+	let lines = written(OutputMode::Datagram, |w| {
+		w.write_netflow(&EXPORTER, UNIX_EPOCH, &v5()).unwrap();
+		w.write_netflow(&EXPORTER, UNIX_EPOCH, &v9(vec![])).unwrap();
+	});
+
+	assert_eq!(lines.len(), 2);
+	assert_eq!(lines[0]["protocol"], "netflow_v5");
+	assert_eq!(lines[0]["datagram"]["DatagramV5"]["flow_seqnum"], 7);
+	assert_eq!(lines[1]["protocol"], "netflow_v9");
+	assert!(lines[1].get("record").is_none());
+}
+
+#[test]
+fn sflow_samples() {
+	let lines = written(OutputMode::Record, |w| w.write_sflow(&EXPORTER, UNIX_EPOCH, &sflow()).unwrap());
+
+	assert_eq!(lines.len(), 2);
+	for line in &lines {
+		assert_eq!(line["protocol"], "sflow_v5");
+		assert_eq!(line["header"], json!({ "agent_addr": "192.0.2.1", "sub_agent_id": 0, "seq_num": 5, "uptime": 1000 }));
+		assert_eq!(line["record"]["Flow"]["rate"], 100);
+	}
+
+	let lines = written(OutputMode::Datagram, |w| w.write_sflow(&EXPORTER, UNIX_EPOCH, &sflow()).unwrap());
+	assert_eq!(lines.len(), 1);
+	assert_eq!(lines[0]["datagram"]["seq_num"], 5);
+}
@@ -0,0 +1,193 @@
+//! Reading flow datagrams from pcap and pcapng capture files
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, UNIX_EPOCH};
+use multiflow::netflow_parse::datagram::NetflowDatagramData;
+use multiflow::netflow_parse::datagram_v5::{NetflowDatagramV5, NetflowDatagramV5Record};
+use multiflow::netflow_parse::NetflowParser;
+use multiflow::pcap::{PcapReader, LINKTYPE_ETHERNET, LINKTYPE_RAW};
+
+fn v5_datagram() -> Vec<u8> {
+	NetflowDatagramV5 {
+		sys_uptime_ms: 1000,
+		unix_sec: 1_700_000_000,
+		unix_nsec: 0,
+		flow_seqnum: 7,
+		engine_type: 0,
+		engine_id: 0,
+		sampling_interval: 0,
+		flow_records: vec![NetflowDatagramV5Record::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2))],
+	}.to_bytes().unwrap()
+}
+
+/// IPv4 and UDP headers from 192.0.2.1:40000 to 192.0.2.2:2055 around `payload`
+fn ipv4_udp(payload: &[u8]) -> Vec<u8> {
+	ipv4_udp_to(2055, payload)
+}
+
+/// IPv4 and UDP headers from 192.0.2.1:40000 to 192.0.2.2 port `dst_port` around `payload`
+fn ipv4_udp_to(dst_port: u16, payload: &[u8]) -> Vec<u8> {
+	let udp_length = 8 + payload.len() as u16;
+	let mut packet = vec![0x45, 0, 0, 0, 0, 1, 0, 0, 64, 17, 0, 0, 192, 0, 2, 1, 192, 0, 2, 2];
+	packet[2..4].copy_from_slice(&(20 + udp_length).to_be_bytes());
+	packet.extend_from_slice(&40000u16.to_be_bytes());
+	packet.extend_from_slice(&dst_port.to_be_bytes());
+	packet.extend_from_slice(&udp_length.to_be_bytes());
+	packet.extend_from_slice(&[0, 0]);
+	packet.extend_from_slice(payload);
+	packet
+}
+
+/// VLAN tagged Ethernet frame around `ip`
+fn ethernet_vlan(ip: &[u8]) -> Vec<u8> {
+	let mut frame = vec![2, 0, 0, 0, 0, 2, 2, 0, 0, 0, 0, 1, 0x81, 0x00, 0, 100, 0x08, 0x00];
+	frame.extend_from_slice(ip);
+	frame
+}
+
+/// Little-endian pcap file with microsecond timestamps
+fn pcap(link_type: u32, packets: &[(u32, u32, &[u8])]) -> Vec<u8> {
+	let mut file = vec![0xD4, 0xC3, 0xB2, 0xA1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0];
+	file.extend_from_slice(&link_type.to_le_bytes());
+	for (sec, usec, data) in packets {
+		for v in [*sec, *usec, data.len() as u32, data.len() as u32] {
+			file.extend_from_slice(&v.to_le_bytes());
+		}
+		file.extend_from_slice(data);
+	}
+	file
+}
+
+/// Big-endian pcapng block of type `block_type`
+fn pcapng_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+	let padded = body.len().next_multiple_of(4);
+	let length = (padded + 12) as u32;
+	let mut block = block_type.to_be_bytes().to_vec();
+	block.extend_from_slice(&length.to_be_bytes());
+	block.extend_from_slice(body);
+	block.resize(8 + padded, 0);
+	block.extend_from_slice(&length.to_be_bytes());
+	block
+}
+
+fn pcapng_section() -> Vec<u8> {
+	// Byte order magic, version 1.0, unknown section length
+	let mut body = vec![0x1A, 0x2B, 0x3C, 0x4D, 0, 1, 0, 0];
+	body.extend_from_slice(&[0xFF; 8]);
+	pcapng_block(0x0A0D0D0A, &body)
+}
+
+fn pcapng_enhanced_packet(interface: u32, ticks: u64, data: &[u8]) -> Vec<u8> {
+	let mut body = vec![];
+	for v in [interface, (ticks >> 32) as u32, ticks as u32, data.len() as u32, data.len() as u32] {
+		body.extend_from_slice(&v.to_be_bytes());
+	}
+	body.extend_from_slice(data);
+	pcapng_block(6, &body)
+}
+
+#[test]
+fn pcap_ethernet_vlan() {
+	let payload = v5_datagram();
+	let frame = ethernet_vlan(&ipv4_udp(&payload));
+	let file = pcap(LINKTYPE_ETHERNET as u32, &[(1_700_000_000, 250_000, &frame), (1_700_000_001, 0, &[0xFF; 20])]);
+
+	let packets = PcapReader::new(&file[..]).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+	assert_eq!(packets.len(), 2);
+	assert_eq!(packets[0].timestamp, UNIX_EPOCH + Duration::from_millis(1_700_000_000_250));
+
+	let udp = packets[0].udp().unwrap();
+	assert_eq!(udp.src, "192.0.2.1:40000".parse::<SocketAddr>().unwrap());
+	assert_eq!(udp.dst, "192.0.2.2:2055".parse::<SocketAddr>().unwrap());
+	assert_eq!(udp.payload, &payload[..]);
+	assert!(packets[1].udp().is_none());
+
+	let Ok((_, NetflowDatagramData::DatagramV5(dg))) = NetflowParser::new().parse(udp.payload, &udp.src) else { panic!("Not a v5 datagram") };
+	assert_eq!(dg.flow_seqnum, 7);
+}
+
+#[test]
+fn pcap_truncated_packet_is_skipped() {
+	let frame = ethernet_vlan(&ipv4_udp(&v5_datagram()));
+	let mut file = pcap(LINKTYPE_ETHERNET as u32, &[(0, 0, &frame[..60])]);
+	// Original length of the whole frame
+	file[36..40].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+
+	let packet = PcapReader::new(&file[..]).unwrap().next_packet().unwrap().unwrap();
+	assert!(packet.udp().is_none());
+}
+
+#[test]
+fn pcapng_interfaces_and_resolution() {
+	let payload = v5_datagram();
+	let mut file = pcapng_section();
+	// Raw IP with the default microsecond resolution
+	file.extend(pcapng_block(1, &[0, LINKTYPE_RAW as u8, 0, 0, 0, 0, 0, 0]));
+	// Ethernet with nanosecond resolution (if_tsresol = 9)
+	file.extend(pcapng_block(1, &[0, 1, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 9, 0, 0, 0, 0, 0, 0, 0]));
+	// Interface statistics, skipped
+	file.extend(pcapng_block(5, &[0; 12]));
+	file.extend(pcapng_enhanced_packet(1, 1_700_000_000_000_000_123, &ethernet_vlan(&ipv4_udp(&payload))));
+	file.extend(pcapng_enhanced_packet(0, 1_700_000_000_000_001, &ipv4_udp(&payload)));
+
+	let packets = PcapReader::new(&file[..]).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+	assert_eq!(packets.len(), 2);
+	assert_eq!(packets[0].timestamp, UNIX_EPOCH + Duration::new(1_700_000_000, 123));
+	assert_eq!(packets[1].timestamp, UNIX_EPOCH + Duration::new(1_700_000_000, 1000));
+	for packet in &packets {
+		assert_eq!(packet.udp().unwrap().payload, &payload[..]);
+	}
+}
+
+#[test]
+fn pcapng_timestamp_out_of_range() {
+	let mut file = pcapng_section();
+	// Resolution of whole seconds (if_tsresol = 0)
+	file.extend(pcapng_block(1, &[0, LINKTYPE_RAW as u8, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]));
+	file.extend(pcapng_enhanced_packet(0, u64::MAX, &ipv4_udp(&[])));
+
+	assert!(PcapReader::new(&file[..]).unwrap().next_packet().is_err());
+}
+
+#[test]
+fn pcapng_packet_of_unknown_interface() {
+	let mut file = pcapng_section();
+	file.extend(pcapng_enhanced_packet(0, 0, &ipv4_udp(&[])));
+
+	assert!(PcapReader::new(&file[..]).unwrap().next_packet().is_err());
+}
+
+#[test]
+fn not_a_capture_file() {
+	assert!(PcapReader::new(&v5_datagram()[..]).is_err());
+}
+
+#[test]
+fn file_ending_mid_packet() {
+	let file = pcap(LINKTYPE_RAW as u32, &[(0, 0, &ipv4_udp(&v5_datagram()))]);
+
+	let mut reader = PcapReader::new(&file[..file.len() - 10]).unwrap();
+	assert!(reader.next_packet().is_err());
+}
+
+#[cfg(feature = "collector")]
+#[test]
+fn datagrams_to_other_ports_are_skipped() {
+	use multiflow::collector::{for_each_pcap_datagram, CollectorStats};
+
+	let payload = v5_datagram();
+	let file = pcap(LINKTYPE_RAW as u32, &[(0, 0, &ipv4_udp_to(2055, &payload)), (0, 0, &ipv4_udp_to(53, &payload)), (0, 0, &ipv4_udp_to(6343, &payload))]);
+	let path = std::env::temp_dir().join(format!("multiflow-ports-{}.pcap", std::process::id()));
+	std::fs::write(&path, file).unwrap();
+
+	let received = |ports: &[u16]| {
+		let mut received = vec![];
+		for_each_pcap_datagram(&path, ports, &CollectorStats::default(), None, |dg| Some(dg.addr), |addr| received.push(addr)).unwrap();
+		received.len()
+	};
+	assert_eq!(received(&[2055, 6343]), 2);
+	assert_eq!(received(&[9995]), 0);
+	assert_eq!(received(&[]), 3);
+
+	std::fs::remove_file(&path).unwrap();
+}
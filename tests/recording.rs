@@ -0,0 +1,142 @@
+//! Datagram recordings and archives read back exactly what was written
+#![cfg(feature = "collector")]
+
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::time::{Duration, UNIX_EPOCH};
+use multiflow::collector::{for_each_datagram_with_idle, CollectorStats, FlowProtocol, ReceivedDatagram};
+use multiflow::collector::archive::{ArchiveConfig, ArchiveReader, DatagramArchive};
+use multiflow::collector::record::{RecordingReader, RecordingWriter};
+use multiflow::netflow_parse::datagram::NetflowDatagramData;
+use multiflow::netflow_parse::datagram_v5::{NetflowDatagramV5, NetflowDatagramV5Record};
+use multiflow::netflow_parse::NetflowParser;
+
+fn datagram(addr: &str, data: &[u8]) -> ReceivedDatagram {
+	ReceivedDatagram { data: data.to_vec(), addr: addr.parse().unwrap(), received: UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789) }
+}
+
+#[test]
+fn recording_round_trip() {
+	let datagrams = [
+		datagram("192.0.2.1:2055", &[0, 9, 0, 0]),
+		datagram("[2001:db8::1]:6343", &[0, 0, 0, 5, 0, 0, 0, 1]),
+		datagram("192.0.2.2:40000", &[]),
+	];
+
+	let mut writer = RecordingWriter::new(vec![]).unwrap();
+	for dg in &datagrams {
+		writer.write(dg).unwrap();
+	}
+	let bytes = writer.into_inner();
+
+	let read = RecordingReader::new(&bytes[..]).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+	assert_eq!(read.len(), 3);
+	for (r, dg) in read.iter().zip(&datagrams) {
+		assert_eq!(r.received, dg.received);
+		assert_eq!(r.addr, dg.addr);
+		assert_eq!(r.data, dg.data);
+	}
+	assert_eq!(read[0].protocol, Some(FlowProtocol::Netflow(9)));
+	assert_eq!(read[1].protocol, Some(FlowProtocol::SFlow));
+	assert_eq!(read[2].protocol, None);
+}
+
+#[test]
+fn truncated_recording() {
+	let mut writer = RecordingWriter::new(vec![]).unwrap();
+	writer.write(&datagram("192.0.2.1:2055", &[0, 5, 0, 0])).unwrap();
+	let bytes = writer.into_inner();
+
+	let mut reader = RecordingReader::new(&bytes[..bytes.len() - 1]).unwrap();
+	assert!(reader.next_datagram().is_err());
+	assert!(RecordingReader::new(&b"MFDG\x02\0\0\0"[..]).is_err());
+}
+
+/// Empty directory for a test, removed first if a previous run left it behind
+fn test_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("multiflow-{}-{}", name, std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	dir
+}
+
+fn v5(flow_seqnum: u32) -> Vec<u8> {
+	NetflowDatagramV5 {
+		sys_uptime_ms: 1000,
+		unix_sec: 1_700_000_000,
+		unix_nsec: 0,
+		flow_seqnum,
+		engine_type: 0,
+		engine_id: 0,
+		sampling_interval: 0,
+		flow_records: vec![NetflowDatagramV5Record::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2))],
+	}.to_bytes().unwrap()
+}
+
+#[test]
+fn archive_rotation_and_replay() {
+	let dir = test_dir("archive");
+	let mut config = ArchiveConfig::new(&dir);
+	// Every datagram gets a file of its own, only the last 3 are kept
+	config.rotate_size = 1;
+	config.max_files = 3;
+
+	let archive = DatagramArchive::open(config).unwrap();
+	for seq in 0..5 {
+		archive.record(&datagram("192.0.2.1:2055", &v5(seq))).unwrap();
+	}
+	archive.record(&datagram("192.0.2.1:6343", &[0, 0, 0, 5])).unwrap();
+	drop(archive);
+	assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+
+	let mut seqs = vec![];
+	ArchiveReader::open(&dir).unwrap().replay_netflow(&mut NetflowParser::new(), |dg, parsed| {
+		assert_eq!(dg.protocol, Some(FlowProtocol::Netflow(5)));
+		let Some(NetflowDatagramData::DatagramV5(parsed)) = parsed else { panic!("Not a v5 datagram") };
+		seqs.push(parsed.flow_seqnum);
+	}).unwrap();
+	assert_eq!(seqs, [3, 4]);
+
+	std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn archive_buffers_until_flushed() {
+	let dir = test_dir("archive-flush");
+	let archive = DatagramArchive::open(ArchiveConfig::new(&dir)).unwrap();
+	archive.record(&datagram("192.0.2.1:2055", &v5(0))).unwrap();
+	archive.record(&datagram("192.0.2.1:2055", &v5(1))).unwrap();
+
+	let path = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+	assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+	archive.flush().unwrap();
+	assert_eq!(ArchiveReader::open(&dir).unwrap().count(), 2);
+
+	// Datagrams recorded after a flush are written when the archive is dropped
+	archive.record(&datagram("192.0.2.1:2055", &v5(2))).unwrap();
+	drop(archive);
+	assert_eq!(ArchiveReader::open(&dir).unwrap().count(), 3);
+
+	std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn idle_collector_flushes_archive() {
+	let dir = test_dir("archive-idle");
+	let archive = DatagramArchive::open(ArchiveConfig::new(&dir)).unwrap();
+	archive.record(&datagram("192.0.2.1:2055", &v5(0))).unwrap();
+
+	// Nothing else arrives while the collector loop keeps running, the datagram still reaches the disk
+	let (tx, rx) = channel::<()>();
+	let reader_dir = dir.clone();
+	let check = std::thread::spawn(move || {
+		std::thread::sleep(Duration::from_millis(1500));
+		let count = ArchiveReader::open(&reader_dir).unwrap().count();
+		drop(tx);
+		count
+	});
+	for_each_datagram_with_idle(&rx, &CollectorStats::default(), None, |_| {}, || archive.flush().unwrap());
+	assert_eq!(check.join().unwrap(), 1);
+
+	std::fs::remove_dir_all(&dir).unwrap();
+}
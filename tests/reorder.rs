@@ -0,0 +1,88 @@
+//! Reorder buffer releases datagrams in sequence order, within the delay and stream limits
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use multiflow::netflow_parse::reorder::ReorderBuffer;
+
+/// NetFlow v9 header without any flow sets
+fn v9(seq: u32) -> Vec<u8> {
+	let mut bytes = vec![0, 9, 0, 0];
+	bytes.extend_from_slice(&1000u32.to_be_bytes());
+	bytes.extend_from_slice(&1_700_000_000u32.to_be_bytes());
+	bytes.extend_from_slice(&seq.to_be_bytes());
+	bytes.extend_from_slice(&0u32.to_be_bytes());
+	bytes
+}
+
+fn seq(data: &[u8]) -> u32 {
+	u32::from_be_bytes(data[12..16].try_into().unwrap())
+}
+
+fn exporter(n: u8) -> SocketAddr {
+	SocketAddr::from(([192, 0, 2, n], 2055))
+}
+
+#[test]
+fn releases_in_order() {
+	let mut buffer = ReorderBuffer::new();
+	let now = Instant::now();
+	let mut released = vec![];
+	for s in [1, 3, 4, 2, 5] {
+		buffer.push(v9(s), exporter(1), now, |_, data| released.push(seq(data)));
+	}
+
+	assert_eq!(released, [1, 2, 3, 4, 5]);
+	assert_eq!(buffer.pending(), 0);
+}
+
+#[test]
+fn expires_by_arrival_time() {
+	let max_delay = Duration::from_millis(100);
+	let mut buffer = ReorderBuffer::new().with_max_delay(max_delay);
+	let start = Instant::now();
+	let mut released = vec![];
+
+	buffer.push(v9(1), exporter(1), start, |_, data| released.push(seq(data)));
+	// 3 arrives before 2, both wait for the missing 2
+	buffer.push(v9(4), exporter(1), start, |_, data| released.push(seq(data)));
+	buffer.push(v9(3), exporter(1), start + Duration::from_millis(50), |_, data| released.push(seq(data)));
+	assert_eq!(released, [1]);
+
+	// The deadline is that of the datagram that arrived first, and expiring at it releases that datagram
+	let deadline = buffer.next_deadline().unwrap();
+	assert_eq!(deadline, start + max_delay);
+	buffer.expire(deadline, |_, data| released.push(seq(data)));
+	assert_eq!(released, [1, 3, 4]);
+	assert_eq!(buffer.next_deadline(), None);
+}
+
+#[test]
+fn expire_before_deadline_releases_nothing() {
+	let mut buffer = ReorderBuffer::new().with_max_delay(Duration::from_millis(100));
+	let start = Instant::now();
+	let mut released = vec![];
+
+	buffer.push(v9(1), exporter(1), start, |_, data| released.push(seq(data)));
+	buffer.push(v9(3), exporter(1), start, |_, data| released.push(seq(data)));
+	buffer.expire(start + Duration::from_millis(99), |_, data| released.push(seq(data)));
+	assert_eq!(released, [1]);
+	assert_eq!(buffer.pending(), 1);
+}
+
+#[test]
+fn stream_count_is_bounded() {
+	let mut buffer = ReorderBuffer::new().with_max_streams(2);
+	let start = Instant::now();
+	let mut released = vec![];
+
+	buffer.push(v9(1), exporter(1), start, |_, data| released.push(seq(data)));
+	buffer.push(v9(3), exporter(1), start, |_, data| released.push(seq(data)));
+	for n in 2..10 {
+		buffer.push(v9(1), exporter(n), start + Duration::from_millis(n as u64), |_, data| released.push(seq(data)));
+	}
+
+	assert_eq!(buffer.streams(), 2);
+	// The evicted stream released its held back datagram
+	assert_eq!(buffer.pending(), 0);
+	assert!(released.contains(&3));
+}
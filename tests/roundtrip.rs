@@ -0,0 +1,245 @@
+//! Round-trip property tests: encoding a datagram, parsing it, and encoding the result again must give the same bytes
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use proptest::prelude::*;
+use multiflow::netflow_parse::datagram::NetflowDatagramData;
+use multiflow::netflow_parse::datagram_v5::{NetflowDatagramV5, NetflowDatagramV5Record};
+use multiflow::netflow_parse::datagram_v9::{NetflowDatagramV9, NetflowDatagramV9FlowSet};
+use multiflow::netflow_parse::datagram_v9_data::{NetflowDatagramDataFlowSet, NetflowV9DataField, NetflowV9DataValue};
+use multiflow::netflow_parse::datagram_v9_template::{NetflowDatagramOptionsTemplate, NetflowDatagramOptionsTemplateScopeField,
+	NetflowDatagramOptionsTemplateSet, NetflowDatagramTemplate, NetflowDatagramTemplateField, NetflowDatagramTemplateSet};
+use multiflow::netflow_parse::encode::NetflowV9Encoder;
+use multiflow::netflow_parse::NetflowParser;
+use multiflow::sflow_parse::datagram::{parse_sflow_data, Datagram};
+use multiflow::sflow_parse::sample::counter::{SFlowCounterDataGeneric, SFlowCounterDataProcessor, SFlowCounterDataVLAN, SFlowCounterRecord, SFlowCounterSample};
+use multiflow::sflow_parse::sample::flow::{SFlowFlowRawPacketHeader, SFlowFlowSample, SFlowFlowSampleRecord};
+use multiflow::sflow_parse::sample::SFlowSample;
+
+fn exporter() -> SocketAddr {
+	"192.0.2.1:2055".parse().unwrap()
+}
+
+fn v5_record() -> impl Strategy<Value = NetflowDatagramV5Record> {
+	(any::<[u32; 9]>(), any::<[u16; 4]>(), any::<[u8; 5]>()).prop_map(|(w, h, b)| {
+		let mut r = NetflowDatagramV5Record::new(Ipv4Addr::from(w[0]), Ipv4Addr::from(w[1]));
+		r.next_hop_ip = Ipv4Addr::from(w[2]);
+		r.snmp_in_if_idx = h[0];
+		r.snmp_out_if_idx = h[1];
+		r.flow_packets = w[3];
+		r.flow_octets = w[4];
+		r.start_sys_uptime = w[5];
+		r.end_sys_uptime = w[6];
+		r.src_port = h[2];
+		r.dst_port = h[3];
+		r.tcp_flags = b[0];
+		r.ip_protocol = b[1];
+		r.ip_tos = b[2];
+		r.src_asn = w[7] as u16;
+		r.dst_asn = w[8] as u16;
+		r.src_mask = b[3];
+		r.dst_mask = b[4];
+		r
+	})
+}
+
+fn v5_datagram() -> impl Strategy<Value = NetflowDatagramV5> {
+	(any::<(u32, u32, u32, u32, u8, u8, u16)>(), prop::collection::vec(v5_record(), 0..=30))
+		.prop_map(|((sys_uptime_ms, unix_sec, unix_nsec, flow_seqnum, engine_type, engine_id, sampling_interval), flow_records)| NetflowDatagramV5 {
+			sys_uptime_ms, unix_sec, unix_nsec, flow_seqnum, engine_type, engine_id, sampling_interval, flow_records,
+		})
+}
+
+/// A template field together with a strategy for its values
+fn v9_field() -> impl Strategy<Value = NetflowDatagramTemplateField> {
+	prop_oneof![
+		// IN_BYTES, IN_PKTS, L4_SRC_PORT, SRC_AS: numbers of any supported size
+		(prop::sample::select(vec![1u16, 2, 7, 16]), prop::sample::select(vec![1u16, 2, 4, 8]))
+			.prop_map(|(type_id, len)| NetflowDatagramTemplateField::new(type_id, len)),
+		Just(NetflowDatagramTemplateField::new(8, 4)),
+		Just(NetflowDatagramTemplateField::new(27, 16)),
+		Just(NetflowDatagramTemplateField::new(56, 6)),
+		Just(NetflowDatagramTemplateField::new(82, 16)),
+		// Types unknown to the parser are kept as raw bytes
+		(40000u16..41000, 1u16..20).prop_map(|(type_id, len)| NetflowDatagramTemplateField::new(type_id, len)),
+	]
+}
+
+fn v9_value(field: &NetflowDatagramTemplateField) -> BoxedStrategy<NetflowV9DataValue> {
+	match (field.type_id, field.field_length) {
+		(8, _) => any::<u32>().prop_map(|a| NetflowV9DataValue::IPv4(Ipv4Addr::from(a))).boxed(),
+		(27, _) => any::<u128>().prop_map(|a| NetflowV9DataValue::IPv6(Ipv6Addr::from(a))).boxed(),
+		(56, _) => any::<[u8; 6]>().prop_map(|m| NetflowV9DataValue::MAC(format!("{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", m[0], m[1], m[2], m[3], m[4], m[5]))).boxed(),
+		(82, len) => "[a-z0-9]{16}".prop_map(move |s: String| NetflowV9DataValue::String(s[..len as usize].to_string())).boxed(),
+		(1 | 2 | 7 | 16, len) => {
+			let max = if len == 8 { u64::MAX } else { (1u64 << (len * 8)) - 1 };
+			(0..=max).prop_map(NetflowV9DataValue::Number).boxed()
+		}
+		(_, len) => prop::collection::vec(any::<u8>(), len as usize).prop_map(NetflowV9DataValue::Unknown).boxed(),
+	}
+}
+
+fn v9_records(fields: Vec<NetflowDatagramTemplateField>) -> impl Strategy<Value = Vec<Vec<NetflowV9DataField>>> {
+	let record = fields.iter().map(|f| {
+		let type_id = f.type_id;
+		v9_value(f).prop_map(move |value| NetflowV9DataField { name: "", type_id, value, enterprise: None })
+	}).collect::<Vec<_>>();
+
+	prop::collection::vec(record, 1..5)
+}
+
+fn v9_datagram() -> impl Strategy<Value = NetflowDatagramV9> {
+	// Records shorter than 4 bytes cannot be told apart from the padding at the end of a flow set
+	let template = prop::collection::vec(v9_field(), 1..10)
+		.prop_filter("record shorter than 4 bytes", |fields| fields.iter().map(|f| f.field_length).sum::<u16>() >= 4);
+	let options_fields = prop::collection::vec(v9_field(), 1..4);
+
+	(template, options_fields, any::<(u32, u32, u32, u32)>())
+		.prop_flat_map(|(fields, option_fields, header)| {
+			// The SCOPE_SYSTEM scope field gets number values like IN_BYTES
+			let record_fields: Vec<_> = [NetflowDatagramTemplateField::new(1, 4)].into_iter().chain(option_fields.iter().copied()).collect();
+			(Just(fields.clone()), Just(option_fields), Just(header), v9_records(fields), v9_records(record_fields))
+		})
+		.prop_map(|(fields, option_fields, (sys_uptime_ms, unix_sec, package_sequence, source_id), records, option_records)| {
+			let template = NetflowDatagramTemplate { template_id: 256, field_count: fields.len() as u16, fields };
+			let options_template = NetflowDatagramOptionsTemplate {
+				template_id: 257,
+				scope_field_count: 1,
+				option_field_count: option_fields.len() as u16,
+				scope_fields: vec![NetflowDatagramOptionsTemplateScopeField::new(1, 4)],
+				option_fields,
+			};
+			NetflowDatagramV9 {
+				sys_uptime_ms,
+				unix_sec,
+				package_sequence,
+				source_id,
+				flow_records: vec![
+					NetflowDatagramV9FlowSet::Template(NetflowDatagramTemplateSet::from_templates(&[template])),
+					NetflowDatagramV9FlowSet::TemplateOption(NetflowDatagramOptionsTemplateSet::from_templates(&[options_template])),
+					NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, records)),
+					NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::options(257, option_records)),
+				],
+			}
+		})
+}
+
+fn counter_record() -> impl Strategy<Value = SFlowCounterRecord> {
+	prop_oneof![
+		(any::<[u32; 16]>(), any::<[u64; 3]>()).prop_map(|(w, l)| SFlowCounterRecord::Generic(SFlowCounterDataGeneric {
+			index: w[0], interface_type: w[1], speed: l[0], direction: w[2], status: w[3], in_octets: l[1],
+			in_ucast_packets: w[4], in_multicast_packets: w[5], in_broadcast_packets: w[6], in_discarded: w[7], in_errors: w[8],
+			in_unknown_protos: w[9], out_octets: l[2], out_ucast_packets: w[10], out_multicast_packets: w[11],
+			out_broadcast_packets: w[12], out_discarded: w[13], out_errors: w[14], out_promiscuous: w[15],
+		})),
+		(any::<[u32; 5]>(), any::<u64>()).prop_map(|(w, octets)| SFlowCounterRecord::VLAN(SFlowCounterDataVLAN {
+			vlan_id: w[0], octets, ucast_packets: w[1], multicast_packets: w[2], broadcast_packets: w[3], discards: w[4],
+		})),
+		(any::<[u32; 3]>(), any::<[u64; 2]>()).prop_map(|(w, l)| SFlowCounterRecord::Processor(SFlowCounterDataProcessor {
+			cpu_percent_5s: w[0], cpu_percent_1m: w[1], cpu_percent_5m: w[2], total_memory: l[0], free_memory: l[1],
+		})),
+	]
+}
+
+fn sflow_sample() -> impl Strategy<Value = SFlowSample> {
+	let raw = (any::<[u32; 3]>(), prop::collection::vec(any::<u8>(), 0..130))
+		.prop_map(|(w, header)| SFlowFlowSampleRecord::Raw(SFlowFlowRawPacketHeader::new(w[0], w[1], w[2], header)));
+
+	prop_oneof![
+		(any::<[u32; 7]>(), prop::collection::vec(raw, 0..4)).prop_map(|(w, records)| SFlowSample::Flow(SFlowFlowSample {
+			seq: w[0], src: w[1], rate: w[2], pool: w[3], dropped: w[4], input_if: w[5], output_if: w[6],
+			record_count: records.len() as u32, records,
+		})),
+		(any::<(u32, u32)>(), prop::collection::vec(counter_record(), 0..4))
+			.prop_map(|((seq, src), records)| SFlowSample::Counter(SFlowCounterSample::new(seq, src, records))),
+	]
+}
+
+fn sflow_datagram() -> impl Strategy<Value = Datagram> {
+	let agent = prop_oneof![
+		any::<u32>().prop_map(|a| IpAddr::V4(Ipv4Addr::from(a))),
+		any::<u128>().prop_map(|a| IpAddr::V6(Ipv6Addr::from(a))),
+	];
+
+	(agent, any::<(u32, u32, u32)>(), prop::collection::vec(sflow_sample(), 0..6))
+		.prop_map(|(agent_addr, (sub_agent_id, seq_num, uptime), sample_record)| Datagram {
+			sflow_version: 5, agent_addr, sub_agent_id, seq_num, uptime, sample_record,
+		})
+}
+
+proptest! {
+	#[test]
+	fn netflow_v5_roundtrip(dg in v5_datagram()) {
+		let bytes = dg.to_bytes().unwrap();
+		let (rest, parsed) = NetflowParser::new().parse(&bytes, &exporter()).unwrap();
+		prop_assert!(rest.is_empty());
+
+		let NetflowDatagramData::DatagramV5(parsed) = parsed else { panic!("Not parsed as NetFlow v5") };
+		prop_assert_eq!(parsed.to_bytes().unwrap(), bytes);
+	}
+
+	#[test]
+	fn netflow_v9_roundtrip(dg in v9_datagram()) {
+		let bytes = NetflowV9Encoder::new().encode(&dg).unwrap();
+		let (rest, parsed) = NetflowParser::new().parse(&bytes, &exporter()).unwrap();
+		prop_assert!(rest.is_empty());
+
+		let NetflowDatagramData::DatagramV9(parsed) = parsed else { panic!("Not parsed as NetFlow v9") };
+		prop_assert_eq!(parsed.flow_records.len(), 4);
+		prop_assert_eq!(NetflowV9Encoder::new().encode(&parsed).unwrap(), bytes);
+	}
+
+	#[test]
+	fn netflow_v9_data_with_known_templates(dg in v9_datagram()) {
+		// Templates sent in an earlier datagram are used for later ones
+		let mut encoder = NetflowV9Encoder::new();
+		let mut parser = NetflowParser::new();
+		let templates = NetflowDatagramV9 { flow_records: dg.flow_records[..2].to_vec(), ..dg.clone() };
+		parser.parse(&encoder.encode(&templates).unwrap(), &exporter()).unwrap();
+
+		let data = NetflowDatagramV9 { flow_records: dg.flow_records[2..].to_vec(), ..dg };
+		let bytes = encoder.encode(&data).unwrap();
+		let (rest, parsed) = parser.parse(&bytes, &exporter()).unwrap();
+		prop_assert!(rest.is_empty());
+
+		let NetflowDatagramData::DatagramV9(parsed) = parsed else { panic!("Not parsed as NetFlow v9") };
+		prop_assert_eq!(encoder.encode(&parsed).unwrap(), bytes);
+	}
+
+	#[test]
+	fn sflow_roundtrip(dg in sflow_datagram()) {
+		let bytes = dg.to_bytes().unwrap();
+		let (rest, parsed) = parse_sflow_data(&bytes).unwrap();
+		prop_assert!(rest.is_empty());
+		prop_assert_eq!(parsed.to_bytes().unwrap(), bytes);
+	}
+}
+
+#[test]
+fn netflow_v9_short_records_skip_padding() {
+	// Records of 1, 2, and 3 bytes padded to 4 bytes, and four 1-byte records without padding
+	let cases: [(u16, &[u64]); 4] = [(1, &[1, 2, 3]), (2, &[7]), (3, &[0, 9]), (1, &[1, 2, 3, 4])];
+	for (length, values) in cases {
+		let fields = vec![NetflowDatagramTemplateField::new(1, length)];
+		let template = NetflowDatagramTemplate { template_id: 256, field_count: 1, fields };
+		let records = values.iter().map(|n| vec![NetflowV9DataField { name: "", type_id: 1, value: NetflowV9DataValue::Number(*n), enterprise: None }]).collect();
+		let dg = NetflowDatagramV9 {
+			sys_uptime_ms: 1000,
+			unix_sec: 1_700_000_000,
+			package_sequence: 1,
+			source_id: 0,
+			flow_records: vec![
+				NetflowDatagramV9FlowSet::Template(NetflowDatagramTemplateSet::from_templates(&[template])),
+				NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, records)),
+			],
+		};
+
+		let bytes = NetflowV9Encoder::new().encode(&dg).unwrap();
+		let Ok((_, NetflowDatagramData::DatagramV9(parsed))) = NetflowParser::new().parse(&bytes, &exporter()) else { panic!("Not parsed as NetFlow v9") };
+		let NetflowDatagramV9FlowSet::Data(data) = &parsed.flow_records[1] else { panic!("Not a data set") };
+		let parsed = data.record_list().iter().map(|r| match r[0].value {
+			NetflowV9DataValue::Number(n) => n,
+			_ => panic!("Not a number: {:?}", r[0]),
+		}).collect::<Vec<_>>();
+		assert_eq!(parsed, values, "{}-byte records", length);
+	}
+}
@@ -0,0 +1,102 @@
+//! Sequence tracking tells gaps, duplicates, late datagrams, and restarts apart
+
+use std::net::{IpAddr, Ipv4Addr};
+use multiflow::netflow_parse::datagram::NetflowDatagramData;
+use multiflow::netflow_parse::datagram_v5::{NetflowDatagramV5, NetflowDatagramV5Record};
+use multiflow::sequence::{SequenceEvent, SequenceKey, SequenceTracker};
+
+const EXPORTER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+const KEY: SequenceKey = SequenceKey::NetflowV9 { exporter: EXPORTER, source_id: 0 };
+
+#[test]
+fn gap() {
+	let mut tracker = SequenceTracker::new();
+	assert_eq!(tracker.observe(KEY, 10, 1, Some(1000)), None);
+	assert_eq!(tracker.observe(KEY, 11, 1, Some(1010)), None);
+	assert_eq!(tracker.observe(KEY, 15, 1, Some(1020)), Some(SequenceEvent::Gap { key: KEY, expected: 12, received: 15, missing: 3 }));
+
+	let stats = tracker.stats(&KEY).unwrap();
+	assert_eq!((stats.received, stats.missing, stats.next_expected), (3, 3, 16));
+}
+
+#[test]
+fn duplicate() {
+	let mut tracker = SequenceTracker::new();
+	tracker.observe(KEY, 10, 1, Some(1000));
+	tracker.observe(KEY, 11, 1, Some(1010));
+	assert_eq!(tracker.observe(KEY, 10, 1, Some(1000)), Some(SequenceEvent::Duplicate { key: KEY, seq: 10 }));
+	assert_eq!(tracker.observe(KEY, 12, 1, Some(1020)), None);
+	assert_eq!(tracker.stats(&KEY).unwrap().duplicates, 1);
+}
+
+#[test]
+fn late_datagram_is_reordered_not_restart() {
+	let mut tracker = SequenceTracker::new();
+	tracker.observe(KEY, 10, 1, Some(1000));
+	tracker.observe(KEY, 12, 1, Some(1020));
+
+	// The late datagram carries an older uptime
+	assert_eq!(tracker.observe(KEY, 11, 1, Some(1010)), Some(SequenceEvent::Reordered { key: KEY, seq: 11, expected: 13, units: 1 }));
+	assert_eq!(tracker.observe(KEY, 13, 1, Some(1030)), None);
+	// A late duplicate is still a duplicate
+	assert_eq!(tracker.observe(KEY, 11, 1, Some(1010)), Some(SequenceEvent::Duplicate { key: KEY, seq: 11 }));
+
+	let stats = tracker.stats(&KEY).unwrap();
+	assert_eq!((stats.missing, stats.reordered, stats.restarts), (0, 1, 0));
+	assert_eq!(stats.last_uptime, Some(1030));
+}
+
+#[test]
+fn restart() {
+	let mut tracker = SequenceTracker::new().with_reorder_window(100);
+	tracker.observe(KEY, 1000, 1, Some(50_000));
+
+	// Uptime went back and the sequence number is beyond the reorder window
+	assert_eq!(tracker.observe(KEY, 0, 1, Some(100)), Some(SequenceEvent::Restart { key: KEY, seq: 0 }));
+	assert_eq!(tracker.observe(KEY, 1, 1, Some(110)), None);
+	// Uptime went back with a sequence number ahead of the expected one
+	assert_eq!(tracker.observe(KEY, 5000, 1, Some(50)), Some(SequenceEvent::Restart { key: KEY, seq: 5000 }));
+	// Far jump back without an uptime
+	assert_eq!(tracker.observe(KEY, 10, 1, None), Some(SequenceEvent::Restart { key: KEY, seq: 10 }));
+	assert_eq!(tracker.stats(&KEY).unwrap().restarts, 3);
+}
+
+#[test]
+fn wraparound() {
+	let mut tracker = SequenceTracker::new();
+	tracker.observe(KEY, u32::MAX - 1, 1, Some(1000));
+	assert_eq!(tracker.observe(KEY, u32::MAX, 1, Some(1010)), None);
+	assert_eq!(tracker.observe(KEY, 0, 1, Some(1020)), None);
+	assert_eq!(tracker.observe(KEY, 3, 1, Some(1030)), Some(SequenceEvent::Gap { key: KEY, expected: 1, received: 3, missing: 2 }));
+
+	// Uptime wrapping around is not a restart
+	tracker.observe(KEY, 4, 1, Some(u32::MAX - 10));
+	assert_eq!(tracker.observe(KEY, 5, 1, Some(20)), None);
+	assert_eq!(tracker.stats(&KEY).unwrap().restarts, 0);
+}
+
+fn v5(flow_seqnum: u32, records: usize) -> NetflowDatagramData {
+	NetflowDatagramData::DatagramV5(NetflowDatagramV5 {
+		sys_uptime_ms: 1000 + flow_seqnum,
+		unix_sec: 1_700_000_000,
+		unix_nsec: 0,
+		flow_seqnum,
+		engine_type: 0,
+		engine_id: 0,
+		sampling_interval: 0,
+		flow_records: vec![NetflowDatagramV5Record::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)); records],
+	})
+}
+
+#[test]
+fn empty_v5_datagrams_are_not_duplicates() {
+	let mut tracker = SequenceTracker::new();
+	assert!(tracker.observe_netflow(EXPORTER, &v5(0, 2)).is_empty());
+	assert!(tracker.observe_netflow(EXPORTER, &v5(2, 0)).is_empty());
+	assert!(tracker.observe_netflow(EXPORTER, &v5(2, 0)).is_empty());
+	assert!(tracker.observe_netflow(EXPORTER, &v5(2, 3)).is_empty());
+	assert!(tracker.observe_netflow(EXPORTER, &v5(5, 1)).is_empty());
+
+	let (_, stats) = tracker.iter().next().unwrap();
+	assert_eq!((stats.received, stats.duplicates, stats.missing), (5, 0, 0));
+}
@@ -0,0 +1,88 @@
+//! Serde representations of parsed datagrams survive a JSON round trip
+#![cfg(feature = "json")]
+
+use std::net::{Ipv4Addr, SocketAddr};
+use multiflow::netflow_parse::datagram::NetflowDatagramData;
+use multiflow::netflow_parse::datagram_v9::{NetflowDatagramV9, NetflowDatagramV9FlowSet};
+use multiflow::netflow_parse::datagram_v9_data::{NetflowDatagramDataFlowSet, NetflowV9DataField, NetflowV9DataValue};
+use multiflow::netflow_parse::datagram_v9_template::{NetflowDatagramTemplate, NetflowDatagramTemplateField, NetflowDatagramTemplateSet};
+use multiflow::netflow_parse::encode::NetflowV9Encoder;
+use multiflow::netflow_parse::NetflowParser;
+
+/// v9 datagram with a template and a data record holding an address, a number, and a field of unknown type
+fn v9_datagram() -> NetflowDatagramData {
+	let template = NetflowDatagramTemplate {
+		template_id: 256,
+		field_count: 3,
+		fields: vec![NetflowDatagramTemplateField::new(8, 4), NetflowDatagramTemplateField::new(1, 4), NetflowDatagramTemplateField::new(40000, 2)],
+	};
+	let record = vec![
+		NetflowV9DataField { name: "", type_id: 8, value: NetflowV9DataValue::IPv4(Ipv4Addr::new(10, 0, 0, 1)), enterprise: None },
+		NetflowV9DataField { name: "", type_id: 1, value: NetflowV9DataValue::Number(1500), enterprise: None },
+		NetflowV9DataField { name: "", type_id: 40000, value: NetflowV9DataValue::Unknown(vec![0xAB, 0xCD]), enterprise: None },
+	];
+	let dg = NetflowDatagramV9 {
+		sys_uptime_ms: 1000,
+		unix_sec: 1_700_000_000,
+		package_sequence: 1,
+		source_id: 0,
+		flow_records: vec![
+			NetflowDatagramV9FlowSet::Template(NetflowDatagramTemplateSet::from_templates(&[template])),
+			NetflowDatagramV9FlowSet::Data(NetflowDatagramDataFlowSet::regular(256, vec![record])),
+		],
+	};
+	let bytes = NetflowV9Encoder::new().encode(&dg).unwrap();
+
+	let addr: SocketAddr = "192.0.2.1:2055".parse().unwrap();
+	NetflowParser::new().parse(&bytes, &addr).unwrap().1
+}
+
+#[test]
+fn v9_round_trip() {
+	let json = serde_json::to_string(&v9_datagram()).unwrap();
+	let back: NetflowDatagramData = serde_json::from_str(&json).unwrap();
+	assert_eq!(serde_json::to_string(&back).unwrap(), json);
+
+	let NetflowDatagramData::DatagramV9(dg) = back else { panic!("Not a v9 datagram") };
+	// Type information and static names are restored from the type map
+	let NetflowDatagramV9FlowSet::Template(templates) = &dg.flow_records[0] else { panic!("Not a template set") };
+	assert_eq!(templates.fields_vec[0][0], NetflowDatagramTemplateField::new(8, 4));
+	assert!(templates.fields_vec[0][2].field_type.is_none());
+
+	let NetflowDatagramV9FlowSet::Data(data) = &dg.flow_records[1] else { panic!("Not a data set") };
+	let record = &data.record_list()[0];
+	assert_eq!(record.iter().map(|f| f.name).collect::<Vec<_>>(), ["IPV4_SRC_ADDR", "IN_BYTES", "UNKNOWN"]);
+	assert!(matches!(&record[2].value, NetflowV9DataValue::Unknown(b) if b == &[0xAB, 0xCD]));
+}
+
+#[test]
+fn values_use_plain_representations() {
+	let json = serde_json::to_value(v9_datagram()).unwrap();
+	let fields = &json["DatagramV9"]["flow_records"][1]["Data"]["records"]["Regular"][0];
+
+	assert_eq!(fields[0]["value"], serde_json::json!({ "IPv4": "10.0.0.1" }));
+	// Raw bytes as lowercase hex
+	assert_eq!(fields[2]["value"], serde_json::json!({ "Unknown": "abcd" }));
+}
+
+#[test]
+fn invalid_hex_is_rejected() {
+	assert!(serde_json::from_str::<NetflowV9DataValue>(r#"{ "Unknown": "abc" }"#).is_err());
+	assert!(serde_json::from_str::<NetflowV9DataValue>(r#"{ "Unknown": "zz" }"#).is_err());
+	assert!(matches!(serde_json::from_str(r#"{ "Unknown": "00FF" }"#), Ok(NetflowV9DataValue::Unknown(b)) if b == [0, 255]));
+}
+
+#[test]
+fn enterprise_fields_round_trip() {
+	// Only enterprise-specific fields carry an enterprise number
+	assert!(!serde_json::to_string(&v9_datagram()).unwrap().contains("enterprise"));
+
+	let field = NetflowDatagramTemplateField::enterprise(1, 29305, 4);
+	let back: NetflowDatagramTemplateField = serde_json::from_str(&serde_json::to_string(&field).unwrap()).unwrap();
+	assert_eq!(back, field);
+	assert_eq!(back.field_type, NetflowDatagramTemplateField::new(1, 4).field_type);
+
+	let json = r#"{ "name": "IN_BYTES", "type_id": 1, "value": { "Number": 5 }, "enterprise": 29305 }"#;
+	let field: NetflowV9DataField = serde_json::from_str(json).unwrap();
+	assert_eq!((field.name, field.enterprise), ("IN_BYTES", Some(29305)));
+}
@@ -0,0 +1,90 @@
+//! sFlow parsing and encoding of hand-built datagrams
+
+use std::net::{IpAddr, Ipv4Addr};
+use multiflow::sflow_parse::datagram::{parse_sflow_data, Datagram};
+use multiflow::sflow_parse::encode::SFlowEncodeError;
+use multiflow::sflow_parse::sample::flow::{SFlowFlowRawPacketHeader, SFlowFlowSample, SFlowFlowSampleRecord};
+use multiflow::sflow_parse::sample::SFlowSample;
+
+fn words(values: &[u32]) -> Vec<u8> {
+	values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+/// Raw packet header record with the header padded to a multiple of 4 bytes
+fn raw_header_record(header: &[u8]) -> Vec<u8> {
+	let padding = (4 - header.len() % 4) % 4;
+	let mut record = words(&[1, (16 + header.len() + padding) as u32, 1, 64, 4, header.len() as u32]);
+	record.extend_from_slice(header);
+	record.resize(record.len() + padding, 0);
+	record
+}
+
+/// Datagram from agent 192.0.2.1 with a single flow sample holding raw packet header records with the given headers
+fn flow_datagram(headers: &[&[u8]]) -> Vec<u8> {
+	let mut sample = words(&[1, 0, 1, 3, 100, 1000, 0, 1, 2, headers.len() as u32]);
+	for header in headers {
+		sample.extend(raw_header_record(header));
+	}
+	let length = (sample.len() - 8) as u32;
+	sample[4..8].copy_from_slice(&length.to_be_bytes());
+
+	let mut datagram = words(&[5, 1, 0xc0000201, 0, 1, 1000, 1]);
+	datagram.extend(sample);
+	datagram
+}
+
+#[test]
+fn raw_header_padding_is_skipped() {
+	let datagram = flow_datagram(&[&[1, 2, 3, 4, 5], &[6, 7, 8, 9]]);
+
+	let (rest, parsed) = parse_sflow_data(&datagram).unwrap();
+	assert!(rest.is_empty());
+	let SFlowSample::Flow(sample) = &parsed.sample_record[0] else { panic!("Not a flow sample") };
+	let headers = sample.records.iter().map(|r| match r {
+		SFlowFlowSampleRecord::Raw(raw) => raw.header.clone(),
+		_ => panic!("Not a raw packet header"),
+	}).collect::<Vec<_>>();
+	assert_eq!(headers, [vec![1, 2, 3, 4, 5], vec![6, 7, 8, 9]]);
+}
+
+fn datagram(records: Vec<SFlowFlowSampleRecord>) -> Datagram {
+	Datagram {
+		sflow_version: 5,
+		agent_addr: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+		sub_agent_id: 0,
+		seq_num: 1,
+		uptime: 1000,
+		sample_record: vec![SFlowSample::Flow(SFlowFlowSample {
+			seq: 1,
+			src: 3,
+			rate: 100,
+			pool: 1000,
+			dropped: 0,
+			input_if: 1,
+			output_if: 2,
+			// Not used for encoding
+			record_count: 0,
+			records,
+		})],
+	}
+}
+
+#[test]
+fn encode_raw_headers() {
+	let dg = datagram(vec![
+		SFlowFlowSampleRecord::Raw(SFlowFlowRawPacketHeader::new(1, 64, 4, vec![1, 2, 3, 4, 5])),
+		SFlowFlowSampleRecord::Raw(SFlowFlowRawPacketHeader::new(1, 64, 4, vec![6, 7, 8, 9])),
+	]);
+
+	assert_eq!(dg.to_bytes().unwrap(), flow_datagram(&[&[1, 2, 3, 4, 5], &[6, 7, 8, 9]]));
+}
+
+#[test]
+fn encode_unsupported() {
+	assert_eq!(datagram(vec![SFlowFlowSampleRecord::Ethernet]).to_bytes().unwrap_err(),
+		SFlowEncodeError::Unsupported("flow records other than raw packet headers"));
+
+	let mut dg = datagram(vec![]);
+	dg.sample_record.push(SFlowSample::ExpFlow);
+	assert_eq!(dg.to_bytes().unwrap_err(), SFlowEncodeError::Unsupported("expanded flow samples"));
+}
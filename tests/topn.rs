@@ -0,0 +1,120 @@
+//! Space-Saving error guarantees and top-N windows
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use multiflow::aggregate::{FlowKey, KeyField};
+use multiflow::flow::FlowRecord;
+use multiflow::topn::{SpaceSaving, TopMetric, TopN};
+
+fn key(port: u16) -> FlowKey {
+	FlowKey { dst_port: Some(port), ..Default::default() }
+}
+
+#[test]
+fn space_saving_error_bounds() {
+	let capacity = 20;
+	let mut summary = SpaceSaving::new(capacity);
+	let mut truth = HashMap::new();
+
+	// A few heavy hitters among many light keys, in a fixed pseudo-random order
+	let mut state = 1u32;
+	for _ in 0..10_000 {
+		state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+		let r = (state >> 16) % 100;
+		let port = if r < 40 { (r % 4) as u16 } else { 100 + (state >> 8) as u16 % 500 };
+		let weight = 1 + (state % 3) as u64;
+		summary.add(key(port), weight);
+		*truth.entry(port).or_insert(0u64) += weight;
+	}
+
+	let total: u64 = truth.values().sum();
+	assert_eq!(summary.total(), total);
+
+	let top = summary.top(capacity);
+	assert_eq!(top.len(), capacity);
+	assert!(top.windows(2).all(|w| w[0].estimate >= w[1].estimate));
+	for entry in &top {
+		let count = truth[&entry.key.dst_port.unwrap()];
+		assert!(entry.estimate >= count, "{:?} underestimates {}", entry, count);
+		assert!(entry.estimate - entry.error <= count, "{:?} error bound misses {}", entry, count);
+	}
+
+	// Every key above total / capacity is tracked
+	for (port, count) in &truth {
+		if *count > total / capacity as u64 {
+			assert!(top.iter().any(|e| e.key == key(*port)), "Heavy hitter {} with {} missing", port, count);
+		}
+	}
+	let mut heavy = top[..4].iter().map(|e| e.key.dst_port.unwrap()).collect::<Vec<_>>();
+	heavy.sort();
+	assert_eq!(heavy, [0, 1, 2, 3]);
+}
+
+#[test]
+fn space_saving_exact_below_capacity() {
+	let mut summary = SpaceSaving::new(4);
+	for (port, weight) in [(1, 5), (2, 3), (1, 2), (3, 1)] {
+		summary.add(key(port), weight);
+	}
+
+	let top = summary.top(2);
+	assert_eq!(top.iter().map(|e| (e.key, e.estimate, e.error)).collect::<Vec<_>>(), [(key(1), 7, 0), (key(2), 3, 0)]);
+
+	summary.clear();
+	assert_eq!(summary.total(), 0);
+	assert!(summary.top(2).is_empty());
+}
+
+fn flow(dst_port: u16, bytes: u64) -> FlowRecord {
+	FlowRecord {
+		exporter: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+		src_addr: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+		dst_addr: Some(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1))),
+		src_port: Some(40000),
+		dst_port: Some(dst_port),
+		protocol: Some(6),
+		tos: None,
+		tcp_flags: None,
+		src_as: None,
+		dst_as: None,
+		input_if: None,
+		output_if: None,
+		start: None,
+		end: None,
+		bytes,
+		packets: 1,
+		flows: 1,
+		sampling_rate: 1,
+	}
+}
+
+/// Time `secs` after the start of a minute
+fn at(secs: u64) -> SystemTime {
+	UNIX_EPOCH + Duration::from_secs(1_699_999_980 + secs)
+}
+
+#[test]
+fn window_rollover_flushes() {
+	let windows = RefCell::new(vec![]);
+	let mut top = TopN::new(vec![KeyField::DstPort], TopMetric::Bytes, 2, Duration::from_secs(60), |w| windows.borrow_mut().push(w));
+
+	top.add(&flow(443, 100), at(0));
+	top.add(&flow(80, 300), at(10));
+	top.add(&flow(53, 10), at(20));
+	top.add(&flow(443, 250), at(59));
+	assert!(windows.borrow().is_empty());
+
+	top.add(&flow(22, 1), at(60));
+	assert_eq!(windows.borrow().len(), 1);
+	top.tick(at(120));
+	drop(top);
+
+	let windows = windows.into_inner();
+	assert_eq!(windows.len(), 2);
+	assert_eq!((windows[0].start, windows[0].end, windows[0].total), (at(0), at(60), 660));
+	assert_eq!(windows[0].entries.iter().map(|e| (e.key.dst_port, e.estimate)).collect::<Vec<_>>(), [(Some(443), 350), (Some(80), 300)]);
+	assert_eq!(windows[1].total, 1);
+	assert_eq!(windows[1].entries[0].key, key(22));
+}